@@ -0,0 +1,344 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Target address parsing. Hostnames and IPv4 addresses are handed to the
+//! system resolver, while bracketed IPv6 literals are parsed here so that a
+//! zone may be given either as an interface name or a numeric index, as in
+//! `[fe80::1%eth0]:22` or `[fe80::1%12]:22`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Addresses of host names, keyed by lowercase name, that override
+/// resolution for a run
+pub type StaticHosts = HashMap<String, Vec<IpAddr>>;
+
+/// Address family targets are resolved and connected over
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    V4,
+    V6,
+    /// Either, in the order the resolver returns them
+    #[default]
+    Any,
+}
+
+impl IpVersion {
+    /// Returns whether `ip` belongs to this family
+    pub fn admits(&self, ip: IpAddr) -> bool {
+	match self {
+	    IpVersion::V4 => ip.is_ipv4(),
+	    IpVersion::V6 => ip.is_ipv6(),
+	    IpVersion::Any => true,
+	}
+    }
+
+    /// Returns the error of a `host` left without an address of this family
+    pub fn missing(&self, host: &str) -> io::Error {
+	let family = match self {
+	    IpVersion::V4 => "IPv4",
+	    IpVersion::V6 => "IPv6",
+	    IpVersion::Any => "IP",
+	};
+	io::Error::new(io::ErrorKind::NotFound, format!("{} has no {} address", host, family))
+    }
+}
+
+/// Resolves a `host:port` address into every socket address it names
+pub fn resolve(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    match addr.strip_prefix('[') {
+	Some(rest) => {
+	    let (literal, port) = rest.split_once(']')
+		.ok_or_else(|| invalid(format!("unbalanced brackets in {}", addr)))?;
+	    let port = port.strip_prefix(':')
+		.ok_or_else(|| invalid(format!("missing port in {}", addr)))?;
+	    let port = port.parse().map_err(|_| invalid(format!("invalid port in {}", addr)))?;
+	    Ok(vec![parse_v6(literal, port)?])
+	},
+	None if addr.contains(']') => Err(invalid(format!("unbalanced brackets in {}", addr))),
+	None => Ok(addr.to_socket_addrs()?.collect()),
+    }
+}
+
+/// Resolves an address that may omit its port, using `port` in that case
+pub fn resolve_or_port(addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Some(literal) = addr.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+	return Ok(vec![parse_v6(literal, port)?])
+    }
+    if let Ok(addrs) = resolve(addr) {
+	return Ok(addrs)
+    }
+    if addr.contains('%') {
+	return Ok(vec![parse_v6(addr, port)?])
+    }
+    Ok((addr, port).to_socket_addrs()?.collect())
+}
+
+/// Returns the byte range of the host within `addr`, a URL or a `host:port`
+/// address
+pub fn host_span(addr: &str) -> std::ops::Range<usize> {
+    let start = addr.find("://").map_or(0, |i| i + 3);
+    let end = addr[start..].find(['/', '?', '#']).map_or(addr.len(), |i| start + i);
+    let start = addr[start..end].rfind('@').map_or(start, |i| start + i + 1);
+    let authority = &addr[start..end];
+    let len = match authority.starts_with('[') {
+	true => authority.find(']').map_or(authority.len(), |i| i + 1),
+	false => authority.find(':').unwrap_or(authority.len()),
+    };
+    start..start + len
+}
+
+/// Returns true if `ip` lies within `net/prefix`
+pub fn in_cidr(ip: IpAddr, net: IpAddr, prefix: u32) -> bool {
+    match (ip, net) {
+	(IpAddr::V4(ip), IpAddr::V4(net)) if prefix <= 32 => {
+	    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+	    u32::from(ip) & mask == u32::from(net) & mask
+	},
+	(IpAddr::V6(ip), IpAddr::V6(net)) if prefix <= 128 => {
+	    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+	    u128::from(ip) & mask == u128::from(net) & mask
+	},
+	_ => false,
+    }
+}
+
+/// Parses a CIDR block such as `203.0.113.0/24` into its network and
+/// prefix length. A lone address is a block of one.
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u32), String> {
+    let invalid = || format!("`{}` is not a CIDR block such as 203.0.113.0/24", cidr);
+    let (net, prefix) = match cidr.trim().split_once('/') {
+	Some((net, prefix)) => (net.parse::<IpAddr>().map_err(|_| invalid())?, prefix.parse().map_err(|_| invalid())?),
+	None => {
+	    let net = cidr.trim().parse::<IpAddr>().map_err(|_| invalid())?;
+	    (net, if net.is_ipv4() { 32 } else { 128 })
+	},
+    };
+    match (net, prefix) {
+	(IpAddr::V4(_), 0..=32) | (IpAddr::V6(_), 0..=128) => Ok((net, prefix)),
+	_ => Err(invalid()),
+    }
+}
+
+/// Reads a file in the format of `/etc/hosts`: an address followed by the
+/// names it stands for on each line, with `#` starting a comment. A name
+/// given on several lines has all of their addresses, in order.
+pub fn read_hosts_file(path: &Path) -> Result<StaticHosts, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut hosts = StaticHosts::new();
+    for (number, line) in text.lines().enumerate() {
+	let mut fields = line.split('#').next().unwrap_or_default().split_whitespace();
+	let ip = match fields.next() {
+	    Some(ip) => ip,
+	    None => continue,
+	};
+	let ip: IpAddr = ip.parse()
+	    .map_err(|_| format!("{}:{}: `{}` is not an IP address", path.display(), number + 1, ip))?;
+	let mut named = false;
+	for name in fields {
+	    let ips = hosts.entry(name.trim_end_matches('.').to_ascii_lowercase()).or_default();
+	    if !ips.contains(&ip) {
+		ips.push(ip);
+	    }
+	    named = true;
+	}
+	if !named {
+	    return Err(format!("{}:{}: {} names no hosts", path.display(), number + 1, ip))
+	}
+    }
+    Ok(hosts)
+}
+
+/// Parses an IPv6 literal with an optional `%zone` suffix
+fn parse_v6(literal: &str, port: u16) -> io::Result<SocketAddr> {
+    let (ip, zone) = match literal.split_once('%') {
+	Some((ip, zone)) => (ip, Some(zone)),
+	None => (literal, None),
+    };
+    let ip: Ipv6Addr = ip.parse().map_err(|_| invalid(format!("invalid IPv6 address {}", ip)))?;
+    let scope_id = match zone {
+	Some(zone) => zone.parse().or_else(|_| interface_index(zone))?,
+	None => 0,
+    };
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+/// Returns the local addresses a check bound to `bind` may send from: the
+/// address itself when it is an IP literal, and otherwise every address of
+/// the interface it names
+pub fn source_addrs(bind: &str) -> io::Result<Vec<IpAddr>> {
+    if let Ok(ip) = bind.parse() {
+	return Ok(vec![ip])
+    }
+    let ips = interface_addrs(bind)?;
+    match ips.is_empty() {
+	true => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("interface {} has no addresses", bind))),
+	false => Ok(ips),
+    }
+}
+
+/// Lists the IPv4 and IPv6 addresses of a named network interface
+#[cfg(unix)]
+fn interface_addrs(name: &str) -> io::Result<Vec<IpAddr>> {
+    interface_index(name)?;
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: head is only read after getifaddrs reports success, and freed once
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+	return Err(io::Error::last_os_error())
+    }
+    let mut ips = Vec::new();
+    let mut entry = head;
+    while !entry.is_null() {
+	// SAFETY: entry is a node of the list returned by getifaddrs, which
+	// stays valid until freeifaddrs
+	let ifa = unsafe { &*entry };
+	entry = ifa.ifa_next;
+	// SAFETY: ifa_name is a NUL terminated string owned by the list
+	if ifa.ifa_addr.is_null() || unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) }.to_bytes() != name.as_bytes() {
+	    continue
+	}
+	// SAFETY: ifa_addr points to a sockaddr of the family it declares
+	match i32::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+	    libc::AF_INET => {
+		let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+		ips.push(IpAddr::from(u32::from_be(sin.sin_addr.s_addr).to_be_bytes()));
+	    },
+	    // Link-local addresses are left out, as they need a scope and only
+	    // reach the link itself
+	    libc::AF_INET6 => {
+		let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+		let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+		if ip.segments()[0] & 0xffc0 != 0xfe80 {
+		    ips.push(IpAddr::V6(ip));
+		}
+	    },
+	    _ => {},
+	}
+    }
+    // SAFETY: head came from a successful getifaddrs and is freed only here
+    unsafe { libc::freeifaddrs(head) };
+    Ok(ips)
+}
+
+/// Lists the IPv4 and IPv6 addresses of a named network interface
+#[cfg(not(unix))]
+fn interface_addrs(name: &str) -> io::Result<Vec<IpAddr>> {
+    Err(invalid(format!("interface names are not supported on this platform, bind to an address of {} instead", name)))
+}
+
+/// Looks up the index of a named network interface
+#[cfg(unix)]
+fn interface_index(name: &str) -> io::Result<u32> {
+    let cname = std::ffi::CString::new(name).map_err(|_| invalid(format!("invalid interface {}", name)))?;
+    // SAFETY: cname is a valid NUL terminated string for the duration of the call
+    match unsafe { libc::if_nametoindex(cname.as_ptr()) } {
+	0 => Err(invalid(format!("unknown interface {}", name))),
+	index => Ok(index),
+    }
+}
+
+/// Looks up the index of a network interface named by its alias, such as
+/// `Ethernet 2`, or its name, such as `ethernet_32768`
+#[cfg(windows)]
+fn interface_index(name: &str) -> io::Result<u32> {
+    #[link(name = "iphlpapi")]
+    extern "system" {
+	fn ConvertInterfaceAliasToLuid(alias: *const u16, luid: *mut u64) -> u32;
+	fn ConvertInterfaceNameToLuidW(name: *const u16, luid: *mut u64) -> u32;
+	fn ConvertInterfaceLuidToIndex(luid: *const u64, index: *mut u32) -> u32;
+    }
+    let wide: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+    if wide[..wide.len() - 1].contains(&0) {
+	return Err(invalid(format!("invalid interface {}", name)))
+    }
+    let mut luid = 0u64;
+    // SAFETY: wide is NUL terminated and luid is the 64-bit NET_LUID both
+    // calls fill in
+    let found = unsafe {
+	ConvertInterfaceAliasToLuid(wide.as_ptr(), &mut luid) == 0
+	    || ConvertInterfaceNameToLuidW(wide.as_ptr(), &mut luid) == 0
+    };
+    let mut index = 0;
+    // SAFETY: luid was filled in by a successful conversion above
+    match found && unsafe { ConvertInterfaceLuidToIndex(&luid, &mut index) } == 0 {
+	true => Ok(index),
+	false => Err(invalid(format!("unknown interface {}", name))),
+    }
+}
+
+/// Looks up the index of a named network interface
+#[cfg(not(any(unix, windows)))]
+fn interface_index(name: &str) -> io::Result<u32> {
+    Err(invalid(format!("interface names are not supported on this platform, use the numeric zone index instead of {}", name)))
+}
+
+/// Builds an [`io::ErrorKind::InvalidInput`] error
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the scope of the single IPv6 address `addr` resolves to
+    fn scope(addrs: io::Result<Vec<SocketAddr>>) -> io::Result<(Ipv6Addr, u16, u32)> {
+	match addrs?.as_slice() {
+	    [SocketAddr::V6(v6)] => Ok((*v6.ip(), v6.port(), v6.scope_id())),
+	    other => panic!("expected one IPv6 address, got {:?}", other),
+	}
+    }
+
+    #[test]
+    fn bracketed_literals_are_parsed() {
+	let fe80 = "fe80::1".parse::<Ipv6Addr>().unwrap();
+	assert_eq!(scope(resolve("[fe80::1]:443")).unwrap(), (fe80, 443, 0));
+	assert_eq!(scope(resolve("[fe80::1%12]:443")).unwrap(), (fe80, 443, 12));
+	assert_eq!(scope(resolve_or_port("[fe80::1%7]", 22)).unwrap(), (fe80, 22, 7));
+	assert_eq!(scope(resolve_or_port("fe80::1%7", 22)).unwrap(), (fe80, 22, 7));
+	assert_eq!(&"https://[fe80::1%25eth0]:8443/health"[host_span("https://[fe80::1%25eth0]:8443/health")],
+		   "[fe80::1%25eth0]");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zones_name_interfaces() {
+	let fe80 = "fe80::1".parse::<Ipv6Addr>().unwrap();
+	let lo = if cfg!(any(target_os = "linux", target_os = "android")) { "lo" } else { "lo0" };
+	let index = interface_index(lo).unwrap();
+	assert_eq!(scope(resolve(&format!("[fe80::1%{}]:443", lo))).unwrap(), (fe80, 443, index));
+
+	// eth0 is looked up the same way wherever it exists
+	match (scope(resolve("[fe80::1%eth0]:443")), interface_index("eth0")) {
+	    (Ok(resolved), Ok(index)) => assert_eq!(resolved, (fe80, 443, index)),
+	    (Err(e), Err(_)) => assert_eq!(e.to_string(), "unknown interface eth0"),
+	    other => panic!("resolution and lookup disagree: {:?}", other),
+	}
+    }
+
+    #[test]
+    fn malformed_literals_are_rejected() {
+	let err = |addr: &str| resolve(addr).unwrap_err();
+	assert_eq!(err("[fe80::1]").to_string(), "missing port in [fe80::1]");
+	assert_eq!(err("[fe80::1]443").to_string(), "missing port in [fe80::1]443");
+	assert_eq!(err("[fe80::1]:").to_string(), "invalid port in [fe80::1]:");
+	assert_eq!(err("[fe80::1:443").to_string(), "unbalanced brackets in [fe80::1:443");
+	assert_eq!(err("fe80::1]:443").to_string(), "unbalanced brackets in fe80::1]:443");
+	assert_eq!(err("[fe80::zz]:443").to_string(), "invalid IPv6 address fe80::zz");
+	assert_eq!(err("[fe80::1%no-such-if0]:443").to_string(), "unknown interface no-such-if0");
+	assert_eq!(err("[fe80::1]").kind(), io::ErrorKind::InvalidInput);
+    }
+}
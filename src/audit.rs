@@ -0,0 +1,139 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Port audits, which detect drift from a firewall policy. A host's ports
+//! are swept with TCP connections at a limited rate, and any listener not
+//! on the host's allowlist fails the check.
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::error::{self, Stage};
+
+/// Ports swept when `ports` is not given
+const DEFAULT_PORTS: &str = "1-1024";
+
+/// Connection attempts per second when `rate` is not given
+const DEFAULT_RATE: u32 = 100;
+
+/// Limit for each connection attempt when the target has no timeout
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Most connection attempts in flight at once
+const MAX_WORKERS: usize = 32;
+
+/// Provides a deserialize target for the `audit` table of port audits,
+/// whose `addr` is the host to sweep
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AuditOptions {
+    /// Ports expected to be open. Any other open port fails the check.
+    #[serde(default)]
+    pub allowed: Vec<u16>,
+    /// Ports and ranges to sweep, e.g. `["1-1024", "8080", "9000-9100"]`.
+    /// Defaults to 1-1024. Allowed ports are always probed.
+    pub ports: Option<Vec<String>>,
+    /// Connection attempts per second. Defaults to 100.
+    pub rate: Option<u32>,
+}
+
+/// Parses ports and ranges such as `8080` and `9000-9100`
+fn parse_ports(specs: &[String]) -> Result<BTreeSet<u16>, String> {
+    let mut ports = BTreeSet::new();
+    for spec in specs {
+	let invalid = || format!("`{}` is not a port or a range of ports such as 1-1024", spec);
+	let (first, last) = match spec.trim().split_once('-') {
+	    Some((first, last)) => (first.trim().parse::<u16>().map_err(|_| invalid())?,
+				    last.trim().parse::<u16>().map_err(|_| invalid())?),
+	    None => {
+		let port = spec.trim().parse::<u16>().map_err(|_| invalid())?;
+		(port, port)
+	    },
+	};
+	if first == 0 || first > last {
+	    return Err(invalid())
+	}
+	ports.extend(first..=last);
+    }
+    Ok(ports)
+}
+
+/// Joins `ports` for a report, or `none`
+fn list(ports: &[u16]) -> String {
+    match ports.is_empty() {
+	true => "none".to_string(),
+	false => ports.iter().map(u16::to_string).collect::<Vec<_>>().join(", "),
+    }
+}
+
+/// Sweeps the ports of `options` on `host` at the configured rate, each
+/// attempt waiting up to `timeout` or [`PROBE_TIMEOUT`]. Fails when a port
+/// that is not allowed accepts a connection.
+pub fn check(host: &str, options: &AuditOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let specs = options.ports.clone().unwrap_or_else(|| vec![DEFAULT_PORTS.to_string()]);
+    let mut ports = parse_ports(&specs)?;
+    ports.extend(options.allowed.iter().filter(|port| **port != 0));
+    let ports: Vec<u16> = ports.into_iter().collect();
+
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    let ip: IpAddr = match bare.parse() {
+	Ok(ip) => ip,
+	Err(_) => (bare, 0).to_socket_addrs()
+	    .map_err(|e| error::from_io(e, Stage::Resolve, None))?
+	    .next()
+	    .ok_or_else(|| format!("no addresses found for {}", host))?
+	    .ip(),
+    };
+    let probe_timeout = timeout.unwrap_or(PROBE_TIMEOUT);
+    let interval = Duration::from_secs(1) / options.rate.unwrap_or(DEFAULT_RATE).max(1);
+
+    // Workers take ports in order and start each attempt at its own slot, so
+    // the sweep never exceeds the rate however quickly ports answer
+    let next = AtomicUsize::new(0);
+    let slot = Mutex::new(Instant::now());
+    let open = Mutex::new(Vec::new());
+    thread::scope(|s| {
+	for _ in 0..MAX_WORKERS.min(ports.len()) {
+	    s.spawn(|| {
+		while let Some(port) = ports.get(next.fetch_add(1, Ordering::Relaxed)) {
+		    let start = {
+			let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+			let start = (*slot).max(Instant::now());
+			*slot = start + interval;
+			start
+		    };
+		    thread::sleep(start.saturating_duration_since(Instant::now()));
+		    if TcpStream::connect_timeout(&SocketAddr::new(ip, *port), probe_timeout).is_ok() {
+			open.lock().unwrap_or_else(|e| e.into_inner()).push(*port);
+		    }
+		}
+	    });
+	}
+    });
+
+    let mut open = open.into_inner().unwrap_or_else(|e| e.into_inner());
+    open.sort_unstable();
+    let unexpected: Vec<u16> = open.iter().copied().filter(|port| !options.allowed.contains(port)).collect();
+    let closed: Vec<u16> = options.allowed.iter().copied().filter(|port| !open.contains(port)).collect();
+    if !unexpected.is_empty() {
+	return Err(From::from(format!("\n\tUnexpected: {} open on {} (allowed: {})\n\tSwept: {} ports",
+				      list(&unexpected), ip, list(&options.allowed), ports.len())))
+    }
+    let mut details = format!("{} ports swept on {}, open: {}", ports.len(), ip, list(&open));
+    if !closed.is_empty() {
+	details.push_str(&format!("; allowed but closed: {}", list(&closed)));
+    }
+    Ok(details)
+}
@@ -0,0 +1,124 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Authentication for HTTP checks. Each credential is given in the
+//! configuration or, with its `_env` counterpart, read from an environment
+//! variable when the check runs so secrets can stay out of the file. The
+//! secret, a password or token, may instead be printed by a
+//! `credential_command` such as a password manager's CLI, or be read from
+//! Vault with a `vault` reference.
+
+use std::env;
+
+use reqwest::blocking::RequestBuilder;
+use serde::Deserialize;
+
+use crate::hook;
+use crate::vault::Session;
+
+/// Credentials sent with a check's request, given as its `auth` table
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Auth {
+    /// HTTP Basic authentication
+    Basic {
+	username: Option<String>,
+	username_env: Option<String>,
+	password: Option<String>,
+	password_env: Option<String>,
+	/// Shell command whose output is the password
+	credential_command: Option<String>,
+	/// Vault secret holding the password, see [`crate::vault`]
+	vault: Option<String>,
+    },
+    /// A bearer token in the `Authorization` header
+    Bearer {
+	token: Option<String>,
+	token_env: Option<String>,
+	/// Shell command whose output is the token
+	credential_command: Option<String>,
+	/// Vault secret holding the token, see [`crate::vault`]
+	vault: Option<String>,
+    },
+}
+
+/// Returns a credential given directly or through an environment variable
+pub(crate) fn credential(name: &str, value: &Option<String>, var: &Option<String>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match (value, var) {
+	(Some(_), Some(_)) => Err(From::from(format!("auth sets both `{0}` and `{0}_env`", name))),
+	(Some(value), None) => Ok(Some(value.clone())),
+	(None, Some(var)) => match env::var(var) {
+	    Ok(value) => Ok(Some(value)),
+	    Err(_) => Err(From::from(format!("environment variable `{}` for auth {} is not set", var, name))),
+	},
+	(None, None) => Ok(None),
+    }
+}
+
+/// Where a password or token comes from, other than the configuration or
+/// the environment
+struct Source<'a> {
+    command: &'a Option<String>,
+    vault: &'a Option<String>,
+}
+
+/// Returns a secret given directly, through an environment variable, by a
+/// command, or from Vault
+fn secret(name: &str, value: &Option<String>, var: &Option<String>, source: Source, session: Option<&Session>)
+	  -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let given = [value.is_some() || var.is_some(), source.command.is_some(), source.vault.is_some()];
+    if given.iter().filter(|&&g| g).count() > 1 {
+	return Err(From::from(format!("auth sets more than one of `{}`, `credential_command`, and `vault`", name)))
+    }
+    if let Some(reference) = source.vault {
+	let session = session.ok_or("auth uses `vault` but the file has no `vault` table")?;
+	return Ok(Some(session.resolve(reference)?))
+    }
+    let command = match source.command {
+	Some(command) => command,
+	None => return credential(name, value, var),
+    };
+    let output = hook::shell(command).output()
+	.map_err(|e| format!("unable to run credential_command: {}", e))?;
+    if !output.status.success() {
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	let reason = stderr.lines().next().unwrap_or_default();
+	return Err(From::from(format!("credential_command exited with {}: {}", output.status, reason)))
+    }
+    // Only the trailing newline most tools print is dropped
+    let stdout = String::from_utf8(output.stdout).map_err(|_| "credential_command printed invalid UTF-8")?;
+    let secret = stdout.strip_suffix('\n').map(|s| s.strip_suffix('\r').unwrap_or(s)).unwrap_or(&stdout);
+    if secret.is_empty() {
+	return Err(From::from("credential_command printed nothing"))
+    }
+    Ok(Some(secret.to_string()))
+}
+
+impl Auth {
+    /// Adds the credentials to `request`, reading any from Vault through
+    /// `session`
+    pub fn apply(&self, request: RequestBuilder, session: Option<&Session>) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+	match self {
+	    Auth::Basic { username, username_env, password, password_env, credential_command, vault } => {
+		let username = credential("username", username, username_env)?
+		    .ok_or("basic auth requires `username` or `username_env`")?;
+		let source = Source { command: credential_command, vault };
+		let password = secret("password", password, password_env, source, session)?;
+		Ok(request.basic_auth(username, password))
+	    },
+	    Auth::Bearer { token, token_env, credential_command, vault } => {
+		let source = Source { command: credential_command, vault };
+		let token = secret("token", token, token_env, source, session)?
+		    .ok_or("bearer auth requires `token`, `token_env`, `credential_command`, or `vault`")?;
+		Ok(request.bearer_auth(token))
+	    },
+	}
+    }
+}
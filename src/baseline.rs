@@ -0,0 +1,172 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Latency baselines. A known-good run is recorded as the JSON summaries
+//! `--json` prints, and later runs report targets whose latency grew beyond
+//! a threshold relative to it, so performance drift shows up during routine
+//! reachability checks.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::NetworkResources;
+use crate::compare::MIN_LATENCY_DIFF_MS;
+use crate::outcome::Outcome;
+use crate::report;
+
+/// Latencies of the targets that passed in a recorded run
+#[derive(Debug, Clone, Default)]
+pub struct Baseline {
+    /// Milliseconds by configuration name and target description
+    latencies: HashMap<(String, String), f64>,
+}
+
+impl Baseline {
+    /// Reads a baseline from `path`, which holds one or more JSON summaries
+    /// as written by [`Baseline::save`] or `--json`
+    pub fn load(path: &Path) -> Result<Baseline, Box<dyn std::error::Error>> {
+	let text = fs::read_to_string(path)?;
+	let mut baseline = Baseline::default();
+	for summary in serde_json::Deserializer::from_str(&text).into_iter::<Value>() {
+	    let summary = summary.map_err(|e| format!("{}: {}", path.display(), e))?;
+	    let summaries = match summary {
+		Value::Array(summaries) => summaries,
+		summary => vec![summary],
+	    };
+	    for summary in summaries.iter() {
+		let config = summary["config"].as_str().unwrap_or_default();
+		let targets = summary["targets"].as_array()
+		    .ok_or_else(|| format!("{}: expected JSON summaries with `targets`", path.display()))?;
+		for target in targets.iter().filter(|t| t["outcome"] == Outcome::Passed.as_str()) {
+		    if let (Some(desc), Some(ms)) = (target["desc"].as_str(), target["latency_ms"].as_f64()) {
+			baseline.latencies.insert((config.to_string(), desc.to_string()), ms);
+		    }
+		}
+	    }
+	}
+	Ok(baseline)
+    }
+
+    /// Records the latest run of each named configuration to `path`, one
+    /// JSON summary per line
+    pub fn save(path: &Path, suites: &[(String, &NetworkResources)]) -> Result<(), Box<dyn std::error::Error>> {
+	let lines: Vec<String> = suites.iter()
+	    .map(|(name, resources)| report::summary(name, resources).to_string())
+	    .collect();
+	fs::write(path, lines.join("\n") + "\n")?;
+	Ok(())
+    }
+
+    /// Returns the number of targets recorded
+    pub fn len(&self) -> usize {
+	self.latencies.len()
+    }
+
+    /// Returns true when no target was recorded
+    pub fn is_empty(&self) -> bool {
+	self.latencies.is_empty()
+    }
+
+    /// Returns the recorded latency of `desc` in the configuration `config`,
+    /// falling back to a target of the same description in any other, as
+    /// when a baseline was recorded from a file since renamed
+    fn latency(&self, config: &str, desc: &str) -> Option<f64> {
+	self.latencies.get(&(config.to_string(), desc.to_string())).copied()
+	    .or_else(|| self.latencies.iter().find(|((_, d), _)| d == desc).map(|(_, ms)| *ms))
+    }
+
+    /// Describes each target of `resources`, checked from the configuration
+    /// named `config`, that passed with a latency more than `threshold_pct`
+    /// percent above its recorded one. Differences below 10ms are ignored.
+    pub fn regressions(&self, config: &str, resources: &NetworkResources, threshold_pct: f64) -> Vec<String> {
+	resources.target.iter()
+	    .filter(|t| t.outcome() == Outcome::Passed)
+	    .filter_map(|t| {
+		let (was, now) = (self.latency(config, &t.desc)?, t.latency.current?);
+		let change = if was > 0.0 { (now - was) / was * 100.0 } else { 0.0 };
+		(now - was >= MIN_LATENCY_DIFF_MS && change >= threshold_pct)
+		    .then(|| format!("{}: latency {:.0}ms vs {:.0}ms in baseline ({:+.0}%)", t.desc, now, was, change))
+	    })
+	    .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use crate::config;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+	env::temp_dir().join(format!("connchk-baseline-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn saved_runs_are_loaded_back() {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+	let (mut resources, _) = config::parse(&format!(
+	    "[[target]]\ndesc = \"up\"\naddr = \"{}\"\nkind = \"Tcp\"\n\
+	     [[target]]\ndesc = \"down\"\naddr = \"{}\"\nkind = \"Tcp\"\n",
+	    listener.local_addr().unwrap(), closed)).unwrap();
+	resources.run_checks();
+	let path = temp_path("round-trip.json");
+	Baseline::save(&path, &[("prod".to_string(), &resources)]).unwrap();
+	let baseline = Baseline::load(&path).unwrap();
+	fs::remove_file(&path).unwrap();
+
+	// Only the passing target is recorded, with the latency it was checked in
+	assert_eq!(baseline.len(), 1);
+	let measured = resources.target[0].result.as_ref().and_then(|r| r.latency).unwrap().as_secs_f64() * 1000.0;
+	assert_eq!(baseline.latency("prod", "up"), Some(measured));
+	assert_eq!(baseline.latency("renamed", "up"), Some(measured));
+	assert_eq!(baseline.latency("prod", "down"), None);
+	assert!(baseline.regressions("prod", &resources, 50.0).is_empty());
+    }
+
+    #[test]
+    fn regressions_start_at_the_threshold() {
+	let path = temp_path("threshold.json");
+	fs::write(&path, "[{\"config\": \"prod\", \"targets\": [\
+			  {\"desc\": \"api\", \"outcome\": \"passed\", \"latency_ms\": 100.0},\
+			  {\"desc\": \"cache\", \"outcome\": \"passed\", \"latency_ms\": 10.0},\
+			  {\"desc\": \"db\", \"outcome\": \"failed\", \"latency_ms\": 1.0}]}]\n").unwrap();
+	let baseline = Baseline::load(&path).unwrap();
+	fs::remove_file(&path).unwrap();
+	assert_eq!(baseline.len(), 2);
+
+	let (mut resources, _) = config::parse("[[target]]\ndesc = \"api\"\naddr = \"api:443\"\nkind = \"Tcp\"\n\
+						[[target]]\ndesc = \"cache\"\naddr = \"cache:6379\"\nkind = \"Tcp\"\n\
+						[[target]]\ndesc = \"db\"\naddr = \"db:5432\"\nkind = \"Tcp\"\n").unwrap();
+	let mut regressed = |api: f64, cache: f64, db: f64| {
+	    for (target, ms) in resources.target.iter_mut().zip([api, cache, db]) {
+		target.latency.current = Some(ms);
+	    }
+	    baseline.regressions("prod", &resources, 50.0)
+	};
+	assert!(regressed(149.9, 10.0, 500.0).is_empty());
+	assert_eq!(regressed(150.0, 10.0, 500.0), ["api: latency 150ms vs 100ms in baseline (+50%)"]);
+	// Growth under 10ms is ignored however large in relative terms
+	assert!(regressed(100.0, 19.9, 500.0).is_empty());
+	assert_eq!(regressed(100.0, 20.0, 500.0), ["cache: latency 20ms vs 10ms in baseline (+100%)"]);
+    }
+
+    #[test]
+    fn files_without_summaries_are_rejected() {
+	let path = temp_path("invalid.json");
+	fs::write(&path, "{\"config\": \"prod\"}\n").unwrap();
+	let err = Baseline::load(&path).unwrap_err().to_string();
+	fs::remove_file(&path).unwrap();
+	assert!(err.ends_with("expected JSON summaries with `targets`"), "{}", err);
+    }
+}
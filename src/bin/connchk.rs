@@ -8,17 +8,486 @@
     copied, modified, or distributed except according to those terms.
 */
 
-use connchk::{arg_handler, NetworkResources};
-
-/// Main entrypoint for connection validation. Once the TOML configuration
-/// file has been deserialized all nested `TcpResource` and `HttpResource`
-/// targets are checked.
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(config_path) = arg_handler() {
-	let config = std::fs::read_to_string(&config_path)?;
-	let mut resources: NetworkResources = toml::from_str(&config)?;
-	resources.check_resources();
-    }
-   
-    Ok(())
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use connchk::{addr, arg_handler, baseline, canary, compare, config, doctor, nagios, profile, report, suite, topology, Action, Args, NetworkResources};
+use connchk::outcome::{CheckEvent, Outcome};
+use connchk::sink::{OutputSink, SinkConfig};
+
+// Counted so --bench-self can report the heap activity of each run
+#[cfg(feature = "bench-self")]
+#[global_allocator]
+static ALLOCATOR: connchk::heap::Counting = connchk::heap::Counting;
+
+/// Reads and deserializes a TOML, YAML, or JSON configuration file, or a
+/// directory of them, printing any deprecation warnings. `--format`,
+/// `--profile`, `--target`, `--only-tags`, `--skip-tags`, `--jobs`,
+/// `--proxy`, `--hosts-file`, and `--sample`, unless `sampled` is false, are
+/// applied here.
+fn load(path: &Path, args: &Args, sampled: bool) -> Result<NetworkResources, Box<dyn std::error::Error>> {
+    let (mut resources, warnings) = config::load(path, args.format)
+	.map_err(|e| format!("{}: {}", path.display(), e))?;
+    for warning in warnings {
+	eprintln!("Warning: {}: {}", path.display(), warning);
+    }
+    if let Some(name) = &args.profile {
+	let (kept, total) = profile::apply(name, &mut resources)
+	    .map_err(|e| format!("{}: {}", path.display(), e))?;
+	eprintln!("Profile {} selected {} of {} targets from {}", name, kept, total, path.display());
+    }
+    if !args.select.is_empty() {
+	for name in args.select.unmatched(&resources.target) {
+	    eprintln!("Warning: {}: --target `{}` matches no targets", path.display(), name);
+	}
+	let (kept, total) = args.select.apply(&mut resources.target);
+	eprintln!("Selected {} of {} targets from {}", kept, total, path.display());
+    }
+    if let Some(sample) = args.sample.as_ref().filter(|_| sampled) {
+	let (kept, total) = sample.apply(&mut resources.target);
+	eprintln!("Sampled {} of {} targets from {} (--seed {})", kept, total, path.display(), sample.seed);
+    }
+    if args.jobs.is_some() {
+	resources.max_concurrency = args.jobs;
+    }
+    if !args.proxy.is_empty() {
+	resources.proxy_chain = Some(args.proxy.clone());
+    }
+    if let Some(hosts_file) = &args.hosts_file {
+	resources.static_hosts = Some(Arc::new(addr::read_hosts_file(hosts_file)?));
+    }
+    // Checks note what the most verbose console shows
+    let configured = resources.sink.iter().flatten()
+	.filter_map(|sink| match sink {
+	    SinkConfig::Console { verbosity, .. } => *verbosity,
+	    _ => None,
+	})
+	.max();
+    resources.verbosity = args.verbosity.or(configured).unwrap_or_default();
+    Ok(resources)
+}
+
+/// Returns the sinks configured for `resources`, or the console or JSON
+/// summary on standard output, as selected by `--json`, when there are none.
+/// Console output is timestamped in watch and canary modes unless
+/// configured otherwise, never colored with `--no-color`, and printed at the
+/// `-q` or `-v` level when given. The `--annotate` and `--output` sinks are
+/// added to either, and a TAP stream on standard output replaces both. With
+/// `--nagios` no sink writes to standard output.
+fn sink_configs(resources: &NetworkResources, args: &Args) -> Vec<SinkConfig> {
+    let watching = Some(args.watch.is_some() || args.canary.is_some());
+    let adjust = |config: &SinkConfig| match config {
+	SinkConfig::Console { timestamps, color, verbosity } => SinkConfig::Console {
+	    timestamps: timestamps.or(watching),
+	    color: if args.no_color { Some(false) } else { *color },
+	    verbosity: args.verbosity.or(*verbosity),
+	},
+	config => config.clone(),
+    };
+    let mut configs = match &resources.sink {
+	Some(sinks) if !sinks.is_empty() => sinks.iter().filter(|s| !(args.nagios && s.prints())).map(adjust).collect(),
+	_ if args.nagios => Vec::new(),
+	_ if matches!(args.report, Some(SinkConfig::Tap { path: None })) => Vec::new(),
+	_ if args.json => vec![SinkConfig::Json { path: None }],
+	_ => vec![adjust(&SinkConfig::Console { timestamps: None, color: None, verbosity: None })],
+    };
+    let added = [&args.annotate, &args.report];
+    configs.extend(added.into_iter().flatten().filter(|s| !(args.nagios && s.prints())).cloned());
+    configs
+}
+
+/// Returns the sinks of `resources` writing its output alone
+fn sinks(resources: &NetworkResources, args: &Args) -> Vec<Box<dyn OutputSink>> {
+    sink_configs(resources, args).iter()
+	.filter(|config| config.shared_report().is_none())
+	.map(SinkConfig::build)
+	.collect()
+}
+
+/// Sinks writing one report of several configurations, each with the paths
+/// of the configurations it receives
+type SharedSinks = Vec<(Box<dyn OutputSink>, Vec<PathBuf>)>;
+
+/// Returns a sink for each report shared by the configurations of
+/// `suites`. They are built afresh for each round, so a report holds the
+/// latest round alone.
+fn shared_sinks(suites: &[Suite], args: &Args) -> SharedSinks {
+    let mut reports: Vec<String> = Vec::new();
+    let mut shared: SharedSinks = Vec::new();
+    for (path, resources, _, _) in suites {
+	for config in sink_configs(resources, args) {
+	    let report = match config.shared_report() {
+		Some(report) => report,
+		None => continue,
+	    };
+	    match reports.iter().position(|r| *r == report) {
+		Some(i) if !shared[i].1.contains(path) => shared[i].1.push(path.clone()),
+		Some(_) => (),
+		None => {
+		    reports.push(report);
+		    shared.push((config.build(), vec![path.clone()]));
+		},
+	    }
+	}
+    }
+    shared
+}
+
+/// A configuration being checked: its path, resources, modification time,
+/// and sinks
+type Suite = (PathBuf, NetworkResources, Option<SystemTime>, Vec<Box<dyn OutputSink>>);
+
+/// Outcomes of the previous run in watch mode, by configuration, description,
+/// and address
+type Outcomes = HashMap<(PathBuf, String, String), Outcome>;
+
+/// Summarizes a run in watch mode, counting the targets whose outcome
+/// changed since the previous run, and records the new outcomes
+fn delta(suites: &[Suite], previous: &mut Outcomes) -> String {
+    let mut current = Outcomes::new();
+    let (mut checked, mut failing, mut newly_failing, mut recovered) = (0, 0, 0, 0);
+    for (path, resources, _, _) in suites {
+	for target in resources.target.iter() {
+	    let outcome = target.outcome();
+	    let key = (path.clone(), target.desc.clone(), target.addr.clone());
+	    match (previous.get(&key), outcome) {
+		(Some(Outcome::Passed) | None, Outcome::Failed) if !previous.is_empty() => newly_failing += 1,
+		(Some(Outcome::Failed), Outcome::Passed) => recovered += 1,
+		_ => (),
+	    }
+	    checked += 1;
+	    failing += usize::from(outcome == Outcome::Failed);
+	    current.insert(key, outcome);
+	}
+    }
+    let first = previous.is_empty();
+    *previous = current;
+    let mut summary = format!("[{}] {} checks, {} failing", report::timestamp(SystemTime::now()), checked, failing);
+    if !first {
+	summary.push_str(&format!(": {} newly failing, {} recovered", newly_failing, recovered));
+    }
+    summary
+}
+
+/// Returns when the configuration at `path` last changed: the latest
+/// modification of the path itself, which for a directory changes as files
+/// come and go, or of any file it was read from
+fn modified(path: &Path, resources: &NetworkResources) -> Option<SystemTime> {
+    std::iter::once(path).chain(resources.sources.iter().map(PathBuf::as_path))
+	.filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+	.max()
+}
+
+/// Replaces `resources` with a fresh load of `path`, keeping the counters
+/// about connchk itself. The previous configuration stays in use when the
+/// new one cannot be loaded.
+fn reload(path: &Path, resources: &mut NetworkResources, mtime: &mut Option<SystemTime>, args: &Args) {
+    *mtime = modified(path, resources);
+    match load(path, args, true) {
+	Ok(mut fresh) => {
+	    fresh.stats = std::mem::take(&mut resources.stats);
+	    fresh.stats.config_reloads += 1;
+	    *mtime = modified(path, &fresh);
+	    *resources = fresh;
+	    eprintln!("Reloaded {}", path.display());
+	},
+	Err(e) => eprintln!("Keeping the previous configuration: {}", e),
+    }
+}
+
+/// Checks every suite `runs` times after a warm-up run, without any output
+/// or notifications, printing the heap activity of each run and a summary
+#[cfg(feature = "bench-self")]
+fn bench_self(suites: &mut [Suite], runs: u32) -> Result<Finish, Box<dyn std::error::Error>> {
+    use connchk::heap;
+
+    let checks = suites.iter().map(|(_, resources, _, _)| resources.target.len()).sum();
+    let started = Instant::now();
+    let mut measured = Vec::new();
+    for run in 0..=runs {
+	let before = heap::usage();
+	for (_, resources, _, _) in suites.iter_mut() {
+	    resources.run();
+	}
+	let activity = heap::usage().since(before);
+	match run {
+	    0 => println!("warm-up: {}", activity),
+	    run => {
+		println!("run {}: {}", run, activity);
+		measured.push(activity);
+	    },
+	}
+    }
+    println!("{}", heap::summary(&measured, checks));
+    let mut overall = suite::Counts::default();
+    for (_, resources, _, _) in suites.iter() {
+	overall.add(suite::Counts::of(resources));
+    }
+    Ok(Finish { code: 0, counts: overall, elapsed: Some(started.elapsed()) })
+}
+
+#[cfg(not(feature = "bench-self"))]
+fn bench_self(_suites: &mut [Suite], _runs: u32) -> Result<Finish, Box<dyn std::error::Error>> {
+    Err(From::from("--bench-self requires connchk to be built with the `bench-self` feature"))
+}
+
+/// Sends the library's diagnostics at `--log-level` to standard error,
+/// timestamped in watch and canary modes and colored like console output
+fn logging(args: &Args) {
+    let color = !args.no_color && io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let builder = tracing_subscriber::fmt()
+	.with_writer(io::stderr)
+	.with_max_level(args.log_level)
+	.with_target(false)
+	.with_ansi(color);
+    match args.watch.is_some() || args.canary.is_some() {
+	true => builder.init(),
+	false => builder.without_time().init(),
+    }
+}
+
+/// How a run ended: its exit status and the outcomes of the checks of its
+/// last round, given in the result line closing the run
+struct Finish {
+    code: i32,
+    counts: suite::Counts,
+    /// How long the last round took, the whole run when not given
+    elapsed: Option<Duration>,
+}
+
+impl Finish {
+    /// Ends a run in which nothing was checked
+    fn unchecked(code: i32) -> Finish {
+	Finish { code, counts: suite::Counts::default(), elapsed: None }
+    }
+}
+
+/// Main entrypoint for connection validation. Whatever way the run ends,
+/// including with an error, it closes with the result line on stderr.
+fn main() {
+    let started = Instant::now();
+    let finish = match arg_handler() {
+	Some(args) => run(&args).unwrap_or_else(|e| {
+	    eprintln!("Error: {:?}", e);
+	    Finish::unchecked(1)
+	}),
+	None => Finish::unchecked(0),
+    };
+    eprintln!("{}", suite::result_line(finish.counts, finish.elapsed.unwrap_or_else(|| started.elapsed())));
+    process::exit(finish.code)
+}
+
+/// Once the TOML configuration file has been deserialized all nested
+/// `TcpResource` and `HttpResource` targets are checked, repeatedly when
+/// running in watch or canary mode.
+fn run(args: &Args) -> Result<Finish, Box<dyn std::error::Error>> {
+    logging(args);
+    if args.action == Action::Doctor {
+	print!("{}", doctor::report());
+	return Ok(Finish::unchecked(0))
+    }
+
+    if args.compare {
+	let [left, right] = args.configs.as_slice() else {
+	    return Err(From::from("--compare requires exactly two configurations"))
+	};
+	let (mut lres, mut rres) = (load(left, args, false)?, load(right, args, false)?);
+	let started = Instant::now();
+	lres.run();
+	rres.run();
+	let (report, diffs) = compare::compare((&suite::name(left, &lres), &lres),
+					       (&suite::name(right, &rres), &rres),
+					       args.threshold);
+	print!("{}", report);
+	let mut overall = suite::Counts::of(&lres);
+	overall.add(suite::Counts::of(&rres));
+	let code = if diffs > 0 { 1 } else { 0 };
+	return Ok(Finish { code, counts: overall, elapsed: Some(started.elapsed()) })
+    }
+
+    let suites = args.configs.iter()
+	.map(|path| {
+	    let resources = load(path, args, true)?;
+	    let sinks = sinks(&resources, args);
+	    let mtime = modified(path, &resources);
+	    Ok((path.clone(), resources, mtime, sinks))
+	})
+	.collect::<Result<Vec<Suite>, Box<dyn std::error::Error>>>();
+    let baseline = match &args.baseline {
+	Some(path) => baseline::Baseline::load(path).map(Some)
+	    .map_err(|e| From::from(format!("--baseline {}: {}", path.display(), e))),
+	None => Ok(None),
+    };
+    let (mut suites, baseline) = match (suites, baseline) {
+	(Ok(suites), Ok(baseline)) => (suites, baseline),
+	(Err(e), _) | (_, Err(e)) if args.nagios => {
+	    println!("{}", nagios::unknown(&e.to_string()));
+	    return Ok(Finish::unchecked(nagios::State::Unknown.code()))
+	},
+	(Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    if let Some(runs) = args.bench_self {
+	return bench_self(&mut suites, runs)
+    }
+    let mut outcomes = Outcomes::new();
+    let mut tally = canary::Tally::default();
+    let started = Instant::now();
+    let server = Arc::new(report::Server::default());
+    if let Some(addr) = args.listen {
+	server.serve(addr)?;
+    }
+
+    loop {
+	let round_started = Instant::now();
+	let (mut failed, mut unknown, mut regressed) = (0, 0, 0);
+	for (path, resources, mtime, outputs) in suites.iter_mut() {
+	    if args.watch.is_some() && modified(path, resources) != *mtime {
+		reload(path, resources, mtime, args);
+		*outputs = sinks(resources, args);
+	    }
+	}
+	// Suites are checked concurrently, then reported in the order given
+	thread::scope(|scope| {
+	    for (path, resources, _, _) in suites.iter_mut() {
+		match args.listen {
+		    // Results are streamed to subscribers as each check completes
+		    Some(_) => {
+			let (name, server) = (suite::name(path, resources), &server);
+			scope.spawn(move || resources.run_with(|event| if let CheckEvent::Finished(result) = event {
+			    server.publish_result(&name, result);
+			}));
+		    },
+		    None => {
+			scope.spawn(|| resources.run());
+		    },
+		}
+	    }
+	});
+	for (path, resources, _, outputs) in suites.iter_mut() {
+	    let name = suite::name(path, resources);
+	    for sink in outputs.iter_mut() {
+		if let Err(e) = sink.emit(&name, resources) {
+		    eprintln!("Output failed: {}", e);
+		}
+	    }
+	    resources.dispatch();
+	    let policy = resources.exit_policy.unwrap_or_default();
+	    if policy.counts_failures() {
+		failed += resources.target.iter().filter(|t| t.failed).count();
+	    }
+	    if policy.counts_unknown() {
+		unknown += resources.target.iter().filter(|t| t.unknown).count();
+	    }
+	    tally.record(&name, resources);
+	    if let Some(baseline) = &baseline {
+		let regressions = baseline.regressions(&name, resources, args.threshold);
+		if !regressions.is_empty() {
+		    eprintln!("Latency regressions in {} since the baseline:\n\t{}", name, regressions.join("\n\t"));
+		}
+		if policy.counts_regressions() {
+		    regressed += regressions.len();
+		}
+	    }
+	}
+	for (sink, paths) in shared_sinks(&suites, args).iter_mut() {
+	    for (path, resources, _, _) in suites.iter().filter(|(path, _, _, _)| paths.contains(path)) {
+		if let Err(e) = sink.emit(&suite::name(path, resources), resources) {
+		    eprintln!("Output failed: {}", e);
+		}
+	    }
+	    if let Err(e) = sink.finish() {
+		eprintln!("Output failed: {}", e);
+	    }
+	}
+	let named: Vec<(String, &NetworkResources)> = suites.iter()
+	    .map(|(path, resources, _, _)| (suite::name(path, resources), resources))
+	    .collect();
+	if let Some(path) = &args.save_baseline {
+	    if let Err(e) = baseline::Baseline::save(path, &named) {
+		eprintln!("Saving baseline to {} failed: {}", path.display(), e);
+	    }
+	}
+	if args.listen.is_some() {
+	    let status = named.iter().map(|(name, resources)| report::summary(name, resources)).collect();
+	    server.update(report::prometheus(&named), status);
+	    for (name, resources) in named.iter() {
+		server.publish_run(name, resources);
+	    }
+	}
+	if args.topology && !args.nagios {
+	    let resources: Vec<&NetworkResources> = named.iter().map(|(_, resources)| *resources).collect();
+	    if let Some(report) = topology::report(&resources) {
+		eprintln!("{}", report);
+	    }
+	}
+	let mut overall = suite::Counts::default();
+	for (_, resources) in named.iter() {
+	    overall.add(suite::Counts::of(resources));
+	}
+	if named.len() > 1 && args.watch.is_none() && args.canary.is_none() && !args.nagios {
+	    for (name, resources) in named.iter() {
+		eprintln!("{}", suite::summary(name, resources));
+	    }
+	    eprintln!("Overall: {}", overall);
+	}
+	// The result line closes each round, after any verdict
+	let elapsed = round_started.elapsed();
+	let result = suite::result_line(overall, elapsed);
+	let finish = |code: i32| Ok(Finish { code, counts: overall, elapsed: Some(elapsed) });
+
+	if args.nagios {
+	    let (state, line) = nagios::status(&named, regressed);
+	    println!("{}", line);
+	    return finish(state.code())
+	}
+
+	if let Some(canary) = &args.canary {
+	    tally.rounds += 1;
+	    if tally.exceeded(canary) {
+		eprintln!("{}", tally.verdict(canary, started.elapsed()));
+		return finish(1)
+	    }
+	    match canary.window.checked_sub(started.elapsed()).filter(|left| !left.is_zero()) {
+		Some(left) => {
+		    eprintln!("{}", result);
+		    // Rounds start every interval, so a slow round shortens the wait
+		    let round = round_started.elapsed();
+		    thread::sleep(canary.interval.saturating_sub(round).min(left));
+		    continue
+		},
+		None => {
+		    eprintln!("{}", tally.verdict(canary, started.elapsed()));
+		    return finish(0)
+		},
+	    }
+	}
+
+	let checked: usize = suites.iter().map(|(_, r, _, _)| r.target.len()).sum();
+	match args.watch {
+	    Some(secs) => {
+		eprintln!("{}", delta(&suites, &mut outcomes));
+		eprintln!("{}", result);
+		thread::sleep(Duration::from_secs(secs))
+	    },
+	    None if failed > 0 => {
+		eprintln!("{} of {} checks failed", failed, checked);
+		return finish(1)
+	    },
+	    None if unknown > 0 => {
+		eprintln!("{} of {} checks could not be determined", unknown, checked);
+		return finish(3)
+	    },
+	    None if regressed > 0 => {
+		eprintln!("{} of {} checks regressed since the baseline", regressed, checked);
+		return finish(4)
+	    },
+	    None => return finish(0),
+	}
+    }
 }
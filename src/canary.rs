@@ -0,0 +1,129 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Time-boxed canary runs. After a deployment the targets are checked
+//! repeatedly for a fixed window, and the run fails as soon as the failures
+//! seen exceed a budget, so a delivery pipeline can use the exit status to
+//! promote or roll back a release.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::NetworkResources;
+use crate::outcome::Outcome;
+
+/// Time between rounds when `--interval` is not given
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Parses a duration given as whole seconds, `90`, or as a sequence of
+/// amounts with units `h`, `m`, `s`, or `ms`, such as `10m` or `1h30m`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let invalid = || format!("`{}` is not a duration such as 90s, 10m, or 1h30m", s);
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    if let Ok(secs) = s.parse::<u64>() {
+	total = Duration::from_secs(secs);
+	rest = "";
+    }
+    while !rest.is_empty() {
+	let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+	let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+	rest = &rest[digits..];
+	let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+	total += match &rest[..unit] {
+	    "h" => Duration::from_secs(amount * 3600),
+	    "m" => Duration::from_secs(amount * 60),
+	    "s" => Duration::from_secs(amount),
+	    "ms" => Duration::from_millis(amount),
+	    _ => return Err(invalid()),
+	};
+	rest = &rest[unit..];
+    }
+    match total.is_zero() {
+	true => Err(invalid()),
+	false => Ok(total),
+    }
+}
+
+/// Formats `duration` to the second, e.g. `3m12s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+	(0, 0, s) => format!("{}s", s),
+	(0, m, s) => format!("{}m{}s", m, s),
+	(h, m, s) => format!("{}h{}m{}s", h, m, s),
+    }
+}
+
+/// Settings of a canary run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canary {
+    /// How long to keep checking
+    pub window: Duration,
+    /// Failed checks tolerated over the whole window
+    pub max_failures: usize,
+    /// Time between the start of one round of checks and the next
+    pub interval: Duration,
+}
+
+/// Failures counted over a canary run
+#[derive(Debug, Clone, Default)]
+pub struct Tally {
+    /// Rounds of checks completed
+    pub rounds: usize,
+    /// Checks that ran, across all rounds
+    pub checks: usize,
+    /// Checks that failed, across all rounds
+    pub failures: usize,
+    /// Failures by configuration and target description
+    failing: BTreeMap<(String, String), usize>,
+}
+
+impl Tally {
+    /// Counts the outcomes of a round of checks of the configuration `name`.
+    /// Skipped checks, and checks whose state is unknown, count toward
+    /// neither checks nor failures.
+    pub fn record(&mut self, name: &str, resources: &NetworkResources) {
+	for target in resources.target.iter() {
+	    match target.outcome() {
+		Outcome::Passed => self.checks += 1,
+		Outcome::Failed => {
+		    self.checks += 1;
+		    self.failures += 1;
+		    *self.failing.entry((name.to_string(), target.desc.clone())).or_default() += 1;
+		},
+		Outcome::Skipped | Outcome::Unknown => (),
+	    }
+	}
+    }
+
+    /// Returns true once the failures exceed the budget of `canary`
+    pub fn exceeded(&self, canary: &Canary) -> bool {
+	self.failures > canary.max_failures
+    }
+
+    /// Describes the run after `elapsed`, naming the targets that failed
+    pub fn verdict(&self, canary: &Canary, elapsed: Duration) -> String {
+	let status = match self.exceeded(canary) {
+	    true => format!("Canary failed after {}", format_duration(elapsed)),
+	    false => format!("Canary passed over {}", format_duration(elapsed)),
+	};
+	let mut verdict = format!("{}: {} of {} checks failed in {} rounds (budget {})",
+				  status, self.failures, self.checks, self.rounds, canary.max_failures);
+	if !self.failing.is_empty() {
+	    let failing: Vec<String> = self.failing.iter()
+		.map(|((name, desc), count)| format!("{} in {} ({})", desc, name, count))
+		.collect();
+	    verdict.push_str(&format!("\n\tFailing: {}", failing.join(", ")));
+	}
+	verdict
+    }
+}
@@ -0,0 +1,82 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Comparison of two environments. Targets are matched by description and
+//! reported when their status differs or their latency differs by more than
+//! a threshold, for validating a new site against the one it replaces.
+
+use std::fmt::Write;
+
+use crate::{NetworkResources, Resource};
+use crate::outcome::Outcome;
+
+/// Latency differences below this many milliseconds are never reported,
+/// however large they are in relative terms
+pub(crate) const MIN_LATENCY_DIFF_MS: f64 = 10.0;
+
+/// Default relative latency difference, in percent, that is reported
+pub const DEFAULT_THRESHOLD_PCT: f64 = 50.0;
+
+/// Describes a target's outcome for the report
+fn status(target: &Resource) -> String {
+    match (target.outcome(), &target.error_code) {
+	(Outcome::Passed, _) => "passed".to_string(),
+	(Outcome::Skipped, _) => match target.skip_reason {
+	    Some(reason) => format!("skipped ({})", reason.as_str()),
+	    None => "skipped".to_string(),
+	},
+	(Outcome::Unknown, _) => "unknown".to_string(),
+	(Outcome::Failed, Some(code)) => format!("FAILED ({})", code),
+	(Outcome::Failed, None) => "FAILED".to_string(),
+    }
+}
+
+/// Compares checked resources from environments named `left` and `right`.
+/// Returns the report and the number of differences found.
+pub fn compare(left: (&str, &NetworkResources), right: (&str, &NetworkResources), threshold_pct: f64) -> (String, usize) {
+    let (lname, lres) = left;
+    let (rname, rres) = right;
+    let mut out = String::new();
+    let mut diffs = 0;
+    let _ = writeln!(out, "Comparing {} with {}", lname, rname);
+
+    for l in lres.target.iter() {
+	let r = match rres.target.iter().find(|r| r.desc == l.desc) {
+	    Some(r) => r,
+	    None => {
+		let _ = writeln!(out, "\t{}: only in {}", l.desc, lname);
+		diffs += 1;
+		continue
+	    }
+	};
+
+	if l.outcome() != r.outcome() {
+	    let _ = writeln!(out, "\t{}: {} vs {}", l.desc, status(l), status(r));
+	    diffs += 1;
+	    continue
+	}
+
+	if let (Outcome::Passed, Some(lms), Some(rms)) = (l.outcome(), l.latency.current, r.latency.current) {
+	    let change = if lms > 0.0 { (rms - lms) / lms * 100.0 } else { 0.0 };
+	    if (rms - lms).abs() >= MIN_LATENCY_DIFF_MS && change.abs() >= threshold_pct {
+		let _ = writeln!(out, "\t{}: latency {:.0}ms vs {:.0}ms ({:+.0}%)", l.desc, lms, rms, change);
+		diffs += 1;
+	    }
+	}
+    }
+
+    for r in rres.target.iter().filter(|r| !lres.target.iter().any(|l| l.desc == r.desc)) {
+	let _ = writeln!(out, "\t{}: only in {}", r.desc, rname);
+	diffs += 1;
+    }
+
+    let _ = writeln!(out, "{} difference(s) found", diffs);
+    (out, diffs)
+}
@@ -0,0 +1,249 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Concurrency control for large runs. In adaptive mode checks start with
+//! modest parallelism, which doubles while checks succeed and the probing
+//! host has file descriptors and ephemeral ports to spare, and is halved
+//! when errors mount or those resources run low. Independently of the
+//! mode, checks that fail because those resources are exhausted are queued
+//! and run again rather than reported as failures of the target.
+
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error;
+
+/// Share of checks in a window that may fail before parallelism is halved
+const MAX_ERROR_RATE: f64 = 0.5;
+
+/// Share of file descriptors or ephemeral ports in use above which
+/// parallelism is halved
+const HIGH_PRESSURE: f64 = 0.8;
+
+/// Share of file descriptors or ephemeral ports in use below which
+/// parallelism may grow
+const LOW_PRESSURE: f64 = 0.5;
+
+/// Times a check is queued after exhausting local resources before the
+/// failure is reported
+pub const MAX_REQUEUES: u32 = 10;
+
+/// File descriptors assumed to be needed by each check run in parallel
+const FDS_PER_CHECK: u64 = 4;
+
+/// How many checks may run at the same time
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "mode")]
+pub enum Concurrency {
+    /// Adjust parallelism between `min` and `max`, starting at `initial`
+    Adaptive {
+	initial: Option<usize>,
+	min: Option<usize>,
+	max: Option<usize>,
+    },
+}
+
+impl Concurrency {
+    /// Returns the largest number of checks that may ever run at once
+    pub fn max(&self) -> usize {
+	match *self {
+	    Concurrency::Adaptive { max, .. } => max.unwrap_or(256).max(1),
+	}
+    }
+}
+
+/// Progress within the current adjustment window
+#[derive(Debug)]
+struct State {
+    limit: usize,
+    active: usize,
+    completed: usize,
+    failed: usize,
+}
+
+/// Gates checks according to a [`Concurrency`] policy
+#[derive(Debug)]
+pub struct Limiter {
+    min: usize,
+    max: usize,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Permission for one check to run, returned with [`Permit::finish`]
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+}
+
+impl Limiter {
+    /// Creates a limiter for `policy`
+    pub fn new(policy: &Concurrency) -> Limiter {
+	let Concurrency::Adaptive { initial, min, .. } = *policy;
+	let max = policy.max();
+	let min = min.unwrap_or(1).clamp(1, max);
+	let limit = initial.unwrap_or(8).clamp(min, max);
+	Limiter {
+	    min,
+	    max,
+	    state: Mutex::new(State { limit, active: 0, completed: 0, failed: 0 }),
+	    cond: Condvar::new(),
+	}
+    }
+
+    /// Returns the current limit
+    pub fn limit(&self) -> usize {
+	self.lock().limit
+    }
+
+    /// Waits until another check may start
+    pub fn acquire(&self) -> Permit<'_> {
+	let mut state = self.lock();
+	while state.active >= state.limit {
+	    state = self.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+	}
+	state.active += 1;
+	Permit { limiter: self }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+	self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Permit<'_> {
+    /// Releases the permit, adjusting the limit using the check's error
+    /// code, if it failed
+    pub fn finish(self, error_code: Option<&str>) {
+	let limiter = self.limiter;
+	let mut state = limiter.lock();
+	state.active -= 1;
+	state.completed += 1;
+	if error_code.is_some() {
+	    state.failed += 1;
+	}
+
+	let exhausted = error_code.is_some_and(error::is_local_exhaustion);
+	if exhausted || state.completed >= state.limit {
+	    let error_rate = state.failed as f64 / state.completed as f64;
+	    let pressure = pressure();
+	    let limit = if exhausted || error_rate > MAX_ERROR_RATE || pressure > HIGH_PRESSURE {
+		(state.limit / 2).max(limiter.min)
+	    } else if pressure < LOW_PRESSURE {
+		(state.limit * 2).min(limiter.max)
+	    } else {
+		state.limit
+	    };
+	    if limit < state.limit {
+		tracing::warn!("Reducing concurrency to {} (error rate {:.0}%, resource use {:.0}%)",
+			       limit, error_rate * 100.0, pressure * 100.0);
+	    }
+	    state.limit = limit;
+	    state.completed = 0;
+	    state.failed = 0;
+	}
+	drop(state);
+	limiter.cond.notify_all();
+    }
+}
+
+/// Returns the delay before running a check queued for the `n`th time,
+/// doubling from 100ms up to five seconds
+pub fn requeue_delay(n: u32) -> Duration {
+    Duration::from_millis(100u64.saturating_mul(1 << n.min(6))).min(Duration::from_secs(5))
+}
+
+/// Checks, once per process, that the file descriptor limit allows
+/// `targets` checks to run in parallel, raising the soft limit towards the
+/// hard limit if needed and warning when that is not enough
+pub fn preflight(targets: usize) {
+    static CHECKED: OnceLock<()> = OnceLock::new();
+    CHECKED.get_or_init(|| {
+	let wanted = targets as u64 * FDS_PER_CHECK;
+	if let Some(limit) = fd_limit(wanted) {
+	    if limit < wanted {
+		tracing::warn!("The open file limit of {} may not allow {} checks in parallel; \
+				checks will be queued when it is reached", limit, targets);
+	    }
+	}
+    });
+}
+
+/// Raises the soft file descriptor limit to at least `wanted`, up to the
+/// hard limit, and returns the resulting soft limit
+#[cfg(unix)]
+fn fd_limit(wanted: u64) -> Option<u64> {
+    // SAFETY: getrlimit and setrlimit only access the provided struct
+    unsafe {
+	let mut rlim: libc::rlimit = std::mem::zeroed();
+	if libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) != 0 {
+	    return None
+	}
+	let current = rlim.rlim_cur as u64;
+	if current >= wanted {
+	    return Some(current)
+	}
+	let raised = (wanted as libc::rlim_t).min(rlim.rlim_max);
+	let new = libc::rlimit { rlim_cur: raised, rlim_max: rlim.rlim_max };
+	if libc::setrlimit(libc::RLIMIT_NOFILE, &new) == 0 {
+	    Some(raised as u64)
+	} else {
+	    Some(current)
+	}
+    }
+}
+
+/// Windows has no comparable per-process socket limit
+#[cfg(not(unix))]
+fn fd_limit(_wanted: u64) -> Option<u64> {
+    None
+}
+
+/// Returns the larger of the shares of file descriptors and ephemeral
+/// ports in use by the probing host
+#[cfg(target_os = "linux")]
+pub fn pressure() -> f64 {
+    fd_usage().unwrap_or(0.0).max(port_usage().unwrap_or(0.0))
+}
+
+/// Resource use is only sampled on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn pressure() -> f64 {
+    0.0
+}
+
+/// Share of the file descriptor limit this process is using
+#[cfg(target_os = "linux")]
+fn fd_usage() -> Option<f64> {
+    let open = std::fs::read_dir("/proc/self/fd").ok()?.count();
+    // SAFETY: getrlimit only writes to the provided struct
+    let limit = unsafe {
+	let mut rlim: libc::rlimit = std::mem::zeroed();
+	if libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) != 0 {
+	    return None
+	}
+	rlim.rlim_cur
+    };
+    Some(open as f64 / limit.max(1) as f64)
+}
+
+/// Share of the ephemeral port range in use by TCP sockets on the host
+#[cfg(target_os = "linux")]
+fn port_usage() -> Option<f64> {
+    let range = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok()?;
+    let mut bounds = range.split_whitespace().filter_map(|n| n.parse::<u32>().ok());
+    let (low, high) = (bounds.next()?, bounds.next()?);
+    let sockets: usize = ["/proc/net/tcp", "/proc/net/tcp6"].iter()
+	.filter_map(|path| std::fs::read_to_string(path).ok())
+	.map(|table| table.lines().count().saturating_sub(1))
+	.sum();
+    Some(sockets as f64 / f64::from(high.saturating_sub(low).max(1)))
+}
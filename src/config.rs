@@ -0,0 +1,769 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Configuration loading and versioning. Files declare the schema they
+//! were written for with a top-level `version`, and files without one are
+//! version 1. The compatibility policy is:
+//!
+//! - a field deprecated in version N still works in files declaring an
+//!   earlier version, where it is migrated and a warning is emitted;
+//! - files declaring version N or later are rejected if they use it;
+//! - files declaring a version newer than [`CURRENT_VERSION`] are rejected
+//!   rather than partially understood.
+//!
+//! Targets may also come from templates: each `[template.<name>]` table
+//! holds `target` entries whose strings refer to parameters as `{param}`,
+//! and each `[[instance]]` names a `template` and supplies its parameters.
+//!
+//! A target's `addr` and the `headers`, `params`, and `json` of its `http`
+//! table may refer to environment variables as `${NAME}`, or
+//! `${NAME:-default}` to fall back when `NAME` is unset, expanded as the file
+//! is loaded. `$${` stands for a literal `${`.
+//!
+//! Files may be written in TOML, YAML, or JSON. All are read into the same
+//! value before templates are expanded and versions migrated, so the formats
+//! accept exactly the same configurations.
+//!
+//! A file may `include` others, by paths relative to it that may use `*`
+//! and `?` in any component, and a directory may be loaded as a whole. Each
+//! file is expanded and migrated by its own version, so templates apply
+//! within the file defining them, and its targets are then appended to those
+//! of the file including it. Other top-level settings may be given by any
+//! one file, or by several when they agree. The aliases of `hosts` tables
+//! are combined, each alias agreeing wherever it is set.
+//!
+//! A `[hosts]` table names addresses, e.g. `db = "10.4.2.15"`, and a target
+//! whose `addr` has an alias as its host, such as `db:5432` or
+//! `https://db/health`, is checked against the alias's address. Aliases
+//! take precedence over names in DNS.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::NetworkResources;
+use crate::addr;
+
+/// Where a target was defined, for pointing at it from CI annotations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// The file, as given or relative to the file including it
+    pub path: PathBuf,
+    /// The line, counted from 1, setting the target's `desc`, when found
+    pub line: Option<usize>,
+}
+
+/// The newest configuration schema this release understands
+pub const CURRENT_VERSION: i64 = 2;
+
+/// A target field replaced or removed in a later schema version
+struct Deprecation {
+    /// The deprecated key
+    key: &'static str,
+    /// First version in which the key is rejected
+    since: i64,
+    /// Key holding the same value from `since` onwards, if any
+    replacement: Option<&'static str>,
+}
+
+/// Target fields deprecated so far, in the order they were deprecated
+const TARGET_DEPRECATIONS: [Deprecation; 2] = [
+    // Options for custom HTTP checks now sit in an `http` table, like the
+    // `dns` and `udp` tables of other kinds
+    Deprecation { key: "custom", since: 2, replacement: Some("http") },
+    // The result of a check was never meant to be configured
+    Deprecation { key: "res", since: 2, replacement: None },
+];
+
+/// Formats a configuration file may be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// Detects the format of the file at `path` from its extension: `.yaml`
+    /// and `.yml` are YAML, `.json` is JSON, and anything else is TOML
+    pub fn from_path(path: &Path) -> Format {
+	match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+	    Some("yaml" | "yml") => Format::Yaml,
+	    Some("json") => Format::Json,
+	    _ => Format::Toml,
+	}
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+	match s.to_ascii_lowercase().as_str() {
+	    "toml" => Ok(Format::Toml),
+	    "yaml" | "yml" => Ok(Format::Yaml),
+	    "json" => Ok(Format::Json),
+	    _ => Err(format!("unknown configuration format `{}`, expected toml, yaml, or json", s)),
+	}
+    }
+}
+
+/// Parses a TOML configuration, migrating it to the current schema.
+/// Returns the resources and any deprecation warnings.
+pub fn parse(text: &str) -> Result<(NetworkResources, Vec<String>), Box<dyn std::error::Error>> {
+    parse_as(text, Format::Toml)
+}
+
+/// Parses a configuration written in `format`, as [`parse`] does for TOML.
+/// Includes are only followed by [`load`], which knows where the file is.
+pub fn parse_as(text: &str, format: Format) -> Result<(NetworkResources, Vec<String>), Box<dyn std::error::Error>> {
+    let mut value = read_value(text, format)?;
+    if value.get("include").is_some() {
+	return Err(From::from("`include` requires loading the configuration from a file"))
+    }
+    expand(&mut value)?;
+    let warnings = migrate(&mut value)?;
+    interpolate(&mut value)?;
+    let aliases = resolve_hosts(&mut value)?;
+    let mut resources: NetworkResources = value.try_into()?;
+    for (target, alias) in resources.target.iter_mut().zip(aliases) {
+	target.alias = alias;
+    }
+    resources.check_dependencies()?;
+    Ok((resources, warnings))
+}
+
+/// Reads a document in `format` into a table
+fn read_value(text: &str, format: Format) -> Result<Value, Box<dyn std::error::Error>> {
+    Ok(match format {
+	Format::Toml => toml::from_str(text)?,
+	// Read through YAML's own value first, which rejects duplicate keys
+	Format::Yaml => match from_json(serde_json::to_value(serde_yaml::from_str::<serde_yaml::Value>(text)?)?, "")? {
+	    Some(Value::Table(table)) => Value::Table(table),
+	    // An empty document, or one holding only comments
+	    None => Value::Table(Table::new()),
+	    _ => return Err(From::from("the document must be a YAML mapping")),
+	},
+	Format::Json => match from_json(serde_json::from_str(text)?, "")? {
+	    Some(Value::Table(table)) => Value::Table(table),
+	    _ => return Err(From::from("the document must be a JSON object")),
+	},
+    })
+}
+
+/// Loads the configuration at `path`, a file or a directory, following its
+/// includes. The file's format is `format` when given and is otherwise
+/// detected from its extension, as it always is for included files and
+/// those in a directory. Returns the resources, which list the files read
+/// as their `sources`, and any deprecation warnings.
+pub fn load(path: &Path, format: Option<Format>) -> Result<(NetworkResources, Vec<String>), Box<dyn std::error::Error>> {
+    let root = fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut loader = Loader { root, ..Loader::default() };
+    let (mut value, origins) = if path.is_dir() {
+	loader.directory(path)?
+    } else {
+	loader.file(path, format)?.unwrap_or_else(|| (Value::Table(Table::new()), Vec::new()))
+    };
+    interpolate(&mut value)?;
+    let aliases = resolve_hosts(&mut value)?;
+    let mut resources: NetworkResources = value.try_into()?;
+    for ((target, origin), alias) in resources.target.iter_mut().zip(origins).zip(aliases) {
+	target.origin = Some(origin);
+	target.alias = alias;
+    }
+    resources.check_dependencies()?;
+    resources.sources = loader.loaded;
+    Ok((resources, loader.warnings))
+}
+
+/// Extensions of the files read from a directory
+const EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+/// A loaded configuration and the origin of each of its targets
+type Loaded = (Value, Vec<Origin>);
+
+/// State of a [`load`] as it follows includes
+#[derive(Default)]
+struct Loader {
+    /// The file or directory loaded, whose own errors need no location
+    root: PathBuf,
+    /// Files being loaded, each included by the one before it
+    stack: Vec<PathBuf>,
+    /// Every file loaded so far
+    loaded: Vec<PathBuf>,
+    /// File setting each top-level key first
+    origins: HashMap<String, PathBuf>,
+    warnings: Vec<String>,
+}
+
+impl Loader {
+    /// Loads a file and those it includes, with the origin of each target,
+    /// returning `None` when it was already loaded through another include
+    fn file(&mut self, path: &Path, format: Option<Format>) -> Result<Option<Loaded>, Box<dyn std::error::Error>> {
+	let canonical = fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+	if self.stack.contains(&canonical) {
+	    return Err(From::from(format!("include cycle: {} is included by a file it includes", path.display())))
+	}
+	if self.loaded.contains(&canonical) {
+	    return Ok(None)
+	}
+	let included = canonical != self.root;
+	let located = |e: Box<dyn std::error::Error>| -> Box<dyn std::error::Error> {
+	    if included { From::from(format!("{}: {}", path.display(), e)) } else { e }
+	};
+	let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+	let mut value = read_value(&text, format.unwrap_or_else(|| Format::from_path(path))).map_err(located)?;
+	self.stack.push(canonical.clone());
+	self.loaded.push(canonical);
+
+	let table = value.as_table_mut().ok_or("the configuration must be a table")?;
+	let includes = match table.remove("include") {
+	    None => Vec::new(),
+	    Some(Value::Array(includes)) => includes,
+	    Some(Value::String(include)) => vec![Value::String(include)],
+	    Some(_) => return Err(located(From::from("`include` must be a path or a list of paths"))),
+	};
+	for key in table.keys() {
+	    self.origins.entry(key.clone()).or_insert_with(|| path.to_path_buf());
+	}
+	expand(&mut value).map_err(located)?;
+	let descs: Vec<Option<&str>> = value.get("target").and_then(Value::as_array).into_iter().flatten()
+	    .map(|target| target.get("desc").and_then(Value::as_str))
+	    .collect();
+	let mut origins: Vec<Origin> = desc_lines(&text, &descs).into_iter()
+	    .map(|line| Origin { path: path.to_path_buf(), line })
+	    .collect();
+	let locate = |warning: String| if included { format!("{}: {}", path.display(), warning) } else { warning };
+	let warnings = migrate(&mut value).map_err(located)?;
+	self.warnings.extend(warnings.into_iter().map(locate));
+
+	let base = path.parent().unwrap_or(Path::new("."));
+	for include in includes {
+	    let pattern = include.as_str().ok_or_else(|| located(From::from("`include` entries must be paths")))?;
+	    let paths = glob(base, pattern)?;
+	    if paths.is_empty() {
+		self.warnings.push(locate(format!("include `{}` matched no files", pattern)));
+	    }
+	    for child in paths {
+		if let Some((child_value, child_origins)) = self.file(&child, None)? {
+		    self.merge(&mut value, child_value, &child)?;
+		    origins.extend(child_origins);
+		}
+	    }
+	}
+	self.stack.pop();
+	Ok(Some((value, origins)))
+    }
+
+    /// Loads every configuration file directly within `dir`, in name order
+    fn directory(&mut self, dir: &Path) -> Result<Loaded, Box<dyn std::error::Error>> {
+	let mut paths: Vec<PathBuf> = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?
+	    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+	    .filter(|path| path.is_file())
+	    .filter(|path| path.extension().and_then(|e| e.to_str())
+		    .is_some_and(|e| EXTENSIONS.contains(&e.to_ascii_lowercase().as_str())))
+	    .collect();
+	paths.sort();
+	if paths.is_empty() {
+	    return Err(From::from(format!("{} holds no configuration files", dir.display())))
+	}
+	let mut value = Value::Table(Table::new());
+	let mut origins = Vec::new();
+	for path in paths {
+	    if let Some((child, child_origins)) = self.file(&path, None)? {
+		self.merge(&mut value, child, &path)?;
+		origins.extend(child_origins);
+	    }
+	}
+	Ok((value, origins))
+    }
+
+    /// Adds the targets and settings of the file at `path` to `into`
+    fn merge(&mut self, into: &mut Value, from: Value, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+	let (into, from) = match (into.as_table_mut(), from) {
+	    (Some(into), Value::Table(from)) => (into, from),
+	    _ => return Err(From::from(format!("{}: the configuration must be a table", path.display()))),
+	};
+	for (key, value) in from {
+	    match (key.as_str(), into.get_mut(&key)) {
+		("version", _) => (),
+		("target", Some(Value::Array(targets))) => match value {
+		    Value::Array(more) => targets.extend(more),
+		    _ => return Err(From::from(format!("{}: `target` must be an array of tables", path.display()))),
+		},
+		// Host aliases combine, so each file may add its own
+		("hosts", Some(Value::Table(hosts))) => match value {
+		    Value::Table(more) => for (alias, addr) in more {
+			match hosts.get(&alias) {
+			    Some(existing) if *existing != addr => {
+				let origin = self.origins.get("hosts").map(|o| o.display().to_string()).unwrap_or_default();
+				return Err(From::from(format!("host alias `{}` is set differently in {} and {}",
+							      alias, origin, path.display())))
+			    },
+			    _ => {
+				hosts.insert(alias, addr);
+			    },
+			}
+		    },
+		    _ => return Err(From::from(format!("{}: `hosts` must be a table", path.display()))),
+		},
+		(_, None) => {
+		    self.origins.insert(key.clone(), path.to_path_buf());
+		    into.insert(key, value);
+		},
+		(_, Some(existing)) if *existing == value => (),
+		(_, Some(_)) => {
+		    let origin = self.origins.get(&key).map(|o| o.display().to_string()).unwrap_or_default();
+		    return Err(From::from(format!("`{}` is set differently in {} and {}", key, origin, path.display())))
+		},
+	    }
+	}
+	Ok(())
+    }
+}
+
+/// Finds the line of `text` setting each of `descs`, in TOML, YAML, or JSON
+/// syntax. Lines are searched from the one found for the previous target, so
+/// repeated descriptions resolve in order. Descriptions written with escapes,
+/// or built from template parameters, are not found.
+fn desc_lines(text: &str, descs: &[Option<&str>]) -> Vec<Option<usize>> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut from = 0;
+    descs.iter().map(|desc| {
+	let desc = (*desc)?;
+	let found = (from..lines.len()).chain(0..from).find(|&i| sets_desc(lines[i], desc))?;
+	from = found + 1;
+	Some(found + 1)
+    }).collect()
+}
+
+/// Returns true when `line` sets `desc` to `value`, as `desc = "value"`,
+/// `desc: value`, or `"desc": "value"`
+fn sets_desc(line: &str, value: &str) -> bool {
+    line.match_indices("desc").any(|(at, _)| {
+	if line[..at].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+	    return false
+	}
+	let rest = line[at + 4..].trim_start_matches(['"', '\'']).trim_start();
+	let rest = match rest.strip_prefix(['=', ':']) {
+	    Some(rest) => rest.trim_start(),
+	    None => return false,
+	};
+	match rest.chars().next() {
+	    Some(quote @ ('"' | '\'')) => rest[1..].strip_prefix(value).is_some_and(|after| after.starts_with(quote)),
+	    _ => rest.strip_prefix(value).is_some_and(|after| {
+		let after = after.trim_start();
+		after.is_empty() || after.starts_with(['#', ',', '}'])
+	    }),
+	}
+    })
+}
+
+/// Expands a pattern relative to `base` into the files matching it, in
+/// name order. Components with `*` or `?` match the entries of their
+/// directory, other than hidden ones; other components are taken as they
+/// are. A pattern without wildcards names a file that must exist.
+fn glob(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    if !pattern.contains(['*', '?']) {
+	return Ok(vec![base.join(pattern)])
+    }
+    let mut paths = vec![base.to_path_buf()];
+    for component in Path::new(pattern).components() {
+	let part = component.as_os_str().to_string_lossy();
+	if !part.contains(['*', '?']) {
+	    paths = paths.into_iter().map(|path| path.join(component)).collect();
+	    continue
+	}
+	let mut matched = Vec::new();
+	for dir in paths.iter().filter(|path| path.is_dir()) {
+	    for entry in fs::read_dir(dir)?.flatten() {
+		let name = entry.file_name().to_string_lossy().into_owned();
+		if !name.starts_with('.') && wildcard(&part, &name) {
+		    matched.push(entry.path());
+		}
+	    }
+	}
+	matched.sort();
+	paths = matched;
+    }
+    Ok(paths.into_iter().filter(|path| path.is_file()).collect())
+}
+
+/// Matches `name` against a pattern in which `*` stands for any run of
+/// characters and `?` for any one
+pub(crate) fn wildcard(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+	match pattern.get(p) {
+	    Some('*') => {
+		star = Some((p, n));
+		p += 1;
+	    },
+	    Some(&c) if c == '?' || c == name[n] => {
+		p += 1;
+		n += 1;
+	    },
+	    _ => match star {
+		// Let the last star swallow one more character
+		Some((sp, sn)) => {
+		    star = Some((sp, sn + 1));
+		    p = sp + 1;
+		    n = sn + 1;
+		},
+		None => return false,
+	    },
+	}
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expands environment variables in the fields of each target that may
+/// refer to them. Every unset variable without a default is reported.
+pub fn interpolate(value: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+    let mut missing = Vec::new();
+    let targets = value.get_mut("target").and_then(Value::as_array_mut);
+    for target in targets.into_iter().flatten().filter_map(Value::as_table_mut) {
+	let desc = target.get("desc").and_then(Value::as_str).unwrap_or("unnamed target").to_string();
+	let mut expand = |field: &str, value: &mut Value| expand_env(value, &mut |name| {
+	    missing.push(format!("{}: `{}` in `{}`", desc, name, field));
+	});
+	if let Some(addr) = target.get_mut("addr") {
+	    expand("addr", addr);
+	}
+	if let Some(http) = target.get_mut("http").and_then(Value::as_table_mut) {
+	    for field in ["headers", "params", "json"] {
+		if let Some(value) = http.get_mut(field) {
+		    expand(&format!("http.{}", field), value);
+		}
+	    }
+	}
+    }
+    if !missing.is_empty() {
+	return Err(From::from(format!("environment variables are not set: {}", missing.join("; "))))
+    }
+    Ok(())
+}
+
+/// Replaces a host in the `addr` of each target that names an alias of the
+/// `hosts` table with the alias's address. Returns the alias each target
+/// used, if any.
+fn resolve_hosts(value: &mut Value) -> Result<Vec<Option<String>>, Box<dyn std::error::Error>> {
+    let mut hosts = HashMap::new();
+    if let Some(table) = value.get("hosts") {
+	let table = table.as_table().ok_or("`hosts` must be a table of aliases and addresses")?;
+	for (alias, addr) in table.iter() {
+	    let addr = addr.as_str().ok_or_else(|| format!("`hosts.{}` must be an address", alias))?;
+	    hosts.insert(alias.clone(), addr.to_string());
+	}
+    }
+    let targets = value.get_mut("target").and_then(Value::as_array_mut);
+    let mut aliases = Vec::new();
+    for target in targets.into_iter().flatten() {
+	let addr = match target.get_mut("addr") {
+	    Some(Value::String(addr)) => addr,
+	    _ => {
+		aliases.push(None);
+		continue
+	    },
+	};
+	let span = addr::host_span(addr);
+	match hosts.get(&addr[span.clone()]) {
+	    Some(host) => {
+		aliases.push(Some(addr[span.clone()].to_string()));
+		// IPv6 addresses need brackets when a port or path follows
+		let host = match host.contains(':') && !host.starts_with('[') && (span.start > 0 || span.end < addr.len()) {
+		    true => format!("[{}]", host),
+		    false => host.clone(),
+		};
+		addr.replace_range(span, &host);
+	    },
+	    None => aliases.push(None),
+	}
+    }
+    Ok(aliases)
+}
+
+/// Expands `${NAME}` in every string within `value`, calling `unset` with
+/// the name of each variable that is not set and has no default
+fn expand_env(value: &mut Value, unset: &mut dyn FnMut(&str)) {
+    match value {
+	Value::String(s) if s.contains("${") => *s = expand_str(s, unset),
+	Value::Array(items) => items.iter_mut().for_each(|item| expand_env(item, unset)),
+	Value::Table(table) => table.iter_mut().for_each(|(_, item)| expand_env(item, unset)),
+	_ => (),
+    }
+}
+
+fn expand_str(s: &str, unset: &mut dyn FnMut(&str)) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+	out.push_str(&rest[..start]);
+	let after = &rest[start..];
+	if let Some(literal) = after.strip_prefix("$${") {
+	    out.push_str("${");
+	    rest = literal;
+	    continue
+	}
+	let end = match after.strip_prefix("${").and_then(|inner| inner.find('}')) {
+	    Some(end) => end,
+	    None => {
+		out.push('$');
+		rest = &after[1..];
+		continue
+	    },
+	};
+	let inner = &after[2..2 + end];
+	let (name, default) = match inner.split_once(":-") {
+	    Some((name, default)) => (name, Some(default)),
+	    None => (inner, None),
+	};
+	match (std::env::var(name), default) {
+	    (Ok(value), _) => out.push_str(&value),
+	    (Err(_), Some(default)) => out.push_str(default),
+	    (Err(_), None) => unset(name),
+	}
+	rest = &after[3 + end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Converts a JSON value, or a YAML document read as one, to the equivalent
+/// TOML value. Nulls are dropped from objects, leaving the field unset, and
+/// rejected elsewhere; `path` locates the value for errors.
+fn from_json(value: serde_json::Value, path: &str) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    use serde_json::Value as Json;
+    let value = match value {
+	Json::Null => return Ok(None),
+	Json::Bool(b) => Value::Boolean(b),
+	Json::Number(n) => match (n.as_i64(), n.as_f64()) {
+	    (Some(i), _) => Value::Integer(i),
+	    (None, Some(f)) if !n.is_u64() => Value::Float(f),
+	    _ => return Err(From::from(format!("{}: {} is too large", path, n))),
+	},
+	Json::String(s) => Value::String(s),
+	Json::Array(items) => {
+	    let mut array = Vec::with_capacity(items.len());
+	    for (i, item) in items.into_iter().enumerate() {
+		let path = format!("{}/{}", path, i);
+		array.push(from_json(item, &path)?.ok_or_else(|| format!("{}: null is not allowed in arrays", path))?);
+	    }
+	    Value::Array(array)
+	},
+	Json::Object(fields) => {
+	    let mut table = Table::new();
+	    for (key, field) in fields {
+		if let Some(field) = from_json(field, &format!("{}/{}", path, key))? {
+		    table.insert(key, field);
+		}
+	    }
+	    Value::Table(table)
+	},
+    };
+    Ok(Some(value))
+}
+
+/// Replaces every `[[instance]]` of a template with the template's targets,
+/// its parameters substituted, appended after the targets of the file
+pub fn expand(value: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+    let table = match value.as_table_mut() {
+	Some(table) => table,
+	None => return Ok(()),
+    };
+    let templates = table.remove("template");
+    let instances = match table.remove("instance") {
+	Some(Value::Array(instances)) => instances,
+	Some(_) => return Err(From::from("`instance` must be an array of tables")),
+	None => return Ok(()),
+    };
+
+    let mut expanded = Vec::new();
+    for instance in instances.iter() {
+	let params = instance.as_table().ok_or("`instance` entries must be tables")?;
+	let name = params.get("template").and_then(Value::as_str)
+	    .ok_or("`instance` entries must name a `template`")?;
+	let targets = templates.as_ref()
+	    .and_then(|t| t.get(name))
+	    .and_then(|t| t.get("target"))
+	    .and_then(Value::as_array)
+	    .ok_or_else(|| format!("no template named `{}` with targets", name))?;
+	for target in targets.iter() {
+	    let mut target = target.clone();
+	    substitute(&mut target, params);
+	    expanded.push(target);
+	}
+    }
+
+    match table.entry("target").or_insert_with(|| Value::Array(Vec::new())) {
+	Value::Array(targets) => targets.extend(expanded),
+	_ => return Err(From::from("`target` must be an array of tables")),
+    }
+    Ok(())
+}
+
+/// Replaces `{param}` in every string within `value` with the parameter's
+/// value. Placeholders without a parameter are left alone.
+fn substitute(value: &mut Value, params: &Table) {
+    match value {
+	Value::String(s) => {
+	    for (key, param) in params.iter().filter(|(key, _)| *key != "template") {
+		let text = match param {
+		    Value::String(text) => text.clone(),
+		    other => other.to_string(),
+		};
+		*s = s.replace(&format!("{{{}}}", key), &text);
+	    }
+	},
+	Value::Array(items) => items.iter_mut().for_each(|item| substitute(item, params)),
+	Value::Table(table) => table.iter_mut().for_each(|(_, item)| substitute(item, params)),
+	_ => (),
+    }
+}
+
+/// Migrates a configuration in place to [`CURRENT_VERSION`], following the
+/// compatibility policy. Returns warnings for each deprecated field found.
+pub fn migrate(value: &mut Value) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let version = match value.get("version") {
+	None => 1,
+	Some(Value::Integer(version)) => *version,
+	Some(other) => return Err(From::from(format!("`version` must be an integer, found {}", other))),
+    };
+    if version > CURRENT_VERSION {
+	return Err(From::from(format!("configuration version {} is newer than the newest supported, {}; \
+				       upgrade connchk to use it", version, CURRENT_VERSION)))
+    }
+    if version < 1 {
+	return Err(From::from(format!("invalid configuration version {}", version)))
+    }
+
+    let mut warnings = Vec::new();
+    let targets = value.get_mut("target").and_then(Value::as_array_mut);
+    for target in targets.into_iter().flatten().filter_map(Value::as_table_mut) {
+	let desc = target.get("desc").and_then(Value::as_str).unwrap_or("unnamed target").to_string();
+	for dep in TARGET_DEPRECATIONS.iter() {
+	    let old = match target.remove(dep.key) {
+		Some(old) => old,
+		None => continue,
+	    };
+	    let advice = match dep.replacement {
+		Some(new) => format!("use `{}` instead", new),
+		None => "remove it".to_string(),
+	    };
+	    if version >= dep.since {
+		return Err(From::from(format!("{}: `{}` is not supported in version {} configurations; {}",
+					      desc, dep.key, dep.since, advice)))
+	    }
+	    warnings.push(format!("{}: `{}` is deprecated and will stop working once the file declares \
+				   `version = {}`; {}", desc, dep.key, dep.since, advice));
+	    if let Some(new) = dep.replacement {
+		if !target.contains_key(new) {
+		    target.insert(new.to_string(), old);
+		}
+	    }
+	}
+    }
+
+    if let Some(table) = value.as_table_mut() {
+	table.insert("version".to_string(), Value::Integer(CURRENT_VERSION));
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Result<Value, Box<dyn std::error::Error>> {
+	read_value(text, Format::Yaml)
+    }
+
+    #[test]
+    fn yaml_nesting_is_read() {
+	let value = yaml("
+target:
+  - desc: API
+    http:
+      headers: {Accept: application/json}
+      ok: [200, 204]
+").unwrap();
+	let target = &value["target"][0];
+	assert_eq!(target["desc"].as_str(), Some("API"));
+	assert_eq!(target["http"]["headers"]["Accept"].as_str(), Some("application/json"));
+	assert_eq!(target["http"]["ok"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn yaml_scalars_are_typed() {
+	let value = yaml("
+count: 3
+ratio: 0.5
+enabled: true
+answer: yes
+quoted: '42'
+unset: null
+empty:
+script: |
+  line one
+  line two
+").unwrap();
+	assert_eq!(value["count"].as_integer(), Some(3));
+	assert_eq!(value["ratio"].as_float(), Some(0.5));
+	assert_eq!(value["enabled"].as_bool(), Some(true));
+	assert_eq!(value["answer"].as_str(), Some("yes"));
+	assert_eq!(value["quoted"].as_str(), Some("42"));
+	assert!(value.get("unset").is_none() && value.get("empty").is_none());
+	assert_eq!(value["script"].as_str(), Some("line one\nline two\n"));
+    }
+
+    #[test]
+    fn yaml_aliases_are_expanded() {
+	let value = yaml("
+timeouts: &fast {timeout_ms: 500}
+target:
+  - http: *fast
+").unwrap();
+	assert_eq!(value["target"][0]["http"]["timeout_ms"].as_integer(), Some(500));
+    }
+
+    #[test]
+    fn empty_yaml_is_an_empty_table() {
+	assert_eq!(yaml("# nothing yet\n").unwrap(), Value::Table(Table::new()));
+    }
+
+    #[test]
+    fn malformed_yaml_is_rejected() {
+	assert!(yaml("- a\n- b\n").unwrap_err().to_string().contains("must be a YAML mapping"));
+	assert!(yaml("a: 1\n---\nb: 2\n").is_err());
+	assert!(yaml("a: [1, null]\n").unwrap_err().to_string().contains("/a/1: null is not allowed"));
+	assert!(yaml("a: {b: 1\n").is_err());
+	assert!(yaml("a: 1\na: 2\n").is_err());
+    }
+
+    #[test]
+    fn yaml_configuration_matches_toml() {
+	let (from_yaml, _) = parse_as("
+target:
+  - desc: GitLab SSH
+    addr: gitlab.com:22
+    kind: Tcp
+", Format::Yaml).unwrap();
+	let (from_toml, _) = parse("[[target]]\ndesc = \"GitLab SSH\"\naddr = \"gitlab.com:22\"\nkind = \"Tcp\"\n").unwrap();
+	assert_eq!(from_yaml.target.len(), 1);
+	assert_eq!(from_yaml.target[0].desc, from_toml.target[0].desc);
+	assert_eq!(from_yaml.target[0].addr, from_toml.target[0].addr);
+    }
+}
@@ -0,0 +1,551 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Minimal DNS wire-format support for resolver checks. Only what is needed
+//! to issue a single-question query over UDP, validate the reply header, and
+//! read address, PTR, and SRV records is implemented here.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::addr;
+use crate::error::{self, Stage};
+
+/// Default number of queries issued by a DNS benchmark check
+const DEFAULT_QUERIES: u32 = 10;
+
+/// Default time to wait for each individual reply before counting it as lost
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default time to wait for a connection to each address of an SRV endpoint
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Provides a deserialize target for DNS resolver benchmark checks.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DnsOptions {
+    /// The name to query for
+    pub name: String,
+    /// Record type to request, e.g. `A` or `AAAA`. Defaults to `A`.
+    pub record: Option<String>,
+    /// Number of queries to issue. Defaults to 10.
+    pub queries: Option<u32>,
+}
+
+/// Provides a deserialize target for the `srv` table of SRV targets, whose
+/// `addr` is a service name such as `_ldap._tcp.example.com`
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SrvOptions {
+    /// Resolver to query, defaulting to the first one the system is
+    /// configured with
+    pub resolver: Option<String>,
+    /// Endpoints that must be reachable for the check to pass. Defaults to
+    /// all of them.
+    pub min_healthy: Option<usize>,
+}
+
+/// One endpoint of a service, from an SRV answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Maps a textual record type onto its numeric QTYPE
+fn qtype(record: &str) -> Result<u16, Box<dyn std::error::Error>> {
+    match record.to_ascii_uppercase().as_str() {
+	"A" => Ok(1),
+	"NS" => Ok(2),
+	"CNAME" => Ok(5),
+	"SOA" => Ok(6),
+	"PTR" => Ok(12),
+	"MX" => Ok(15),
+	"TXT" => Ok(16),
+	"AAAA" => Ok(28),
+	"SRV" => Ok(33),
+	other => Err(From::from(format!("unsupported DNS record type: {}", other))),
+    }
+}
+
+/// Returns a pseudo-random query ID
+pub fn query_id() -> u16 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    hasher.finish() as u16
+}
+
+/// Builds a recursive query packet with a single question
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut pkt = Vec::with_capacity(512);
+    pkt.extend_from_slice(&id.to_be_bytes());
+    // RD set, QDCOUNT 1, no answer/authority/additional records
+    pkt.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.trim_end_matches('.').split('.') {
+	if label.is_empty() || label.len() > 63 {
+	    return Err(From::from(format!("invalid DNS name: {}", name)));
+	}
+	pkt.push(label.len() as u8);
+	pkt.extend_from_slice(label.as_bytes());
+    }
+    pkt.push(0);
+    pkt.extend_from_slice(&qtype.to_be_bytes());
+    pkt.extend_from_slice(&1u16.to_be_bytes());
+    Ok(pkt)
+}
+
+/// Validates a reply header against the query ID, returning the RCODE
+pub fn reply_rcode(id: u16, reply: &[u8]) -> Option<u8> {
+    if reply.len() < 12 || u16::from_be_bytes([reply[0], reply[1]]) != id || reply[2] & 0x80 == 0 {
+	return None
+    }
+    Some(reply[3] & 0x0f)
+}
+
+/// Resolves the resolver address, assuming port 53 when none is given
+fn resolver_addr(addr: &str) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    addr::resolve_or_port(addr, 53)?.into_iter().next()
+	.ok_or_else(|| From::from(format!("unable to resolve {}", addr)))
+}
+
+/// Sends a single query to `server` and waits up to `timeout` for a
+/// matching reply.
+pub fn query(server: SocketAddr, name: &str, qtype: u16, timeout: Duration) -> Result<Duration, Box<dyn std::error::Error>> {
+    let bind: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(timeout))?;
+    let id = query_id();
+    let pkt = build_query(id, name, qtype)?;
+    let mut buf = [0u8; 1500];
+    let now = Instant::now();
+    socket.send(&pkt)?;
+    loop {
+	let len = socket.recv(&mut buf)
+	    .map_err(|e| error::from_io(e, Stage::Response, Some(timeout)))?;
+	match reply_rcode(id, &buf[..len]) {
+	    Some(0) | Some(3) => return Ok(now.elapsed()),
+	    Some(rcode) => return Err(From::from(format!("server returned RCODE {}", rcode))),
+	    // Stray or late replies to an earlier query are ignored
+	    None => continue,
+	}
+    }
+}
+
+/// Returns the resolvers the system is configured with, in order, read
+/// from `/etc/resolv.conf` or the Windows network settings
+pub fn nameservers() -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+    let (config, _) = hickory_resolver::system_conf::read_system_conf()?;
+    let mut servers: Vec<SocketAddr> = Vec::new();
+    // Each resolver is listed once per protocol
+    for server in config.name_servers() {
+	if !servers.contains(&server.socket_addr) {
+	    servers.push(server.socket_addr);
+	}
+    }
+    Ok(servers)
+}
+
+/// Returns the first resolver the system is configured with
+fn system_resolver() -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let servers = nameservers().map_err(|e| format!("unable to read the system's resolvers ({}); \
+						       set `resolver` in the `srv` table", e))?;
+    servers.first().copied()
+	.ok_or_else(|| From::from("the system has no resolver; set `resolver` in the `srv` table"))
+}
+
+/// Reads a possibly compressed name starting at `pos`, returning it and the
+/// position after it in the message
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..128 {
+	let len = *msg.get(pos)? as usize;
+	match len {
+	    0 => {
+		let name = labels.join(".");
+		return Some((name, end.unwrap_or(pos + 1)))
+	    },
+	    l if l & 0xc0 == 0xc0 => {
+		let target = ((l & 0x3f) << 8) | *msg.get(pos + 1)? as usize;
+		end.get_or_insert(pos + 2);
+		pos = target;
+	    },
+	    l => {
+		labels.push(String::from_utf8_lossy(msg.get(pos + 1..pos + 1 + l)?).into_owned());
+		pos += 1 + l;
+	    },
+	}
+    }
+    // A loop of compression pointers
+    None
+}
+
+/// Data of a resource record, for the types connchk reads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv(SrvRecord),
+    Other(u16),
+}
+
+/// A resource record read from a reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    /// True for records of the answer section, false for the authority
+    /// and additional sections
+    pub answer: bool,
+    pub data: RData,
+}
+
+/// Reads the records of every section of `reply`
+pub fn records(reply: &[u8]) -> Option<Vec<Record>> {
+    let field = |at: usize| reply.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let questions = field(4)?;
+    let answers = field(6)? as usize;
+    let total = answers + field(8)? as usize + field(10)? as usize;
+    let mut pos = 12;
+    for _ in 0..questions {
+	pos = read_name(reply, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for i in 0..total {
+	let (name, after) = read_name(reply, pos)?;
+	pos = after;
+	let rtype = field(pos)?;
+	let rdlen = field(pos + 8)? as usize;
+	let rdata = pos + 10;
+	let bytes = reply.get(rdata..rdata + rdlen)?;
+	let data = match rtype {
+	    1 => RData::A(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+	    28 => RData::Aaaa(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+	    12 => RData::Ptr(read_name(reply, rdata)?.0),
+	    33 => RData::Srv(SrvRecord {
+		priority: field(rdata)?,
+		weight: field(rdata + 2)?,
+		port: field(rdata + 4)?,
+		target: read_name(reply, rdata + 6)?.0,
+	    }),
+	    other => RData::Other(other),
+	};
+	records.push(Record { name, answer: i < answers, data });
+	pos = rdata + rdlen;
+    }
+    Some(records)
+}
+
+/// Reads the SRV records from the answer section of `reply`
+fn parse_srv(reply: &[u8]) -> Option<Vec<SrvRecord>> {
+    let srv = records(reply)?.into_iter()
+	.filter(|r| r.answer)
+	.filter_map(|r| match r.data {
+	    RData::Srv(srv) => Some(srv),
+	    _ => None,
+	})
+	.collect();
+    Some(srv)
+}
+
+/// Repeats a query over TCP, for replies too large for a datagram
+fn query_tcp(server: SocketAddr, pkt: &[u8], timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)
+	.map_err(|e| error::from_io(e, Stage::Connect, Some(timeout)))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(&(pkt.len() as u16).to_be_bytes())?;
+    stream.write_all(pkt)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).map_err(|e| error::from_io(e, Stage::Response, Some(timeout)))?;
+    let mut reply = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply).map_err(|e| error::from_io(e, Stage::Response, Some(timeout)))?;
+    Ok(reply)
+}
+
+/// Sends the query `pkt` with `id` to `server` over UDP, repeating it over
+/// TCP when the reply is truncated, and returns the reply once its RCODE is
+/// NOERROR
+fn exchange(server: SocketAddr, name: &str, id: u16, pkt: &[u8], timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let bind: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(timeout))?;
+    let mut buf = [0u8; 1500];
+    socket.send(pkt)?;
+    let mut reply = loop {
+	let len = socket.recv(&mut buf)
+	    .map_err(|e| error::from_io(e, Stage::Response, Some(timeout)))?;
+	if reply_rcode(id, &buf[..len]).is_some() {
+	    break buf[..len].to_vec()
+	}
+    };
+    if reply[2] & 0x02 != 0 {
+	reply = query_tcp(server, pkt, timeout)?;
+    }
+    match reply_rcode(id, &reply) {
+	Some(0) => Ok(reply),
+	Some(3) => Err(From::from(format!("{} does not exist", name))),
+	Some(rcode) => Err(From::from(format!("server returned RCODE {}", rcode))),
+	None => Err(From::from("malformed reply")),
+    }
+}
+
+/// Looks up the SRV records of `name`, ordered by priority and then by
+/// descending weight
+pub fn resolve_srv(server: SocketAddr, name: &str, timeout: Duration) -> Result<Vec<SrvRecord>, Box<dyn std::error::Error>> {
+    let id = query_id();
+    let reply = exchange(server, name, id, &build_query(id, name, 33)?, timeout)?;
+    let mut records = parse_srv(&reply).ok_or("malformed SRV answer")?;
+    records.sort_by_key(|r| (r.priority, std::cmp::Reverse(r.weight)));
+    Ok(records)
+}
+
+/// Resolves the SRV records of the service `name` and opens a TCP connection
+/// to each endpoint, as clients discovering the service would. Succeeds
+/// when at least `min_healthy` endpoints, or all of them, are reachable.
+pub fn check_srv(name: &str, options: &SrvOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let server = match &options.resolver {
+	Some(resolver) => resolver_addr(resolver)?,
+	None => system_resolver()?,
+    };
+    let records = resolve_srv(server, name, timeout.unwrap_or(QUERY_TIMEOUT))?;
+    // A lone record targeting the root means the service is not offered
+    if records.is_empty() || records.iter().all(|r| r.target.is_empty()) {
+	return Err(From::from(format!("{} has no SRV records", name)))
+    }
+    check_endpoints(&records, options, timeout)
+}
+
+/// Connects to the endpoint of each of `records` in the order given, that of
+/// [`resolve_srv`], trying every address of an endpoint until one answers.
+/// Each connection waits up to `timeout`, or [`CONNECT_TIMEOUT`] when none
+/// is given.
+fn check_endpoints(records: &[SrvRecord], options: &SrvOptions, timeout: Option<Duration>)
+		   -> Result<String, Box<dyn std::error::Error>> {
+    let timeout = timeout.unwrap_or(CONNECT_TIMEOUT);
+    let mut reachable = Vec::new();
+    let mut unreachable = Vec::new();
+    for record in records {
+	let endpoint = format!("{}:{}", record.target, record.port);
+	let started = Instant::now();
+	let result = addr::resolve(&endpoint).and_then(|addrs| crate::connect_timeout(&addrs, timeout));
+	match result {
+	    Ok(_) => reachable.push(format!("{} ({:.0}ms)", endpoint, started.elapsed().as_secs_f64() * 1000.0)),
+	    Err(e) => unreachable.push(format!("{}: {}", endpoint, e)),
+	}
+    }
+
+    let wanted = options.min_healthy.unwrap_or(records.len()).min(records.len());
+    if reachable.len() < wanted {
+	return Err(From::from(format!("\n\tReachable: {}/{} (need {})\n\tDetails: {}",
+				      reachable.len(), records.len(), wanted, unreachable.join("; "))))
+    }
+    let mut details = format!("{}/{} endpoints reachable: {}", reachable.len(), records.len(), reachable.join(", "));
+    if !unreachable.is_empty() {
+	details.push_str(&format!("; unreachable: {}", unreachable.join("; ")));
+    }
+    Ok(details)
+}
+
+/// Returns the nearest-rank percentile from a sorted slice of samples
+pub(crate) fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Issues the configured number of queries against the resolver at `addr`
+/// and reports the success rate along with p50/p90/p99 latency. Each query
+/// waits up to `timeout`, or [`QUERY_TIMEOUT`] when none is given. Fails
+/// only when no query was answered.
+pub fn benchmark(addr: &str, options: &DnsOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let server = resolver_addr(addr)?;
+    let qtype = qtype(options.record.as_deref().unwrap_or("A"))?;
+    let queries = options.queries.unwrap_or(DEFAULT_QUERIES).max(1);
+    let timeout = timeout.unwrap_or(QUERY_TIMEOUT);
+
+    let mut samples = Vec::with_capacity(queries as usize);
+    let mut last_err = None;
+    for _ in 0..queries {
+	match query(server, &options.name, qtype, timeout) {
+	    Ok(dur) => samples.push(dur),
+	    Err(e) => last_err = Some(e),
+	}
+    }
+
+    if samples.is_empty() {
+	let detail = last_err.map(|e| e.to_string()).unwrap_or_default();
+	return Err(From::from(format!("\n\tAnswered: 0/{}\n\tDetails: {}", queries, detail)))
+    }
+
+    samples.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    Ok(format!("answered {}/{} ({:.0}%), p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms",
+	       samples.len(), queries,
+	       samples.len() as f64 * 100.0 / queries as f64,
+	       ms(percentile(&samples, 50)),
+	       ms(percentile(&samples, 90)),
+	       ms(percentile(&samples, 99))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a reply to a query for `name` with `rcode`, holding `answers`
+    /// and then `additional` records, each named by a pointer to the
+    /// question and given as a type and its data
+    fn reply(id: u16, name: &str, rcode: u8, answers: &[(u16, Vec<u8>)], additional: &[(u16, Vec<u8>)]) -> Vec<u8> {
+	let mut pkt = build_query(id, name, 1).unwrap();
+	pkt[2] = 0x81;
+	pkt[3] = 0x80 | rcode;
+	pkt[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+	pkt[10..12].copy_from_slice(&(additional.len() as u16).to_be_bytes());
+	for (rtype, data) in answers.iter().chain(additional) {
+	    pkt.extend_from_slice(&[0xc0, 0x0c]);
+	    pkt.extend_from_slice(&rtype.to_be_bytes());
+	    pkt.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x0e, 0x10]);
+	    pkt.extend_from_slice(&(data.len() as u16).to_be_bytes());
+	    pkt.extend_from_slice(data);
+	}
+	pkt
+    }
+
+    #[test]
+    fn query_is_encoded() {
+	let pkt = build_query(0x1234, "www.example.com.", 28).unwrap();
+	let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+	expected.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x1c\x00\x01");
+	assert_eq!(pkt, expected);
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+	assert!(build_query(1, "a..b", 1).is_err());
+	assert!(build_query(1, &format!("{}.com", "x".repeat(64)), 1).is_err());
+    }
+
+    #[test]
+    fn record_types_are_mapped() {
+	assert_eq!(qtype("aaaa").unwrap(), 28);
+	assert_eq!(qtype("SRV").unwrap(), 33);
+	assert!(qtype("HINFO").is_err());
+    }
+
+    #[test]
+    fn reply_header_is_validated() {
+	let pkt = reply(7, "example.com", 3, &[], &[]);
+	assert_eq!(reply_rcode(7, &pkt), Some(3));
+	assert_eq!(reply_rcode(8, &pkt), None);
+	assert_eq!(reply_rcode(7, &build_query(7, "example.com", 1).unwrap()), None);
+	assert_eq!(reply_rcode(7, &pkt[..11]), None);
+    }
+
+    #[test]
+    fn records_of_every_section_are_read() {
+	let mut srv = vec![0, 10, 0, 5, 0x01, 0xbb];
+	srv.extend_from_slice(&[0xc0, 0x0c]);
+	let pkt = reply(1, "example.com", 0, &[
+	    (1, vec![192, 0, 2, 1]),
+	    (28, Ipv6Addr::LOCALHOST.octets().to_vec()),
+	    (33, srv),
+	], &[(5, vec![0])]);
+	let records = records(&pkt).unwrap();
+	assert_eq!(records.len(), 4);
+	assert_eq!(records[0], Record { name: "example.com".into(), answer: true, data: RData::A(Ipv4Addr::new(192, 0, 2, 1)) });
+	assert_eq!(records[1].data, RData::Aaaa(Ipv6Addr::LOCALHOST));
+	assert_eq!(records[2].data, RData::Srv(SrvRecord { priority: 10, weight: 5, port: 443, target: "example.com".into() }));
+	assert_eq!(records[3], Record { name: "example.com".into(), answer: false, data: RData::Other(5) });
+    }
+
+    #[test]
+    fn malformed_replies_are_rejected() {
+	let pkt = reply(1, "example.com", 0, &[(1, vec![192, 0, 2, 1])], &[]);
+	assert!(records(&pkt[..pkt.len() - 1]).is_none());
+	// An A record with six bytes of data
+	let pkt = reply(1, "example.com", 0, &[(1, vec![0; 6])], &[]);
+	assert!(records(&pkt).is_none());
+	// A compression pointer pointing at itself
+	let mut pkt = reply(1, "example.com", 0, &[(1, vec![192, 0, 2, 1])], &[]);
+	let answer = pkt.len() - 16;
+	pkt[answer..answer + 2].copy_from_slice(&[0xc0, answer as u8]);
+	assert!(records(&pkt).is_none());
+    }
+
+    #[test]
+    fn srv_records_are_ordered() {
+	let target = |name: &[u8]| {
+	    let mut data = vec![0, 0, 0, 0, 0, 80];
+	    data.extend_from_slice(name);
+	    data
+	};
+	let mut low = target(b"\x01b\x00");
+	low[1] = 20;
+	let mut heavy = target(b"\x01c\x00");
+	heavy[3] = 9;
+	let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let addr = server.local_addr().unwrap();
+	let handle = std::thread::spawn(move || {
+	    let mut buf = [0u8; 512];
+	    let (len, peer) = server.recv_from(&mut buf).unwrap();
+	    let id = u16::from_be_bytes([buf[0], buf[1]]);
+	    assert_eq!(&buf[12..len - 4], b"\x04_ldp\x04_tcp\x07example\x03com\x00");
+	    // A stray reply to another query comes first and is ignored
+	    server.send_to(&reply(id.wrapping_add(1), "_ldp._tcp.example.com", 0, &[], &[]), peer).unwrap();
+	    let answers = [(33, low), (33, target(b"\x01a\x00")), (33, heavy)];
+	    server.send_to(&reply(id, "_ldp._tcp.example.com", 0, &answers, &[]), peer).unwrap();
+	});
+	let records = resolve_srv(addr, "_ldp._tcp.example.com", Duration::from_secs(5)).unwrap();
+	handle.join().unwrap();
+	let targets: Vec<&str> = records.iter().map(|r| r.target.as_str()).collect();
+	assert_eq!(targets, ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn srv_endpoints_are_tried_on_every_address() {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let up = listener.local_addr().unwrap().port();
+	let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+	let record = |priority: u16, weight: u16, port: u16, target: &str| SrvRecord {
+	    priority, weight, port, target: target.to_string(),
+	};
+	// `localhost` may resolve to ::1 first, where nothing listens
+	let records = vec![
+	    record(10, 5, up, "localhost"),
+	    record(10, 1, down, "127.0.0.1"),
+	    record(20, 0, up, "127.0.0.1"),
+	];
+	let options = SrvOptions { resolver: None, min_healthy: Some(2) };
+	let details = check_endpoints(&records, &options, Some(Duration::from_secs(2))).unwrap();
+	let listed: Vec<&str> = details.split(": ").nth(1).unwrap().split(", ").collect();
+	assert!(details.starts_with("2/3 endpoints reachable: "), "{}", details);
+	assert!(listed[0].starts_with(&format!("localhost:{} (", up)), "{}", details);
+	assert!(listed[1].starts_with(&format!("127.0.0.1:{} (", up)), "{}", details);
+	assert!(details.contains(&format!("; unreachable: 127.0.0.1:{}: ", down)), "{}", details);
+
+	// Without a timeout of its own each connection still gives up
+	let options = SrvOptions { resolver: None, min_healthy: None };
+	let err = check_endpoints(&records, &options, None).unwrap_err().to_string();
+	assert!(err.starts_with("\n\tReachable: 2/3 (need 3)"), "{}", err);
+    }
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+	let samples: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+	assert_eq!(percentile(&samples, 50), Duration::from_millis(5));
+	assert_eq!(percentile(&samples, 99), Duration::from_millis(10));
+	assert_eq!(percentile(&samples[..1], 0), Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,188 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Self-diagnostics for the probing host. Reports the local facts that most
+//! often explain a run where everything appears to be down: routing, DNS,
+//! proxies, the clock, and the TLS trust store.
+
+use std::env;
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::dns;
+
+/// Proxy variables honored by HTTP clients, checked in both cases
+const PROXY_VARS: [&str; 4] = ["HTTP_PROXY", "HTTPS_PROXY", "ALL_PROXY", "NO_PROXY"];
+
+/// Common locations of the system CA bundle
+const CA_BUNDLES: [&str; 5] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+    "/usr/local/etc/openssl/cert.pem",
+];
+
+/// Name queried to test each DNS server's responsiveness
+const PROBE_NAME: &str = "example.com";
+
+/// Builds the full diagnostics report
+pub fn report() -> String {
+    let mut out = String::new();
+    section(&mut out, "Default routes", default_routes());
+    section(&mut out, "DNS servers", dns_servers());
+    section(&mut out, "Proxy environment", proxy_env());
+    if cfg!(windows) {
+	section(&mut out, "System proxy", system_proxy());
+    }
+    section(&mut out, "Clock", clock());
+    section(&mut out, "TLS trust store", trust_store());
+    out
+}
+
+/// Appends a titled section with one indented line per entry
+fn section(out: &mut String, title: &str, lines: Vec<String>) {
+    let _ = writeln!(out, "{}:", title);
+    if lines.is_empty() {
+	let _ = writeln!(out, "\tnone found");
+    }
+    for line in lines {
+	let _ = writeln!(out, "\t{}", line);
+    }
+}
+
+/// Reads default routes from the kernel routing table
+#[cfg(target_os = "linux")]
+fn default_routes() -> Vec<String> {
+    let table = match std::fs::read_to_string("/proc/net/route") {
+	Ok(table) => table,
+	Err(e) => return vec![format!("unable to read /proc/net/route: {}", e)],
+    };
+
+    table.lines()
+	.skip(1)
+	.filter_map(|line| {
+	    let fields: Vec<&str> = line.split_whitespace().collect();
+	    match fields.as_slice() {
+		[iface, "00000000", gw, _, _, _, metric, "00000000", ..] => {
+		    let gw = u32::from_str_radix(gw, 16).ok()?;
+		    let gw = std::net::Ipv4Addr::from(gw.to_le_bytes());
+		    Some(format!("0.0.0.0/0 via {} dev {} metric {}", gw, iface, metric))
+		},
+		_ => None,
+	    }
+	})
+	.collect()
+}
+
+/// Reads default routes from `netstat`, where no procfs is available
+#[cfg(not(target_os = "linux"))]
+fn default_routes() -> Vec<String> {
+    match std::process::Command::new("netstat").arg("-rn").output() {
+	Ok(output) => String::from_utf8_lossy(&output.stdout)
+	    .lines()
+	    .filter(|l| l.starts_with("default") || l.trim_start().starts_with("0.0.0.0"))
+	    .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+	    .collect(),
+	Err(e) => vec![format!("unable to run netstat: {}", e)],
+    }
+}
+
+/// Lists configured nameservers and whether each answers a query
+fn dns_servers() -> Vec<String> {
+    let servers = match dns::nameservers() {
+	Ok(servers) => servers,
+	Err(e) => return vec![format!("unable to read the system's resolvers: {}", e)],
+    };
+
+    servers.into_iter()
+	.map(|server| match dns::query(server, PROBE_NAME, 1, dns::QUERY_TIMEOUT) {
+	    Ok(dur) => format!("{}: responded in {}ms", server.ip(), dur.as_millis()),
+	    Err(e) => format!("{}: no response ({})", server.ip(), e),
+	})
+	.collect()
+}
+
+/// Lists proxy related environment variables that are set
+fn proxy_env() -> Vec<String> {
+    PROXY_VARS.iter()
+	.flat_map(|var| [var.to_string(), var.to_lowercase()])
+	.filter_map(|var| env::var(&var).ok().map(|val| format!("{}={}", var, val)))
+	.collect()
+}
+
+/// Reads the WinINet proxy settings that HTTP checks inherit
+#[cfg(windows)]
+fn system_proxy() -> Vec<String> {
+    let settings = match crate::proxy::internet_settings() {
+	Some(settings) => settings,
+	None => return vec!["unable to run reg".to_string()],
+    };
+    let mut lines = vec![format!("ProxyEnable={} (used by checks)", settings.proxy_enable)];
+    if let Some(server) = &settings.proxy_server {
+	lines.push(format!("ProxyServer={} (used by checks)", server));
+    }
+    if let Some(bypass) = &settings.proxy_override {
+	lines.push(format!("ProxyOverride={}", bypass));
+    }
+    if let Some(url) = &settings.auto_config_url {
+	lines.push(format!("AutoConfigURL={}", url));
+    }
+    lines.push(format!("AutoDetect={}", settings.auto_detect));
+    match settings.pac_url() {
+	Some(url) if cfg!(feature = "pac") => lines.push(format!("auto-config script: {} (used by checks without a proxy setting)", url)),
+	Some(url) => lines.push(format!("auto-config script: {} (NOT used, built without the pac feature)", url)),
+	None => (),
+    }
+    lines
+}
+
+/// The WinINet proxy settings only exist on Windows
+#[cfg(not(windows))]
+fn system_proxy() -> Vec<String> {
+    Vec::new()
+}
+
+/// Reports whether the kernel considers the clock synchronized
+#[cfg(target_os = "linux")]
+fn clock() -> Vec<String> {
+    // SAFETY: adjtimex with zeroed modes only reads the kernel clock state
+    let state = unsafe {
+	let mut tx: libc::timex = std::mem::zeroed();
+	libc::adjtimex(&mut tx)
+    };
+    let status = match state {
+	-1 => format!("unable to query clock state: {}", std::io::Error::last_os_error()),
+	libc::TIME_ERROR => "NOT synchronized".to_string(),
+	_ => "synchronized".to_string(),
+    };
+    vec![status]
+}
+
+/// Reports whether the clock is synchronized, where the platform exposes it
+#[cfg(not(target_os = "linux"))]
+fn clock() -> Vec<String> {
+    vec!["synchronization status unavailable on this platform".to_string()]
+}
+
+/// Describes the trust anchors available to TLS checks
+fn trust_store() -> Vec<String> {
+    let mut lines = vec![format!("bundled webpki roots: {} anchors (used by checks)",
+				 webpki_roots::TLS_SERVER_ROOTS.len())];
+    if let Ok(file) = env::var("SSL_CERT_FILE") {
+	let found = if Path::new(&file).exists() { "present" } else { "MISSING" };
+	lines.push(format!("SSL_CERT_FILE={} ({})", file, found));
+    }
+    match CA_BUNDLES.iter().find(|p| Path::new(p).exists()) {
+	Some(path) => lines.push(format!("system bundle: {}", path)),
+	None => lines.push("system bundle: not found".to_string()),
+    }
+    lines
+}
@@ -0,0 +1,407 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Classification of check failures. Timeouts record how far a check got
+//! before giving up, and every error maps onto a short machine-readable code
+//! for use in notifications and reports.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::outcome::SkipReason;
+use crate::privilege::InsufficientPrivileges;
+
+/// The step a check was performing when it stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Resolving the target's name
+    Resolve,
+    /// Establishing the connection, including any TLS handshake when the
+    /// two cannot be told apart
+    Connect,
+    /// Performing the TLS handshake on an established connection
+    Tls,
+    /// Waiting for a response on an established connection
+    Response,
+}
+
+impl Stage {
+    /// Returns the lowercase name of the stage
+    pub fn as_str(&self) -> &'static str {
+	match self {
+	    Stage::Resolve => "resolve",
+	    Stage::Connect => "connect",
+	    Stage::Tls => "tls",
+	    Stage::Response => "response",
+	}
+    }
+
+    /// Describes what had completed when the check stopped at this stage
+    fn progress(&self) -> &'static str {
+	match self {
+	    Stage::Resolve => "name not resolved",
+	    Stage::Connect => "not connected",
+	    Stage::Tls => "connected, TLS not done",
+	    Stage::Response => "connected, awaiting response",
+	}
+    }
+}
+
+/// A check that ran out of time
+#[derive(Debug)]
+pub struct Timeout {
+    /// Where the check was when time ran out
+    pub stage: Stage,
+    /// The configured timeout, when the limit was not the system default
+    pub limit: Option<Duration>,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self.limit {
+	    Some(limit) => write!(f, "timed out after {}ms during {} ({})",
+				  limit.as_millis(), self.stage.as_str(), self.stage.progress()),
+	    None => write!(f, "timed out at the system default during {} ({})",
+			   self.stage.as_str(), self.stage.progress()),
+	}
+    }
+}
+
+impl Error for Timeout {}
+
+/// A name that could not be resolved for a reason other than a timeout
+#[derive(Debug)]
+pub struct NotResolved(pub io::Error);
+
+impl fmt::Display for NotResolved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "name not resolved: {}", self.0)
+    }
+}
+
+impl Error for NotResolved {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+	Some(&self.0)
+    }
+}
+
+/// A check that was never started because its run was out of time
+#[derive(Debug)]
+pub struct DeadlineReached {
+    pub limit: Duration,
+}
+
+impl fmt::Display for DeadlineReached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "run deadline of {}ms reached before the check started", self.limit.as_millis())
+    }
+}
+
+impl Error for DeadlineReached {}
+
+/// A check that was not started because a target it depends on failed
+#[derive(Debug)]
+pub struct DependencyFailed(pub String);
+
+impl fmt::Display for DependencyFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "dependency failed: {}", self.0)
+    }
+}
+
+impl Error for DependencyFailed {}
+
+/// A check that was not started because its target is disabled
+#[derive(Debug)]
+pub struct Disabled;
+
+impl fmt::Display for Disabled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "disabled")
+    }
+}
+
+impl Error for Disabled {}
+
+/// A check that was not started because its target is under maintenance,
+/// with the note given for it
+#[derive(Debug)]
+pub struct Maintenance(pub String);
+
+impl fmt::Display for Maintenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self.0.is_empty() {
+	    true => write!(f, "maintenance"),
+	    false => write!(f, "maintenance: {}", self.0),
+	}
+    }
+}
+
+impl Error for Maintenance {}
+
+/// A check that stopped on a bug in connchk rather than a problem with the
+/// target, such as a panic
+#[derive(Debug)]
+pub struct Internal(pub String);
+
+impl fmt::Display for Internal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "internal error: {}", self.0)
+    }
+}
+
+impl Error for Internal {}
+
+/// Returns why a check did not run when `err` means it was skipped
+pub fn skip_reason(err: &(dyn Error + 'static)) -> Option<SkipReason> {
+    if err.is::<DependencyFailed>() {
+	Some(SkipReason::DependencyFailed)
+    } else if err.is::<Disabled>() {
+	Some(SkipReason::Disabled)
+    } else if err.is::<InsufficientPrivileges>() {
+	Some(SkipReason::InsufficientPrivileges)
+    } else if err.is::<Maintenance>() {
+	Some(SkipReason::Maintenance)
+    } else if err.is::<DeadlineReached>() {
+	Some(SkipReason::Deadline)
+    } else {
+	None
+    }
+}
+
+/// A response whose body did not satisfy the check's assertions
+#[derive(Debug)]
+pub struct BodyMismatch(pub String);
+
+impl fmt::Display for BodyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}", self.0)
+    }
+}
+
+impl Error for BodyMismatch {}
+
+/// A response for which the check's `assert` expression was not true
+#[derive(Debug)]
+pub struct AssertionFailed(pub String);
+
+impl fmt::Display for AssertionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}", self.0)
+    }
+}
+
+impl Error for AssertionFailed {}
+
+/// A check expected to fail that did not: the connection was allowed, or
+/// failed in a way that does not show it was blocked
+#[derive(Debug)]
+pub struct UnexpectedSuccess(pub String);
+
+impl fmt::Display for UnexpectedSuccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UnexpectedSuccess {}
+
+/// A check that passed, but slower than its `max_latency_ms`
+#[derive(Debug)]
+pub struct LatencyExceeded {
+    pub took: Duration,
+    pub limit: Duration,
+}
+
+impl fmt::Display for LatencyExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "\n\tLatency: {}ms exceeds the limit of {}ms", self.took.as_millis(), self.limit.as_millis())
+    }
+}
+
+impl Error for LatencyExceeded {}
+
+/// A host that resolved to an address outside the expected CIDR blocks
+#[derive(Debug)]
+pub struct UnexpectedAddress(pub String);
+
+impl fmt::Display for UnexpectedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UnexpectedAddress {}
+
+/// Returns true when `code` shows a connection was blocked: refused,
+/// unreachable, denied by a local firewall, or timed out while connecting
+pub fn is_blocked(code: &str) -> bool {
+    matches!(code, "connection_refused" | "host_unreachable" | "network_unreachable"
+	     | "permission_denied" | "timeout" | "timeout.connect")
+}
+
+/// Wraps an I/O error from `stage`, turning timeouts into [`Timeout`] and
+/// other resolution failures into [`NotResolved`]
+pub fn from_io(err: io::Error, stage: Stage, limit: Option<Duration>) -> Box<dyn Error> {
+    match (err.kind(), stage) {
+	(io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock, _) => Box::new(Timeout { stage, limit }),
+	(_, Stage::Resolve) => Box::new(NotResolved(err)),
+	_ => Box::new(err),
+    }
+}
+
+/// Wraps a `reqwest` error, turning timeouts into [`Timeout`]
+pub fn from_reqwest(err: reqwest::Error, limit: Option<Duration>) -> Box<dyn Error> {
+    if err.is_timeout() {
+	let stage = if err.is_connect() { Stage::Connect } else { Stage::Response };
+	Box::new(Timeout { stage, limit })
+    } else {
+	Box::new(err)
+    }
+}
+
+/// Returns a short machine-readable code describing `err`
+pub fn code(err: &(dyn Error + 'static)) -> String {
+    if let Some(timeout) = err.downcast_ref::<Timeout>() {
+	return format!("timeout.{}", timeout.stage.as_str())
+    }
+    if err.is::<NotResolved>() {
+	return "name_not_resolved".to_string()
+    }
+    if let Some(reason) = skip_reason(err) {
+	return reason.as_str().to_string()
+    }
+    if err.is::<Internal>() {
+	return "internal".to_string()
+    }
+    if err.is::<BodyMismatch>() {
+	return "body_mismatch".to_string()
+    }
+    if err.is::<AssertionFailed>() {
+	return "assertion_failed".to_string()
+    }
+    if err.is::<UnexpectedSuccess>() {
+	return "unexpected_success".to_string()
+    }
+    if err.is::<UnexpectedAddress>() {
+	return "unexpected_address".to_string()
+    }
+    if err.is::<LatencyExceeded>() {
+	return "latency_exceeded".to_string()
+    }
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+	return io_code(err).to_string()
+    }
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+	if is_dns_error(err) {
+	    return "name_not_resolved".to_string()
+	}
+	if let Some(io) = source_io(err) {
+	    return io_code(io).to_string()
+	}
+	let code = if err.is_connect() { "connect" } else if err.is_status() { "status" } else { "http" };
+	return code.to_string()
+    }
+    "error".to_string()
+}
+
+/// Returns true for codes meaning the probing host, rather than the
+/// target, ran out of file descriptors, ephemeral ports, or buffers
+pub fn is_local_exhaustion(code: &str) -> bool {
+    matches!(code, "address_unavailable" | "too_many_open_files" | "no_buffer_space")
+}
+
+/// Finds the first I/O error in an error's chain of sources
+fn source_io<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a io::Error> {
+    let mut source = err.source();
+    while let Some(err) = source {
+	if let Some(io) = err.downcast_ref::<io::Error>() {
+	    return Some(io)
+	}
+	source = err.source();
+    }
+    None
+}
+
+/// Returns true when the connector behind a `reqwest` error failed to
+/// resolve the host name
+fn is_dns_error(err: &(dyn Error + 'static)) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+	if err.to_string().starts_with("dns error") {
+	    return true
+	}
+	source = err.source();
+    }
+    false
+}
+
+/// Maps WinSock error codes that the standard library leaves uncategorized
+/// onto the codes used for the equivalent Unix errors
+#[cfg(windows)]
+fn wsa_code(err: &io::Error) -> Option<&'static str> {
+    match err.raw_os_error()? {
+	// WSAHOST_NOT_FOUND, WSATRY_AGAIN, WSANO_RECOVERY, WSANO_DATA
+	11001..=11004 => Some("name_not_resolved"),
+	// WSAENETUNREACH, WSAEHOSTUNREACH
+	10051 => Some("network_unreachable"),
+	10065 => Some("host_unreachable"),
+	// WSAEMFILE, WSAENOBUFS
+	10024 => Some("too_many_open_files"),
+	10055 => Some("no_buffer_space"),
+	_ => None,
+    }
+}
+
+/// WinSock codes only occur on Windows
+#[cfg(not(windows))]
+fn wsa_code(_err: &io::Error) -> Option<&'static str> {
+    None
+}
+
+/// Maps Unix errors that the standard library leaves uncategorized, and
+/// which indicate the probing host itself ran out of resources
+#[cfg(unix)]
+fn errno_code(err: &io::Error) -> Option<&'static str> {
+    match err.raw_os_error()? {
+	libc::EMFILE | libc::ENFILE => Some("too_many_open_files"),
+	libc::ENOBUFS => Some("no_buffer_space"),
+	_ => None,
+    }
+}
+
+/// Unix error numbers only apply on Unix
+#[cfg(not(unix))]
+fn errno_code(_err: &io::Error) -> Option<&'static str> {
+    None
+}
+
+/// Maps I/O error kinds onto codes. WinSock errors such as WSAETIMEDOUT
+/// and WSAECONNREFUSED already map onto the same kinds as their Unix
+/// counterparts.
+fn io_code(err: &io::Error) -> &'static str {
+    if let Some(code) = wsa_code(err).or_else(|| errno_code(err)) {
+	return code
+    }
+    match err.kind() {
+	io::ErrorKind::ConnectionRefused => "connection_refused",
+	io::ErrorKind::ConnectionReset => "connection_reset",
+	io::ErrorKind::ConnectionAborted => "connection_aborted",
+	io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => "timeout",
+	io::ErrorKind::AddrNotAvailable => "address_unavailable",
+	io::ErrorKind::HostUnreachable => "host_unreachable",
+	io::ErrorKind::NetworkUnreachable => "network_unreachable",
+	io::ErrorKind::PermissionDenied => "permission_denied",
+	io::ErrorKind::InvalidInput => "invalid_address",
+	_ => "io",
+    }
+}
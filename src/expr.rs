@@ -0,0 +1,627 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! A small expression language for assertions on HTTP responses, such as
+//! `status in 200..299 && json.status == "ok" && latency_ms < 300`.
+//!
+//! Expressions combine comparisons with `&&`, `||`, `!`, and parentheses.
+//! Values are numbers, strings in double quotes, `true`, `false`, `null`,
+//! lists like `[200, 204]`, and the names `status`, `latency_ms`, `body`,
+//! `json`, and `headers`, whose fields are reached with `.name`, `["name"]`,
+//! or `[0]`. Comparisons are `==`, `!=`, `<`, `<=`, `>`, `>=`, `in` a list,
+//! an inclusive range like `200..299`, a string, or an object's keys,
+//! `contains`, and `matches` a regular expression. Fields missing
+//! from the response are `null`.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use serde_json::{Map, Number, Value};
+
+use crate::error::AssertionFailed;
+use regex::Regex;
+
+/// Names an expression may refer to
+const NAMES: &[&str] = &["status", "latency_ms", "body", "json", "headers"];
+
+/// Characters of a value shown with a failed assertion
+const VALUE_CHARS: usize = 80;
+
+/// An expression that could not be parsed
+#[derive(Debug)]
+pub struct ExprError(String);
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Str(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+/// A step from a value into one of its fields or elements
+#[derive(Debug)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+enum Node {
+    Literal(Value),
+    Path(String, Vec<Segment>),
+    List(Vec<Node>),
+    Not(Box<Node>),
+    Neg(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(Cmp, Box<Node>, Box<Node>),
+    InRange(Box<Node>, Box<Node>, Box<Node>),
+    In(Box<Node>, Box<Node>),
+    Contains(Box<Node>, Box<Node>),
+    Matches(Box<Node>, Regex),
+}
+
+/// A parsed assertion
+#[derive(Debug)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+/// Symbols, longest first so `..=` is not read as `..`
+const SYMBOLS: &[&str] = &["..=", "&&", "||", "==", "!=", "<=", ">=", "..", "<", ">", "!", "-",
+			   ".", "(", ")", "[", "]", ","];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+	if c.is_whitespace() {
+	    chars.next();
+	} else if c.is_ascii_digit() {
+	    tokens.push(Token::Number(number(&mut chars)?));
+	} else if c == '"' {
+	    chars.next();
+	    tokens.push(Token::Str(string(&mut chars)?));
+	} else if c.is_alphabetic() || c == '_' {
+	    let mut ident = String::new();
+	    while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+		ident.push(c);
+		chars.next();
+	    }
+	    tokens.push(Token::Ident(ident));
+	} else {
+	    let rest: String = chars.clone().take(3).collect();
+	    let symbol = SYMBOLS.iter().find(|s| rest.starts_with(**s))
+		.ok_or_else(|| ExprError(format!("unexpected `{}`", c)))?;
+	    for _ in 0..symbol.len() {
+		chars.next();
+	    }
+	    tokens.push(Token::Symbol(symbol));
+	}
+    }
+    Ok(tokens)
+}
+
+/// Reads a number, leaving a following `..` alone
+fn number(chars: &mut Peekable<Chars>) -> Result<f64, ExprError> {
+    let mut text = String::new();
+    while let Some(&c) = chars.peek() {
+	let fraction = c == '.' && !text.contains('.')
+	    && chars.clone().nth(1).is_some_and(|next| next.is_ascii_digit());
+	if !c.is_ascii_digit() && !fraction {
+	    break
+	}
+	text.push(c);
+	chars.next();
+    }
+    text.parse().map_err(|_| ExprError(format!("invalid number `{}`", text)))
+}
+
+/// Reads the rest of a string after its opening quote
+fn string(chars: &mut Peekable<Chars>) -> Result<String, ExprError> {
+    let mut text = String::new();
+    while let Some(c) = chars.next() {
+	match c {
+	    '"' => return Ok(text),
+	    '\\' => match chars.next() {
+		Some('n') => text.push('\n'),
+		Some('t') => text.push('\t'),
+		Some(c) => text.push(c),
+		None => break,
+	    },
+	    c => text.push(c),
+	}
+    }
+    Err(ExprError("unterminated string".to_string()))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+	self.tokens.get(self.pos)
+    }
+
+    /// Consumes the next token when it is the symbol or keyword `word`
+    fn eat(&mut self, word: &str) -> bool {
+	let found = match self.peek() {
+	    Some(Token::Symbol(s)) => *s == word,
+	    Some(Token::Ident(i)) => i == word,
+	    _ => false,
+	};
+	if found {
+	    self.pos += 1;
+	}
+	found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ExprError> {
+	match self.eat(symbol) {
+	    true => Ok(()),
+	    false => Err(ExprError(format!("expected `{}`{}", symbol, self.found()))),
+	}
+    }
+
+    /// Describes the next token for errors
+    fn found(&self) -> String {
+	match self.peek() {
+	    Some(Token::Number(n)) => format!(", found {}", n),
+	    Some(Token::Str(s)) => format!(", found \"{}\"", s),
+	    Some(Token::Ident(i)) => format!(", found `{}`", i),
+	    Some(Token::Symbol(s)) => format!(", found `{}`", s),
+	    None => " at the end".to_string(),
+	}
+    }
+
+    fn or(&mut self) -> Result<Node, ExprError> {
+	let mut node = self.and()?;
+	while self.eat("||") {
+	    node = Node::Or(Box::new(node), Box::new(self.and()?));
+	}
+	Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, ExprError> {
+	let mut node = self.comparison()?;
+	while self.eat("&&") {
+	    node = Node::And(Box::new(node), Box::new(self.comparison()?));
+	}
+	Ok(node)
+    }
+
+    fn comparison(&mut self) -> Result<Node, ExprError> {
+	let left = Box::new(self.unary()?);
+	let cmp = [("==", Cmp::Eq), ("!=", Cmp::Ne), ("<=", Cmp::Le), (">=", Cmp::Ge), ("<", Cmp::Lt), (">", Cmp::Gt)]
+	    .into_iter()
+	    .find(|(symbol, _)| self.eat(symbol));
+	if let Some((_, cmp)) = cmp {
+	    return Ok(Node::Compare(cmp, left, Box::new(self.unary()?)))
+	}
+	if self.eat("in") {
+	    let set = self.unary()?;
+	    if self.eat("..") || self.eat("..=") {
+		return Ok(Node::InRange(left, Box::new(set), Box::new(self.unary()?)))
+	    }
+	    return Ok(Node::In(left, Box::new(set)))
+	}
+	if self.eat("contains") {
+	    return Ok(Node::Contains(left, Box::new(self.unary()?)))
+	}
+	if self.eat("matches") {
+	    return match self.peek().cloned() {
+		Some(Token::Str(pattern)) => {
+		    self.pos += 1;
+		    let pattern = Regex::new(&pattern)
+			.map_err(|e| ExprError(format!("invalid pattern \"{}\": {}", pattern, e)))?;
+		    Ok(Node::Matches(left, pattern))
+		},
+		_ => Err(ExprError(format!("expected a pattern string after `matches`{}", self.found()))),
+	    }
+	}
+	Ok(*left)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+	if self.eat("!") {
+	    return Ok(Node::Not(Box::new(self.unary()?)))
+	}
+	if self.eat("-") {
+	    return Ok(Node::Neg(Box::new(self.unary()?)))
+	}
+	self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, ExprError> {
+	let token = self.peek().cloned();
+	self.pos += 1;
+	match token {
+	    Some(Token::Number(n)) => Ok(Node::Literal(number_value(n))),
+	    Some(Token::Str(s)) => Ok(Node::Literal(Value::String(s))),
+	    Some(Token::Symbol("(")) => {
+		let node = self.or()?;
+		self.expect(")")?;
+		Ok(node)
+	    },
+	    Some(Token::Symbol("[")) => {
+		let mut items = Vec::new();
+		while !self.eat("]") {
+		    if !items.is_empty() {
+			self.expect(",")?;
+		    }
+		    items.push(self.or()?);
+		}
+		Ok(Node::List(items))
+	    },
+	    Some(Token::Ident(word)) => match word.as_str() {
+		"true" => Ok(Node::Literal(Value::Bool(true))),
+		"false" => Ok(Node::Literal(Value::Bool(false))),
+		"null" => Ok(Node::Literal(Value::Null)),
+		name if NAMES.contains(&name) => self.path(word),
+		_ => Err(ExprError(format!("unknown name `{}`, expected one of {}", word, NAMES.join(", ")))),
+	    },
+	    _ => {
+		self.pos -= 1;
+		Err(ExprError(format!("expected a value{}", self.found())))
+	    },
+	}
+    }
+
+    fn path(&mut self, name: String) -> Result<Node, ExprError> {
+	let mut segments = Vec::new();
+	loop {
+	    if self.eat(".") {
+		match self.peek().cloned() {
+		    Some(Token::Ident(key)) => segments.push(Segment::Key(key)),
+		    Some(Token::Number(n)) if n.fract() == 0.0 => segments.push(Segment::Index(n as usize)),
+		    _ => return Err(ExprError(format!("expected a field name after `.`{}", self.found()))),
+		}
+		self.pos += 1;
+	    } else if self.eat("[") {
+		match self.peek().cloned() {
+		    Some(Token::Str(key)) => segments.push(Segment::Key(key)),
+		    Some(Token::Number(n)) if n >= 0.0 && n.fract() == 0.0 => segments.push(Segment::Index(n as usize)),
+		    _ => return Err(ExprError(format!("expected a field name or index in `[]`{}", self.found()))),
+		}
+		self.pos += 1;
+		self.expect("]")?;
+	    } else {
+		return Ok(Node::Path(name, segments))
+	    }
+	}
+    }
+}
+
+/// Returns true when two values are equal, comparing numbers by value
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+	(Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+	(Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(a, b)),
+	(a, b) => a == b,
+    }
+}
+
+/// Renders a value for error messages, shortened when long
+fn show(value: &Value) -> String {
+    let text = value.to_string();
+    match text.char_indices().nth(VALUE_CHARS) {
+	Some((end, _)) => format!("{}...", &text[..end]),
+	None => text,
+    }
+}
+
+/// Returns `n` as a JSON number, integral when it has no fraction
+fn number_value(n: f64) -> Value {
+    match n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+	true => Value::from(n as i64),
+	false => Number::from_f64(n).map_or(Value::Null, Value::Number),
+    }
+}
+
+fn boolean(value: Value) -> Result<bool, String> {
+    match value {
+	Value::Bool(b) => Ok(b),
+	other => Err(format!("expected true or false, found {}", show(&other))),
+    }
+}
+
+fn float(value: &Value) -> Result<f64, String> {
+    value.as_f64().ok_or_else(|| format!("expected a number, found {}", show(value)))
+}
+
+impl Node {
+    fn eval(&self, vars: &Value) -> Result<Value, String> {
+	match self {
+	    Node::Literal(value) => Ok(value.clone()),
+	    Node::Path(name, segments) => {
+		let mut value = &vars[name.as_str()];
+		for segment in segments {
+		    value = match segment {
+			Segment::Key(key) => &value[key.as_str()],
+			Segment::Index(index) => &value[*index],
+		    };
+		}
+		Ok(value.clone())
+	    },
+	    Node::List(items) => items.iter().map(|item| item.eval(vars)).collect::<Result<_, _>>().map(Value::Array),
+	    Node::Not(node) => Ok(Value::Bool(!boolean(node.eval(vars)?)?)),
+	    Node::Neg(node) => Ok(number_value(-float(&node.eval(vars)?)?)),
+	    // Both short-circuit, so `json.ok && json.items[0].id == 1` is safe
+	    Node::And(a, b) => Ok(Value::Bool(boolean(a.eval(vars)?)? && boolean(b.eval(vars)?)?)),
+	    Node::Or(a, b) => Ok(Value::Bool(boolean(a.eval(vars)?)? || boolean(b.eval(vars)?)?)),
+	    Node::Compare(cmp, a, b) => {
+		let (a, b) = (a.eval(vars)?, b.eval(vars)?);
+		let ordering = match (&a, &b) {
+		    _ if matches!(cmp, Cmp::Eq | Cmp::Ne) => None,
+		    (Value::Number(x), Value::Number(y)) => x.as_f64().partial_cmp(&y.as_f64()),
+		    (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+		    _ => return Err(format!("cannot order {} and {}", show(&a), show(&b))),
+		};
+		let result = match (cmp, ordering) {
+		    (Cmp::Eq, _) => equal(&a, &b),
+		    (Cmp::Ne, _) => !equal(&a, &b),
+		    (Cmp::Lt, Some(o)) => o.is_lt(),
+		    (Cmp::Le, Some(o)) => o.is_le(),
+		    (Cmp::Gt, Some(o)) => o.is_gt(),
+		    (Cmp::Ge, Some(o)) => o.is_ge(),
+		    (_, None) => false,
+		};
+		Ok(Value::Bool(result))
+	    },
+	    Node::InRange(value, lo, hi) => {
+		let value = value.eval(vars)?;
+		let (lo, hi) = (float(&lo.eval(vars)?)?, float(&hi.eval(vars)?)?);
+		Ok(Value::Bool(value.as_f64().is_some_and(|v| lo <= v && v <= hi)))
+	    },
+	    Node::In(item, set) => contains(&set.eval(vars)?, &item.eval(vars)?).map(Value::Bool),
+	    Node::Contains(set, item) => contains(&set.eval(vars)?, &item.eval(vars)?).map(Value::Bool),
+	    Node::Matches(value, pattern) => match value.eval(vars)? {
+		Value::String(text) => Ok(Value::Bool(pattern.is_match(&text))),
+		Value::Null => Ok(Value::Bool(false)),
+		other => Err(format!("`matches` needs a string, found {}", show(&other))),
+	    },
+	}
+    }
+
+    /// Collects the paths the expression reads, for failure details
+    fn paths<'a>(&'a self, found: &mut Vec<&'a Node>) {
+	match self {
+	    Node::Path(..) => found.push(self),
+	    Node::List(items) => items.iter().for_each(|item| item.paths(found)),
+	    Node::Not(node) | Node::Neg(node) | Node::Matches(node, _) => node.paths(found),
+	    Node::And(a, b) | Node::Or(a, b) | Node::Compare(_, a, b) | Node::In(a, b) | Node::Contains(a, b) => {
+		a.paths(found);
+		b.paths(found);
+	    },
+	    Node::InRange(a, b, c) => {
+		a.paths(found);
+		b.paths(found);
+		c.paths(found);
+	    },
+	    Node::Literal(_) => (),
+	}
+    }
+}
+
+/// Returns true when `set`, a list, string, or object, holds `item`
+fn contains(set: &Value, item: &Value) -> Result<bool, String> {
+    match (set, item) {
+	(Value::Array(items), item) => Ok(items.iter().any(|i| equal(i, item))),
+	(Value::String(text), Value::String(part)) => Ok(text.contains(part.as_str())),
+	(Value::Object(map), Value::String(key)) => Ok(map.contains_key(key)),
+	(Value::Null, _) => Ok(false),
+	(set, item) => Err(format!("cannot look for {} in {}", show(item), show(set))),
+    }
+}
+
+impl Expression {
+    /// Parses `source`
+    pub fn parse(source: &str) -> Result<Expression, ExprError> {
+	let mut parser = Parser { tokens: tokenize(source)?, pos: 0 };
+	let root = parser.or()?;
+	match parser.peek() {
+	    Some(_) => Err(ExprError(format!("unexpected input{}", parser.found()))),
+	    None => Ok(Expression { source: source.to_string(), root }),
+	}
+    }
+
+    /// Evaluates the expression with the names in `vars`, failing unless it
+    /// is true. Failures show the value of every name the expression read.
+    pub fn check(&self, vars: &Value) -> Result<(), AssertionFailed> {
+	let reason = match self.root.eval(vars).and_then(boolean) {
+	    Ok(true) => return Ok(()),
+	    Ok(false) => "is false".to_string(),
+	    Err(e) => format!("could not be evaluated: {}", e),
+	};
+	let mut paths = Vec::new();
+	self.root.paths(&mut paths);
+	let mut shown: Vec<String> = Vec::new();
+	for path in paths {
+	    if let Node::Path(name, segments) = path {
+		let text = segments.iter().fold(name.clone(), |text, segment| match segment {
+		    Segment::Key(key) if key.chars().all(|c| c.is_alphanumeric() || c == '_') => format!("{}.{}", text, key),
+		    Segment::Key(key) => format!("{}[{:?}]", text, key),
+		    Segment::Index(index) => format!("{}[{}]", text, index),
+		});
+		let line = format!("{} = {}", text, show(&path.eval(vars).unwrap_or_default()));
+		if !shown.contains(&line) {
+		    shown.push(line);
+		}
+	    }
+	}
+	Err(AssertionFailed(format!("assert `{}` {} ({})", self.source, reason, shown.join(", "))))
+    }
+}
+
+/// Returns the names an HTTP assertion may use: the response's `status`,
+/// `latency_ms`, `body`, the body parsed as `json` or null when it is not
+/// JSON, and `headers` by lowercase name
+pub fn http_vars(status: u16, latency: Duration, headers: &HeaderMap, body: &str) -> Value {
+    let mut header_map = Map::new();
+    for name in headers.keys() {
+	let values: Vec<&str> = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+	header_map.insert(name.as_str().to_string(), Value::String(values.join(", ")));
+    }
+    let latency_ms = Number::from_f64(latency.as_secs_f64() * 1000.0).map_or(Value::Null, Value::Number);
+    let mut vars = Map::new();
+    vars.insert("status".to_string(), Value::from(status));
+    vars.insert("latency_ms".to_string(), latency_ms);
+    vars.insert("body".to_string(), Value::String(body.to_string()));
+    vars.insert("json".to_string(), serde_json::from_str(body).unwrap_or(Value::Null));
+    vars.insert("headers".to_string(), Value::Object(header_map));
+    Value::Object(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{HeaderValue, CONTENT_TYPE, SET_COOKIE};
+    use serde_json::json;
+
+    use super::*;
+
+    fn vars() -> Value {
+	let mut headers = HeaderMap::new();
+	headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+	headers.append(SET_COOKIE, HeaderValue::from_static("a=1"));
+	headers.append(SET_COOKIE, HeaderValue::from_static("b=2"));
+	let body = r#"{"status": "ok", "count": 3, "ratio": 0.5, "items": [{"id": 1}, {"id": 2}], "tags": {"env": "prod"}, "a b": true}"#;
+	http_vars(200, Duration::from_millis(120), &headers, body)
+    }
+
+    fn eval(source: &str) -> Result<(), AssertionFailed> {
+	Expression::parse(source).unwrap_or_else(|e| panic!("{}: {}", source, e)).check(&vars())
+    }
+
+    #[test]
+    fn comparisons_hold() {
+	for source in [
+	    "status == 200", "status != 404", "latency_ms < 300", "latency_ms <= 120", "status > 199", "status >= 200",
+	    "json.status == \"ok\"", "json.ratio == 0.5", "json.count == 3.0", "\"abc\" < \"abd\"", "-json.count < 0",
+	    "json.missing == null", "json.items == json.items", "[1, 2] != [2, 1]",
+	] {
+	    assert!(eval(source).is_ok(), "{}", source);
+	}
+	assert!(eval("status < 200").is_err());
+    }
+
+    #[test]
+    fn logic_combines_and_short_circuits() {
+	for source in [
+	    "status == 200 && json.status == \"ok\"", "status == 500 || json.count == 3", "!(status == 500)",
+	    "true && (false || true)", "!false", "json.missing != null && json.missing.deep > 1 || true",
+	    "!(false && json.status > 1)",
+	] {
+	    assert!(eval(source).is_ok(), "{}", source);
+	}
+	assert!(eval("status == 200 && json.count == 4").is_err());
+    }
+
+    #[test]
+    fn membership_holds() {
+	for source in [
+	    "status in [200, 204]", "status in 200..299", "status in 200..=200", "json.ratio in 0..1",
+	    "\"env\" in json.tags", "\"ok\" in json.status", "json.status in [\"ok\", \"degraded\"]",
+	    "body contains \"items\"", "[1, 2] contains 2", "headers[\"set-cookie\"] contains \"b=2\"",
+	    "!(json.missing contains 1)",
+	] {
+	    assert!(eval(source).is_ok(), "{}", source);
+	}
+	assert!(eval("status in 300..399").is_err());
+	assert!(eval("\"stage\" in json.tags").is_err());
+	assert!(eval("json.status in [\"down\"]").is_err());
+    }
+
+    #[test]
+    fn patterns_match() {
+	assert!(eval("headers[\"content-type\"] matches \"^application/(json|xml)$\"").is_ok());
+	assert!(eval("json.status matches \"(?i)^OK$\"").is_ok());
+	assert!(eval("json.missing matches \"x\"").is_err());
+    }
+
+    #[test]
+    fn paths_reach_fields_and_elements() {
+	for source in [
+	    "json.items[1].id == 2", "json.items.0.id == 1", "json[\"a b\"] == true", "json.tags[\"env\"] == \"prod\"",
+	    "json.items[5] == null", "headers[\"set-cookie\"] == \"a=1, b=2\"", "body contains \"\\\"status\\\"\"",
+	] {
+	    assert!(eval(source).is_ok(), "{}", source);
+	}
+    }
+
+    #[test]
+    fn failures_show_the_values_read() {
+	let err = eval("json.status == \"down\" && status == 200").unwrap_err();
+	assert_eq!(err.0, "assert `json.status == \"down\" && status == 200` is false (json.status = \"ok\", status = 200)");
+	let err = eval("json.tags[\"a-b\"] == 1").unwrap_err();
+	assert!(err.0.ends_with("(json.tags[\"a-b\"] = null)"), "{}", err.0);
+    }
+
+    #[test]
+    fn type_errors_are_reported() {
+	let reason = |source: &str| eval(source).unwrap_err().0;
+	assert!(reason("json.status < 1").contains("could not be evaluated: cannot order \"ok\" and 1"));
+	assert!(reason("status && true").contains("could not be evaluated"));
+	assert!(reason("-json.status == 1").contains("expected a number, found \"ok\""));
+	assert!(reason("json.count matches \"3\"").contains("`matches` needs a string, found 3"));
+	assert!(reason("1 in json.count").contains("cannot look for 1 in 3"));
+	assert!(reason("status").contains("could not be evaluated"));
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+	let error = |source: &str| Expression::parse(source).unwrap_err().to_string();
+	assert_eq!(error("status =="), "expected a value at the end");
+	assert_eq!(error("status == 200 200"), "unexpected input, found 200");
+	assert_eq!(error("(status == 200"), "expected `)` at the end");
+	assert_eq!(error("code == 200"), "unknown name `code`, expected one of status, latency_ms, body, json, headers");
+	assert_eq!(error("body == \"open"), "unterminated string");
+	assert_eq!(error("status = 200"), "unexpected `=`");
+	assert_eq!(error("json. == 1"), "expected a field name after `.`, found `==`");
+	assert_eq!(error("json[true] == 1"), "expected a field name or index in `[]`, found `true`");
+	assert_eq!(error("body matches status"), "expected a pattern string after `matches`, found `status`");
+	assert!(error("body matches \"(\"").starts_with("invalid pattern \"(\""));
+	assert_eq!(error("status in [200 204]"), "expected `,`, found 204");
+    }
+
+    #[test]
+    fn http_vars_hold_the_response() {
+	let vars = http_vars(503, Duration::from_millis(5), &HeaderMap::new(), "not json");
+	assert_eq!(vars["status"], json!(503));
+	assert_eq!(vars["latency_ms"], json!(5.0));
+	assert_eq!(vars["json"], Value::Null);
+	assert_eq!(vars["body"], json!("not json"));
+	assert_eq!(vars["headers"], json!({}));
+    }
+}
@@ -0,0 +1,134 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Heap accounting. Built with the `bench-self` feature, the connchk binary
+//! installs [`Counting`] as its global allocator so `--bench-self` can
+//! report how much each run allocates, and whether the live heap keeps
+//! growing from run to run as it would when a long-running watch fragments
+//! memory. Without it every reading is zero.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting allocations and the bytes in use
+pub struct Counting;
+
+fn allocated(size: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED.fetch_add(size as u64, Ordering::Relaxed);
+    LIVE.fetch_add(size, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+	let ptr = System.alloc(layout);
+	if !ptr.is_null() {
+	    allocated(layout.size());
+	}
+	ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+	let ptr = System.alloc_zeroed(layout);
+	if !ptr.is_null() {
+	    allocated(layout.size());
+	}
+	ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+	System.dealloc(ptr, layout);
+	LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+	let new = System.realloc(ptr, layout, new_size);
+	if !new.is_null() {
+	    allocated(new_size);
+	    LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+	}
+	new
+    }
+}
+
+/// Totals counted by [`Counting`] since the process started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub allocations: u64,
+    pub bytes: u64,
+    /// Bytes allocated and not yet freed
+    pub live: usize,
+}
+
+impl Usage {
+    /// Returns the heap activity since the `earlier` reading
+    pub fn since(&self, earlier: Usage) -> Activity {
+	Activity {
+	    allocations: self.allocations - earlier.allocations,
+	    bytes: self.bytes - earlier.bytes,
+	    growth: self.live as i64 - earlier.live as i64,
+	}
+    }
+}
+
+/// Returns the current totals
+pub fn usage() -> Usage {
+    Usage {
+	allocations: ALLOCATIONS.load(Ordering::Relaxed),
+	bytes: ALLOCATED.load(Ordering::Relaxed),
+	live: LIVE.load(Ordering::Relaxed),
+    }
+}
+
+/// Heap activity between two readings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Activity {
+    pub allocations: u64,
+    pub bytes: u64,
+    /// Change in the bytes in use, negative when more was freed
+    pub growth: i64,
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{} allocations, {} allocated, live heap {}{}", self.allocations, size(self.bytes),
+	       if self.growth < 0 { "-" } else { "+" }, size(self.growth.unsigned_abs()))
+    }
+}
+
+/// Formats a byte count in KiB or MiB
+fn size(bytes: u64) -> String {
+    match bytes {
+	b if b < 1024 => format!("{}B", b),
+	b if b < 1024 * 1024 => format!("{:.1}KiB", b as f64 / 1024.0),
+	b => format!("{:.1}MiB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// Returns the summary of `runs` measured over `checks` targets each: the
+/// average allocations per run and per check, and the growth of the live
+/// heap across the runs
+pub fn summary(runs: &[Activity], checks: usize) -> String {
+    if runs.is_empty() {
+	return "no runs measured".to_string()
+    }
+    let count = runs.len() as u64;
+    let allocations = runs.iter().map(|r| r.allocations).sum::<u64>() / count;
+    let bytes = runs.iter().map(|r| r.bytes).sum::<u64>() / count;
+    let growth: i64 = runs.iter().map(|r| r.growth).sum();
+    format!("{} runs of {} checks: {} allocations ({} per check), {} allocated per run; live heap {}{} after warm-up",
+	    count, checks, allocations, allocations / checks.max(1) as u64, size(bytes),
+	    if growth < 0 { "-" } else { "+" }, size(growth.unsigned_abs()))
+}
@@ -0,0 +1,127 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Warm-up and cool-down hooks. A hook runs a shell command or requests a
+//! URL before or after the checks of a run or of a target group, e.g. to
+//! bring a VPN tunnel up and tear it down again. A target may also name an
+//! annotation hook whose output is attached to its result.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+/// Provides a deserialize target for a single hook. Exactly one of
+/// `command` or `url` should be given.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Hook {
+    /// Shell command to run
+    pub command: Option<String>,
+    /// URL to request with a GET
+    pub url: Option<String>,
+}
+
+/// Hooks run before and after a set of checks
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Hooks {
+    pub pre: Option<Vec<Hook>>,
+    pub post: Option<Vec<Hook>>,
+    /// Resolvers for the group's targets without their own `dns_servers`.
+    /// Only read from `groups` tables.
+    pub dns_servers: Option<Vec<String>>,
+}
+
+impl Hook {
+    /// Runs the hook, failing on a non-zero exit or non-success status
+    pub fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+	match (&self.command, &self.url) {
+	    (Some(command), None) => {
+		let status = shell(command).status()?;
+		if status.success() {
+		    Ok(())
+		} else {
+		    Err(From::from(format!("`{}` exited with {}", command, status)))
+		}
+	    },
+	    (None, Some(url)) => {
+		let resp = Client::new().get(url).send()?;
+		if resp.status().is_success() {
+		    Ok(())
+		} else {
+		    Err(From::from(format!("{} returned {}", url, resp.status())))
+		}
+	    },
+	    _ => Err(From::from("hooks require exactly one of `command` or `url`")),
+	}
+    }
+}
+
+/// Provides a deserialize target for a per-target post-check hook. The
+/// command runs after the check with `CONNCHK_DESC`, `CONNCHK_ADDR`, and
+/// `CONNCHK_STATUS` (`passed` or `failed`) set. With `key` its whole output
+/// becomes that annotation, otherwise each `key=value` line of output is an
+/// annotation.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Annotate {
+    pub command: String,
+    pub key: Option<String>,
+}
+
+impl Annotate {
+    /// Runs the command for the target at `addr` and collects annotations
+    pub fn run(&self, desc: &str, addr: &str, failed: bool)
+	       -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+	let output = shell(&self.command)
+	    .env("CONNCHK_DESC", desc)
+	    .env("CONNCHK_ADDR", addr)
+	    .env("CONNCHK_STATUS", if failed { "failed" } else { "passed" })
+	    .output()?;
+	if !output.status.success() {
+	    return Err(From::from(format!("`{}` exited with {}", self.command, output.status)))
+	}
+
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let mut annotations = BTreeMap::new();
+	match &self.key {
+	    Some(key) => {
+		annotations.insert(key.clone(), stdout.trim_end().to_string());
+	    },
+	    None => {
+		for (key, value) in stdout.lines().filter_map(|line| line.split_once('=')) {
+		    annotations.insert(key.trim().to_string(), value.trim().to_string());
+		}
+	    },
+	}
+	Ok(annotations)
+    }
+}
+
+/// Runs each hook in order, reporting failures without stopping
+pub fn run_all(hooks: Option<&Vec<Hook>>) {
+    for hook in hooks.into_iter().flatten() {
+	if let Err(e) = hook.run() {
+	    tracing::error!("Hook failed: {}", e);
+	}
+    }
+}
+
+/// Builds a command that runs `command` through the platform shell
+pub fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+	let mut cmd = Command::new("cmd");
+	cmd.args(["/C", command]);
+	cmd
+    } else {
+	let mut cmd = Command::new("sh");
+	cmd.args(["-c", command]);
+	cmd
+    }
+}
@@ -0,0 +1,181 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! ICMP echo checks, for hosts that answer pings but have no open TCP port
+//! to connect to. Echo requests are sent from an ICMP socket where the
+//! process may open one, and by the system `ping` command otherwise.
+
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::process::Command;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::addr;
+use crate::error::{self, Stage};
+use crate::privilege::{self, InsufficientPrivileges};
+
+/// Time allowed for a reply when no timeout is configured
+pub const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Payload carried by every echo request, used to recognise replies
+const PAYLOAD: &[u8] = b"connchk echo....";
+
+/// Sequence number of the next echo request
+static SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+/// Computes the Internet checksum of `data`
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2)
+	.map(|pair| u32::from(pair[0]) << 8 | u32::from(*pair.get(1).unwrap_or(&0)))
+	.sum();
+    while sum >> 16 != 0 {
+	sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Builds an echo request. The kernel fills in the ICMPv6 checksum.
+fn request(v6: bool, seq: u16) -> Vec<u8> {
+    let kind = if v6 { 128 } else { 8 };
+    let id = (std::process::id() & 0xffff) as u16;
+    let mut packet = vec![kind, 0, 0, 0];
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(PAYLOAD);
+    if !v6 {
+	let sum = checksum(&packet);
+	packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet
+}
+
+/// Returns true if `packet` is the reply to request `seq`. Raw IPv4
+/// sockets include the IP header, and unprivileged sockets may rewrite the
+/// identifier, so only the sequence number and payload are compared.
+fn is_reply(v6: bool, packet: &[u8], seq: u16) -> bool {
+    let packet = match packet.first() {
+	Some(first) if !v6 && first >> 4 == 4 => &packet[usize::from(first & 0x0f) * 4..],
+	_ => packet,
+    };
+    let kind = if v6 { 129 } else { 0 };
+    packet.len() >= 8 + PAYLOAD.len()
+	&& packet[0] == kind
+	&& packet[6..8] == seq.to_be_bytes()
+	&& packet[8..].starts_with(PAYLOAD)
+}
+
+/// How an echo request was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// From an ICMP socket opened by connchk
+    Socket,
+    /// By running the system `ping` command
+    Command,
+}
+
+/// Sends an echo request to `addr`, a hostname or IP address, and returns
+/// the round-trip time of the reply and how it was measured. Falls back to
+/// the system `ping` command when no ICMP socket can be opened, and reports
+/// [`InsufficientPrivileges`] only when that is unavailable too.
+pub fn ping(addr: &str, timeout: Option<Duration>) -> Result<(Duration, Method), Box<dyn Error>> {
+    match echo(addr, timeout) {
+	Err(e) if e.is::<InsufficientPrivileges>() => match ping_command(addr, timeout) {
+	    Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => {
+		Err(Box::new(InsufficientPrivileges))
+	    },
+	    result => result.map(|rtt| (rtt, Method::Command)),
+	},
+	result => result.map(|rtt| (rtt, Method::Socket)),
+    }
+}
+
+/// Runs the system `ping` command for a single echo request and returns
+/// the round-trip time it reports
+pub fn ping_command(addr: &str, timeout: Option<Duration>) -> Result<Duration, Box<dyn Error>> {
+    let limit = timeout.unwrap_or(ECHO_TIMEOUT);
+    let secs = limit.as_secs().max(1).to_string();
+    let mut cmd = Command::new("ping");
+    if cfg!(windows) {
+	cmd.args(["-n", "1", "-w", &limit.as_millis().to_string(), addr]);
+    } else if cfg!(target_os = "macos") {
+	cmd.args(["-c", "1", "-t", &secs, addr]);
+    } else {
+	cmd.args(["-c", "1", "-W", &secs, addr]);
+    }
+    let output = cmd.output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match (output.status.success(), stdout.lines().find_map(reply_time)) {
+	(true, Some(rtt)) => Ok(rtt),
+	_ => {
+	    let stderr = String::from_utf8_lossy(&output.stderr);
+	    let reason = stderr.lines().chain(stdout.lines()).rev()
+		.find(|line| !line.trim().is_empty())
+		.unwrap_or("no output");
+	    Err(From::from(format!("ping reported no reply: {}", reason.trim())))
+	},
+    }
+}
+
+/// Parses the round-trip time from a reply line of `ping` output, such as
+/// `time=12.3 ms` or `time<1ms`
+fn reply_time(line: &str) -> Option<Duration> {
+    let (_, rest) = line.split_once("time")?;
+    let rest = rest.strip_prefix('=').or_else(|| rest.strip_prefix('<'))?;
+    let value: String = rest.chars().take_while(|c| c.is_ascii_digit() || *c == '.').collect();
+    let ms: f64 = value.parse().ok()?;
+    Some(Duration::from_secs_f64(ms / 1000.0))
+}
+
+/// Sends an echo request to `addr`, a hostname or IP address, from an ICMP
+/// socket and returns the round-trip time of the reply
+pub fn echo(addr: &str, timeout: Option<Duration>) -> Result<Duration, Box<dyn Error>> {
+    let target = addr::resolve_or_port(addr, 0)
+	.map_err(|e| error::from_io(e, Stage::Resolve, timeout))?
+	.into_iter()
+	.next()
+	.ok_or_else(|| format!("{} did not resolve to any address", addr))?;
+    let v6 = target.is_ipv6();
+    let access = privilege::icmp_access(v6).ok_or(InsufficientPrivileges)?;
+    let socket = privilege::icmp_socket(v6, access).map_err(|e| -> Box<dyn Error> {
+	if privilege::is_denied(&e) { Box::new(InsufficientPrivileges) } else { Box::new(e) }
+    })?;
+    let limit = timeout.unwrap_or(ECHO_TIMEOUT);
+    socket.set_read_timeout(Some(limit))?;
+    let socket = UdpSocket::from(socket);
+
+    let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let start = Instant::now();
+    socket.send_to(&request(v6, seq), target)
+	.map_err(|e| error::from_io(e, Stage::Connect, timeout))?;
+
+    let mut buf = [0u8; 1500];
+    loop {
+	let (len, from) = socket.recv_from(&mut buf)
+	    .map_err(|e| error::from_io(e, Stage::Response, timeout))?;
+	if same_host(from, target) && is_reply(v6, &buf[..len], seq) {
+	    return Ok(start.elapsed())
+	}
+	let remaining = limit.checked_sub(start.elapsed())
+	    .filter(|remaining| !remaining.is_zero())
+	    .ok_or_else(|| error::from_io(std::io::ErrorKind::TimedOut.into(), Stage::Response, timeout))?;
+	socket.set_read_timeout(Some(remaining))?;
+    }
+}
+
+/// Compares the hosts of two socket addresses, ignoring ports
+fn same_host(a: SocketAddr, b: SocketAddr) -> bool {
+    let unmap = |ip: IpAddr| match ip {
+	IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(v6)),
+	ip => ip,
+    };
+    unmap(a.ip()) == unmap(b.ip())
+}
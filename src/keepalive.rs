@@ -0,0 +1,384 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! HTTP connection reuse checks. Two requests are sent one after the other
+//! over a single connection, as clients with a connection pool would, so
+//! servers and middleboxes that close every connection after one response
+//! are caught even though each request on its own succeeds.
+//!
+//! Drain checks also send a request with `Connection: close` and verify the
+//! server then closes the connection gracefully, rather than holding it open
+//! or resetting it, as load balancers draining a backend should.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use rustls::{ClientConnection, ServerName, StreamOwned};
+
+use crate::error::{self, Stage};
+use crate::phase::TlsSession;
+
+/// Longest wait for the server to close a connection after a response
+/// when the target has no timeout
+const CLOSE_WAIT: Duration = Duration::from_secs(5);
+
+/// A plain or TLS connection
+trait Connection: Read + Write {}
+
+impl<T: Read + Write> Connection for T {}
+
+/// The parts of a response that decide whether its connection can be reused
+struct Response {
+    status: u16,
+    /// Whether the connection stays open after the response
+    persistent: bool,
+}
+
+/// Reads a response, including its body, leaving the stream at the start
+/// of the next one
+fn read_response<S: Read>(reader: &mut BufReader<S>) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+	return Err(From::from("connection closed before a response"))
+    }
+    let mut parts = line.split_whitespace();
+    let version = parts.next().unwrap_or_default().to_string();
+    let status: u16 = parts.next().and_then(|s| s.parse().ok())
+	.ok_or_else(|| format!("malformed status line `{}`", line.trim_end()))?;
+
+    let (mut length, mut chunked, mut connection) = (None, false, String::new());
+    loop {
+	line.clear();
+	if reader.read_line(&mut line)? == 0 {
+	    return Err(From::from("connection closed mid-headers"))
+	}
+	let header = line.trim_end();
+	if header.is_empty() {
+	    break
+	}
+	if let Some((name, value)) = header.split_once(':') {
+	    let value = value.trim();
+	    match name.trim().to_ascii_lowercase().as_str() {
+		"content-length" => length = value.parse::<u64>().ok(),
+		"transfer-encoding" => chunked = value.to_ascii_lowercase().contains("chunked"),
+		"connection" => connection = value.to_ascii_lowercase(),
+		_ => (),
+	    }
+	}
+    }
+
+    let closes = connection.split(',').any(|token| token.trim() == "close");
+    let mut persistent = match version.as_str() {
+	"HTTP/1.0" => connection.split(',').any(|token| token.trim() == "keep-alive") && !closes,
+	_ => !closes,
+    };
+    if (100..200).contains(&status) || status == 204 || status == 304 {
+	return Ok(Response { status, persistent })
+    }
+    if chunked {
+	loop {
+	    line.clear();
+	    reader.read_line(&mut line)?;
+	    let size = u64::from_str_radix(line.trim().split(';').next().unwrap_or_default(), 16)
+		.map_err(|_| format!("malformed chunk size `{}`", line.trim()))?;
+	    if size == 0 {
+		// Skip trailers up to the blank line ending the body
+		loop {
+		    line.clear();
+		    if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+			break
+		    }
+		}
+		break
+	    }
+	    io_copy(reader, size + 2)?;
+	}
+    } else if let Some(length) = length {
+	io_copy(reader, length)?;
+    } else {
+	// The body runs until the server closes the connection
+	persistent = false;
+    }
+    Ok(Response { status, persistent })
+}
+
+/// Discards `len` bytes from `reader`
+fn io_copy<S: Read>(reader: &mut BufReader<S>, len: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let copied = std::io::copy(&mut reader.by_ref().take(len), &mut std::io::sink())?;
+    if copied < len {
+	return Err(From::from("connection closed mid-body"))
+    }
+    Ok(())
+}
+
+/// Builds a GET request for `url` with the given `Connection` header
+fn request(url: &Url, connection: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let host = match (url.host_str(), url.port()) {
+	(Some(host), Some(port)) => format!("{}:{}", host, port),
+	(Some(host), None) => host.to_string(),
+	(None, _) => return Err(From::from(format!("missing host in {}", url))),
+    };
+    let path = match url.query() {
+	Some(query) => format!("{}?{}", url.path(), query),
+	None => url.path().to_string(),
+    };
+    Ok(format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: connchk/{}\r\nAccept: */*\r\n\
+		Connection: {}\r\n\r\n", path, host, env!("CARGO_PKG_VERSION"), connection))
+}
+
+/// Sends two GET requests for `url` over `stream`, failing if the second
+/// cannot reuse the connection
+fn exchange(stream: Box<dyn Connection>, url: &Url) -> Result<String, Box<dyn std::error::Error>> {
+    let request = request(url, "keep-alive")?;
+    let mut reader = BufReader::new(stream);
+
+    let now = Instant::now();
+    reader.get_mut().write_all(request.as_bytes())?;
+    reader.get_mut().flush()?;
+    let first = read_response(&mut reader)?;
+    let first_took = now.elapsed();
+    if !first.persistent {
+	return Err(From::from(format!("\n\tKeep-alive: the server will not reuse the connection after a {} response", first.status)))
+    }
+
+    let now = Instant::now();
+    let second = reader.get_mut().write_all(request.as_bytes())
+	.map_err(Box::<dyn std::error::Error>::from)
+	.and_then(|_| read_response(&mut reader));
+    match second {
+	Ok(second) => Ok(format!("connection reused, second request {:.0}ms (first {:.0}ms, status {})",
+				 now.elapsed().as_secs_f64() * 1000.0, first_took.as_secs_f64() * 1000.0, second.status)),
+	Err(e) => Err(From::from(format!("\n\tKeep-alive: a second request over the same connection failed: {}", e))),
+    }
+}
+
+/// Connects directly to the host of `url`, over TLS with the trust roots of
+/// `session` for HTTPS. `option` names the target option needing the
+/// connection, for errors.
+fn connect(url: &Url, session: &TlsSession, timeout: Option<Duration>, option: &str)
+	   -> Result<Box<dyn Connection>, Box<dyn std::error::Error>> {
+    let host = url.host_str()
+	.ok_or_else(|| format!("missing host in {}", url))?
+	.trim_start_matches('[').trim_end_matches(']')
+	.to_string();
+    let port = url.port_or_known_default()
+	.ok_or_else(|| format!("missing port in {}", url))?;
+    let addr = (host.as_str(), port).to_socket_addrs()
+	.map_err(|e| error::from_io(e, Stage::Resolve, None))?
+	.next()
+	.ok_or_else(|| format!("no addresses found for {}", host))?;
+    let stream = match timeout {
+	Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+	None => TcpStream::connect(addr),
+    };
+    let stream = stream.map_err(|e| error::from_io(e, Stage::Connect, timeout))?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    match url.scheme() {
+	"https" => {
+	    let conn = ClientConnection::new(session.config()?, ServerName::try_from(host.as_str())?)?;
+	    Ok(Box::new(StreamOwned::new(conn, stream)))
+	},
+	"http" => Ok(Box::new(stream)),
+	scheme => Err(From::from(format!("{} requires an http or https target, not {}", option, scheme))),
+    }
+}
+
+/// Connects directly to the host of `url` and verifies a second request
+/// can be sent over the connection used for the first. HTTPS connections
+/// use the trust roots of `session`.
+pub fn verify(url: &str, session: &TlsSession, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let url = Url::parse(url)?;
+    let stream = connect(&url, session, timeout, "keep_alive")?;
+    exchange(stream, &url)
+}
+
+/// Sends a request for `url` with `Connection: close` and verifies the
+/// server closes the connection gracefully after its response, within the
+/// timeout or [`CLOSE_WAIT`]. Fails when the connection is reset or held
+/// open.
+pub fn verify_close(url: &str, session: &TlsSession, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let url = Url::parse(url)?;
+    let wait = timeout.unwrap_or(CLOSE_WAIT);
+    let stream = connect(&url, session, Some(wait), "drain")?;
+    let mut reader = BufReader::new(stream);
+    reader.get_mut().write_all(request(&url, "close")?.as_bytes())?;
+    reader.get_mut().flush()?;
+    let response = read_response(&mut reader)?;
+
+    let now = Instant::now();
+    let mut rest = Vec::new();
+    let took = |now: Instant| now.elapsed().as_secs_f64() * 1000.0;
+    match reader.read_to_end(&mut rest) {
+	Ok(0) => Ok(format!("closed gracefully {:.0}ms after a {} response", took(now), response.status)),
+	Ok(n) => Err(From::from(format!("\n\tDrain: {} unexpected bytes followed the response to Connection: close", n))),
+	Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {
+	    Err(From::from(format!("\n\tDrain: the server reset the connection (RST) instead of closing it after a {} response",
+				   response.status)))
+	},
+	Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+	    Err(From::from(format!("\n\tDrain: the server kept the connection open for {:.0}ms after a {} response to Connection: close",
+				   took(now), response.status)))
+	},
+	// rustls reports a TCP close without a TLS close_notify this way
+	Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+	    Ok(format!("closed {:.0}ms after a {} response, without a TLS close_notify", took(now), response.status))
+	},
+	Err(e) => Err(From::from(format!("\n\tDrain: the connection failed after the response: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn read(bytes: &str) -> (Result<Response, Box<dyn std::error::Error>>, String) {
+	let mut reader = BufReader::new(bytes.as_bytes());
+	let response = read_response(&mut reader);
+	let mut rest = String::new();
+	reader.read_to_string(&mut rest).unwrap();
+	(response, rest)
+    }
+
+    #[test]
+    fn bodies_are_read_up_to_the_next_response() {
+	let (response, rest) = read("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloNEXT");
+	let response = response.unwrap();
+	assert_eq!((response.status, response.persistent), (200, true));
+	assert_eq!(rest, "NEXT");
+
+	let chunked = "HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n\
+		       5;name=value\r\nhello\r\nA\r\n0123456789\r\n0\r\nChecksum: abc\r\n\r\nNEXT";
+	let (response, rest) = read(chunked);
+	assert!(response.unwrap().persistent);
+	assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn bodiless_statuses_skip_the_body() {
+	for status in ["100 Continue", "204 No Content", "304 Not Modified"] {
+	    let (response, rest) = read(&format!("HTTP/1.1 {}\r\nContent-Length: 5\r\n\r\nNEXT", status));
+	    assert!(response.unwrap().persistent);
+	    assert_eq!(rest, "NEXT", "{}", status);
+	}
+    }
+
+    #[test]
+    fn persistence_follows_version_and_connection() {
+	let persistent = |head: &str| read(&format!("{}\r\nContent-Length: 0\r\n\r\n", head)).0.unwrap().persistent;
+	assert!(persistent("HTTP/1.1 200 OK"));
+	assert!(!persistent("HTTP/1.1 200 OK\r\nConnection: Close"));
+	assert!(!persistent("HTTP/1.1 200 OK\r\nConnection: upgrade, close"));
+	assert!(!persistent("HTTP/1.0 200 OK"));
+	assert!(persistent("HTTP/1.0 200 OK\r\nConnection: Keep-Alive"));
+	assert!(!persistent("HTTP/1.0 200 OK\r\nConnection: keep-alive, close"));
+	// Without a length the body ends when the connection does
+	assert!(!read("HTTP/1.1 200 OK\r\n\r\nbody").0.unwrap().persistent);
+    }
+
+    #[test]
+    fn malformed_and_truncated_responses_fail() {
+	let error = |bytes: &str| read(bytes).0.err().expect("should fail").to_string();
+	assert_eq!(error(""), "connection closed before a response");
+	assert_eq!(error("HTTP/1.1 OK\r\n\r\n"), "malformed status line `HTTP/1.1 OK`");
+	assert_eq!(error("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n"), "connection closed mid-headers");
+	assert_eq!(error("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabc"), "connection closed mid-body");
+	assert_eq!(error("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"), "malformed chunk size `zz`");
+	assert_eq!(error("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab"), "connection closed mid-body");
+    }
+
+    #[test]
+    fn requests_name_the_host_and_path() {
+	let url = Url::parse("http://api.test:8080/health?full=1").unwrap();
+	let sent = request(&url, "close").unwrap();
+	assert!(sent.starts_with("GET /health?full=1 HTTP/1.1\r\nHost: api.test:8080\r\n"), "{}", sent);
+	assert!(sent.ends_with("\r\nConnection: close\r\n\r\n"), "{}", sent);
+	let url = Url::parse("http://api.test/").unwrap();
+	assert!(request(&url, "keep-alive").unwrap().contains("\r\nHost: api.test\r\n"));
+    }
+
+    /// Serves one connection, answering each request with the next of
+    /// `responses` and then closing, or resetting when `reset` is set
+    fn server(responses: &'static [&'static str], reset: bool) -> String {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}/", listener.local_addr().unwrap());
+	thread::spawn(move || {
+	    let (stream, _) = listener.accept().unwrap();
+	    let mut reader = BufReader::new(stream.try_clone().unwrap());
+	    for response in responses {
+		let mut line = String::new();
+		while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+		(&stream).write_all(response.as_bytes()).unwrap();
+	    }
+	    if reset {
+		socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)).unwrap();
+	    }
+	});
+	url
+    }
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    #[test]
+    fn reused_connections_pass() {
+	let url = server(&[OK, OK], false);
+	let details = verify(&url, &TlsSession::default(), TIMEOUT).unwrap();
+	assert!(details.starts_with("connection reused, second request "), "{}", details);
+	assert!(details.ends_with(", status 200)"), "{}", details);
+    }
+
+    #[test]
+    fn connections_closed_after_one_response_fail() {
+	let url = server(&["HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"], false);
+	let err = verify(&url, &TlsSession::default(), TIMEOUT).unwrap_err();
+	assert_eq!(err.to_string(), "\n\tKeep-alive: the server will not reuse the connection after a 200 response");
+
+	// A server that closes without saying so is caught by the second request
+	let url = server(&[OK], false);
+	let err = verify(&url, &TlsSession::default(), TIMEOUT).unwrap_err();
+	assert!(err.to_string().starts_with("\n\tKeep-alive: a second request over the same connection failed: "), "{}", err);
+    }
+
+    #[test]
+    fn drains_pass_on_a_graceful_close() {
+	let url = server(&[OK], false);
+	let details = verify_close(&url, &TlsSession::default(), TIMEOUT).unwrap();
+	assert!(details.starts_with("closed gracefully "), "{}", details);
+	assert!(details.ends_with("ms after a 200 response"), "{}", details);
+    }
+
+    #[test]
+    fn drains_fail_on_a_reset() {
+	let url = server(&[OK], true);
+	let err = verify_close(&url, &TlsSession::default(), TIMEOUT).unwrap_err();
+	assert_eq!(err.to_string(), "\n\tDrain: the server reset the connection (RST) instead of closing it after a 200 response");
+    }
+
+    #[test]
+    fn drains_fail_when_held_open() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}/", listener.local_addr().unwrap());
+	let held = thread::spawn(move || {
+	    let (mut stream, _) = listener.accept().unwrap();
+	    stream.write_all(OK.as_bytes()).unwrap();
+	    // Held until the client gives up
+	    let _ = stream.read_to_end(&mut Vec::new());
+	});
+	let err = verify_close(&url, &TlsSession::default(), Some(Duration::from_millis(200))).unwrap_err();
+	assert!(err.to_string().starts_with("\n\tDrain: the server kept the connection open for "), "{}", err);
+	held.join().unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Multicast DNS resolution checks. A `.local` name is resolved the way
+//! Bonjour and Avahi clients resolve it, by asking the local segment, so
+//! printers and AirPlay devices stranded on the wrong VLAN show up as
+//! failures even when every unicast check passes. Names are one of:
+//!
+//! - a host, e.g. `printer.local`, resolved to its addresses;
+//! - a service type, e.g. `_ipp._tcp.local`, browsed for its instances;
+//! - a service instance, e.g. `Office Printer._ipp._tcp.local`, resolved
+//!   to the host and port it advertises.
+//!
+//! Queries are sent from an ephemeral port, so responders answer with a
+//! unicast reply to that port (RFC 6762, section 6.7).
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use socket2::SockRef;
+
+use crate::addr;
+use crate::dns::{self, RData, Record, SrvRecord};
+use crate::error::{self, Stage};
+
+/// Where queries are sent when no `group` is given
+const DEFAULT_GROUP: &str = "224.0.0.251:5353";
+
+/// Time allowed to resolve a name when the target has no timeout
+const DEFAULT_WAIT: Duration = Duration::from_secs(2);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+
+/// Provides a deserialize target for mDNS checks. All fields are optional.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MdnsOptions {
+    /// Open a TCP connection to each resolved endpoint
+    pub connect: Option<bool>,
+    /// Port to connect to when `addr` is a host, which advertises none
+    pub port: Option<u16>,
+    /// Local IPv4 address of the interface to query from
+    pub interface: Option<Ipv4Addr>,
+    /// Group and port queried, `224.0.0.251:5353` if not given. A unicast
+    /// address queries a single responder.
+    pub group: Option<String>,
+}
+
+/// A host resolved from a name, with the port it advertises if any
+struct Endpoint {
+    /// The service instance advertising the host, when browsing
+    instance: Option<String>,
+    host: String,
+    port: Option<u16>,
+    addrs: Vec<IpAddr>,
+}
+
+impl Endpoint {
+    fn describe(&self) -> String {
+	let mut desc = match self.port {
+	    Some(port) => format!("{}:{}", self.host, port),
+	    None => self.host.clone(),
+	};
+	let addrs: Vec<String> = self.addrs.iter().map(IpAddr::to_string).collect();
+	desc.push_str(&format!(" ({})", addrs.join(", ")));
+	match &self.instance {
+	    Some(instance) => format!("{} at {}", instance, desc),
+	    None => desc,
+	}
+    }
+}
+
+/// Issues queries and keeps every record answered, as responders often
+/// include the records a client will need next
+struct Querier {
+    socket: UdpSocket,
+    group: SocketAddr,
+    deadline: Instant,
+    records: Vec<Record>,
+}
+
+impl Querier {
+    fn new(options: &MdnsOptions, wait: Duration) -> Result<Querier, Box<dyn std::error::Error>> {
+	let group = options.group.as_deref().unwrap_or(DEFAULT_GROUP);
+	let group = addr::resolve_or_port(group, 5353)
+	    .map_err(|e| error::from_io(e, Stage::Resolve, Some(wait)))?
+	    .into_iter()
+	    .find(SocketAddr::is_ipv4)
+	    .ok_or_else(|| format!("{} is not an IPv4 address", group))?;
+	let local = SocketAddr::new(IpAddr::V4(options.interface.unwrap_or(Ipv4Addr::UNSPECIFIED)), 0);
+	let socket = UdpSocket::bind(local)?;
+	if let Some(interface) = options.interface {
+	    SockRef::from(&socket).set_multicast_if_v4(&interface)?;
+	}
+	Ok(Querier { socket, group, deadline: Instant::now() + wait, records: Vec::new() })
+    }
+
+    /// Asks each question, a name and record type, reading replies until
+    /// `done` holds for the records gathered or `until` passes
+    fn ask(&mut self, questions: &[(&str, u16)], until: Instant, done: impl Fn(&Querier) -> bool) -> Result<(), Box<dyn std::error::Error>> {
+	if done(self) {
+	    return Ok(())
+	}
+	for (name, qtype) in questions {
+	    let mut pkt = dns::build_query(dns::query_id(), name, *qtype)?;
+	    // Multicast queries do not ask for recursion
+	    pkt[2] &= !0x01;
+	    self.socket.send_to(&pkt, self.group).map_err(|e| error::from_io(e, Stage::Connect, None))?;
+	}
+
+	let until = until.min(self.deadline);
+	let mut buf = [0u8; 9000];
+	while let Some(left) = until.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+	    self.socket.set_read_timeout(Some(left))?;
+	    match self.socket.recv_from(&mut buf) {
+		Ok((len, _)) => {
+		    let reply = &buf[..len];
+		    if len >= 12 && reply[2] & 0x80 != 0 {
+			self.records.extend(dns::records(reply).unwrap_or_default());
+		    }
+		    if done(self) {
+			break
+		    }
+		},
+		Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => break,
+		Err(e) => return Err(error::from_io(e, Stage::Response, None)),
+	    }
+	}
+	Ok(())
+    }
+
+    fn addresses(&self, host: &str) -> Vec<IpAddr> {
+	let mut addrs: Vec<IpAddr> = self.records.iter()
+	    .filter(|r| r.name.eq_ignore_ascii_case(host))
+	    .filter_map(|r| match r.data {
+		RData::A(ip) => Some(IpAddr::V4(ip)),
+		RData::Aaaa(ip) => Some(IpAddr::V6(ip)),
+		_ => None,
+	    })
+	    .collect();
+	addrs.sort();
+	addrs.dedup();
+	addrs
+    }
+
+    fn srv(&self, instance: &str) -> Option<SrvRecord> {
+	self.records.iter()
+	    .filter(|r| r.name.eq_ignore_ascii_case(instance))
+	    .find_map(|r| match &r.data {
+		RData::Srv(srv) => Some(srv.clone()),
+		_ => None,
+	    })
+    }
+
+    fn instances(&self, service: &str) -> Vec<String> {
+	let mut instances: Vec<String> = self.records.iter()
+	    .filter(|r| r.name.eq_ignore_ascii_case(service))
+	    .filter_map(|r| match &r.data {
+		RData::Ptr(instance) => Some(instance.clone()),
+		_ => None,
+	    })
+	    .collect();
+	instances.sort();
+	instances.dedup();
+	instances
+    }
+
+    /// Resolves a host to its addresses
+    fn host(&mut self, host: &str) -> Result<Vec<IpAddr>, String> {
+	self.ask(&[(host, TYPE_A)], self.deadline, |q| !q.addresses(host).is_empty()).map_err(|e| e.to_string())?;
+	let addrs = self.addresses(host);
+	if addrs.is_empty() {
+	    return Err(format!("{} did not answer", host))
+	}
+	Ok(addrs)
+    }
+
+    /// Resolves service instances to the hosts and ports they advertise,
+    /// querying for all of them at once so one silent instance does not
+    /// use up the time the others need
+    fn resolve_instances(&mut self, instances: &[String]) -> Result<Vec<Result<Endpoint, String>>, Box<dyn std::error::Error>> {
+	let questions: Vec<(&str, u16)> = instances.iter().map(|i| (i.as_str(), TYPE_SRV)).collect();
+	// Leave half the remaining time for the hosts the instances name
+	let now = Instant::now();
+	let halfway = now + self.deadline.saturating_duration_since(now) / 2;
+	self.ask(&questions, halfway, |q| instances.iter().all(|i| q.srv(i).is_some()))?;
+	let mut hosts: Vec<String> = instances.iter().filter_map(|i| self.srv(i)).map(|srv| srv.target).collect();
+	hosts.sort();
+	hosts.dedup();
+	let questions: Vec<(&str, u16)> = hosts.iter().map(|h| (h.as_str(), TYPE_A)).collect();
+	self.ask(&questions, self.deadline, |q| hosts.iter().all(|h| !q.addresses(h).is_empty()))?;
+
+	let resolved = instances.iter().map(|instance| {
+	    let srv = self.srv(instance).ok_or_else(|| format!("{} did not answer", instance))?;
+	    let addrs = self.addresses(&srv.target);
+	    if addrs.is_empty() {
+		return Err(format!("{} did not answer for {}", srv.target, instance))
+	    }
+	    Ok(Endpoint { instance: Some(instance.clone()), host: srv.target, port: Some(srv.port), addrs })
+	});
+	Ok(resolved.collect())
+    }
+}
+
+/// Returns true for service types such as `_ipp._tcp.local`
+fn is_service(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+/// Returns true for service instances such as `Office._ipp._tcp.local`
+fn is_instance(name: &str) -> bool {
+    !is_service(name) && (name.contains("._tcp.") || name.contains("._udp."))
+}
+
+/// Resolves `name` over multicast DNS and, with `connect`, opens a TCP
+/// connection to each endpoint found. A service type passes when any of its
+/// instances resolves, and connects, and lists those that did not.
+pub fn check(name: &str, options: &MdnsOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let name = name.trim_end_matches('.');
+    let wait = timeout.unwrap_or(DEFAULT_WAIT);
+    let mut querier = Querier::new(options, wait)?;
+    let mut endpoints = Vec::new();
+    let mut problems = Vec::new();
+
+    if is_service(name) || is_instance(name) {
+	let instances = if is_service(name) {
+	    // Browse for half the time allowed, leaving the rest for resolving
+	    // instances whose records were not included with the answers
+	    let browse = Instant::now() + wait / 2;
+	    querier.ask(&[(name, TYPE_PTR)], browse, |_| false)?;
+	    let instances = querier.instances(name);
+	    if instances.is_empty() {
+		return Err(From::from(format!("no instances of {} answered within {:.1}s", name, wait.as_secs_f64() / 2.0)))
+	    }
+	    instances
+	} else {
+	    vec![name.to_string()]
+	};
+	for resolved in querier.resolve_instances(&instances)? {
+	    match resolved {
+		Ok(endpoint) => endpoints.push(endpoint),
+		Err(e) => problems.push(e),
+	    }
+	}
+    } else {
+	let addrs = querier.host(name)?;
+	endpoints.push(Endpoint { instance: None, host: name.to_string(), port: options.port, addrs });
+    }
+
+    if options.connect.unwrap_or(false) {
+	let connect = |endpoint: &Endpoint| -> Result<String, String> {
+	    let port = endpoint.port.ok_or_else(|| format!("{}: set `port` to connect to a host", endpoint.host))?;
+	    let addr = SocketAddr::new(*endpoint.addrs.first().ok_or("no addresses")?, port);
+	    let started = Instant::now();
+	    match TcpStream::connect_timeout(&addr, wait) {
+		Ok(_) => Ok(format!("{}, connected in {:.0}ms", endpoint.describe(), started.elapsed().as_secs_f64() * 1000.0)),
+		Err(e) => Err(format!("{}: {}", endpoint.describe(), e)),
+	    }
+	};
+	let (connected, failed): (Vec<_>, Vec<_>) = endpoints.iter().map(connect).partition(Result::is_ok);
+	problems.extend(failed.into_iter().filter_map(Result::err));
+	let connected: Vec<String> = connected.into_iter().filter_map(Result::ok).collect();
+	if connected.is_empty() {
+	    return Err(From::from(format!("\n\tUnreachable: {}", problems.join("; "))))
+	}
+	return Ok(summarize(connected, &problems))
+    }
+    if endpoints.is_empty() {
+	return Err(From::from(format!("\n\tUnresolved: {}", problems.join("; "))))
+    }
+    Ok(summarize(endpoints.iter().map(Endpoint::describe).collect(), &problems))
+}
+
+/// Lists the endpoints that succeeded, and any that did not
+fn summarize(found: Vec<String>, problems: &[String]) -> String {
+    let mut details = format!("{} resolved: {}", found.len(), found.join("; "));
+    if !problems.is_empty() {
+	details.push_str(&format!("; failed: {}", problems.join("; ")));
+    }
+    details
+}
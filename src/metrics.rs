@@ -0,0 +1,155 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Latency metrics kept across runs. In watch mode each successful check
+//! adds a sample, and the reported latency can be smoothed so a single
+//! outlier on a jittery link does not dominate. Counters describing the
+//! connchk process itself are kept here too.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+/// How latency samples are combined into the reported value
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "mode")]
+pub enum Smoothing {
+    /// Report each sample as measured
+    #[default]
+    Raw,
+    /// Exponentially weighted moving average. `alpha` between 0 and 1 sets
+    /// the weight of the newest sample.
+    Ewma { alpha: f64 },
+    /// Mean of the last `size` samples
+    Window { size: usize },
+}
+
+impl Smoothing {
+    /// Returns a short label for output, or `None` for raw samples
+    pub fn label(&self) -> Option<&'static str> {
+	match self {
+	    Smoothing::Raw => None,
+	    Smoothing::Ewma { .. } => Some("ewma"),
+	    Smoothing::Window { .. } => Some("avg"),
+	}
+    }
+}
+
+/// Latency history for a single resource
+#[derive(Debug, Clone, Default)]
+pub struct LatencyTracker {
+    ewma: Option<f64>,
+    window: VecDeque<f64>,
+    /// The most recently reported, possibly smoothed, latency
+    pub current: Option<f64>,
+}
+
+impl LatencyTracker {
+    /// Adds a sample in milliseconds and returns the smoothed latency
+    pub fn record(&mut self, sample: f64, smoothing: &Smoothing) -> f64 {
+	let value = match *smoothing {
+	    Smoothing::Raw => sample,
+	    Smoothing::Ewma { alpha } => {
+		let alpha = alpha.clamp(0.0, 1.0);
+		let value = match self.ewma {
+		    Some(prev) => alpha * sample + (1.0 - alpha) * prev,
+		    None => sample,
+		};
+		self.ewma = Some(value);
+		value
+	    },
+	    Smoothing::Window { size } => {
+		self.window.push_back(sample);
+		while self.window.len() > size.max(1) {
+		    self.window.pop_front();
+		}
+		self.window.iter().sum::<f64>() / self.window.len() as f64
+	    },
+	};
+	self.current = Some(value);
+	value
+    }
+}
+
+/// Counters describing the health of the connchk process itself, rather
+/// than of its targets
+#[derive(Debug, Clone, Default)]
+pub struct SelfMetrics {
+    /// Runs completed
+    pub runs: u64,
+    /// Checks executed across all runs
+    pub checks: u64,
+    /// Duration of the latest run, including hooks
+    pub last_run: Duration,
+    /// Checks queued during the latest run because the probing host ran
+    /// out of file descriptors or ports
+    pub queue_depth: u64,
+    /// Notifications that could not be delivered
+    pub notifier_failures: u64,
+    /// Times the configuration was reloaded in watch mode
+    pub config_reloads: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(smoothing: Smoothing, samples: &[f64]) -> Vec<f64> {
+	let mut tracker = LatencyTracker::default();
+	samples.iter().map(|&sample| tracker.record(sample, &smoothing)).collect()
+    }
+
+    #[test]
+    fn raw_samples_are_reported_as_measured() {
+	assert_eq!(recorded(Smoothing::Raw, &[10.0, 50.0, 20.0]), [10.0, 50.0, 20.0]);
+    }
+
+    #[test]
+    fn window_averages_the_latest_samples() {
+	let smoothing = Smoothing::Window { size: 3 };
+	assert_eq!(recorded(smoothing, &[10.0, 20.0, 30.0, 100.0, 5.0]), [10.0, 15.0, 20.0, 50.0, 45.0]);
+	// A window of one, or none, reports each sample
+	assert_eq!(recorded(Smoothing::Window { size: 1 }, &[10.0, 20.0]), [10.0, 20.0]);
+	assert_eq!(recorded(Smoothing::Window { size: 0 }, &[10.0, 20.0]), [10.0, 20.0]);
+    }
+
+    #[test]
+    fn ewma_weights_the_newest_sample_by_alpha() {
+	// The first sample seeds the average
+	assert_eq!(recorded(Smoothing::Ewma { alpha: 0.25 }, &[100.0, 20.0, 20.0]), [100.0, 80.0, 65.0]);
+	// Alpha is clamped between 0, ignoring new samples, and 1, raw samples
+	assert_eq!(recorded(Smoothing::Ewma { alpha: -1.0 }, &[100.0, 20.0]), [100.0, 100.0]);
+	assert_eq!(recorded(Smoothing::Ewma { alpha: 2.0 }, &[100.0, 20.0]), [100.0, 20.0]);
+    }
+
+    #[test]
+    fn current_latency_follows_the_last_record() {
+	let mut tracker = LatencyTracker::default();
+	assert_eq!(tracker.current, None);
+	tracker.record(40.0, &Smoothing::Window { size: 2 });
+	tracker.record(60.0, &Smoothing::Window { size: 2 });
+	assert_eq!(tracker.current, Some(50.0));
+    }
+
+    #[test]
+    fn smoothing_is_configured_by_mode() {
+	#[derive(Deserialize)]
+	struct Config {
+	    smoothing: Smoothing,
+	}
+	let smoothing = |toml: &str| toml::from_str::<Config>(toml).unwrap().smoothing;
+	assert!(matches!(smoothing("smoothing = { mode = \"Raw\" }"), Smoothing::Raw));
+	assert!(matches!(smoothing("smoothing = { mode = \"Ewma\", alpha = 0.3 }"), Smoothing::Ewma { alpha } if alpha == 0.3));
+	assert!(matches!(smoothing("smoothing = { mode = \"Window\", size = 5 }"), Smoothing::Window { size: 5 }));
+	assert_eq!(Smoothing::Window { size: 5 }.label(), Some("avg"));
+	assert_eq!(Smoothing::Raw.label(), None);
+    }
+}
@@ -0,0 +1,165 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Latency comparison of equivalent endpoints, such as regional mirrors or
+//! the sites behind a geo-DNS name. Every endpoint is timed with TCP
+//! connections to find the fastest from the probing host, and the check
+//! fails when the endpoints spread beyond a latency band or when the name
+//! clients use leads to an endpoint much slower than the fastest.
+
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::addr;
+
+/// Connections timed per endpoint when `samples` is not given
+const DEFAULT_SAMPLES: u32 = 3;
+
+/// Provides a deserialize target for the `mirrors` table of mirror checks,
+/// whose `addr` is the name clients use to reach one of the endpoints
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MirrorOptions {
+    /// The equivalent endpoints, as `host:port` addresses or URLs
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// Milliseconds the slowest reachable endpoint may trail the fastest
+    pub band_ms: Option<f64>,
+    /// Milliseconds the endpoint `addr` resolves to may trail the fastest
+    pub tolerance_ms: Option<f64>,
+    /// Connections timed per endpoint, of which the median counts.
+    /// Defaults to 3.
+    pub samples: Option<u32>,
+    /// Endpoints that must be reachable for the check to pass. Defaults to
+    /// all of them.
+    pub min_reachable: Option<usize>,
+}
+
+/// A reachable endpoint and how fast it answered
+struct Timed {
+    endpoint: String,
+    ips: Vec<IpAddr>,
+    median: Duration,
+}
+
+/// Returns the `host:port` address of `endpoint`, which may be a URL
+fn socket_addr(endpoint: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if !endpoint.contains("://") {
+	return Ok(endpoint.to_string())
+    }
+    let url = Url::parse(endpoint)?;
+    let host = url.host_str().ok_or_else(|| format!("missing host in {}", endpoint))?;
+    let port = url.port_or_known_default().ok_or_else(|| format!("missing port in {}", endpoint))?;
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Connects to `endpoint` `samples` times, returning its addresses and the
+/// median time to connect
+fn time(endpoint: &str, samples: u32, timeout: Option<Duration>) -> Result<Timed, Box<dyn std::error::Error>> {
+    let addrs: Vec<SocketAddr> = addr::resolve(&socket_addr(endpoint)?)?;
+    let first = addrs.first().ok_or("no addresses")?;
+    let mut times = Vec::with_capacity(samples as usize);
+    for _ in 0..samples {
+	let started = Instant::now();
+	match timeout {
+	    Some(timeout) => TcpStream::connect_timeout(first, timeout)?,
+	    None => TcpStream::connect(first)?,
+	};
+	times.push(started.elapsed());
+    }
+    times.sort();
+    Ok(Timed {
+	endpoint: endpoint.to_string(),
+	ips: addrs.iter().map(SocketAddr::ip).collect(),
+	median: times[times.len() / 2],
+    })
+}
+
+/// Milliseconds in `duration`
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Times every endpoint of `options` and reports the fastest. Fails when
+/// fewer than `min_reachable` answer, when they spread beyond `band_ms`, or
+/// when `tolerance_ms` is given and `addr` does not lead to an endpoint
+/// within it of the fastest.
+pub fn check(addr: &str, options: &MirrorOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    if options.endpoints.is_empty() {
+	return Err(From::from("Mirrors targets require `endpoints` in their `mirrors` table"))
+    }
+    let samples = options.samples.unwrap_or(DEFAULT_SAMPLES).max(1);
+    let mut reachable = Vec::new();
+    let mut unreachable = Vec::new();
+    for endpoint in options.endpoints.iter() {
+	match time(endpoint, samples, timeout) {
+	    Ok(timed) => reachable.push(timed),
+	    Err(e) => unreachable.push(format!("{}: {}", endpoint, e)),
+	}
+    }
+    reachable.sort_by_key(|timed| timed.median);
+
+    let total = options.endpoints.len();
+    let wanted = options.min_reachable.unwrap_or(total).clamp(1, total);
+    if reachable.len() < wanted {
+	return Err(From::from(format!("\n\tReachable: {}/{} (need {})\n\tDetails: {}",
+				      reachable.len(), total, wanted, unreachable.join("; "))))
+    }
+    let fastest = &reachable[0];
+    let ranked: Vec<String> = reachable.iter()
+	.map(|timed| format!("{} ({:.0}ms)", timed.endpoint, ms(timed.median)))
+	.collect();
+    let mut details = format!("fastest of {}/{} endpoints: {}", reachable.len(), total, ranked.join(", "));
+    if !unreachable.is_empty() {
+	details.push_str(&format!("; unreachable: {}", unreachable.join("; ")));
+    }
+
+    let slowest = &reachable[reachable.len() - 1];
+    let spread = ms(slowest.median - fastest.median);
+    if let Some(band) = options.band_ms.filter(|band| spread > *band) {
+	return Err(From::from(format!("\n\tBand: {} trails {} by {:.0}ms, beyond {:.0}ms\n\tDetails: {}",
+				      slowest.endpoint, fastest.endpoint, spread, band, details)))
+    }
+
+    // The name clients use leads to whichever endpoint shares an address
+    // with it; its port is irrelevant, so the fastest endpoint's stands in
+    let port = socket_addr(&fastest.endpoint).ok()
+	.and_then(|addr| addr.rsplit_once(':').and_then(|(_, port)| port.parse().ok()))
+	.unwrap_or(443);
+    let selected = socket_addr(addr)
+	.and_then(|addr| addr::resolve_or_port(&addr, port).map_err(From::from))
+	.map(|addrs| reachable.iter().position(|timed| addrs.iter().any(|a| timed.ips.contains(&a.ip()))));
+    let behind = match selected {
+	Ok(Some(rank)) => {
+	    let behind = ms(reachable[rank].median - fastest.median);
+	    details.push_str(&format!("; {} selects {} (+{:.0}ms)", addr, reachable[rank].endpoint, behind));
+	    Some(behind)
+	},
+	Ok(None) => {
+	    details.push_str(&format!("; {} selects none of the reachable endpoints", addr));
+	    None
+	},
+	Err(e) => {
+	    details.push_str(&format!("; {} could not be resolved: {}", addr, e));
+	    None
+	},
+    };
+    match (options.tolerance_ms, behind) {
+	(Some(tolerance), Some(behind)) if behind > tolerance => {
+	    Err(From::from(format!("\n\tSelection: {} is {:.0}ms slower than {}, beyond {:.0}ms\n\tDetails: {}",
+				   addr, behind, fastest.endpoint, tolerance, details)))
+	},
+	(Some(_), None) => Err(From::from(format!("\n\tSelection: {} does not lead to a reachable endpoint\n\tDetails: {}",
+						  addr, details))),
+	_ => Ok(details),
+    }
+}
@@ -0,0 +1,144 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Multicast group checks. The group is joined, which sends an IGMP or MLD
+//! report upstream, and the check waits for traffic sent to it. Multicast
+//! routing tends to break silently, when a querier or RP goes away or PIM
+//! state expires, and a receiver that hears nothing is the only symptom.
+
+use std::collections::BTreeSet;
+use std::io;
+#[cfg(windows)]
+use std::net::Ipv6Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::addr;
+use crate::error::{self, Stage};
+
+/// Time allowed for traffic to arrive when the target has no timeout
+const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+
+/// Provides a deserialize target for multicast checks. All fields are
+/// optional.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MulticastOptions {
+    /// Local IPv4 address of the interface to join on, or the index of
+    /// the interface for IPv6 groups
+    pub interface: Option<String>,
+    /// Source to receive from, joining the group source-specifically
+    /// (SSM). IPv4 only.
+    pub source: Option<Ipv4Addr>,
+    /// Packets needed to pass, 1 if not given
+    pub min_packets: Option<usize>,
+    /// Text a packet must contain to be counted, such as the payload of a
+    /// test beacon
+    pub expect: Option<String>,
+}
+
+/// Opens a socket receiving datagrams sent to `group` and joins the group
+fn join(group: SocketAddr, options: &MulticastOptions) -> Result<UdpSocket, Box<dyn std::error::Error>> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    // Binding to the group itself keeps out traffic for other groups on the
+    // same port, which Windows does not allow
+    #[cfg(not(windows))]
+    let bind = group;
+    #[cfg(windows)]
+    let bind = match group {
+	SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), group.port()),
+	SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), group.port()),
+    };
+    socket.bind(&bind.into())?;
+
+    let interface = options.interface.as_deref();
+    match group.ip() {
+	IpAddr::V4(ip) => {
+	    let interface = match interface {
+		Some(interface) => interface.parse::<Ipv4Addr>()
+		    .map_err(|_| format!("interface `{}` is not an IPv4 address", interface))?,
+		None => Ipv4Addr::UNSPECIFIED,
+	    };
+	    match options.source {
+		Some(source) => socket.join_ssm_v4(&source, &ip, &interface)?,
+		None => socket.join_multicast_v4(&ip, &interface)?,
+	    }
+	},
+	IpAddr::V6(ip) => {
+	    if options.source.is_some() {
+		return Err(From::from("source-specific joins are only supported for IPv4 groups"))
+	    }
+	    let index = match interface {
+		Some(interface) => interface.parse::<u32>()
+		    .map_err(|_| format!("interface `{}` is not an interface index", interface))?,
+		None => 0,
+	    };
+	    socket.join_multicast_v6(&ip, index)?;
+	},
+    }
+    Ok(socket.into())
+}
+
+/// Joins the group at `addr`, e.g. `239.1.2.3:5000`, and waits until
+/// `min_packets` packets arrive or `timeout` passes
+pub fn check(addr: &str, options: &MulticastOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let group = addr::resolve(addr)
+	.map_err(|e| error::from_io(e, Stage::Resolve, timeout))?
+	.into_iter()
+	.next()
+	.ok_or_else(|| format!("{} did not resolve to any address", addr))?;
+    if !group.ip().is_multicast() {
+	return Err(From::from(format!("{} is not a multicast group", group.ip())))
+    }
+    let wanted = options.min_packets.unwrap_or(1);
+    let wait = timeout.unwrap_or(DEFAULT_WAIT);
+    let socket = join(group, options)?;
+
+    let start = Instant::now();
+    let mut first = None;
+    let mut received = 0;
+    let mut sources = BTreeSet::new();
+    let mut buf = [0u8; 65536];
+    while received < wanted {
+	let left = match wait.checked_sub(start.elapsed()).filter(|left| !left.is_zero()) {
+	    Some(left) => left,
+	    None => break,
+	};
+	socket.set_read_timeout(Some(left))?;
+	match socket.recv_from(&mut buf) {
+	    Ok((len, from)) => {
+		let matched = options.expect.as_ref()
+		    .is_none_or(|expect| String::from_utf8_lossy(&buf[..len]).contains(expect.as_str()));
+		if matched {
+		    received += 1;
+		    first.get_or_insert_with(|| start.elapsed());
+		    sources.insert(from.ip());
+		}
+	    },
+	    Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => break,
+	    Err(e) => return Err(error::from_io(e, Stage::Response, Some(wait))),
+	}
+    }
+
+    let sources: Vec<String> = sources.iter().map(IpAddr::to_string).collect();
+    let what = if options.expect.is_some() { "matching packets" } else { "packets" };
+    match first {
+	None => Err(From::from(format!("no {} sent to {} within {:.1}s", what, group, wait.as_secs_f64()))),
+	Some(_) if received < wanted => Err(From::from(format!("\n\tReceived: {}/{} {} within {:.1}s\n\tSources: {}",
+								received, wanted, what, wait.as_secs_f64(), sources.join(", ")))),
+	Some(first) => Ok(format!("{} {} from {}, the first after {:.0}ms",
+				  received, what, sources.join(", "), first.as_secs_f64() * 1000.0)),
+    }
+}
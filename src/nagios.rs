@@ -0,0 +1,205 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Nagios and Icinga plugin output. A run is summarized as a single status
+//! line carrying the latency of each target as performance data, and its
+//! state is the plugin's exit code.
+
+use crate::{NetworkResources, Resource};
+use crate::notify::Severity;
+use crate::outcome::Outcome;
+
+/// Plugin states, in the order of their exit codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+	match self {
+	    State::Ok => "OK",
+	    State::Warning => "WARNING",
+	    State::Critical => "CRITICAL",
+	    State::Unknown => "UNKNOWN",
+	}
+    }
+
+    /// Returns the exit code of a plugin ending in this state
+    pub fn code(&self) -> i32 {
+	*self as i32
+    }
+}
+
+/// Removes the `|` separating the text of a status line from its
+/// performance data
+fn text(desc: &str) -> String {
+    desc.split_whitespace().collect::<Vec<_>>().join(" ").replace('|', "/")
+}
+
+/// Quotes a performance data label, which may not hold `=`
+fn label(label: &str) -> String {
+    format!("'{}'", text(label).replace('=', "_").replace('\'', "''"))
+}
+
+/// Returns the latency of a checked target as performance data, with its
+/// `max_latency_ms` as the critical threshold
+fn perfdata(name: Option<&str>, target: &Resource) -> Option<String> {
+    let latency = target.result.as_ref()?.latency?;
+    let desc = match name {
+	Some(name) => format!("{}/{}", name, target.desc),
+	None => target.desc.clone(),
+    };
+    let critical = target.max_latency_ms.map(|ms| ms.to_string()).unwrap_or_default();
+    Some(format!("{}={:.3}ms;;{};0;", label(&desc), latency.as_secs_f64() * 1000.0, critical))
+}
+
+/// Returns the state and status line of a plugin run over the latest run
+/// of each named configuration. Failed targets whose `severity` is
+/// `warning` or `info` only warn, as do `regressed` latency regressions,
+/// and other failures are critical. Checks whose state is unknown make the
+/// run unknown when nothing failed.
+pub fn status(suites: &[(String, &NetworkResources)], regressed: usize) -> (State, String) {
+    let targets: Vec<&Resource> = suites.iter()
+	.flat_map(|(_, resources)| resources.target.iter())
+	.filter(|t| t.result.is_some())
+	.collect();
+    let with = |outcome: Outcome| -> Vec<&Resource> {
+	targets.iter().copied().filter(|t| t.outcome() == outcome).collect()
+    };
+    let (failed, unknown) = (with(Outcome::Failed), with(Outcome::Unknown));
+    let critical = failed.iter()
+	.any(|t| !matches!(t.severity, Some(Severity::Warning | Severity::Info)));
+    let descs = |targets: &[&Resource]| targets.iter().map(|t| text(&t.desc)).collect::<Vec<_>>().join(", ");
+    let (state, mut line) = if !failed.is_empty() {
+	let state = if critical { State::Critical } else { State::Warning };
+	(state, format!("{} of {} checks failed: {}", failed.len(), targets.len(), descs(&failed)))
+    } else if regressed > 0 {
+	(State::Warning, format!("{} of {} checks regressed since the baseline", regressed, targets.len()))
+    } else if !unknown.is_empty() {
+	(State::Unknown, format!("{} of {} checks could not be determined: {}", unknown.len(), targets.len(), descs(&unknown)))
+    } else {
+	(State::Ok, format!("{} of {} checks passed", with(Outcome::Passed).len(), targets.len()))
+    };
+    let perfdata: Vec<String> = suites.iter()
+	.flat_map(|(name, resources)| {
+	    let name = (suites.len() > 1).then_some(name.as_str());
+	    resources.target.iter().filter_map(move |t| perfdata(name, t))
+	})
+	.collect();
+    if !perfdata.is_empty() {
+	line.push_str(&format!(" | {}", perfdata.join(" ")));
+    }
+    (state, format!("CONNCHK {} - {}", state.as_str(), line))
+}
+
+/// Returns the status line of a plugin run whose checks could not run,
+/// e.g. for an invalid configuration
+pub fn unknown(reason: &str) -> String {
+    format!("CONNCHK {} - {}", State::Unknown.as_str(), text(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::config;
+    use crate::outcome::{CheckResult, SkipReason};
+
+    /// Returns a configuration whose targets ended with the given outcomes
+    /// and latencies in microseconds
+    fn checked(toml: &str, ends: &[(Outcome, Option<u64>)]) -> NetworkResources {
+	let (mut resources, _) = config::parse(toml).unwrap();
+	for (target, &(outcome, latency)) in resources.target.iter_mut().zip(ends) {
+	    target.failed = outcome == Outcome::Failed;
+	    target.unknown = outcome == Outcome::Unknown;
+	    target.skip_reason = (outcome == Outcome::Skipped).then_some(SkipReason::Disabled);
+	    target.result = Some(CheckResult {
+		desc: target.desc.clone(),
+		addr: target.addr.clone(),
+		alias: None,
+		outcome,
+		skip_reason: target.skip_reason,
+		latency: latency.map(Duration::from_micros),
+		smoothed_ms: None,
+		attempts: 1,
+		details: None,
+		error: None,
+		error_code: None,
+		annotations: Default::default(),
+		trace: Vec::new(),
+		addresses: Vec::new(),
+		peer: None,
+		resolve_time: None,
+	    });
+	}
+	resources
+    }
+
+    const TARGETS: &str = "[[target]]\ndesc = \"api\"\naddr = \"api:443\"\nkind = \"Tcp\"\nmax_latency_ms = 250\n\
+			   [[target]]\ndesc = \"cache\"\naddr = \"cache:6379\"\nkind = \"Tcp\"\nseverity = \"Warning\"\n\
+			   [[target]]\ndesc = \"db\"\naddr = \"db:5432\"\nkind = \"Tcp\"\n";
+
+    fn status_of(ends: &[(Outcome, Option<u64>)], regressed: usize) -> (State, String) {
+	let resources = checked(TARGETS, ends);
+	status(&[("prod".to_string(), &resources)], regressed)
+    }
+
+    #[test]
+    fn passing_runs_are_ok() {
+	let ends = [(Outcome::Passed, Some(12_000)), (Outcome::Passed, Some(3_500)), (Outcome::Skipped, None)];
+	assert_eq!(status_of(&ends, 0),
+		   (State::Ok, "CONNCHK OK - 2 of 3 checks passed | 'api'=12.000ms;;250;0; 'cache'=3.500ms;;;0;".to_string()));
+    }
+
+    #[test]
+    fn warning_failures_and_regressions_warn() {
+	let ends = [(Outcome::Passed, Some(12_000)), (Outcome::Failed, None), (Outcome::Passed, Some(1_250))];
+	assert_eq!(status_of(&ends, 0),
+		   (State::Warning, "CONNCHK WARNING - 1 of 3 checks failed: cache | 'api'=12.000ms;;250;0; 'db'=1.250ms;;;0;".to_string()));
+
+	let ends = [(Outcome::Passed, Some(12_000)), (Outcome::Passed, Some(1_000)), (Outcome::Unknown, None)];
+	assert_eq!(status_of(&ends, 1),
+		   (State::Warning, "CONNCHK WARNING - 1 of 3 checks regressed since the baseline | \
+				     'api'=12.000ms;;250;0; 'cache'=1.000ms;;;0;".to_string()));
+    }
+
+    #[test]
+    fn other_failures_are_critical() {
+	let ends = [(Outcome::Passed, Some(12_000)), (Outcome::Failed, None), (Outcome::Failed, None)];
+	assert_eq!(status_of(&ends, 1),
+		   (State::Critical, "CONNCHK CRITICAL - 2 of 3 checks failed: cache, db | 'api'=12.000ms;;250;0;".to_string()));
+    }
+
+    #[test]
+    fn unknown_checks_make_the_run_unknown() {
+	let ends = [(Outcome::Passed, Some(12_000)), (Outcome::Unknown, None), (Outcome::Unknown, None)];
+	assert_eq!(status_of(&ends, 0),
+		   (State::Unknown, "CONNCHK UNKNOWN - 2 of 3 checks could not be determined: cache, db | \
+				     'api'=12.000ms;;250;0;".to_string()));
+	assert_eq!(unknown("bad | config\n  at line 3"), "CONNCHK UNKNOWN - bad / config at line 3");
+	let codes: Vec<i32> = [State::Ok, State::Warning, State::Critical, State::Unknown].iter().map(State::code).collect();
+	assert_eq!(codes, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn perfdata_labels_are_named_and_escaped() {
+	let prod = checked("[[target]]\ndesc = \"it's a=b | c\"\naddr = \"api:443\"\nkind = \"Tcp\"\n",
+			   &[(Outcome::Passed, Some(2_000))]);
+	let lab = checked("[[target]]\ndesc = \"api\"\naddr = \"api:443\"\nkind = \"Tcp\"\n",
+			  &[(Outcome::Passed, Some(5_000))]);
+	let (state, line) = status(&[("prod".to_string(), &prod), ("lab".to_string(), &lab)], 0);
+	assert_eq!(state, State::Ok);
+	assert_eq!(line, "CONNCHK OK - 2 of 2 checks passed | 'prod/it''s a_b / c'=2.000ms;;;0; 'lab/api'=5.000ms;;;0;");
+    }
+}
@@ -0,0 +1,432 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Webhook notifications for failed checks. Payloads are JSON documents
+//! POSTed to each configured notifier, optionally signed with HMAC-SHA256
+//! so receivers can authenticate them. Routes select which notifiers hear
+//! about which targets.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Client;
+use ring::hmac;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::Resource;
+use crate::outcome::Outcome;
+
+/// Header carrying the hex encoded HMAC-SHA256 of the request body
+pub const SIGNATURE_HEADER: &str = "X-Connchk-Signature";
+
+/// Default delay before the first redelivery attempt
+const DEFAULT_BACKOFF_MS: u64 = 500;
+
+/// Seconds a target that stays failed is held back before it is notified
+/// again when no routes are configured
+pub const DEFAULT_THROTTLE_SECS: u64 = 900;
+
+/// Provides a deserialize target for webhook notifiers
+#[derive(Deserialize, Debug, Clone)]
+pub struct Notifier {
+    /// Name used to refer to this notifier from a [`Route`]
+    pub name: Option<String>,
+    /// URL the payload is POSTed to
+    pub url: String,
+    /// Shared secret used to sign payloads. Unsigned when absent.
+    pub secret: Option<String>,
+    /// Number of redelivery attempts after a failed delivery
+    pub retries: Option<u32>,
+    /// Delay before the first redelivery, doubled after every attempt
+    pub backoff_ms: Option<u64>,
+}
+
+/// Returns the `sha256=<hex>` signature of `body` under `secret`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body);
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+impl Notifier {
+    /// Delivers `payload`, retrying with exponential backoff until the
+    /// receiver answers with a success status or retries are exhausted.
+    pub fn send(&self, payload: &Value) -> Result<(), Box<dyn std::error::Error>> {
+	let body = serde_json::to_vec(payload)?;
+	let client = Client::new();
+	let mut delay = Duration::from_millis(self.backoff_ms.unwrap_or(DEFAULT_BACKOFF_MS));
+	let mut attempt = 0;
+	loop {
+	    let mut req = client.post(&self.url)
+		.header(reqwest::header::CONTENT_TYPE, "application/json")
+		.body(body.clone());
+	    if let Some(secret) = &self.secret {
+		req = req.header(SIGNATURE_HEADER, sign(secret, &body));
+	    }
+
+	    let err = match req.send() {
+		Ok(resp) if resp.status().is_success() => return Ok(()),
+		Ok(resp) => format!("receiver returned {}", resp.status()),
+		Err(e) => e.to_string(),
+	    };
+
+	    if attempt >= self.retries.unwrap_or(0) {
+		let msg = format!("delivery to {} failed after {} attempt(s): {}", self.url, attempt + 1, err);
+		return Err(From::from(msg))
+	    }
+	    thread::sleep(delay);
+	    delay *= 2;
+	    attempt += 1;
+	}
+    }
+}
+
+/// Provides a deserialize target for a dead man's switch. The URL is
+/// requested at the end of every run so an external service can alert when
+/// connchk itself stops running.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Heartbeat {
+    /// URL requested with a GET after each run
+    pub url: String,
+    /// Only ping when every check in the run passed
+    pub only_when_passing: Option<bool>,
+}
+
+impl Heartbeat {
+    /// Pings the heartbeat URL, treating any non-success status as an error
+    pub fn ping(&self) -> Result<(), Box<dyn std::error::Error>> {
+	let resp = Client::new().get(&self.url).send()?;
+	if resp.status().is_success() {
+	    Ok(())
+	} else {
+	    Err(From::from(format!("heartbeat {} returned {}", self.url, resp.status())))
+	}
+    }
+}
+
+/// Classifies how important a target is, for use in routing
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Which check outcomes a [`Route`] forwards
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum State {
+    /// Only failed checks
+    #[default]
+    Failed,
+    /// Only passed checks
+    Passed,
+    /// Every check
+    Any,
+}
+
+/// Provides a deserialize target for routing rules. A route sends the
+/// targets it matches to one named [`Notifier`]. Unset filters match every
+/// target.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Route {
+    /// Name of the notifier to deliver to
+    pub notifier: String,
+    /// Matches targets carrying any of these tags
+    pub tags: Option<Vec<String>>,
+    /// Matches targets with any of these severities
+    pub severity: Option<Vec<Severity>>,
+    /// Matches targets in this state. Defaults to `Failed`.
+    pub state: Option<State>,
+    /// Suppresses repeat notifications about the same target in the same
+    /// state for this many seconds
+    pub throttle_secs: Option<u64>,
+    /// Re-sends targets that remain failed at this interval, in seconds.
+    /// Takes the place of `throttle_secs` for failed targets.
+    pub remind_secs: Option<u64>,
+    /// Name of a second notifier that also receives a target once it has
+    /// been failing for `escalate_after_secs`
+    pub escalate_to: Option<String>,
+    /// How long a target must be failing before it is escalated
+    pub escalate_after_secs: Option<u64>,
+    /// When each target was last delivered, keyed by description and state
+    #[serde(skip)]
+    sent: HashMap<(String, bool), Instant>,
+    /// When each matched target started failing
+    #[serde(skip)]
+    down_since: HashMap<String, Instant>,
+    /// When each failing target was last escalated
+    #[serde(skip)]
+    escalated: HashMap<String, Instant>,
+}
+
+impl Route {
+    /// Returns the route taken by notifications when none are configured:
+    /// every failed target, repeated at most every [`DEFAULT_THROTTLE_SECS`]
+    pub fn fallback() -> Route {
+	Route { throttle_secs: Some(DEFAULT_THROTTLE_SECS), ..Default::default() }
+    }
+
+    /// Returns true when `target` is in a state this route forwards
+    fn matches_state(&self, target: &Resource) -> bool {
+	match self.state.unwrap_or_default() {
+	    State::Failed => target.failed,
+	    State::Passed => target.outcome() == Outcome::Passed,
+	    State::Any => true,
+	}
+    }
+
+    /// Returns true when `target` passes the tag and severity filters
+    fn matches(&self, target: &Resource) -> bool {
+	let tags = match (&self.tags, &target.tags) {
+	    (Some(want), Some(have)) => want.iter().any(|t| have.contains(t)),
+	    (Some(_), None) => false,
+	    (None, _) => true,
+	};
+	let severity = match (&self.severity, target.severity) {
+	    (Some(want), Some(have)) => want.contains(&have),
+	    (Some(_), None) => false,
+	    (None, _) => true,
+	};
+	tags && severity
+    }
+
+    /// Returns how long repeat notifications are held back for a target in
+    /// the given state
+    fn window(&self, failed: bool) -> Duration {
+	let secs = if failed { self.remind_secs.or(self.throttle_secs) } else { self.throttle_secs };
+	Duration::from_secs(secs.unwrap_or(0))
+    }
+
+    /// Returns the targets this route should deliver now, recording them as
+    /// sent so repeats are throttled.
+    pub fn select<'a>(&mut self, targets: &'a [Resource]) -> Vec<&'a Resource> {
+	let now = Instant::now();
+	let matched: Vec<&Resource> = targets.iter().filter(|t| self.matches(t)).collect();
+	for t in matched {
+	    if t.failed {
+		self.down_since.entry(t.desc.clone()).or_insert(now);
+	    } else {
+		self.down_since.remove(&t.desc);
+	    }
+	}
+
+	let (failed_window, passed_window) = (self.window(true), self.window(false));
+	self.sent.retain(|(_, failed), at| {
+	    now.duration_since(*at) < if *failed { failed_window } else { passed_window }
+	});
+
+	let selected: Vec<&Resource> = targets.iter()
+	    .filter(|t| self.matches(t) && self.matches_state(t))
+	    .filter(|t| !self.sent.contains_key(&(t.desc.clone(), t.failed)))
+	    .collect();
+	for t in selected.iter() {
+	    if !self.window(t.failed).is_zero() {
+		self.sent.insert((t.desc.clone(), t.failed), now);
+	    }
+	}
+	selected
+    }
+
+    /// Returns the matched targets that have been failing long enough to go
+    /// to the `escalate_to` notifier, whether or not [`Route::select`] held
+    /// them back. Escalations are repeated at the reminder interval while a
+    /// target stays down. Call after [`Route::select`], which tracks how long
+    /// targets have been failing.
+    pub fn escalations<'a>(&mut self, targets: &'a [Resource]) -> Vec<&'a Resource> {
+	if self.escalate_to.is_none() {
+	    return Vec::new()
+	}
+	let now = Instant::now();
+	let after = Duration::from_secs(self.escalate_after_secs.unwrap_or(0));
+	let window = self.window(true);
+	let down_since = &self.down_since;
+	self.escalated.retain(|desc, at| down_since.contains_key(desc) && now.duration_since(*at) < window);
+
+	let escalated: Vec<&Resource> = targets.iter()
+	    .filter(|t| t.failed && self.matches(t))
+	    .filter(|t| self.down_since.get(&t.desc).is_some_and(|since| now.duration_since(*since) >= after))
+	    .filter(|t| !self.escalated.contains_key(&t.desc))
+	    .collect();
+	if !window.is_zero() {
+	    for t in escalated.iter() {
+		self.escalated.insert(t.desc.clone(), now);
+	    }
+	}
+	escalated
+    }
+}
+
+/// Builds the JSON payload describing `targets` out of `checked` checks
+pub fn payload(checked: usize, targets: &[&Resource]) -> Value {
+    let entry = |t: &&Resource| json!({
+	"desc": t.desc,
+	"addr": t.addr,
+	"alias": t.alias,
+	"result": t.res,
+	"error": t.error_code,
+	"skip_reason": t.skip_reason.map(|r| r.as_str()),
+	"latency_ms": t.latency.current,
+	"hint": t.hint,
+	"runbook_url": t.runbook_url,
+	"annotations": t.annotations,
+    });
+    let failed: Vec<Value> = targets.iter().filter(|t| t.failed).map(entry).collect();
+    let passed: Vec<Value> = targets.iter().filter(|t| t.outcome() == Outcome::Passed).map(entry).collect();
+    let skipped: Vec<Value> = targets.iter().filter(|t| t.outcome() == Outcome::Skipped).map(entry).collect();
+    let unknown: Vec<Value> = targets.iter().filter(|t| t.outcome() == Outcome::Unknown).map(entry).collect();
+    let mut payload = json!({ "checked": checked, "failed": failed });
+    if !passed.is_empty() {
+	payload["passed"] = Value::from(passed);
+    }
+    if !skipped.is_empty() {
+	payload["skipped"] = Value::from(skipped);
+    }
+    if !unknown.is_empty() {
+	payload["unknown"] = Value::from(unknown);
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use crate::config;
+    use crate::outcome::SkipReason;
+
+    /// Returns the targets of a configuration, failing those whose
+    /// description starts with `down`
+    fn targets(toml: &str) -> Vec<Resource> {
+	let (resources, _) = config::parse(toml).unwrap();
+	let mut targets = resources.target;
+	for t in targets.iter_mut() {
+	    t.failed = t.desc.starts_with("down");
+	}
+	targets
+    }
+
+    fn route(toml: &str) -> Route {
+	toml::from_str(toml).unwrap()
+    }
+
+    fn descs(targets: &[&Resource]) -> Vec<String> {
+	targets.iter().map(|t| t.desc.clone()).collect()
+    }
+
+    #[test]
+    fn signatures_match_known_answers() {
+	// RFC 4231, test case 2
+	assert_eq!(sign("Jefe", b"what do ya want for nothing?"),
+		   "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+	assert_eq!(sign("", b""), "sha256=b613679a0814d9ec772f95d778c35fc5ff1697c493715653c6c712144292c5ad");
+    }
+
+    #[test]
+    fn deliveries_carry_the_signature_header() {
+	let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let notifier = Notifier {
+	    name: None,
+	    url: format!("http://{}/hook", server.local_addr().unwrap()),
+	    secret: Some("a-shared-secret".to_string()),
+	    retries: None,
+	    backoff_ms: None,
+	};
+	let received = thread::spawn(move || {
+	    let (mut stream, _) = server.accept().unwrap();
+	    let mut request = Vec::new();
+	    let mut buf = [0; 4096];
+	    // The body is sent with the headers or right after them
+	    while !String::from_utf8_lossy(&request).ends_with("\"failed\":[]}") {
+		let n = stream.read(&mut buf).unwrap();
+		assert!(n > 0, "connection closed early");
+		request.extend_from_slice(&buf[..n]);
+	    }
+	    stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").unwrap();
+	    String::from_utf8(request).unwrap()
+	});
+	notifier.send(&json!({ "checked": 1, "failed": [] })).unwrap();
+	let request = received.join().unwrap().to_ascii_lowercase();
+	assert!(request.contains("\r\nx-connchk-signature: \
+				  sha256=ea75b972122f5cb414c64437effbc6890e833d781cc630944062d8fcd3ce4eed\r\n"),
+		"{}", request);
+    }
+
+    #[test]
+    fn routes_select_by_state() {
+	let mut targets = targets("[[target]]\ndesc = \"down db\"\naddr = \"db:5432\"\nkind = \"Tcp\"\n\
+				   [[target]]\ndesc = \"up api\"\naddr = \"api:443\"\nkind = \"Tcp\"\n\
+				   [[target]]\ndesc = \"skipped cache\"\naddr = \"cache:6379\"\nkind = \"Tcp\"\n\
+				   [[target]]\ndesc = \"unknown queue\"\naddr = \"queue:5672\"\nkind = \"Tcp\"\n");
+	targets[2].skip_reason = Some(SkipReason::Disabled);
+	targets[3].unknown = true;
+	assert_eq!(descs(&route("notifier = \"n\"").select(&targets)), ["down db"]);
+	assert_eq!(descs(&route("notifier = \"n\"\nstate = \"Failed\"").select(&targets)), ["down db"]);
+	assert_eq!(descs(&route("notifier = \"n\"\nstate = \"Passed\"").select(&targets)), ["up api"]);
+	assert_eq!(descs(&route("notifier = \"n\"\nstate = \"Any\"").select(&targets)),
+		   ["down db", "up api", "skipped cache", "unknown queue"]);
+    }
+
+    #[test]
+    fn routes_select_by_tag_and_severity() {
+	let targets = targets("[[target]]\ndesc = \"down orders\"\naddr = \"orders:5432\"\nkind = \"Tcp\"\n\
+			       tags = [\"team-a\", \"db\"]\nseverity = \"Critical\"\n\
+			       [[target]]\ndesc = \"down search\"\naddr = \"search:9200\"\nkind = \"Tcp\"\n\
+			       tags = [\"team-b\"]\nseverity = \"Warning\"\n\
+			       [[target]]\ndesc = \"down untagged\"\naddr = \"misc:80\"\nkind = \"Tcp\"\n");
+	assert_eq!(descs(&route("notifier = \"n\"\ntags = [\"db\", \"team-b\"]").select(&targets)),
+		   ["down orders", "down search"]);
+	assert_eq!(descs(&route("notifier = \"n\"\nseverity = [\"Critical\"]").select(&targets)), ["down orders"]);
+	assert_eq!(descs(&route("notifier = \"n\"\ntags = [\"team-a\"]\nseverity = [\"Warning\"]").select(&targets)),
+		   Vec::<String>::new());
+	assert_eq!(descs(&route("notifier = \"n\"").select(&targets)).len(), 3);
+    }
+
+    #[test]
+    fn routes_throttle_repeats_per_state() {
+	let mut targets = targets("[[target]]\ndesc = \"down db\"\naddr = \"db:5432\"\nkind = \"Tcp\"\n");
+	let mut route = route("notifier = \"n\"\nstate = \"Any\"\nthrottle_secs = 900");
+	assert_eq!(descs(&route.select(&targets)), ["down db"]);
+	assert!(route.select(&targets).is_empty());
+	targets[0].failed = false;
+	assert_eq!(descs(&route.select(&targets)), ["down db"]);
+    }
+
+    #[test]
+    fn fallback_route_throttles_repeated_failures() {
+	let mut targets = targets("[[target]]\ndesc = \"down db\"\naddr = \"db:5432\"\nkind = \"Tcp\"\n\
+				   [[target]]\ndesc = \"up api\"\naddr = \"api:443\"\nkind = \"Tcp\"\n");
+	let mut route = Route::fallback();
+	assert_eq!(descs(&route.select(&targets)), ["down db"]);
+	assert!(route.select(&targets).is_empty());
+	// A new failure is not held back by the earlier one
+	targets[1].failed = true;
+	assert_eq!(descs(&route.select(&targets)), ["up api"]);
+    }
+
+    #[test]
+    fn escalations_outlast_reminder_throttling() {
+	let targets = targets("[[target]]\ndesc = \"down db\"\naddr = \"db:5432\"\nkind = \"Tcp\"\n\
+			       [[target]]\ndesc = \"up api\"\naddr = \"api:443\"\nkind = \"Tcp\"\n");
+	let mut route = route("notifier = \"slack\"\nremind_secs = 900\n\
+			       escalate_to = \"pager\"\nescalate_after_secs = 1800\n");
+	assert_eq!(descs(&route.select(&targets)), ["down db"]);
+	assert!(route.escalations(&targets).is_empty());
+
+	// Still down half an hour later, within the reminder interval
+	route.down_since.insert("down db".to_string(), Instant::now() - Duration::from_secs(1800));
+	assert!(route.select(&targets).is_empty());
+	assert_eq!(descs(&route.escalations(&targets)), ["down db"]);
+	// Escalations are throttled in their own right
+	assert!(route.escalations(&targets).is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Structured check results for library users who render or act on the
+//! outcome of a run themselves instead of reading printed output.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+/// How a single check ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The target was reachable and every assertion held
+    Passed,
+    /// The target was unreachable or an assertion failed
+    Failed,
+    /// The check did not run, for the [`SkipReason`] given with it
+    Skipped,
+    /// The check ran into an internal error, so the state of the target is
+    /// not known
+    Unknown,
+}
+
+impl Outcome {
+    /// Returns the lowercase name of the outcome
+    pub fn as_str(&self) -> &'static str {
+	match self {
+	    Outcome::Passed => "passed",
+	    Outcome::Failed => "failed",
+	    Outcome::Skipped => "skipped",
+	    Outcome::Unknown => "unknown",
+	}
+    }
+}
+
+/// Why a check did not run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// A target it depends on failed
+    DependencyFailed,
+    /// The target is configured with `enabled = false`
+    Disabled,
+    /// Checks of its kind need privileges connchk does not have
+    InsufficientPrivileges,
+    /// The target is configured as under `maintenance`
+    Maintenance,
+    /// The run's deadline passed before the check started
+    Deadline,
+}
+
+impl SkipReason {
+    /// Returns the reason as used in reports and error codes
+    pub fn as_str(&self) -> &'static str {
+	match self {
+	    SkipReason::DependencyFailed => "dependency_failed",
+	    SkipReason::Disabled => "disabled",
+	    SkipReason::InsufficientPrivileges => "insufficient_privileges",
+	    SkipReason::Maintenance => "maintenance",
+	    SkipReason::Deadline => "deadline",
+	}
+    }
+}
+
+/// How much of each result is printed, from `-q` to `-vv`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Verbosity {
+    /// Only checks that did not pass, and the summary
+    Quiet,
+    #[default]
+    Normal,
+    /// Also resolved and connected addresses, HTTP versions, and redirects
+    Verbose,
+    /// Also response headers, and the TLS version and handshake timings of
+    /// HTTPS targets, measured over an extra connection
+    VeryVerbose,
+}
+
+/// Diagnostics noted while checking a target, each shown from a
+/// [`Verbosity`], the addresses it resolved to, the one it connected to, and
+/// how long resolving its host took. Clones share the same lines, so the
+/// redirect policy of an HTTP client can note the redirects it follows.
+/// Lines above the level of the run are never formatted.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    lines: Arc<Mutex<Vec<(Verbosity, String)>>>,
+    addresses: Arc<Mutex<Vec<IpAddr>>>,
+    peer: Arc<Mutex<Option<IpAddr>>>,
+    resolve_time: Arc<Mutex<Option<Duration>>>,
+    level: Verbosity,
+}
+
+impl Trace {
+    /// Notes the line returned by `line`, shown from `level`, when the run
+    /// goes that far
+    pub fn note<F: FnOnce() -> String>(&self, level: Verbosity, line: F) {
+	if level <= self.level {
+	    self.lines.lock().unwrap_or_else(|e| e.into_inner()).push((level, line()));
+	}
+    }
+
+    /// Notes addresses the target resolved to or was reached at
+    pub fn resolved<I: IntoIterator<Item = IpAddr>>(&self, ips: I) {
+	let mut addresses = self.addresses.lock().unwrap_or_else(|e| e.into_inner());
+	for ip in ips {
+	    if !addresses.contains(&ip) {
+		addresses.push(ip);
+	    }
+	}
+    }
+
+    /// Returns the addresses noted so far
+    pub fn addresses(&self) -> Vec<IpAddr> {
+	self.addresses.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Notes the address the target was reached at
+    pub fn connected(&self, ip: IpAddr) {
+	self.resolved([ip]);
+	*self.peer.lock().unwrap_or_else(|e| e.into_inner()) = Some(ip);
+    }
+
+    /// Returns the address the target was reached at, or else the only
+    /// address it resolved to
+    pub fn peer(&self) -> Option<IpAddr> {
+	let peer = *self.peer.lock().unwrap_or_else(|e| e.into_inner());
+	peer.or_else(|| match self.addresses().as_slice() {
+	    [ip] => Some(*ip),
+	    _ => None,
+	})
+    }
+
+    /// Notes how long resolving the target's host name took
+    pub fn resolved_in(&self, elapsed: Duration) {
+	*self.resolve_time.lock().unwrap_or_else(|e| e.into_inner()) = Some(elapsed);
+    }
+
+    /// Returns how long resolving the target's host name took, when it was
+    /// resolved by connchk
+    pub fn resolve_time(&self) -> Option<Duration> {
+	*self.resolve_time.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the verbosity of the run
+    pub fn level(&self) -> Verbosity {
+	self.level
+    }
+
+    /// Forgets the lines of an earlier run, noting those up to `level` from
+    /// now on
+    pub fn reset(&mut self, level: Verbosity) {
+	self.level = level;
+	self.clear();
+    }
+
+    /// Forgets the lines, addresses, and timing of an earlier attempt
+    pub fn clear(&self) {
+	self.lines.lock().unwrap_or_else(|e| e.into_inner()).clear();
+	self.addresses.lock().unwrap_or_else(|e| e.into_inner()).clear();
+	*self.peer.lock().unwrap_or_else(|e| e.into_inner()) = None;
+	*self.resolve_time.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Returns the lines noted so far
+    pub fn lines(&self) -> Vec<(Verbosity, String)> {
+	self.lines.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// The result of checking a single [`Resource`](crate::Resource)
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    /// Description of the target
+    pub desc: String,
+    /// Address of the target, with any host alias replaced
+    pub addr: String,
+    /// The host alias the address was given with
+    pub alias: Option<String>,
+    pub outcome: Outcome,
+    /// Why the check did not run, for skipped checks
+    pub skip_reason: Option<SkipReason>,
+    /// Duration of the final attempt, when it succeeded
+    pub latency: Option<Duration>,
+    /// Latency after smoothing, in milliseconds, when it succeeded
+    pub smoothed_ms: Option<f64>,
+    /// Number of attempts made, including retries
+    pub attempts: u32,
+    /// Additional details reported by the check, such as DNS percentiles
+    pub details: Option<String>,
+    /// Description of the error for failed and skipped checks
+    pub error: Option<String>,
+    /// Machine-readable code for the error
+    pub error_code: Option<String>,
+    /// Key/value pairs attached by the target's annotation hook
+    pub annotations: BTreeMap<String, String>,
+    /// Diagnostics of the final attempt, such as the addresses resolved and
+    /// the redirects followed, with the verbosity showing each
+    pub trace: Vec<(Verbosity, String)>,
+    /// Addresses the final attempt resolved the target's host to, or
+    /// reached it at
+    pub addresses: Vec<IpAddr>,
+    /// Address the final attempt connected to, the first proxy's when
+    /// proxied, or else the only address it resolved to. None when every
+    /// address was checked.
+    pub peer: Option<IpAddr>,
+    /// Time the final attempt took to resolve the target's host name
+    pub resolve_time: Option<Duration>,
+}
+
+/// Progress of a run, passed to the callback of
+/// [`NetworkResources::run_with`](crate::NetworkResources::run_with) as each
+/// check starts and finishes
+// Finished is by far the larger variant, but events are passed by reference
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum CheckEvent {
+    /// A check began its first attempt. Checks that are skipped finish
+    /// without starting.
+    Started {
+	desc: String,
+	addr: String,
+    },
+    /// A check finished, with its result
+    Finished(CheckResult),
+}
+
+/// The results of a whole run, as returned by
+/// [`check_file`](crate::check_file)
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    /// The result of each target
+    pub results: Vec<CheckResult>,
+    /// Time taken by the run
+    pub elapsed: Duration,
+}
+
+impl RunSummary {
+    /// Returns the number of results with `outcome`
+    pub fn count(&self, outcome: Outcome) -> usize {
+	self.results.iter().filter(|r| r.outcome == outcome).count()
+    }
+
+    /// Returns true when no check failed. Skipped checks do not count as
+    /// failures.
+    pub fn is_success(&self) -> bool {
+	self.count(Outcome::Failed) == 0
+    }
+
+    /// Returns the results of the checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+	self.results.iter().filter(|r| r.outcome == Outcome::Failed)
+    }
+}
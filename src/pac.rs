@@ -0,0 +1,1197 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Proxy auto-config (PAC) support. A PAC file is a JavaScript function,
+//! `FindProxyForURL(url, host)`, returning the proxies to use for a URL.
+//! This module evaluates the subset of JavaScript such files are written
+//! in: functions, `var`, `if`/`else`, `for` and `while` loops, string,
+//! number, boolean, and array values, the usual operators, common string
+//! methods, and the standard PAC helpers except the date and time ones.
+//!
+//! Scripts come from a URL, a file, or the operating system's settings,
+//! and are cached for a few minutes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Client;
+
+/// How long a fetched script is used before it is fetched again
+const CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Bounds on evaluation, so a broken script cannot hang or crash a run
+const MAX_STEPS: usize = 1_000_000;
+const MAX_DEPTH: usize = 64;
+
+/// A script that could not be loaded, parsed, or evaluated
+#[derive(Debug)]
+pub struct PacError(String);
+
+impl fmt::Display for PacError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "PAC: {}", self.0)
+    }
+}
+
+impl std::error::Error for PacError {}
+
+fn err<T>(msg: impl Into<String>) -> Result<T, PacError> {
+    Err(PacError(msg.into()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Punct(&'static str),
+}
+
+/// Punctuation, longest first so that `===` is not read as `==` and `=`
+const PUNCTUATION: [&str; 34] = [
+    "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "++", "--", "+=", "-=",
+    "(", ")", "{", "}", "[", "]", ";", ",", ".", "+", "-", "*", "/", "%", "!",
+    "=", "<", ">", "?", ":", "&", "|",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, PacError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+	if c.is_whitespace() {
+	    rest = &rest[c.len_utf8()..];
+	} else if let Some(comment) = rest.strip_prefix("//") {
+	    rest = comment.find('\n').map_or("", |end| &comment[end..]);
+	} else if let Some(comment) = rest.strip_prefix("/*") {
+	    let end = comment.find("*/").ok_or_else(|| PacError("unterminated comment".to_string()))?;
+	    rest = &comment[end + 2..];
+	} else if c == '"' || c == '\'' {
+	    let mut text = String::new();
+	    let mut chars = rest[1..].char_indices();
+	    let end = loop {
+		match chars.next() {
+		    Some((i, q)) if q == c => break i + 2,
+		    Some((_, '\\')) => match chars.next() {
+			Some((_, 'n')) => text.push('\n'),
+			Some((_, 't')) => text.push('\t'),
+			Some((_, other)) => text.push(other),
+			None => return err("unterminated string"),
+		    },
+		    Some((_, other)) => text.push(other),
+		    None => return err("unterminated string"),
+		}
+	    };
+	    tokens.push(Token::Str(text));
+	    rest = &rest[end..];
+	} else if c.is_ascii_digit() {
+	    let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '.')).unwrap_or(rest.len());
+	    let number = rest[..end].parse::<f64>()
+		.map_err(|_| PacError(format!("invalid number `{}`", &rest[..end])))?;
+	    tokens.push(Token::Num(number));
+	    rest = &rest[end..];
+	} else if c.is_alphabetic() || c == '_' || c == '$' {
+	    let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$')).unwrap_or(rest.len());
+	    tokens.push(Token::Ident(rest[..end].to_string()));
+	    rest = &rest[end..];
+	} else {
+	    let punct = PUNCTUATION.iter().find(|p| rest.starts_with(*p))
+		.ok_or_else(|| PacError(format!("unexpected `{}`", c)))?;
+	    tokens.push(Token::Punct(punct));
+	    rest = &rest[punct.len()..];
+	}
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Value(Value),
+    Array(Vec<Expr>),
+    Name(String),
+    Assign(String, Option<&'static str>, Box<Expr>),
+    Step(String, f64, bool),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Member(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Function(Arc<Function>),
+    Var(Vec<(String, Option<Expr>)>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    While(Expr, Box<Stmt>),
+    Return(Option<Expr>),
+    Break,
+    Continue,
+    Block(Vec<Stmt>),
+    Expr(Expr),
+}
+
+#[derive(Debug)]
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+/// A parsed PAC file
+#[derive(Debug)]
+pub struct Program(Vec<Stmt>);
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+	self.tokens.get(self.pos)
+    }
+
+    fn at(&self, punct: &str) -> bool {
+	matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn at_word(&self, word: &str) -> bool {
+	matches!(self.peek(), Some(Token::Ident(w)) if w == word)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+	let found = self.at(punct);
+	if found {
+	    self.pos += 1;
+	}
+	found
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), PacError> {
+	if self.eat(punct) {
+	    Ok(())
+	} else {
+	    err(format!("expected `{}`, found {}", punct, self.describe()))
+	}
+    }
+
+    fn describe(&self) -> String {
+	match self.peek() {
+	    Some(Token::Ident(w)) => format!("`{}`", w),
+	    Some(Token::Str(s)) => format!("\"{}\"", s),
+	    Some(Token::Num(n)) => n.to_string(),
+	    Some(Token::Punct(p)) => format!("`{}`", p),
+	    None => "the end of the script".to_string(),
+	}
+    }
+
+    fn ident(&mut self) -> Result<String, PacError> {
+	match self.tokens.get(self.pos).cloned() {
+	    Some(Token::Ident(name)) => {
+		self.pos += 1;
+		Ok(name)
+	    },
+	    _ => err(format!("expected a name, found {}", self.describe())),
+	}
+    }
+
+    fn statement(&mut self) -> Result<Stmt, PacError> {
+	if self.eat("{") {
+	    let mut body = Vec::new();
+	    while !self.eat("}") {
+		if self.peek().is_none() {
+		    return err("unclosed block")
+		}
+		body.push(self.statement()?);
+	    }
+	    return Ok(Stmt::Block(body))
+	}
+	if self.eat(";") {
+	    return Ok(Stmt::Block(Vec::new()))
+	}
+	let word = match self.peek() {
+	    Some(Token::Ident(word)) => word.clone(),
+	    _ => String::new(),
+	};
+	let stmt = match word.as_str() {
+	    "function" => {
+		self.pos += 1;
+		let name = self.ident()?;
+		self.expect("(")?;
+		let mut params = Vec::new();
+		while !self.eat(")") {
+		    params.push(self.ident()?);
+		    if !self.at(")") {
+			self.expect(",")?;
+		    }
+		}
+		let body = match self.statement()? {
+		    Stmt::Block(body) => body,
+		    _ => return err(format!("expected the body of function {}", name)),
+		};
+		return Ok(Stmt::Function(Arc::new(Function { name, params, body })))
+	    },
+	    "var" | "let" | "const" => self.declaration()?,
+	    "if" => {
+		self.pos += 1;
+		self.expect("(")?;
+		let cond = self.expression()?;
+		self.expect(")")?;
+		let then = Box::new(self.statement()?);
+		let otherwise = if self.at_word("else") {
+		    self.pos += 1;
+		    Some(Box::new(self.statement()?))
+		} else {
+		    None
+		};
+		return Ok(Stmt::If(cond, then, otherwise))
+	    },
+	    "for" => {
+		self.pos += 1;
+		self.expect("(")?;
+		let init = match self.peek() {
+		    Some(Token::Ident(word)) if matches!(word.as_str(), "var" | "let" | "const") => {
+			Some(Box::new(self.declaration()?))
+		    },
+		    _ if self.at(";") => None,
+		    _ => Some(Box::new(Stmt::Expr(self.expression()?))),
+		};
+		self.expect(";")?;
+		let cond = if self.at(";") { None } else { Some(self.expression()?) };
+		self.expect(";")?;
+		let step = if self.at(")") { None } else { Some(self.expression()?) };
+		self.expect(")")?;
+		return Ok(Stmt::For(init, cond, step, Box::new(self.statement()?)))
+	    },
+	    "while" => {
+		self.pos += 1;
+		self.expect("(")?;
+		let cond = self.expression()?;
+		self.expect(")")?;
+		return Ok(Stmt::While(cond, Box::new(self.statement()?)))
+	    },
+	    "return" => {
+		self.pos += 1;
+		if self.at(";") || self.at("}") {
+		    Stmt::Return(None)
+		} else {
+		    Stmt::Return(Some(self.expression()?))
+		}
+	    },
+	    "break" => {
+		self.pos += 1;
+		Stmt::Break
+	    },
+	    "continue" => {
+		self.pos += 1;
+		Stmt::Continue
+	    },
+	    _ => Stmt::Expr(self.expression()?),
+	};
+	// Semicolons are optional, as they mostly are in JavaScript
+	self.eat(";");
+	Ok(stmt)
+    }
+
+    /// Parses `var a = 1, b` without the trailing semicolon
+    fn declaration(&mut self) -> Result<Stmt, PacError> {
+	self.pos += 1;
+	let mut names = Vec::new();
+	loop {
+	    let name = self.ident()?;
+	    let init = if self.eat("=") { Some(self.expression()?) } else { None };
+	    names.push((name, init));
+	    if !self.eat(",") {
+		break
+	    }
+	}
+	Ok(Stmt::Var(names))
+    }
+
+    fn expression(&mut self) -> Result<Expr, PacError> {
+	let target = self.conditional()?;
+	for op in ["=", "+=", "-="] {
+	    if self.eat(op) {
+		let name = match target {
+		    Expr::Name(name) => name,
+		    _ => return err(format!("can only assign to a variable with `{}`", op)),
+		};
+		let compound = match op {
+		    "+=" => Some("+"),
+		    "-=" => Some("-"),
+		    _ => None,
+		};
+		return Ok(Expr::Assign(name, compound, Box::new(self.expression()?)))
+	    }
+	}
+	Ok(target)
+    }
+
+    fn conditional(&mut self) -> Result<Expr, PacError> {
+	let cond = self.binary(0)?;
+	if !self.eat("?") {
+	    return Ok(cond)
+	}
+	let then = self.expression()?;
+	self.expect(":")?;
+	let otherwise = self.expression()?;
+	Ok(Expr::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)))
+    }
+
+    /// Parses binary operators by precedence, loosest first
+    fn binary(&mut self, level: usize) -> Result<Expr, PacError> {
+	const LEVELS: [&[&str]; 7] = [
+	    &["||"], &["&&"], &["|"], &["&"], &["===", "!==", "==", "!="],
+	    &["<=", ">=", "<", ">"], &["+", "-"],
+	];
+	if level == LEVELS.len() {
+	    return self.multiplicative()
+	}
+	let mut left = self.binary(level + 1)?;
+	while let Some(op) = LEVELS[level].iter().find(|op| self.at(op)) {
+	    self.pos += 1;
+	    let right = self.binary(level + 1)?;
+	    left = Expr::Binary(op, Box::new(left), Box::new(right));
+	}
+	Ok(left)
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, PacError> {
+	let mut left = self.unary()?;
+	while let Some(op) = ["*", "/", "%"].iter().find(|op| self.at(op)) {
+	    self.pos += 1;
+	    left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+	}
+	Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, PacError> {
+	for op in ["!", "-", "+"] {
+	    if self.eat(op) {
+		return Ok(Expr::Unary(op, Box::new(self.unary()?)))
+	    }
+	}
+	for (op, delta) in [("++", 1.0), ("--", -1.0)] {
+	    if self.eat(op) {
+		return Ok(Expr::Step(self.ident()?, delta, true))
+	    }
+	}
+	if self.at_word("typeof") {
+	    self.pos += 1;
+	    return Ok(Expr::Unary("typeof", Box::new(self.unary()?)))
+	}
+	self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr, PacError> {
+	let mut expr = self.primary()?;
+	loop {
+	    if self.eat("(") {
+		let mut args = Vec::new();
+		while !self.eat(")") {
+		    args.push(self.expression()?);
+		    if !self.at(")") {
+			self.expect(",")?;
+		    }
+		}
+		expr = Expr::Call(Box::new(expr), args);
+	    } else if self.eat(".") {
+		expr = Expr::Member(Box::new(expr), self.ident()?);
+	    } else if self.eat("[") {
+		let index = self.expression()?;
+		self.expect("]")?;
+		expr = Expr::Index(Box::new(expr), Box::new(index));
+	    } else if let (Expr::Name(name), Some(delta)) = (&expr, self.step()) {
+		expr = Expr::Step(name.clone(), delta, false);
+	    } else {
+		return Ok(expr)
+	    }
+	}
+    }
+
+    /// Consumes a postfix `++` or `--`
+    fn step(&mut self) -> Option<f64> {
+	if self.eat("++") {
+	    Some(1.0)
+	} else if self.eat("--") {
+	    Some(-1.0)
+	} else {
+	    None
+	}
+    }
+
+    fn primary(&mut self) -> Result<Expr, PacError> {
+	let token = self.tokens.get(self.pos).cloned();
+	self.pos += 1;
+	match token {
+	    Some(Token::Str(s)) => Ok(Expr::Value(Value::Str(s))),
+	    Some(Token::Num(n)) => Ok(Expr::Value(Value::Num(n))),
+	    Some(Token::Ident(word)) => Ok(match word.as_str() {
+		"true" => Expr::Value(Value::Bool(true)),
+		"false" => Expr::Value(Value::Bool(false)),
+		"null" | "undefined" => Expr::Value(Value::Undefined),
+		_ => Expr::Name(word),
+	    }),
+	    Some(Token::Punct("(")) => {
+		let expr = self.expression()?;
+		self.expect(")")?;
+		Ok(expr)
+	    },
+	    Some(Token::Punct("[")) => {
+		let mut items = Vec::new();
+		while !self.eat("]") {
+		    items.push(self.expression()?);
+		    if !self.at("]") {
+			self.expect(",")?;
+		    }
+		}
+		Ok(Expr::Array(items))
+	    },
+	    _ => {
+		self.pos -= 1;
+		err(format!("unexpected {}", self.describe()))
+	    },
+	}
+    }
+}
+
+/// Parses a PAC file
+pub fn parse(source: &str) -> Result<Program, PacError> {
+    let mut parser = Parser { tokens: tokenize(source)?, pos: 0 };
+    let mut program = Vec::new();
+    while parser.peek().is_some() {
+	program.push(parser.statement()?);
+    }
+    Ok(Program(program))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Undefined,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+	match self {
+	    Value::Undefined => false,
+	    Value::Bool(b) => *b,
+	    Value::Num(n) => *n != 0.0 && !n.is_nan(),
+	    Value::Str(s) => !s.is_empty(),
+	    Value::Array(_) => true,
+	}
+    }
+
+    fn num(&self) -> f64 {
+	match self {
+	    Value::Undefined => f64::NAN,
+	    Value::Bool(b) => f64::from(u8::from(*b)),
+	    Value::Num(n) => *n,
+	    Value::Str(s) if s.trim().is_empty() => 0.0,
+	    Value::Str(s) => s.trim().parse().unwrap_or(f64::NAN),
+	    Value::Array(_) => f64::NAN,
+	}
+    }
+
+    fn text(&self) -> String {
+	match self {
+	    Value::Undefined => "undefined".to_string(),
+	    Value::Bool(b) => b.to_string(),
+	    Value::Num(n) if n.fract() == 0.0 && n.abs() < 1e15 => format!("{}", *n as i64),
+	    Value::Num(n) => n.to_string(),
+	    Value::Str(s) => s.clone(),
+	    Value::Array(items) => items.iter().map(Value::text).collect::<Vec<_>>().join(","),
+	}
+    }
+
+    /// Loose equality, `==`
+    fn loosely_equals(&self, other: &Value) -> bool {
+	match (self, other) {
+	    (Value::Str(a), Value::Str(b)) => a == b,
+	    (Value::Undefined, Value::Undefined) => true,
+	    (Value::Undefined, _) | (_, Value::Undefined) => false,
+	    (Value::Array(_), _) | (_, Value::Array(_)) => false,
+	    (a, b) => a.num() == b.num(),
+	}
+    }
+}
+
+/// Outcome of a statement
+enum Flow {
+    Normal,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+struct Interpreter<'a> {
+    functions: HashMap<&'a str, &'a Function>,
+    globals: HashMap<String, Value>,
+    scopes: Vec<HashMap<String, Value>>,
+    steps: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    fn tick(&mut self) -> Result<(), PacError> {
+	self.steps += 1;
+	if self.steps > MAX_STEPS {
+	    return err("script ran for too long")
+	}
+	Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Value, PacError> {
+	self.scopes.last().and_then(|scope| scope.get(name))
+	    .or_else(|| self.globals.get(name))
+	    .cloned()
+	    .ok_or_else(|| PacError(format!("`{}` is not defined", name)))
+    }
+
+    fn declare(&mut self, name: &str, value: Value) {
+	match self.scopes.last_mut() {
+	    Some(scope) => scope.insert(name.to_string(), value),
+	    None => self.globals.insert(name.to_string(), value),
+	};
+    }
+
+    fn assign(&mut self, name: &str, value: Value) {
+	match self.scopes.last_mut() {
+	    Some(scope) if scope.contains_key(name) => scope.insert(name.to_string(), value),
+	    _ => self.globals.insert(name.to_string(), value),
+	};
+    }
+
+    fn block(&mut self, body: &'a [Stmt]) -> Result<Flow, PacError> {
+	for stmt in body {
+	    match self.statement(stmt)? {
+		Flow::Normal => (),
+		flow => return Ok(flow),
+	    }
+	}
+	Ok(Flow::Normal)
+    }
+
+    fn statement(&mut self, stmt: &'a Stmt) -> Result<Flow, PacError> {
+	self.tick()?;
+	match stmt {
+	    Stmt::Function(function) => {
+		self.functions.insert(&function.name, function);
+	    },
+	    Stmt::Var(names) => {
+		for (name, init) in names {
+		    let value = match init {
+			Some(init) => self.eval(init)?,
+			None => Value::Undefined,
+		    };
+		    self.declare(name, value);
+		}
+	    },
+	    Stmt::If(cond, then, otherwise) => {
+		if self.eval(cond)?.truthy() {
+		    return self.statement(then)
+		} else if let Some(otherwise) = otherwise {
+		    return self.statement(otherwise)
+		}
+	    },
+	    Stmt::For(init, cond, step, body) => {
+		if let Some(init) = init {
+		    self.statement(init)?;
+		}
+		loop {
+		    if let Some(cond) = cond {
+			if !self.eval(cond)?.truthy() {
+			    break
+			}
+		    }
+		    match self.statement(body)? {
+			Flow::Return(value) => return Ok(Flow::Return(value)),
+			Flow::Break => break,
+			Flow::Normal | Flow::Continue => (),
+		    }
+		    if let Some(step) = step {
+			self.eval(step)?;
+		    }
+		}
+	    },
+	    Stmt::While(cond, body) => {
+		while self.eval(cond)?.truthy() {
+		    match self.statement(body)? {
+			Flow::Return(value) => return Ok(Flow::Return(value)),
+			Flow::Break => break,
+			Flow::Normal | Flow::Continue => (),
+		    }
+		}
+	    },
+	    Stmt::Return(value) => {
+		let value = match value {
+		    Some(value) => self.eval(value)?,
+		    None => Value::Undefined,
+		};
+		return Ok(Flow::Return(value))
+	    },
+	    Stmt::Break => return Ok(Flow::Break),
+	    Stmt::Continue => return Ok(Flow::Continue),
+	    Stmt::Block(body) => return self.block(body),
+	    Stmt::Expr(expr) => {
+		self.eval(expr)?;
+	    },
+	}
+	Ok(Flow::Normal)
+    }
+
+    fn eval(&mut self, expr: &'a Expr) -> Result<Value, PacError> {
+	self.tick()?;
+	Ok(match expr {
+	    Expr::Value(value) => value.clone(),
+	    Expr::Array(items) => Value::Array(items.iter().map(|i| self.eval(i)).collect::<Result<_, _>>()?),
+	    Expr::Name(name) => self.lookup(name)?,
+	    Expr::Assign(name, compound, value) => {
+		let mut value = self.eval(value)?;
+		if let Some(op) = compound {
+		    value = binary(op, self.lookup(name)?, value);
+		}
+		self.assign(name, value.clone());
+		value
+	    },
+	    Expr::Step(name, delta, prefix) => {
+		let old = self.lookup(name)?.num();
+		self.assign(name, Value::Num(old + delta));
+		Value::Num(if *prefix { old + delta } else { old })
+	    },
+	    Expr::Unary(op, operand) => {
+		let value = self.eval(operand)?;
+		match *op {
+		    "!" => Value::Bool(!value.truthy()),
+		    "-" => Value::Num(-value.num()),
+		    "typeof" => Value::Str(match value {
+			Value::Undefined => "undefined",
+			Value::Bool(_) => "boolean",
+			Value::Num(_) => "number",
+			Value::Str(_) => "string",
+			Value::Array(_) => "object",
+		    }.to_string()),
+		    _ => Value::Num(value.num()),
+		}
+	    },
+	    Expr::Binary("&&", left, right) => {
+		let left = self.eval(left)?;
+		if left.truthy() { self.eval(right)? } else { left }
+	    },
+	    Expr::Binary("||", left, right) => {
+		let left = self.eval(left)?;
+		if left.truthy() { left } else { self.eval(right)? }
+	    },
+	    Expr::Binary(op, left, right) => {
+		let left = self.eval(left)?;
+		let right = self.eval(right)?;
+		binary(op, left, right)
+	    },
+	    Expr::Cond(cond, then, otherwise) => {
+		if self.eval(cond)?.truthy() { self.eval(then)? } else { self.eval(otherwise)? }
+	    },
+	    Expr::Member(object, property) => match (self.eval(object)?, property.as_str()) {
+		(Value::Str(s), "length") => Value::Num(s.chars().count() as f64),
+		(Value::Array(items), "length") => Value::Num(items.len() as f64),
+		(_, property) => return err(format!("unsupported property `{}`", property)),
+	    },
+	    Expr::Index(object, index) => {
+		let object = self.eval(object)?;
+		let index = self.eval(index)?.num();
+		let index = if index >= 0.0 && index.fract() == 0.0 { index as usize } else { usize::MAX };
+		match object {
+		    Value::Array(items) => items.get(index).cloned().unwrap_or(Value::Undefined),
+		    Value::Str(s) => s.chars().nth(index).map_or(Value::Undefined, |c| Value::Str(c.to_string())),
+		    _ => Value::Undefined,
+		}
+	    },
+	    Expr::Call(callee, args) => {
+		let args = args.iter().map(|a| self.eval(a)).collect::<Result<Vec<_>, _>>()?;
+		match &**callee {
+		    Expr::Member(object, method) => {
+			let object = self.eval(object)?;
+			method_call(object, method, &args)?
+		    },
+		    Expr::Name(name) => self.call(name, args)?,
+		    _ => return err("only named functions and methods can be called"),
+		}
+	    },
+	})
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, PacError> {
+	let function = match self.functions.get(name) {
+	    Some(function) => *function,
+	    None => return builtin(name, &args),
+	};
+	if self.scopes.len() >= MAX_DEPTH {
+	    return err("functions nested too deeply")
+	}
+	let mut scope = HashMap::new();
+	for (i, param) in function.params.iter().enumerate() {
+	    scope.insert(param.clone(), args.get(i).cloned().unwrap_or(Value::Undefined));
+	}
+	self.scopes.push(scope);
+	let flow = self.block(&function.body);
+	self.scopes.pop();
+	match flow? {
+	    Flow::Return(value) => Ok(value),
+	    _ => Ok(Value::Undefined),
+	}
+    }
+}
+
+fn binary(op: &str, left: Value, right: Value) -> Value {
+    match op {
+	"+" => match (&left, &right) {
+	    (Value::Str(_), _) | (_, Value::Str(_)) | (Value::Array(_), _) | (_, Value::Array(_)) => {
+		Value::Str(left.text() + &right.text())
+	    },
+	    _ => Value::Num(left.num() + right.num()),
+	},
+	"-" => Value::Num(left.num() - right.num()),
+	"*" => Value::Num(left.num() * right.num()),
+	"/" => Value::Num(left.num() / right.num()),
+	"%" => Value::Num(left.num() % right.num()),
+	"&" => Value::Num(f64::from(left.num() as i64 as i32 & right.num() as i64 as i32)),
+	"|" => Value::Num(f64::from(left.num() as i64 as i32 | right.num() as i64 as i32)),
+	"==" => Value::Bool(left.loosely_equals(&right)),
+	"!=" => Value::Bool(!left.loosely_equals(&right)),
+	"===" => Value::Bool(left == right),
+	"!==" => Value::Bool(left != right),
+	_ => {
+	    let ordering = match (&left, &right) {
+		(Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
+		_ => left.num().partial_cmp(&right.num()),
+	    };
+	    Value::Bool(match (op, ordering) {
+		(_, None) => false,
+		("<", Some(o)) => o.is_lt(),
+		(">", Some(o)) => o.is_gt(),
+		("<=", Some(o)) => o.is_le(),
+		(_, Some(o)) => o.is_ge(),
+	    })
+	},
+    }
+}
+
+/// Converts a JavaScript string index argument to a character index
+fn char_index(value: Option<&Value>, len: usize, default: usize) -> usize {
+    match value {
+	None | Some(Value::Undefined) => default,
+	Some(value) => {
+	    let n = value.num();
+	    if n.is_nan() || n < 0.0 { 0 } else { (n as usize).min(len) }
+	},
+    }
+}
+
+fn method_call(object: Value, method: &str, args: &[Value]) -> Result<Value, PacError> {
+    let s = match object {
+	Value::Str(s) => s,
+	Value::Array(items) if method == "indexOf" => {
+	    let pos = items.iter().position(|item| Some(item) == args.first());
+	    return Ok(Value::Num(pos.map_or(-1.0, |p| p as f64)))
+	},
+	Value::Array(items) if method == "join" => {
+	    let sep = args.first().map_or(",".to_string(), Value::text);
+	    return Ok(Value::Str(items.iter().map(Value::text).collect::<Vec<_>>().join(&sep)))
+	},
+	_ => return err(format!("unsupported method `{}`", method)),
+    };
+    let chars: Vec<char> = s.chars().collect();
+    let arg = |i: usize| args.get(i).map(Value::text).unwrap_or_default();
+    let position = |found: Option<usize>| Value::Num(found.map_or(-1.0, |byte| s[..byte].chars().count() as f64));
+    Ok(match method {
+	"toLowerCase" => Value::Str(s.to_lowercase()),
+	"toUpperCase" => Value::Str(s.to_uppercase()),
+	"trim" => Value::Str(s.trim().to_string()),
+	"toString" => Value::Str(s),
+	"indexOf" => position(s.find(&arg(0))),
+	"lastIndexOf" => position(s.rfind(&arg(0))),
+	"startsWith" => Value::Bool(s.starts_with(&arg(0))),
+	"endsWith" => Value::Bool(s.ends_with(&arg(0))),
+	"includes" => Value::Bool(s.contains(&arg(0))),
+	"charAt" => Value::Str(chars.get(char_index(args.first(), chars.len(), 0)).map(char::to_string).unwrap_or_default()),
+	"substring" => {
+	    let a = char_index(args.first(), chars.len(), 0);
+	    let b = char_index(args.get(1), chars.len(), chars.len());
+	    Value::Str(chars[a.min(b)..a.max(b)].iter().collect())
+	},
+	"substr" => {
+	    let start = char_index(args.first(), chars.len(), 0);
+	    let len = char_index(args.get(1), chars.len() - start, chars.len() - start);
+	    Value::Str(chars[start..start + len].iter().collect())
+	},
+	"split" => Value::Array(s.split(arg(0).as_str()).map(|part| Value::Str(part.to_string())).collect()),
+	"replace" => Value::Str(s.replacen(&arg(0), &arg(1), 1)),
+	_ => return err(format!("unsupported method `{}`", method)),
+    })
+}
+
+/// Resolves `host` to an IPv4 address, as PAC's `dnsResolve` does
+fn resolve(host: &str) -> Option<Ipv4Addr> {
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+	return Some(ip)
+    }
+    (host, 0).to_socket_addrs().ok()?
+	.find_map(|addr| match addr.ip() {
+	    IpAddr::V4(ip) => Some(ip),
+	    IpAddr::V6(_) => None,
+	})
+}
+
+/// Returns the address this host would use to reach the internet, without
+/// sending anything
+fn my_ip() -> Ipv4Addr {
+    let local = UdpSocket::bind("0.0.0.0:0")
+	.and_then(|socket| socket.connect("198.51.100.1:53").and_then(|_| socket.local_addr()));
+    match local.map(|addr| addr.ip()) {
+	Ok(IpAddr::V4(ip)) if !ip.is_unspecified() => ip,
+	_ => Ipv4Addr::LOCALHOST,
+    }
+}
+
+/// Matches `text` against a shell expression with `*` and `?` wildcards
+pub fn sh_exp_match(text: &str, pattern: &str) -> bool {
+    let (text, pattern): (Vec<char>, Vec<char>) = (text.chars().collect(), pattern.chars().collect());
+    let (mut t, mut p) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+	match pattern.get(p) {
+	    Some('*') => {
+		star = Some((p, t));
+		p += 1;
+	    },
+	    Some(&c) if c == '?' || c == text[t] => {
+		t += 1;
+		p += 1;
+	    },
+	    _ => match star {
+		Some((sp, st)) => {
+		    p = sp + 1;
+		    t = st + 1;
+		    star = Some((sp, st + 1));
+		},
+		None => return false,
+	    },
+	}
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn builtin(name: &str, args: &[Value]) -> Result<Value, PacError> {
+    let arg = |i: usize| args.get(i).map(Value::text).unwrap_or_default();
+    Ok(match name {
+	"isPlainHostName" => Value::Bool(!arg(0).contains('.')),
+	"dnsDomainIs" => Value::Bool(arg(0).to_lowercase().ends_with(&arg(1).to_lowercase())),
+	"localHostOrDomainIs" => {
+	    let (host, full) = (arg(0).to_lowercase(), arg(1).to_lowercase());
+	    Value::Bool(host == full || (!host.contains('.') && full.split('.').next() == Some(host.as_str())))
+	},
+	"isResolvable" => Value::Bool(resolve(&arg(0)).is_some()),
+	"dnsResolve" => resolve(&arg(0)).map_or(Value::Undefined, |ip| Value::Str(ip.to_string())),
+	"myIpAddress" => Value::Str(my_ip().to_string()),
+	"dnsDomainLevels" => Value::Num(arg(0).matches('.').count() as f64),
+	"shExpMatch" => Value::Bool(sh_exp_match(&arg(0), &arg(1))),
+	"isInNet" => {
+	    let parse = |s: String| s.parse::<Ipv4Addr>().ok().map(u32::from);
+	    match (resolve(&arg(0)).map(u32::from), parse(arg(1)), parse(arg(2))) {
+		(Some(host), Some(net), Some(mask)) => Value::Bool(host & mask == net & mask),
+		_ => Value::Bool(false),
+	    }
+	},
+	"convert_addr" => arg(0).parse::<Ipv4Addr>().map_or(Value::Num(0.0), |ip| Value::Num(f64::from(u32::from(ip)))),
+	"alert" => Value::Undefined,
+	"parseInt" => Value::Num(arg(0).trim().parse::<i64>().map_or(f64::NAN, |n| n as f64)),
+	"String" => Value::Str(arg(0)),
+	"weekdayRange" | "dateRange" | "timeRange" => return err(format!("`{}` is not supported", name)),
+	_ => return err(format!("`{}` is not defined", name)),
+    })
+}
+
+impl Program {
+    /// Runs `FindProxyForURL(url, host)`, returning its result such as
+    /// `PROXY proxy.example.com:8080; DIRECT`
+    pub fn find_proxy(&self, url: &str, host: &str) -> Result<String, PacError> {
+	let mut interpreter = Interpreter {
+	    functions: HashMap::new(),
+	    globals: HashMap::new(),
+	    scopes: Vec::new(),
+	    steps: 0,
+	};
+	interpreter.block(&self.0)?;
+	let args = vec![Value::Str(url.to_string()), Value::Str(host.to_string())];
+	if !interpreter.functions.contains_key("FindProxyForURL") {
+	    return err("the script does not define FindProxyForURL")
+	}
+	match interpreter.call("FindProxyForURL", args)? {
+	    Value::Str(result) => Ok(result),
+	    other => err(format!("FindProxyForURL returned {} instead of a string", other.text())),
+	}
+    }
+}
+
+/// Translates the first usable entry of a `FindProxyForURL` result into a
+/// proxy chain for [`crate::proxy::parse_chain`]. `DIRECT` gives an empty
+/// chain.
+pub fn chain(result: &str) -> Result<Vec<String>, PacError> {
+    for entry in result.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+	let mut parts = entry.split_whitespace();
+	let kind = parts.next().unwrap_or_default().to_ascii_uppercase();
+	let addr = parts.next();
+	match (kind.as_str(), addr) {
+	    ("DIRECT", _) => return Ok(Vec::new()),
+	    ("PROXY" | "HTTP", Some(addr)) => return Ok(vec![format!("http://{}", addr)]),
+	    ("SOCKS" | "SOCKS5", Some(addr)) => return Ok(vec![format!("socks5://{}", addr)]),
+	    // HTTPS and SOCKS4 proxies are not supported; try the next entry
+	    _ => continue,
+	}
+    }
+    err(format!("no supported proxy in `{}`", result))
+}
+
+/// Scripts fetched so far and when
+type Cache = Mutex<HashMap<String, (Instant, Arc<Program>)>>;
+
+fn cache() -> &'static Cache {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Loads the script at `source`: `system` for the operating system's
+/// auto-config URL, an `http(s)` URL, or a file path. Scripts are cached
+/// for a few minutes. Returns `None` when `system` finds no script.
+pub fn load(source: &str, timeout: Option<Duration>) -> Result<Option<Arc<Program>>, PacError> {
+    let location = match source {
+	"system" => match system_url() {
+	    Some(url) => url,
+	    None => return Ok(None),
+	},
+	other => other.to_string(),
+    };
+
+    if let Some((fetched, program)) = cache().lock().unwrap_or_else(|e| e.into_inner()).get(&location) {
+	if fetched.elapsed() < CACHE_TTL {
+	    return Ok(Some(program.clone()))
+	}
+    }
+
+    let text = if location.starts_with("http://") || location.starts_with("https://") {
+	let mut builder = Client::builder().no_proxy();
+	if let Some(timeout) = timeout {
+	    builder = builder.timeout(timeout);
+	}
+	builder.build()
+	    .and_then(|client| client.get(&location).send())
+	    .and_then(|resp| resp.error_for_status())
+	    .and_then(|resp| resp.text())
+	    .map_err(|e| PacError(format!("fetching {}: {}", location, e)))?
+    } else {
+	let path = location.strip_prefix("file://").unwrap_or(&location);
+	std::fs::read_to_string(path).map_err(|e| PacError(format!("reading {}: {}", path, e)))?
+    };
+    let program = Arc::new(parse(&text).map_err(|e| PacError(format!("{}: {}", location, e.0)))?);
+    cache().lock().unwrap_or_else(|e| e.into_inner()).insert(location, (Instant::now(), program.clone()));
+    Ok(Some(program))
+}
+
+/// Returns the auto-config URL from the WinINet settings
+#[cfg(windows)]
+fn system_url() -> Option<String> {
+    crate::proxy::internet_settings().and_then(|settings| settings.pac_url())
+}
+
+/// Returns the auto-config URL from the system configuration, when enabled
+#[cfg(target_os = "macos")]
+fn system_url() -> Option<String> {
+    let output = std::process::Command::new("scutil").arg("--proxy").output().ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| text.lines()
+	.filter_map(|line| line.split_once(" : "))
+	.find(|(key, _)| key.trim() == name)
+	.map(|(_, value)| value.trim().to_string());
+    match field("ProxyAutoConfigEnable").as_deref() {
+	Some("1") => field("ProxyAutoConfigURLString"),
+	_ => None,
+    }
+}
+
+/// Returns the auto-config URL from the GNOME proxy settings, when in
+/// automatic mode
+#[cfg(not(any(windows, target_os = "macos")))]
+fn system_url() -> Option<String> {
+    let setting = |key: &str| {
+	let output = std::process::Command::new("gsettings")
+	    .args(["get", "org.gnome.system.proxy", key])
+	    .output().ok()?;
+	let value = String::from_utf8_lossy(&output.stdout).trim().trim_matches('\'').to_string();
+	Some(value).filter(|v| !v.is_empty())
+    };
+    match setting("mode").as_deref() {
+	Some("auto") => setting("autoconfig-url"),
+	_ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A script in the style of enterprise PAC files: internal networks and
+    /// domains go direct, some hosts through SOCKS, and the rest through a
+    /// proxy with a fallback
+    const SCRIPT: &str = r#"
+// Corporate routing
+var internal = [".corp.example.com", ".lab.example.com"];
+
+function isInternal(host) {
+    for (var i = 0; i < internal.length; i++) {
+	if (dnsDomainIs(host, internal[i])) {
+	    return true;
+	}
+    }
+    return false;
+}
+
+function FindProxyForURL(url, host) {
+    host = host.toLowerCase();
+    if (isPlainHostName(host) || isInternal(host)) {
+	return "DIRECT";
+    }
+    /* Private ranges never leave the site */
+    if (isInNet(host, "10.0.0.0", "255.0.0.0") || isInNet(host, "192.168.0.0", "255.255.0.0")) {
+	return "DIRECT";
+    }
+    if (shExpMatch(url, "ftp:*") || shExpMatch(host, "*.onion")) {
+	return "SOCKS5 socks.example.com:1080";
+    }
+    if (url.substring(0, 6) === "https:") {
+	return "HTTPS secure.example.com:443; PROXY proxy.example.com:8080; DIRECT";
+    }
+    return host.indexOf("cdn") >= 0 ? "PROXY cdn-proxy.example.com:3128" : "PROXY proxy.example.com:8080; DIRECT";
+}
+"#;
+
+    fn find(url: &str, host: &str) -> String {
+	parse(SCRIPT).unwrap().find_proxy(url, host).unwrap()
+    }
+
+    #[test]
+    fn domains_go_direct() {
+	assert_eq!(find("http://wiki.corp.example.com/", "wiki.corp.example.com"), "DIRECT");
+	assert_eq!(find("http://intranet/", "intranet"), "DIRECT");
+	assert_eq!(find("http://WIKI.LAB.EXAMPLE.COM/", "WIKI.LAB.EXAMPLE.COM"), "DIRECT");
+	assert_ne!(find("http://corp.example.com/", "corp.example.com"), "DIRECT");
+    }
+
+    #[test]
+    fn networks_go_direct() {
+	assert_eq!(find("http://10.1.2.3/", "10.1.2.3"), "DIRECT");
+	assert_eq!(find("http://192.168.40.1/", "192.168.40.1"), "DIRECT");
+	assert_eq!(find("http://192.169.0.1/", "192.169.0.1"), "PROXY proxy.example.com:8080; DIRECT");
+    }
+
+    #[test]
+    fn patterns_select_socks() {
+	assert_eq!(find("ftp://files.example.org/", "files.example.org"), "SOCKS5 socks.example.com:1080");
+	assert_eq!(find("http://abc.onion/", "abc.onion"), "SOCKS5 socks.example.com:1080");
+    }
+
+    #[test]
+    fn fallback_chain_skips_unsupported_entries() {
+	let result = find("https://example.org/", "example.org");
+	assert_eq!(chain(&result).unwrap(), ["http://proxy.example.com:8080"]);
+	assert_eq!(chain("DIRECT").unwrap(), Vec::<String>::new());
+	assert_eq!(chain("SOCKS s:1080").unwrap(), ["socks5://s:1080"]);
+	assert!(chain("HTTPS secure:443; SOCKS4 old:1080").is_err());
+	assert_eq!(find("http://cdn.example.org/", "cdn.example.org"), "PROXY cdn-proxy.example.com:3128");
+    }
+
+    #[test]
+    fn shell_expressions_match() {
+	assert!(sh_exp_match("http://example.com/a/b", "*/a/*"));
+	assert!(sh_exp_match("abc", "a?c"));
+	assert!(sh_exp_match("", "*"));
+	assert!(!sh_exp_match("abc", "a?"));
+	assert!(!sh_exp_match("abd", "*c"));
+    }
+
+    #[test]
+    fn builtins_follow_the_pac_definitions() {
+	let value = |name: &str, args: &[&str]| {
+	    let args: Vec<Value> = args.iter().map(|a| Value::Str(a.to_string())).collect();
+	    builtin(name, &args).unwrap().text()
+	};
+	assert_eq!(value("dnsDomainIs", &["www.example.com", ".example.com"]), "true");
+	assert_eq!(value("dnsDomainIs", &["example.com", ".example.com"]), "false");
+	assert_eq!(value("localHostOrDomainIs", &["www", "www.example.com"]), "true");
+	assert_eq!(value("localHostOrDomainIs", &["www.other.com", "www.example.com"]), "false");
+	assert_eq!(value("dnsDomainLevels", &["www.example.com"]), "2");
+	assert_eq!(value("isInNet", &["172.16.5.4", "172.16.0.0", "255.240.0.0"]), "true");
+	assert_eq!(value("isInNet", &["172.32.0.1", "172.16.0.0", "255.240.0.0"]), "false");
+	assert_eq!(value("isInNet", &["10.0.0.1", "not an address", "255.0.0.0"]), "false");
+	assert_eq!(value("dnsResolve", &["127.0.0.1"]), "127.0.0.1");
+	assert!(builtin("timeRange", &[]).is_err());
+    }
+
+    #[test]
+    fn language_features_evaluate() {
+	let script = r#"
+function FindProxyForURL(url, host) {
+    var parts = host.split("."), n = 0, out = "";
+    while (true) {
+	n++;
+	if (n > 10) break;
+	if (n % 2) continue;
+	out += n;
+    }
+    var kind = typeof parts;
+    return [parts.length, parts[0].charAt(0).toUpperCase(), kind, !false, 7 / 2, "a" + 1, parts.indexOf("b")].join(",") + " " + n + " " + out;
+}
+"#;
+	let program = parse(script).unwrap();
+	assert_eq!(program.find_proxy("http://a.b.c/", "a.b.c").unwrap(), "3,A,object,true,3.5,a1,1 11 246810");
+    }
+
+    #[test]
+    fn broken_scripts_are_reported() {
+	assert!(parse("function FindProxyForURL(url, host) { return \"DIRECT\"").is_err());
+	assert!(parse("var s = 'unterminated;").is_err());
+	assert!(parse("var a = 1 # 2;").is_err());
+	let missing = parse("function other() { return 'DIRECT'; }").unwrap();
+	assert!(missing.find_proxy("http://a/", "a").unwrap_err().to_string().contains("does not define FindProxyForURL"));
+	let number = parse("function FindProxyForURL(url, host) { return 1; }").unwrap();
+	assert!(number.find_proxy("http://a/", "a").unwrap_err().to_string().contains("instead of a string"));
+	let endless = parse("function FindProxyForURL(url, host) { while (true) {} }").unwrap();
+	assert!(endless.find_proxy("http://a/", "a").is_err());
+	let deep = parse("function f(n) { return f(n + 1); } function FindProxyForURL(url, host) { return f(0); }").unwrap();
+	assert!(deep.find_proxy("http://a/", "a").is_err());
+	let undefined = parse("function FindProxyForURL(url, host) { return nope(host); }").unwrap();
+	assert!(undefined.find_proxy("http://a/", "a").unwrap_err().to_string().contains("`nope` is not defined"));
+    }
+
+    #[test]
+    fn scripts_load_from_files() {
+	let path = std::env::temp_dir().join(format!("connchk-pac-{}.js", std::process::id()));
+	std::fs::write(&path, SCRIPT).unwrap();
+	let program = load(path.to_str().unwrap(), None).unwrap().unwrap();
+	assert_eq!(program.find_proxy("http://10.0.0.1/", "10.0.0.1").unwrap(), "DIRECT");
+	std::fs::remove_file(&path).unwrap();
+	assert!(load("/nonexistent/proxy.pac", None).unwrap_err().to_string().contains("reading /nonexistent/proxy.pac"));
+    }
+}
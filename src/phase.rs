@@ -0,0 +1,389 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Per-phase connection timing. Name resolution, the TCP handshake, and,
+//! for HTTPS targets, the TLS handshake are performed step by step so each
+//! can be measured and held to its own budget. TLS state is kept between
+//! runs so that session resumption can be exercised and verified.
+
+use std::io::{self, Read};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use reqwest::Url;
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, ProtocolVersion, RootCertStore, ServerName};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use serde::Deserialize;
+
+use crate::addr;
+use crate::error::{self, Stage};
+
+/// Provides a deserialize target for per-phase latency budgets. Any phase
+/// left unset is not checked.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Budget {
+    /// Maximum time for name resolution
+    pub dns_ms: Option<u64>,
+    /// Maximum time for the TCP handshake
+    pub connect_ms: Option<u64>,
+    /// Maximum time for the TLS handshake
+    pub tls_ms: Option<u64>,
+    /// Maximum time for the complete check
+    pub total_ms: Option<u64>,
+}
+
+impl Budget {
+    /// Returns true when any phase other than the total is budgeted
+    pub fn has_phases(&self) -> bool {
+	self.dns_ms.is_some() || self.connect_ms.is_some() || self.tls_ms.is_some()
+    }
+
+    /// Compares measured timings against the budget, returning a description
+    /// of every phase that went over.
+    pub fn verify(&self, timings: &PhaseTimings, total: Duration) -> Result<(), Box<dyn std::error::Error>> {
+	let phases = [
+	    ("dns_ms", self.dns_ms, Some(timings.dns)),
+	    ("connect_ms", self.connect_ms, Some(timings.connect)),
+	    ("tls_ms", self.tls_ms, timings.tls),
+	    ("total_ms", self.total_ms, Some(total)),
+	];
+
+	let over: Vec<String> = phases.iter()
+	    .filter_map(|(name, limit, took)| match (limit, took) {
+		(Some(limit), Some(took)) if took.as_millis() > *limit as u128 => {
+		    Some(format!("{} {} > {}", name, took.as_millis(), limit))
+		},
+		_ => None,
+	    })
+	    .collect();
+
+	if over.is_empty() {
+	    Ok(())
+	} else {
+	    Err(From::from(format!("\n\tBudget exceeded: {}", over.join(", "))))
+	}
+    }
+}
+
+/// Time spent in each phase of establishing a connection
+#[derive(Debug, Clone, Default)]
+pub struct PhaseTimings {
+    pub dns: Duration,
+    pub connect: Duration,
+    /// Only present for targets that use TLS
+    pub tls: Option<Duration>,
+    /// Whether the TLS handshake resumed an earlier session
+    pub resumed: Option<bool>,
+    /// The TLS version negotiated, e.g. `TLS 1.3`
+    pub tls_version: Option<String>,
+}
+
+/// How a target's TLS session resumption is treated
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsResumption {
+    /// Report whether resumption happened without affecting the result
+    Report,
+    /// Fail the check when a cached session is not resumed
+    Require,
+}
+
+/// Counts full certificate verifications. Resumed handshakes skip
+/// verification, which is how resumption is detected. Without an inner
+/// verifier every certificate is accepted.
+struct CountingVerifier {
+    inner: Option<WebPkiVerifier>,
+    count: Arc<AtomicUsize>,
+}
+
+impl ServerCertVerifier for CountingVerifier {
+    fn verify_server_cert(&self,
+			  end_entity: &Certificate,
+			  intermediates: &[Certificate],
+			  server_name: &ServerName,
+			  scts: &mut dyn Iterator<Item = &[u8]>,
+			  ocsp_response: &[u8],
+			  now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+	self.count.fetch_add(1, Ordering::SeqCst);
+	match &self.inner {
+	    Some(inner) => inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now),
+	    None => Ok(ServerCertVerified::assertion()),
+	}
+    }
+}
+
+/// Certificates a [`TlsSession`] accepts, matching those the target's HTTP
+/// client accepts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trust {
+    /// PEM certificates trusted in addition to the bundled webpki roots
+    pub ca_bundle: Option<PathBuf>,
+    /// Accept any certificate
+    pub insecure: bool,
+}
+
+/// TLS client state trusting the bundled webpki roots and the target's
+/// [`Trust`], matching the trust store used by `reqwest` with `rustls-tls`.
+/// Sessions cached here are offered for resumption on later handshakes.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSession {
+    trust: Trust,
+    /// Built on the first handshake, so an unreadable `ca_bundle` fails the
+    /// check rather than the run
+    config: Arc<OnceLock<Arc<ClientConfig>>>,
+    verifications: Arc<AtomicUsize>,
+    handshakes: Arc<AtomicUsize>,
+}
+
+impl TlsSession {
+    /// Sets the certificates accepted, starting a new session when they
+    /// differ from the current ones, as after reloading the configuration
+    pub fn set_trust(&mut self, trust: Trust) {
+	if self.trust != trust {
+	    *self = TlsSession { trust, ..TlsSession::default() };
+	}
+    }
+
+    /// Returns the client configuration, so other connections share the
+    /// trust roots and session cache
+    pub(crate) fn config(&self) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error>> {
+	if let Some(config) = self.config.get() {
+	    return Ok(config.clone())
+	}
+	let inner = match self.trust.insecure {
+	    true => None,
+	    false => Some(WebPkiVerifier::new(self.roots()?, None)),
+	};
+	let verifier = CountingVerifier { inner, count: self.verifications.clone() };
+	let config = ClientConfig::builder()
+	    .with_safe_defaults()
+	    .with_custom_certificate_verifier(Arc::new(verifier))
+	    .with_no_client_auth();
+	Ok(self.config.get_or_init(|| Arc::new(config)).clone())
+    }
+
+    /// Returns the bundled webpki roots along with those of `ca_bundle`
+    fn roots(&self) -> Result<RootCertStore, Box<dyn std::error::Error>> {
+	let mut roots = RootCertStore::empty();
+	roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+	    OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+	}));
+	if let Some(path) = &self.trust.ca_bundle {
+	    let pem = std::fs::read(path).map_err(|e| format!("unable to read ca_bundle {}: {}", path.display(), e))?;
+	    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+		.map_err(|e| format!("invalid ca_bundle {}: {}", path.display(), e))?;
+	    for cert in certs {
+		roots.add(&Certificate(cert))
+		    .map_err(|e| format!("invalid ca_bundle {}: {}", path.display(), e))?;
+	    }
+	}
+	Ok(roots)
+    }
+
+    /// Returns true once at least one handshake has completed, meaning a
+    /// session may be available to resume
+    pub fn has_session(&self) -> bool {
+	self.handshakes.load(Ordering::SeqCst) > 0
+    }
+
+    /// Completes a TLS handshake with `host` over `stream`, returning the
+    /// handshake duration and whether an earlier session was resumed.
+    fn handshake(&self, host: &str, stream: &mut TcpStream, timeout: Option<Duration>) -> Result<(Duration, bool, Option<String>), Box<dyn std::error::Error>> {
+	let name = ServerName::try_from(host)?;
+	let mut conn = ClientConnection::new(self.config()?, name)?;
+	let verified = self.verifications.load(Ordering::SeqCst);
+	let now = Instant::now();
+	while conn.is_handshaking() {
+	    conn.complete_io(stream).map_err(|e| error::from_io(e, Stage::Tls, timeout))?;
+	}
+	let took = now.elapsed();
+	let resumed = self.verifications.load(Ordering::SeqCst) == verified;
+	let version = conn.protocol_version().map(|version| match version {
+	    ProtocolVersion::TLSv1_3 => "TLS 1.3".to_string(),
+	    ProtocolVersion::TLSv1_2 => "TLS 1.2".to_string(),
+	    other => format!("{:?}", other),
+	});
+
+	// TLS 1.3 session tickets arrive after the handshake completes, so
+	// read briefly to give the server a chance to deliver them
+	stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+	let mut buf = [0u8; 4096];
+	while let Ok(n) = conn.read_tls(stream) {
+	    if n == 0 || conn.process_new_packets().is_err() {
+		break
+	    }
+	    while let Ok(n) = conn.reader().read(&mut buf) {
+		if n == 0 {
+		    break
+		}
+	    }
+	}
+
+	self.handshakes.fetch_add(1, Ordering::SeqCst);
+	Ok((took, resumed, version))
+    }
+}
+
+/// Measures each phase of connecting to a TCP `host:port` address
+pub fn measure_tcp(addr: &str, timeout: Option<Duration>) -> Result<PhaseTimings, Box<dyn std::error::Error>> {
+    measure(addr, || addr::resolve(addr), None, timeout)
+}
+
+/// Measures each phase of connecting to the host named in an HTTP(s) URL,
+/// including the TLS handshake for `https` URLs using `session`.
+pub fn measure_url(url: &str, session: &TlsSession, timeout: Option<Duration>) -> Result<PhaseTimings, Box<dyn std::error::Error>> {
+    let url = Url::parse(url)?;
+    let host = url.host_str()
+	.ok_or_else(|| format!("missing host in {}", url))?
+	.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default()
+	.ok_or_else(|| format!("missing port in {}", url))?;
+    let resolve = || Ok((host, port).to_socket_addrs()?.collect());
+    let tls = if url.scheme() == "https" { Some(session) } else { None };
+    measure(host, resolve, tls, timeout)
+}
+
+/// Resolves, connects, and optionally completes a TLS handshake with `host`,
+/// timing each step. The connection and handshake are each limited to
+/// `timeout` when one is given.
+fn measure<F>(host: &str, resolve: F, tls: Option<&TlsSession>, timeout: Option<Duration>) -> Result<PhaseTimings, Box<dyn std::error::Error>>
+where F: FnOnce() -> io::Result<Vec<SocketAddr>>
+{
+    let mut timings = PhaseTimings::default();
+
+    let now = Instant::now();
+    let addr = resolve()
+	.map_err(|e| error::from_io(e, Stage::Resolve, None))?
+	.into_iter()
+	.next()
+	.ok_or_else(|| format!("no addresses found for {}", host))?;
+    timings.dns = now.elapsed();
+
+    let now = Instant::now();
+    let stream = match timeout {
+	Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+	None => TcpStream::connect(addr),
+    };
+    let mut stream = stream.map_err(|e| error::from_io(e, Stage::Connect, timeout))?;
+    timings.connect = now.elapsed();
+
+    if let Some(session) = tls {
+	stream.set_read_timeout(timeout)?;
+	stream.set_write_timeout(timeout)?;
+	let (took, resumed, version) = session.handshake(host, &mut stream, timeout)?;
+	timings.tls = Some(took);
+	timings.resumed = Some(resumed);
+	timings.tls_version = version;
+    }
+
+    stream.shutdown(Shutdown::Both)?;
+    Ok(timings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+
+    use rustls::{PrivateKey, ServerConfig, ServerConnection};
+
+    use super::*;
+
+    const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+    /// Accepts one TLS connection on a local port, presenting a certificate
+    /// for 127.0.0.1 issued by the test CA
+    fn server() -> (String, thread::JoinHandle<()>) {
+	let read = |name: &str| std::fs::read(Path::new(DATA).join(name)).unwrap();
+	let certs = rustls_pemfile::certs(&mut read("server.pem").as_slice()).unwrap()
+	    .into_iter().map(Certificate).collect();
+	let key = rustls_pemfile::pkcs8_private_keys(&mut read("server.key").as_slice()).unwrap().remove(0);
+	let config = ServerConfig::builder()
+	    .with_safe_defaults()
+	    .with_no_client_auth()
+	    .with_single_cert(certs, PrivateKey(key))
+	    .unwrap();
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("https://{}/", listener.local_addr().unwrap());
+	let handle = thread::spawn(move || {
+	    let (mut stream, _) = listener.accept().unwrap();
+	    let mut conn = ServerConnection::new(Arc::new(config)).unwrap();
+	    while conn.is_handshaking() {
+		if conn.complete_io(&mut stream).is_err() {
+		    return
+		}
+	    }
+	    let _ = conn.complete_io(&mut stream);
+	});
+	(url, handle)
+    }
+
+    fn measure(trust: Trust) -> Result<PhaseTimings, Box<dyn std::error::Error>> {
+	let mut session = TlsSession::default();
+	session.set_trust(trust);
+	let (url, handle) = server();
+	let timings = measure_url(&url, &session, Some(Duration::from_secs(5)));
+	handle.join().unwrap();
+	timings
+    }
+
+    #[test]
+    fn bundled_roots_reject_private_ca() {
+	let err = measure(Trust::default()).unwrap_err();
+	assert!(err.to_string().contains("UnknownIssuer"), "{}", err);
+    }
+
+    #[test]
+    fn ca_bundle_is_trusted() {
+	let trust = Trust { ca_bundle: Some(Path::new(DATA).join("ca.pem")), insecure: false };
+	let timings = measure(trust).unwrap();
+	assert!(timings.tls.is_some());
+	assert_eq!(timings.resumed, Some(false));
+    }
+
+    #[test]
+    fn insecure_accepts_any_certificate() {
+	assert!(measure(Trust { ca_bundle: None, insecure: true }).is_ok());
+    }
+
+    #[test]
+    fn unreadable_ca_bundle_is_reported() {
+	let session = TlsSession { trust: Trust { ca_bundle: Some("missing.pem".into()), insecure: false }, ..TlsSession::default() };
+	let err = session.config().unwrap_err();
+	assert!(err.to_string().starts_with("unable to read ca_bundle missing.pem"));
+    }
+
+    #[test]
+    fn changed_trust_starts_a_new_session() {
+	let mut session = TlsSession::default();
+	session.handshakes.store(1, Ordering::SeqCst);
+	session.set_trust(Trust::default());
+	assert!(session.has_session());
+	session.set_trust(Trust { ca_bundle: None, insecure: true });
+	assert!(!session.has_session());
+    }
+
+    #[test]
+    fn budget_reports_every_phase_over() {
+	let budget = Budget { dns_ms: Some(10), connect_ms: Some(10), tls_ms: Some(10), total_ms: None };
+	let timings = PhaseTimings {
+	    dns: Duration::from_millis(5),
+	    connect: Duration::from_millis(20),
+	    tls: Some(Duration::from_millis(30)),
+	    ..PhaseTimings::default()
+	};
+	let err = budget.verify(&timings, Duration::from_millis(55)).unwrap_err();
+	assert_eq!(err.to_string(), "\n\tBudget exceeded: connect_ms 20 > 10, tls_ms 30 > 10");
+	assert!(budget.has_phases());
+    }
+}
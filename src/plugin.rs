@@ -0,0 +1,40 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Check types defined outside connchk. A downstream crate implements
+//! [`Check`] for a protocol connchk does not speak, such as gRPC, and adds
+//! instances with [`NetworkResources::register`](crate::NetworkResources::register).
+//! They then run alongside the built-in checks, with the same retries,
+//! dependencies, hooks, outputs, and notifications.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A check type provided by a library user
+pub trait Check: Send + Sync {
+    /// Runs the check once, giving up after `timeout` when one is set.
+    /// Returns details to report with the success, if any.
+    fn execute(&self, timeout: Option<Duration>) -> Result<Option<String>, Box<dyn std::error::Error>>;
+
+    /// Describes what is checked. Used as the description and address of
+    /// the target.
+    fn describe(&self) -> String;
+}
+
+/// A registered [`Check`], shared by every run of its target
+#[derive(Clone)]
+pub struct Plugin(pub Arc<dyn Check>);
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	f.debug_tuple("Plugin").field(&self.0.describe()).finish()
+    }
+}
@@ -0,0 +1,99 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Privileges required by checks that open ICMP sockets. Unprivileged ICMP
+//! sockets are preferred where the platform offers them, raw sockets are
+//! used otherwise. A check that can open neither falls back to the system
+//! `ping` command, and is skipped rather than reported as failed when that
+//! is missing too.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::OnceLock;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// The kind of ICMP socket the process is able to open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpAccess {
+    /// A datagram ICMP socket, which needs no special privileges
+    Unprivileged,
+    /// A raw socket, which needs CAP_NET_RAW, root, or Administrator
+    Raw,
+}
+
+/// A check that could not run because the process lacks privileges
+#[derive(Debug)]
+pub struct InsufficientPrivileges;
+
+impl fmt::Display for InsufficientPrivileges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "insufficient privileges")
+    }
+}
+
+impl Error for InsufficientPrivileges {}
+
+/// Returns true when `err` means the process was not permitted to open a
+/// socket
+pub fn is_denied(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied
+}
+
+/// Opens an ICMP socket of the given kind
+pub fn icmp_socket(v6: bool, access: IcmpAccess) -> io::Result<Socket> {
+    let (domain, protocol) = if v6 {
+	(Domain::IPV6, Protocol::ICMPV6)
+    } else {
+	(Domain::IPV4, Protocol::ICMPV4)
+    };
+    let ty = match access {
+	IcmpAccess::Unprivileged => Type::DGRAM,
+	IcmpAccess::Raw => Type::RAW,
+    };
+    Socket::new(domain, ty, Some(protocol))
+}
+
+/// Returns the kind of ICMP socket the process can open, if any. The probe
+/// runs once per address family and is cached.
+pub fn icmp_access(v6: bool) -> Option<IcmpAccess> {
+    static V4: OnceLock<Option<IcmpAccess>> = OnceLock::new();
+    static V6: OnceLock<Option<IcmpAccess>> = OnceLock::new();
+    let cell = if v6 { &V6 } else { &V4 };
+    *cell.get_or_init(|| {
+	[IcmpAccess::Unprivileged, IcmpAccess::Raw].into_iter()
+	    .find(|access| icmp_socket(v6, *access).is_ok())
+    })
+}
+
+/// Warns once, at the start of a run, when ICMP checks cannot open their
+/// own sockets
+pub fn warn_icmp() {
+    static WARNED: OnceLock<()> = OnceLock::new();
+    if icmp_access(false).is_none() {
+	WARNED.get_or_init(|| {
+	    tracing::warn!("ICMP sockets are unavailable, so ICMP checks will run the system `ping` \
+			    command, or be skipped without it: {}", guidance());
+	});
+    }
+}
+
+/// Describes how to grant the privileges ICMP checks need on this platform
+pub fn guidance() -> &'static str {
+    if cfg!(target_os = "linux") {
+	"grant CAP_NET_RAW with `setcap cap_net_raw+ep` on the binary, \
+	 or allow unprivileged ICMP via the net.ipv4.ping_group_range sysctl"
+    } else if cfg!(windows) {
+	"run connchk from an elevated Administrator prompt"
+    } else {
+	"run connchk as root"
+    }
+}
@@ -0,0 +1,93 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Named run profiles. Each `[profile.NAME]` table of a configuration picks
+//! a subset of its targets and overrides their timeouts and retries, so one
+//! file serves both a quick smoke test and a thorough nightly sweep, chosen
+//! with `--profile NAME`.
+
+use serde::Deserialize;
+
+use crate::{NetworkResources, Selection};
+
+/// Provides a deserialize target for a `[profile.NAME]` table
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Profile {
+    /// Targets must have a `desc` matching one of these patterns, in which
+    /// `*` and `?` are wildcards. Every target when not given.
+    pub targets: Option<Vec<String>>,
+    /// Targets must carry at least one of these tags
+    pub only_tags: Option<Vec<String>>,
+    /// Targets carrying any of these tags are left out
+    pub skip_tags: Option<Vec<String>>,
+    /// Limit in milliseconds for every check, replacing their own
+    /// `timeout_ms`
+    pub timeout_ms: Option<u64>,
+    /// Retries of every failed check, replacing their own `retries`
+    pub retries: Option<u32>,
+    /// Delay in milliseconds before each retry, replacing their own
+    /// `retry_delay_ms`
+    pub retry_delay_ms: Option<u64>,
+    /// Limit in milliseconds for the whole run, replacing `deadline_ms`
+    pub deadline_ms: Option<u64>,
+}
+
+impl Profile {
+    /// Returns the targets the profile selects
+    pub fn selection(&self) -> Selection {
+	Selection {
+	    names: self.targets.clone().unwrap_or_default(),
+	    only_tags: self.only_tags.clone().unwrap_or_default(),
+	    skip_tags: self.skip_tags.clone().unwrap_or_default(),
+	}
+    }
+
+    /// Removes the targets the profile leaves out of `resources` and
+    /// overrides the settings of the rest, returning the number of targets
+    /// kept out of the original total
+    pub fn apply(&self, resources: &mut NetworkResources) -> (usize, usize) {
+	let counts = self.selection().apply(&mut resources.target);
+	if self.timeout_ms.is_some() {
+	    resources.timeout_ms = self.timeout_ms;
+	}
+	if self.deadline_ms.is_some() {
+	    resources.deadline_ms = self.deadline_ms;
+	}
+	for target in resources.target.iter_mut() {
+	    if self.timeout_ms.is_some() {
+		target.timeout_ms = self.timeout_ms;
+	    }
+	    if self.retries.is_some() {
+		target.retries = self.retries;
+	    }
+	    if self.retry_delay_ms.is_some() {
+		target.retry_delay_ms = self.retry_delay_ms;
+	    }
+	}
+	counts
+    }
+}
+
+/// Applies the profile `name` defined in `resources`, failing with the
+/// names of those defined when it is not one of them
+pub fn apply(name: &str, resources: &mut NetworkResources) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let profile = resources.profile.as_ref().and_then(|profiles| profiles.get(name)).cloned();
+    match profile {
+	Some(profile) => Ok(profile.apply(resources)),
+	None => {
+	    let mut defined: Vec<&str> = resources.profile.iter().flatten().map(|(name, _)| name.as_str()).collect();
+	    defined.sort_unstable();
+	    match defined.is_empty() {
+		true => Err(From::from(format!("profile `{}` is not defined; add a [profile.{}] table", name, name))),
+		false => Err(From::from(format!("profile `{}` is not defined; defined profiles are {}", name, defined.join(", ")))),
+	    }
+	},
+    }
+}
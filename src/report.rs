@@ -0,0 +1,521 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Machine-readable reports of a run: a JSON summary, and metrics in the
+//! Prometheus text format. Both cover the targets and the connchk process
+//! itself, and can be served over HTTP along with a WebSocket stream of
+//! result events.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::digest;
+use serde_json::{json, Value};
+
+use crate::NetworkResources;
+use crate::metrics::SelfMetrics;
+use crate::outcome::{CheckResult, Outcome};
+
+/// Formats `time` as an RFC 3339 timestamp in UTC, to the second
+pub fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Converts days since 1970-01-01 to a civil date, after Howard
+    // Hinnant's days_from_civil inverse
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3_600, rem % 3_600 / 60, rem % 60)
+}
+
+/// Describes a single check result as JSON
+pub fn result_json(result: &CheckResult) -> Value {
+    json!({
+	"desc": result.desc,
+	"addr": result.addr,
+	"alias": result.alias,
+	"outcome": result.outcome.as_str(),
+	"latency_ms": result.latency.map(|l| l.as_secs_f64() * 1000.0),
+	"smoothed_ms": result.smoothed_ms,
+	"ip": result.peer,
+	"resolve_ms": result.resolve_time.map(|t| t.as_secs_f64() * 1000.0),
+	"attempts": result.attempts,
+	"details": result.details,
+	"error": result.error,
+	"error_code": result.error_code,
+	"skip_reason": result.skip_reason.map(|r| r.as_str()),
+	"annotations": result.annotations,
+    })
+}
+
+/// Describes the connchk process as JSON
+fn self_json(stats: &SelfMetrics) -> Value {
+    json!({
+	"runs": stats.runs,
+	"checks": stats.checks,
+	"run_duration_ms": stats.last_run.as_secs_f64() * 1000.0,
+	"queue_depth": stats.queue_depth,
+	"notifier_failures": stats.notifier_failures,
+	"config_reloads": stats.config_reloads,
+    })
+}
+
+/// Summarizes the latest run of the configuration `name` as JSON
+pub fn summary(name: &str, resources: &NetworkResources) -> Value {
+    let results: Vec<&CheckResult> = resources.target.iter().filter_map(|t| t.result.as_ref()).collect();
+    let count = |outcome: Outcome| results.iter().filter(|r| r.outcome == outcome).count();
+    json!({
+	"config": name,
+	"checked": results.len(),
+	"passed": count(Outcome::Passed),
+	"failed": count(Outcome::Failed),
+	"skipped": count(Outcome::Skipped),
+	"unknown": count(Outcome::Unknown),
+	"targets": results.iter().map(|r| result_json(r)).collect::<Vec<Value>>(),
+	"connchk": self_json(&resources.stats),
+    })
+}
+
+/// Name, type, help text, and value of a metric about connchk itself
+type SelfMetric = (&'static str, &'static str, &'static str, fn(&SelfMetrics) -> f64);
+
+/// Escapes a Prometheus label value
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Renders metrics for the latest run of each named configuration in the
+/// Prometheus text exposition format
+pub fn prometheus(suites: &[(String, &NetworkResources)]) -> String {
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: &dyn Fn(&mut String, &str, &NetworkResources)| {
+	let _ = writeln!(out, "# HELP {} {}", name, help);
+	let _ = writeln!(out, "# TYPE {} {}", name, kind);
+	for (config, resources) in suites {
+	    samples(&mut out, &label(config), resources);
+	}
+    };
+
+    family("connchk_target_up", "gauge", "Whether the target passed its latest check",
+	   &|out, config, resources| {
+	       for target in resources.target.iter().filter(|t| t.res.is_some() && !t.unknown && t.skip_reason.is_none()) {
+		   let _ = writeln!(out, "connchk_target_up{{config=\"{}\",desc=\"{}\",kind=\"{:?}\"}} {}",
+				    config, label(&target.desc), target.kind, u8::from(!target.failed));
+	       }
+	   });
+    family("connchk_target_attempts", "gauge", "Attempts made in the target's latest check, including retries",
+	   &|out, config, resources| {
+	       for target in resources.target.iter().filter(|t| t.res.is_some() && !t.unknown && t.skip_reason.is_none()) {
+		   let _ = writeln!(out, "connchk_target_attempts{{config=\"{}\",desc=\"{}\",kind=\"{:?}\"}} {}",
+				    config, label(&target.desc), target.kind, target.attempts);
+	       }
+	   });
+    family("connchk_target_latency_milliseconds", "gauge", "Latency of the target's latest successful check",
+	   &|out, config, resources| {
+	       for target in resources.target.iter().filter(|t| t.outcome() == Outcome::Passed) {
+		   if let Some(ms) = target.latency.current {
+		       let _ = writeln!(out, "connchk_target_latency_milliseconds{{config=\"{}\",desc=\"{}\",kind=\"{:?}\"}} {}",
+					config, label(&target.desc), target.kind, ms);
+		   }
+	       }
+	   });
+    family("connchk_target_skipped", "gauge", "Whether the target's latest check was skipped, by reason",
+	   &|out, config, resources| {
+	       for target in resources.target.iter() {
+		   if let Some(reason) = target.skip_reason {
+		       let _ = writeln!(out, "connchk_target_skipped{{config=\"{}\",desc=\"{}\",kind=\"{:?}\",reason=\"{}\"}} 1",
+					config, label(&target.desc), target.kind, reason.as_str());
+		   }
+	       }
+	   });
+    family("connchk_target_unknown", "gauge", "Whether the target's latest check ended in an internal error",
+	   &|out, config, resources| {
+	       for target in resources.target.iter().filter(|t| t.res.is_some()) {
+		   let _ = writeln!(out, "connchk_target_unknown{{config=\"{}\",desc=\"{}\",kind=\"{:?}\"}} {}",
+				    config, label(&target.desc), target.kind, u8::from(target.unknown));
+	       }
+	   });
+
+    let counters: [SelfMetric; 6] = [
+	("connchk_runs_total", "counter", "Runs completed", |s| s.runs as f64),
+	("connchk_checks_total", "counter", "Checks executed", |s| s.checks as f64),
+	("connchk_run_duration_seconds", "gauge", "Duration of the latest run", |s| s.last_run.as_secs_f64()),
+	("connchk_queue_depth", "gauge", "Checks queued in the latest run for lack of local resources",
+	 |s| s.queue_depth as f64),
+	("connchk_notifier_failures_total", "counter", "Notifications that could not be delivered",
+	 |s| s.notifier_failures as f64),
+	("connchk_config_reloads_total", "counter", "Configuration reloads", |s| s.config_reloads as f64),
+    ];
+    for (name, kind, help, value) in counters {
+	family(name, kind, help, &|out, config, resources| {
+	    let _ = writeln!(out, "{}{{config=\"{}\"}} {}", name, config, value(&resources.stats));
+	});
+    }
+    out
+}
+
+/// GUID appended to a WebSocket key to derive the accept value, per
+/// RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest wait for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest wait for a WebSocket subscriber to take an event before it is
+/// dropped
+const SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// State shared with the HTTP server started by [`Server::serve`]. The
+/// server answers `/metrics` with Prometheus metrics, `/status` with the
+/// JSON summaries of the latest runs, and upgrades `/events` to a WebSocket
+/// that receives an event for every result as checks complete.
+#[derive(Default)]
+pub struct Server {
+    metrics: Mutex<String>,
+    status: Mutex<Value>,
+    subscribers: Mutex<Vec<Arc<Subscriber>>>,
+}
+
+/// A WebSocket client of `/events`
+struct Subscriber {
+    stream: Mutex<TcpStream>,
+    closed: AtomicBool,
+}
+
+impl Subscriber {
+    /// Sends `frames`, closing the connection when they cannot be sent
+    /// within [`SEND_TIMEOUT`]
+    fn send(&self, frames: &[Vec<u8>]) {
+	let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+	if !self.closed.load(Ordering::SeqCst) && !frames.iter().all(|frame| stream.write_all(frame).is_ok()) {
+	    self.close(&stream);
+	}
+    }
+
+    /// Marks the subscriber closed and shuts its connection, ending the
+    /// thread reading from it
+    fn close(&self, stream: &TcpStream) {
+	self.closed.store(true, Ordering::SeqCst);
+	let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Server {
+    /// Starts serving on `addr` from a background thread, for as long as
+    /// the process runs. Each client is answered from a thread of its own,
+    /// so a slow one holds up no other.
+    pub fn serve(self: &Arc<Self>, addr: SocketAddr) -> io::Result<()> {
+	let listener = TcpListener::bind(addr)?;
+	let server = self.clone();
+	thread::spawn(move || {
+	    for stream in listener.incoming().flatten() {
+		let server = server.clone();
+		thread::spawn(move || server.handle(stream));
+	    }
+	});
+	Ok(())
+    }
+
+    /// Replaces the metrics and status served for polling
+    pub fn update(&self, metrics: String, status: Value) {
+	*self.metrics.lock().unwrap_or_else(|e| e.into_inner()) = metrics;
+	*self.status.lock().unwrap_or_else(|e| e.into_inner()) = status;
+    }
+
+    /// Sends a `result` event for a check of the configuration `name` that
+    /// just completed to every WebSocket subscriber
+    pub fn publish_result(&self, name: &str, result: &CheckResult) {
+	let mut event = result_json(result);
+	event["type"] = json!("result");
+	event["config"] = json!(name);
+	self.broadcast(&event);
+    }
+
+    /// Sends a `run` event with the summary of a completed run of the
+    /// configuration `name` to every WebSocket subscriber
+    pub fn publish_run(&self, name: &str, resources: &NetworkResources) {
+	let mut run = summary(name, resources);
+	if let Some(run) = run.as_object_mut() {
+	    run.remove("targets");
+	    run.insert("type".to_string(), json!("run"));
+	}
+	self.broadcast(&run);
+    }
+
+    /// Sends `event` to every subscriber, dropping those that have gone
+    /// away or fallen behind. The list is not held while sending, so a
+    /// stalled subscriber delays no other.
+    fn broadcast(&self, event: &Value) {
+	let subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).clone();
+	if subscribers.is_empty() {
+	    return
+	}
+	let frames = [frame(OPCODE_TEXT, event.to_string().as_bytes())];
+	for subscriber in subscribers.iter() {
+	    subscriber.send(&frames);
+	}
+	self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+	    .retain(|subscriber| !subscriber.closed.load(Ordering::SeqCst));
+    }
+
+    /// Answers a single request
+    fn handle(&self, mut stream: TcpStream) {
+	let mut buf = [0u8; 4096];
+	if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err() {
+	    return
+	}
+	let len = stream.read(&mut buf).unwrap_or(0);
+	let request = String::from_utf8_lossy(&buf[..len]);
+	let path = request.split_whitespace().nth(1).unwrap_or_default();
+	let (content_type, body) = match path {
+	    "/metrics" => ("text/plain; version=0.0.4", self.metrics.lock().unwrap_or_else(|e| e.into_inner()).clone()),
+	    "/status" => ("application/json", self.status.lock().unwrap_or_else(|e| e.into_inner()).to_string()),
+	    "/events" => return self.subscribe(stream, &request),
+	    _ => {
+		let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+		return
+	    },
+	};
+	let response = format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+				Connection: close\r\n\r\n{}", content_type, body.len(), body);
+	let _ = stream.write_all(response.as_bytes());
+    }
+
+    /// Completes a WebSocket handshake, adds the client as a subscriber,
+    /// and then answers its control frames until it goes away
+    fn subscribe(&self, mut stream: TcpStream, request: &str) {
+	let key = request.lines()
+	    .filter_map(|line| line.split_once(':'))
+	    .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+	    .map(|(_, value)| value.trim());
+	let key = match key {
+	    Some(key) => key,
+	    None => {
+		let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+		return
+	    },
+	};
+	let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, WEBSOCKET_GUID).as_bytes());
+	let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+				Sec-WebSocket-Accept: {}\r\n\r\n", STANDARD.encode(digest.as_ref()));
+	let reader = stream.set_write_timeout(Some(SEND_TIMEOUT))
+	    .and_then(|_| stream.set_read_timeout(None))
+	    .and_then(|_| stream.write_all(response.as_bytes()))
+	    .and_then(|_| stream.try_clone());
+	let mut reader = match reader {
+	    Ok(reader) => reader,
+	    Err(_) => return,
+	};
+	let subscriber = Arc::new(Subscriber { stream: Mutex::new(stream), closed: AtomicBool::new(false) });
+	self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(subscriber.clone());
+
+	// Clients only send control frames here; anything else is ignored
+	while let Ok((opcode, payload)) = read_frame(&mut reader) {
+	    match opcode {
+		OPCODE_PING => subscriber.send(&[frame(OPCODE_PONG, &payload)]),
+		OPCODE_CLOSE => {
+		    // The close is echoed with the client's status code, if any
+		    subscriber.send(&[frame(OPCODE_CLOSE, payload.get(..2).unwrap_or_default())]);
+		    break
+		},
+		_ => (),
+	    }
+	}
+	subscriber.close(&reader);
+	self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+	    .retain(|other| !Arc::ptr_eq(other, &subscriber));
+    }
+}
+
+/// WebSocket opcodes, per RFC 6455
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Largest frame accepted from a subscriber
+const MAX_FRAME: u64 = 64 * 1024;
+
+/// Encodes `payload` as a single unmasked WebSocket frame
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len();
+    let mut frame = vec![0x80 | opcode];
+    if len < 126 {
+	frame.push(len as u8);
+    } else if len <= usize::from(u16::MAX) {
+	frame.push(126);
+	frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+	frame.push(127);
+	frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads a frame from a client, returning its opcode and unmasked payload
+fn read_frame(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    let len = match head[1] & 0x7f {
+	126 => {
+	    let mut len = [0u8; 2];
+	    stream.read_exact(&mut len)?;
+	    u64::from(u16::from_be_bytes(len))
+	},
+	127 => {
+	    let mut len = [0u8; 8];
+	    stream.read_exact(&mut len)?;
+	    u64::from_be_bytes(len)
+	},
+	len => u64::from(len),
+    };
+    if len > MAX_FRAME {
+	return Err(io::Error::new(io::ErrorKind::InvalidData, "WebSocket frame too large"))
+    }
+    let mut mask = [0u8; 4];
+    if head[1] & 0x80 != 0 {
+	stream.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+	*byte ^= mask[i % 4];
+    }
+    Ok((head[0] & 0x0f, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts a server on a loopback port, returning it and its address
+    fn server() -> (Arc<Server>, SocketAddr) {
+	let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+	let server = Arc::new(Server::default());
+	server.serve(addr).unwrap();
+	(server, addr)
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+	let mut stream = TcpStream::connect(addr).unwrap();
+	stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	stream.write_all(format!("GET {} HTTP/1.1\r\nHost: connchk\r\n\r\n", path).as_bytes()).unwrap();
+	let mut response = String::new();
+	stream.read_to_string(&mut response).unwrap();
+	response
+    }
+
+    #[test]
+    fn silent_clients_hold_up_no_other() {
+	let (server, addr) = server();
+	server.update("connchk_runs_total{config=\"a\"} 1\n".to_string(), json!([]));
+	let _silent = TcpStream::connect(addr).unwrap();
+	let response = get(addr, "/metrics");
+	assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+	assert!(response.ends_with("\r\n\r\nconnchk_runs_total{config=\"a\"} 1\n"), "{}", response);
+	assert!(get(addr, "/status").ends_with("\r\n\r\n[]"));
+	assert!(get(addr, "/missing").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    /// Subscribes to `/events`, returning the upgraded connection once the
+    /// server has added it
+    fn subscribe(server: &Server, addr: SocketAddr) -> TcpStream {
+	let before = server.subscribers.lock().unwrap().len();
+	let mut stream = TcpStream::connect(addr).unwrap();
+	stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	stream.write_all(b"GET /events HTTP/1.1\r\nHost: connchk\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+			   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+	let mut head = Vec::new();
+	let mut byte = [0u8; 1];
+	while !head.ends_with(b"\r\n\r\n") {
+	    stream.read_exact(&mut byte).unwrap();
+	    head.push(byte[0]);
+	}
+	let head = String::from_utf8(head).unwrap();
+	assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+	// The accept value of the key in RFC 6455's example
+	assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", head);
+	while server.subscribers.lock().unwrap().len() == before {
+	    thread::yield_now();
+	}
+	stream
+    }
+
+    /// Encodes a masked client frame
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+	let mask = [0x37, 0xfa, 0x21, 0x3d];
+	let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+	frame.extend_from_slice(&mask);
+	frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+	frame
+    }
+
+    #[test]
+    fn results_are_streamed_to_subscribers() {
+	let (server, addr) = server();
+	let mut client = subscribe(&server, addr);
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let result = crate::check_tcp(&listener.local_addr().unwrap().to_string()).unwrap();
+	server.publish_result("prod", &result);
+	let (opcode, payload) = read_frame(&mut client).unwrap();
+	assert_eq!(opcode, OPCODE_TEXT);
+	let event: Value = serde_json::from_slice(&payload).unwrap();
+	assert_eq!((event["type"].as_str(), event["config"].as_str()), (Some("result"), Some("prod")));
+	assert_eq!(event["outcome"], "passed");
+    }
+
+    #[test]
+    fn pings_are_answered_and_closes_echoed() {
+	let (server, addr) = server();
+	let mut client = subscribe(&server, addr);
+	client.write_all(&client_frame(OPCODE_PING, b"hello")).unwrap();
+	assert_eq!(read_frame(&mut client).unwrap(), (OPCODE_PONG, b"hello".to_vec()));
+	client.write_all(&client_frame(OPCODE_CLOSE, &[0x03, 0xe8, b'b', b'y', b'e'])).unwrap();
+	assert_eq!(read_frame(&mut client).unwrap(), (OPCODE_CLOSE, vec![0x03, 0xe8]));
+	assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+	while !server.subscribers.lock().unwrap().is_empty() {
+	    thread::yield_now();
+	}
+    }
+
+    #[test]
+    fn stalled_subscribers_are_dropped() {
+	let (server, addr) = server();
+	// Never read from, so its buffers fill and sends time out
+	let _stalled = subscribe(&server, addr);
+	let mut live = subscribe(&server, addr);
+	let event = json!({"padding": "x".repeat(32 * 1024)});
+	let started = std::time::Instant::now();
+	while server.subscribers.lock().unwrap().len() == 2 {
+	    assert!(started.elapsed() < Duration::from_secs(60), "the stalled subscriber was never dropped");
+	    let reader = live.try_clone().unwrap();
+	    // The live subscriber keeps up, taking each event as it is sent
+	    let drain = thread::spawn(move || read_frame(&mut { reader }).map(|(opcode, _)| opcode));
+	    server.broadcast(&event);
+	    assert_eq!(drain.join().unwrap().unwrap(), OPCODE_TEXT);
+	}
+	server.broadcast(&json!({"type": "run"}));
+	assert_eq!(read_frame(&mut live).unwrap().1, br#"{"type":"run"}"#);
+    }
+}
@@ -0,0 +1,212 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Name resolution backends for the hosts of Tcp and Http targets. The
+//! system resolver uses the platform's getaddrinfo, the builtin resolver
+//! speaks DNS itself through hickory so it works where there is none, as in
+//! static musl builds, and the static resolver answers from a fixed map.
+//! Other backends implement [`Resolver`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, NameServerConfigGroup, Protocol, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use serde::Deserialize;
+
+use crate::addr;
+use crate::error::{self, Stage};
+
+/// Looks up the addresses of host names
+pub trait Resolver: Send + Sync + fmt::Debug {
+    /// Returns every address of `host`, waiting up to `timeout` where the
+    /// backend allows it
+    fn lookup(&self, host: &str, timeout: Duration) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>>;
+}
+
+/// Provides a deserialize target for the `resolver` table of a target or
+/// configuration, selected by its `type`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ResolverConfig {
+    /// The platform's resolver, through getaddrinfo
+    System,
+    /// connchk's own DNS client, asking `servers` in turn, or those the
+    /// system is configured with when not given
+    Builtin { servers: Option<Vec<String>> },
+    /// A fixed map of host names to addresses. Names missing from it fail
+    /// to resolve.
+    Static { hosts: HashMap<String, Vec<IpAddr>> },
+}
+
+impl ResolverConfig {
+    /// Returns the resolver described by this configuration
+    pub fn build(&self) -> Arc<dyn Resolver> {
+	match self {
+	    ResolverConfig::System => Arc::new(System),
+	    ResolverConfig::Builtin { servers } => Arc::new(Builtin { servers: servers.clone().unwrap_or_default() }),
+	    ResolverConfig::Static { hosts } => Arc::new(Static {
+		hosts: hosts.iter()
+		    .map(|(name, ips)| (name.trim_end_matches('.').to_ascii_lowercase(), ips.clone()))
+		    .collect(),
+	    }),
+	}
+    }
+}
+
+/// Resolves with getaddrinfo, which takes no timeout
+#[derive(Debug)]
+pub struct System;
+
+impl Resolver for System {
+    fn lookup(&self, host: &str, _timeout: Duration) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+	let addrs = (host, 0).to_socket_addrs().map_err(|e| error::from_io(e, Stage::Resolve, None))?;
+	Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// Resolves over DNS without the platform's resolver
+#[derive(Debug)]
+pub struct Builtin {
+    /// Resolvers asked in turn, those the system is configured with, read
+    /// from `/etc/resolv.conf` or the Windows network settings, when empty
+    pub servers: Vec<String>,
+}
+
+impl Builtin {
+    /// Returns the configuration to resolve with: `servers`, each over UDP
+    /// with TCP for truncated replies, or else the system's resolvers and
+    /// search domains
+    fn config(&self) -> Result<hickory_resolver::config::ResolverConfig, Box<dyn std::error::Error>> {
+	if self.servers.is_empty() {
+	    let (config, _) = hickory_resolver::system_conf::read_system_conf()
+		.map_err(|e| format!("unable to read the system's resolvers ({}); set `servers` in the `resolver` table", e))?;
+	    return Ok(config)
+	}
+	let mut group = Vec::new();
+	for server in &self.servers {
+	    let addr: SocketAddr = addr::resolve_or_port(server, 53)?.into_iter().next()
+		.ok_or_else(|| format!("unable to resolve {}", server))?;
+	    group.push(NameServerConfig::new(addr, Protocol::Udp));
+	    group.push(NameServerConfig::new(addr, Protocol::Tcp));
+	}
+	Ok(hickory_resolver::config::ResolverConfig::from_parts(None, Vec::new(), NameServerConfigGroup::from(group)))
+    }
+}
+
+impl Resolver for Builtin {
+    /// Asks for the IPv4 and IPv6 addresses of `host`, returning those of
+    /// either family when the other fails. Each lookup starts with an
+    /// empty cache, so every check measures a fresh resolution.
+    fn lookup(&self, host: &str, timeout: Duration) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+	let mut opts = ResolverOpts::default();
+	opts.timeout = timeout;
+	opts.attempts = 1;
+	opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+	opts.use_hosts_file = false;
+	let resolver = hickory_resolver::Resolver::new(self.config()?, opts)?;
+	let ips = resolver.lookup_ip(host).map_err(|e| {
+	    let kind = match e.kind() {
+		ResolveErrorKind::Timeout => io::ErrorKind::TimedOut,
+		_ => io::ErrorKind::NotFound,
+	    };
+	    error::from_io(io::Error::new(kind, format!("{}: {}", host, e)), Stage::Resolve, Some(timeout))
+	})?;
+	Ok(ips.iter().collect())
+    }
+}
+
+/// Resolves from a map keyed by lowercase host name
+#[derive(Debug)]
+pub struct Static {
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Resolver for Static {
+    fn lookup(&self, host: &str, _timeout: Duration) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+	match self.hosts.get(&host.trim_end_matches('.').to_ascii_lowercase()) {
+	    Some(ips) if !ips.is_empty() => Ok(ips.clone()),
+	    _ => {
+		let e = io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the static resolver's hosts", host));
+		Err(error::from_io(e, Stage::Resolve, None))
+	    },
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::thread;
+
+    use super::*;
+
+    /// Serves queries on a local port until none arrives for a second,
+    /// answering A queries with 192.0.2.1 and failing all others with
+    /// SERVFAIL
+    fn server() -> (String, thread::JoinHandle<()>) {
+	let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let addr = socket.local_addr().unwrap().to_string();
+	let handle = thread::spawn(move || {
+	    let mut buf = [0u8; 512];
+	    while let Ok((_, peer)) = socket.recv_from(&mut buf) {
+		// The question ends after its name, type, and class
+		let mut end = 12;
+		while buf[end] != 0 {
+		    end += 1 + buf[end] as usize;
+		}
+		end += 5;
+		let mut reply = buf[..end].to_vec();
+		reply[2] = 0x81;
+		reply[10..12].copy_from_slice(&[0, 0]);
+		match u16::from_be_bytes([buf[end - 4], buf[end - 3]]) {
+		    1 => {
+			reply[3] = 0x80;
+			reply[6..8].copy_from_slice(&[0, 1]);
+			reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+		    },
+		    _ => reply[3] = 0x82,
+		}
+		let _ = socket.send_to(&reply, peer);
+	    }
+	});
+	(addr, handle)
+    }
+
+    #[test]
+    fn builtin_keeps_addresses_of_one_family() {
+	let (addr, handle) = server();
+	let resolver = ResolverConfig::Builtin { servers: Some(vec![addr]) }.build();
+	let ips = resolver.lookup("app.example.com.", Duration::from_secs(2)).unwrap();
+	assert_eq!(ips, [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+	handle.join().unwrap();
+    }
+
+    #[test]
+    fn builtin_reports_unresolved_names() {
+	let resolver = ResolverConfig::Builtin { servers: Some(vec!["127.0.0.1:9".to_string()]) }.build();
+	let err = resolver.lookup("app.example.com.", Duration::from_millis(200)).unwrap_err();
+	assert!(matches!(error::code(err.as_ref()).as_str(), "name_not_resolved" | "timeout.resolve"));
+    }
+
+    #[test]
+    fn static_matches_names_without_case() {
+	let hosts = HashMap::from([("App.Example.com.".to_string(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])]);
+	let resolver = ResolverConfig::Static { hosts }.build();
+	assert_eq!(resolver.lookup("app.example.COM", Duration::ZERO).unwrap(), [IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+	let err = resolver.lookup("db.example.com", Duration::ZERO).unwrap_err();
+	assert_eq!(error::code(err.as_ref()), "name_not_resolved");
+    }
+}
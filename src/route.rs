@@ -0,0 +1,308 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Route presence checks, which put routing state in the same report as
+//! the reachability it explains. The routes for a prefix are read from a
+//! local BIRD or FRR daemon over its control socket, or from a looking-glass
+//! HTTP API, and checked for the expected next hop and AS path.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Limit for reading routes when the target has no timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where BIRD's control socket usually lives
+const BIRD_SOCKET: &str = "/run/bird/bird.ctl";
+
+/// Where FRR's BGP daemon usually listens for vtysh
+const FRR_SOCKET: &str = "/var/run/frr/bgpd.vty";
+
+/// Where routes are read from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteSource {
+    /// A BIRD 2 daemon, through its control socket
+    Bird,
+    /// An FRR BGP daemon, through its vty socket
+    Frr,
+    /// A looking-glass HTTP API answering in JSON
+    LookingGlass,
+}
+
+/// Provides a deserialize target for the `route` table of route checks,
+/// whose `addr` is a prefix such as `203.0.113.0/24`
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RouteOptions {
+    /// Where routes are read from. Defaults to `looking_glass` when `url`
+    /// is given and `bird` otherwise.
+    pub source: Option<RouteSource>,
+    /// Control socket of the daemon, overriding the usual location
+    pub socket: Option<PathBuf>,
+    /// Looking-glass URL, in which `{prefix}` is replaced by the prefix
+    pub url: Option<String>,
+    /// Next hop a route must have
+    pub next_hop: Option<String>,
+    /// AS path a route must have, e.g. `64500 64501`
+    pub as_path: Option<String>,
+    /// AS a route must originate from, the last of its path
+    pub origin_as: Option<u32>,
+}
+
+/// A route for the prefix, as reported by the source
+#[derive(Debug, Default)]
+struct Route {
+    next_hops: Vec<String>,
+    /// The AS path, its numbers separated by single spaces
+    as_path: String,
+}
+
+impl Route {
+    fn origin(&self) -> Option<u32> {
+	self.as_path.split_whitespace().last()
+	    .and_then(|asn| asn.trim_matches(|c| c == '{' || c == '}').parse().ok())
+    }
+
+    fn matches(&self, options: &RouteOptions) -> bool {
+	options.next_hop.as_ref().is_none_or(|hop| self.next_hops.iter().any(|h| h == hop))
+	    && options.as_path.as_ref().is_none_or(|path| normalize(path) == self.as_path)
+	    && options.origin_as.is_none_or(|asn| self.origin() == Some(asn))
+    }
+
+    fn describe(&self) -> String {
+	let hops = if self.next_hops.is_empty() { "unknown".to_string() } else { self.next_hops.join(", ") };
+	let path = if self.as_path.is_empty() { "empty" } else { &self.as_path };
+	format!("via {} with AS path {}", hops, path)
+    }
+}
+
+/// Joins the numbers of an AS path with single spaces
+fn normalize(path: &str) -> String {
+    path.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Connects to a daemon's control socket
+#[cfg(unix)]
+fn connect(path: &Path, timeout: Duration) -> Result<std::os::unix::net::UnixStream, Box<dyn std::error::Error>> {
+    let stream = std::os::unix::net::UnixStream::connect(path)
+	.map_err(|e| format!("unable to connect to {}: {}", path.display(), e))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn connect(path: &Path, _: Duration) -> Result<std::net::TcpStream, Box<dyn std::error::Error>> {
+    Err(From::from(format!("unable to connect to {}: control sockets are only supported on Unix", path.display())))
+}
+
+/// Runs `show route <prefix> all` over BIRD's control socket, whose replies
+/// are lines prefixed with a four-digit code and `-`, or with a space when
+/// continuing the previous code, ending with a code and a space
+fn bird(prefix: &str, socket: &Path, timeout: Duration) -> Result<Vec<Route>, Box<dyn std::error::Error>> {
+    let stream = connect(socket, timeout)?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    (&stream).write_all(format!("show route {} all\n", prefix).as_bytes())?;
+
+    let mut output = Vec::new();
+    loop {
+	line.clear();
+	if reader.read_line(&mut line)? == 0 {
+	    return Err(From::from("BIRD closed the connection mid-reply"))
+	}
+	let line = line.trim_end_matches('\n');
+	let code = line.get(..4).filter(|code| code.bytes().all(|b| b.is_ascii_digit()));
+	match (code, line.as_bytes().get(4)) {
+	    // Network not found
+	    (Some("8001"), _) => return Ok(Vec::new()),
+	    (Some(code), Some(b' ')) if code.starts_with(['8', '9']) => {
+		return Err(From::from(format!("BIRD: {}", &line[5..])))
+	    },
+	    (Some(_), Some(b' ')) => {
+		output.push(line[5..].to_string());
+		break
+	    },
+	    (Some(_), Some(b'-')) => output.push(line[5..].to_string()),
+	    _ => output.push(line.strip_prefix(' ').unwrap_or(line).to_string()),
+	}
+    }
+    Ok(bird_routes(&output))
+}
+
+/// Reads routes from the output of `show route ... all`, in which each
+/// route starts on an unindented line, or one indented with spaces for
+/// further routes to the same network, and its attributes follow on lines
+/// indented with a tab
+fn bird_routes(output: &[String]) -> Vec<Route> {
+    let mut routes: Vec<Route> = Vec::new();
+    for line in output.iter().filter(|l| !l.trim().is_empty() && !l.starts_with("Table ")) {
+	if !line.starts_with('\t') {
+	    routes.push(Route::default());
+	    continue
+	}
+	let route = match routes.last_mut() {
+	    Some(route) => route,
+	    None => continue,
+	};
+	let attribute = line.trim();
+	if let Some(via) = attribute.strip_prefix("via ") {
+	    if let Some(hop) = via.split_whitespace().next() {
+		route.next_hops.push(hop.to_string());
+	    }
+	} else if let Some(hop) = attribute.strip_prefix("BGP.next_hop:") {
+	    for hop in hop.split_whitespace() {
+		if !route.next_hops.iter().any(|h| h == hop) {
+		    route.next_hops.push(hop.to_string());
+		}
+	    }
+	} else if let Some(path) = attribute.strip_prefix("BGP.as_path:") {
+	    route.as_path = normalize(path);
+	}
+    }
+    routes
+}
+
+/// Runs `show bgp ... json` over FRR's vty socket, which takes a command
+/// ending with a NUL byte and ends its reply with three NUL bytes and a
+/// status
+fn frr(prefix: &str, socket: &Path, timeout: Duration) -> Result<Vec<Route>, Box<dyn std::error::Error>> {
+    let mut stream = connect(socket, timeout)?;
+    let afi = if prefix.contains(':') { "ipv6" } else { "ipv4" };
+    stream.write_all(format!("show bgp {} unicast {} json\0", afi, prefix).as_bytes())?;
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 8192];
+    while !(reply.len() >= 4 && reply[reply.len() - 4..reply.len() - 1] == [0, 0, 0]) {
+	let len = stream.read(&mut buf)?;
+	if len == 0 {
+	    return Err(From::from("FRR closed the connection mid-reply"))
+	}
+	reply.extend_from_slice(&buf[..len]);
+    }
+    let status = reply[reply.len() - 1];
+    let text = String::from_utf8_lossy(&reply[..reply.len() - 4]);
+    if text.contains("not in table") {
+	return Ok(Vec::new())
+    }
+    if status != 0 {
+	return Err(From::from(format!("FRR: {}", text.trim())))
+    }
+    let value: Value = serde_json::from_str(&text).map_err(|e| format!("FRR returned invalid JSON: {}", e))?;
+    Ok(json_routes(&value, prefix))
+}
+
+/// Fetches routes from a looking-glass API
+fn looking_glass(prefix: &str, url: &str, timeout: Duration) -> Result<Vec<Route>, Box<dyn std::error::Error>> {
+    let url = url.replace("{prefix}", prefix);
+    let resp = Client::builder().timeout(timeout).build()?.get(&url).send()?;
+    if !resp.status().is_success() {
+	return Err(From::from(format!("{} returned {}", url, resp.status())))
+    }
+    let value: Value = resp.json().map_err(|e| format!("{} returned invalid JSON: {}", url, e))?;
+    Ok(json_routes(&value, prefix))
+}
+
+/// Collects routes from JSON in the shapes common to looking glasses and
+/// FRR: any object with a next hop or AS path is a route, unless it names
+/// a different `prefix` or `network`
+fn json_routes(value: &Value, prefix: &str) -> Vec<Route> {
+    let mut routes = Vec::new();
+    collect(value, prefix, &mut routes);
+    routes
+}
+
+fn collect(value: &Value, prefix: &str, routes: &mut Vec<Route>) {
+    let map = match value {
+	Value::Array(items) => return items.iter().for_each(|item| collect(item, prefix, routes)),
+	Value::Object(map) => map,
+	_ => return,
+    };
+    let other = ["prefix", "network"].iter()
+	.filter_map(|key| map.get(*key).and_then(Value::as_str))
+	.any(|p| !p.eq_ignore_ascii_case(prefix));
+    if other {
+	return
+    }
+
+    let field = |keys: &[&str]| keys.iter().find_map(|key| map.get(*key));
+    let as_path = field(&["as_path", "aspath", "as-path"])
+	.or_else(|| map.get("bgp").and_then(|bgp| bgp.get("as_path")))
+	.and_then(path_text);
+    let mut next_hops: Vec<String> = field(&["next_hop", "nexthop", "next-hop", "gateway"])
+	.and_then(Value::as_str)
+	.map(str::to_string)
+	.into_iter()
+	.collect();
+    if let Some(Value::Array(hops)) = map.get("nexthops") {
+	next_hops.extend(hops.iter().filter_map(|hop| hop.get("ip").and_then(Value::as_str)).map(str::to_string));
+    }
+
+    if as_path.is_some() || !next_hops.is_empty() {
+	routes.push(Route { next_hops, as_path: as_path.unwrap_or_default() });
+    } else {
+	map.values().for_each(|item| collect(item, prefix, routes));
+    }
+}
+
+/// Reads an AS path given as text, a list of numbers, or FRR's object
+fn path_text(value: &Value) -> Option<String> {
+    match value {
+	Value::String(path) => Some(normalize(path)),
+	Value::Array(asns) => Some(asns.iter().map(|asn| match asn {
+	    Value::String(asn) => asn.clone(),
+	    other => other.to_string(),
+	}).collect::<Vec<_>>().join(" ")),
+	Value::Object(_) => value.get("string").and_then(path_text),
+	_ => None,
+    }
+}
+
+/// Reads the routes for the prefix `addr` and checks one of them has the
+/// expected next hop, AS path, and origin
+pub fn check(addr: &str, options: &RouteOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let timeout = timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let source = options.source.unwrap_or(if options.url.is_some() { RouteSource::LookingGlass } else { RouteSource::Bird });
+    let socket = |default: &str| options.socket.clone().unwrap_or_else(|| PathBuf::from(default));
+    let routes = match source {
+	RouteSource::Bird => bird(addr, &socket(BIRD_SOCKET), timeout)?,
+	RouteSource::Frr => frr(addr, &socket(FRR_SOCKET), timeout)?,
+	RouteSource::LookingGlass => {
+	    let url = options.url.as_deref().ok_or("looking_glass routes require a `url`")?;
+	    looking_glass(addr, url, timeout)?
+	},
+    };
+    if routes.is_empty() {
+	return Err(From::from(format!("no route for {}", addr)))
+    }
+
+    match routes.iter().find(|route| route.matches(options)) {
+	Some(route) => Ok(format!("{} routes for {}, matched {}", routes.len(), addr, route.describe())),
+	None => {
+	    let mut expected = Vec::new();
+	    if let Some(hop) = &options.next_hop {
+		expected.push(format!("next hop {}", hop));
+	    }
+	    if let Some(path) = &options.as_path {
+		expected.push(format!("AS path {}", normalize(path)));
+	    }
+	    if let Some(asn) = options.origin_as {
+		expected.push(format!("origin AS{}", asn));
+	    }
+	    let found: Vec<String> = routes.iter().map(Route::describe).collect();
+	    Err(From::from(format!("\n\tExpected: {}\n\tRoutes: {}", expected.join(", "), found.join("; "))))
+	},
+    }
+}
@@ -0,0 +1,227 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Random sampling of targets for smoke runs against large configurations.
+//! Selection is driven by a seed so a sample can be reproduced, and can be
+//! stratified so every tag keeps its share of the sample.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Resource;
+
+/// How many targets to keep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleSize {
+    /// A share of the targets, in percent, rounded up
+    Percent(f64),
+    /// A number of targets
+    Count(usize),
+}
+
+impl FromStr for SampleSize {
+    type Err = String;
+
+    /// Parses `10%` as a percentage and `25` as a count
+    fn from_str(s: &str) -> Result<SampleSize, String> {
+	match s.trim().strip_suffix('%') {
+	    Some(pct) => match pct.trim().parse::<f64>() {
+		Ok(pct) if pct > 0.0 && pct <= 100.0 => Ok(SampleSize::Percent(pct)),
+		_ => Err(format!("`{}` is not a percentage between 0 and 100", s)),
+	    },
+	    None => match s.trim().parse::<usize>() {
+		Ok(count) if count > 0 => Ok(SampleSize::Count(count)),
+		_ => Err(format!("`{}` is neither a positive count nor a percentage", s)),
+	    },
+	}
+    }
+}
+
+/// A sampling of targets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub size: SampleSize,
+    /// Keep each tag's share of the targets, grouping targets by their first
+    /// tag
+    pub by_tag: bool,
+    /// Seed of the selection. The same seed selects the same targets from
+    /// the same configuration.
+    pub seed: u64,
+}
+
+/// Returns a seed for runs that did not ask for one
+pub fn random_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_nanos() as u64 ^ u64::from(std::process::id()).rotate_left(32)
+}
+
+/// SplitMix64, which is plenty for picking targets
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+	self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+	let mut z = self.0;
+	z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+	z ^ (z >> 31)
+    }
+
+    /// Returns a value in `0..bound`
+    fn below(&mut self, bound: usize) -> usize {
+	(self.next() % bound as u64) as usize
+    }
+}
+
+impl Sample {
+    /// Returns the number of targets kept out of `total`
+    fn wanted(&self, total: usize) -> usize {
+	match self.size {
+	    SampleSize::Percent(pct) => (total as f64 * pct / 100.0).ceil() as usize,
+	    SampleSize::Count(count) => count,
+	}.min(total)
+    }
+
+    /// Returns the indices of the selected targets, in configuration order
+    pub fn select(&self, targets: &[Resource]) -> Vec<usize> {
+	let mut strata: BTreeMap<Option<&str>, Vec<usize>> = BTreeMap::new();
+	for (i, target) in targets.iter().enumerate() {
+	    let key = if self.by_tag {
+		target.tags.as_ref().and_then(|tags| tags.first()).map(String::as_str)
+	    } else {
+		None
+	    };
+	    strata.entry(key).or_default().push(i);
+	}
+
+	// Divide the sample among strata in proportion to their size, giving
+	// the seats left over by rounding down to the largest remainders
+	let total = targets.len();
+	let wanted = self.wanted(total);
+	let mut quotas: Vec<(usize, usize)> = strata.values()
+	    .map(|members| (wanted * members.len() / total.max(1), wanted * members.len() % total.max(1)))
+	    .collect();
+	let mut left = wanted - quotas.iter().map(|(quota, _)| quota).sum::<usize>();
+	let mut order: Vec<usize> = (0..quotas.len()).collect();
+	order.sort_by(|&a, &b| quotas[b].1.cmp(&quotas[a].1));
+	for i in order {
+	    if left == 0 {
+		break
+	    }
+	    quotas[i].0 += 1;
+	    left -= 1;
+	}
+
+	let mut rng = Rng(self.seed);
+	let mut selected = Vec::with_capacity(wanted);
+	for (mut members, (quota, _)) in strata.into_values().zip(quotas) {
+	    // Partial Fisher-Yates shuffle of the first `quota` members
+	    for i in 0..quota.min(members.len()) {
+		let j = i + rng.below(members.len() - i);
+		members.swap(i, j);
+		selected.push(members[i]);
+	    }
+	}
+	selected.sort_unstable();
+	selected
+    }
+
+    /// Keeps only the selected targets. Returns how many were kept out of
+    /// how many.
+    pub fn apply(&self, targets: &mut Vec<Resource>) -> (usize, usize) {
+	let total = targets.len();
+	let selected = self.select(targets);
+	let mut index = 0;
+	targets.retain(|_| {
+	    let keep = selected.binary_search(&index).is_ok();
+	    index += 1;
+	    keep
+	});
+	(targets.len(), total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    /// Returns `count` targets, tagged in turn with each of `tags`
+    fn targets(count: usize, tags: &[&str]) -> Vec<Resource> {
+	let mut toml = String::new();
+	for i in 0..count {
+	    toml.push_str(&format!("[[target]]\ndesc = \"t{}\"\naddr = \"10.0.0.{}:22\"\nkind = \"Tcp\"\n", i, i));
+	    if !tags.is_empty() {
+		toml.push_str(&format!("tags = [\"{}\"]\n", tags[i % tags.len()]));
+	    }
+	}
+	config::parse(&toml).unwrap().0.target
+    }
+
+    fn sample(size: SampleSize, by_tag: bool, seed: u64) -> Sample {
+	Sample { size, by_tag, seed }
+    }
+
+    #[test]
+    fn sizes_are_parsed() {
+	assert_eq!("10%".parse(), Ok(SampleSize::Percent(10.0)));
+	assert_eq!(" 2.5 % ".parse(), Ok(SampleSize::Percent(2.5)));
+	assert_eq!("25".parse(), Ok(SampleSize::Count(25)));
+	for invalid in ["0%", "101%", "-5%", "0", "-1", "ten", ""] {
+	    assert!(invalid.parse::<SampleSize>().is_err(), "{}", invalid);
+	}
+    }
+
+    #[test]
+    fn the_same_seed_selects_the_same_targets() {
+	let targets = targets(100, &[]);
+	let first = sample(SampleSize::Count(10), false, 42).select(&targets);
+	assert_eq!(first.len(), 10);
+	assert_eq!(first, sample(SampleSize::Count(10), false, 42).select(&targets));
+	assert!(first.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", first);
+	let others: Vec<Vec<usize>> = (43..48).map(|seed| sample(SampleSize::Count(10), false, seed).select(&targets)).collect();
+	assert!(others.iter().any(|other| *other != first), "every seed selected {:?}", first);
+    }
+
+    #[test]
+    fn samples_larger_than_the_targets_keep_them_all() {
+	let mut targets = targets(5, &[]);
+	assert_eq!(sample(SampleSize::Count(50), false, 7).select(&targets), [0, 1, 2, 3, 4]);
+	assert_eq!(sample(SampleSize::Count(50), true, 7).apply(&mut targets), (5, 5));
+	assert!(sample(SampleSize::Count(3), false, 7).select(&[]).is_empty());
+    }
+
+    #[test]
+    fn percentages_round_up() {
+	let targets = targets(21, &[]);
+	assert_eq!(sample(SampleSize::Percent(10.0), false, 1).select(&targets).len(), 3);
+	assert_eq!(sample(SampleSize::Percent(100.0), false, 1).select(&targets).len(), 21);
+    }
+
+    #[test]
+    fn tags_keep_their_share() {
+	// 30 `web` targets for every 10 `db`
+	let targets = targets(40, &["web", "web", "web", "db"]);
+	let selected = sample(SampleSize::Count(8), true, 3).select(&targets);
+	let db = selected.iter().filter(|&&i| targets[i].tags.as_ref().unwrap()[0] == "db").count();
+	assert_eq!((selected.len(), db), (8, 2));
+    }
+
+    #[test]
+    fn apply_keeps_only_the_selected_targets() {
+	let mut targets = targets(10, &[]);
+	let chosen = sample(SampleSize::Count(4), false, 9);
+	let expected: Vec<String> = chosen.select(&targets).into_iter().map(|i| targets[i].desc.clone()).collect();
+	assert_eq!(chosen.apply(&mut targets), (4, 10));
+	let kept: Vec<String> = targets.iter().map(|t| t.desc.clone()).collect();
+	assert_eq!(kept, expected);
+    }
+}
@@ -0,0 +1,396 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Validation of JSON documents against a JSON Schema, for catching
+//! contract drift in the responses of HTTP checks. The common validation
+//! keywords are supported: `type`, `enum`, `const`, the numeric, string,
+//! array, and object constraints, `allOf`, `anyOf`, `oneOf`, `not`, and
+//! `$ref` within the same schema. `pattern` uses the `regex` crate.
+//! Unknown keywords, including `format`, are ignored.
+
+use serde_json::{Map, Value};
+
+use regex::Regex;
+
+/// Most violations listed in a failure
+const MAX_REPORTED: usize = 5;
+
+/// Validates `instance` against `schema`, returning a description of each
+/// violation found
+pub fn validate(schema: &Value, instance: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    Validator { root: schema }.check(schema, instance, "", &mut errors, 0);
+    errors
+}
+
+/// Summarizes violations for a failure message
+pub fn summarize(errors: &[String]) -> String {
+    let mut summary = errors.iter().take(MAX_REPORTED).cloned().collect::<Vec<_>>().join("; ");
+    if errors.len() > MAX_REPORTED {
+	summary.push_str(&format!("; and {} more", errors.len() - MAX_REPORTED));
+    }
+    summary
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+/// Returns the JSON type names `value` satisfies
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+	"null" => value.is_null(),
+	"boolean" => value.is_boolean(),
+	"object" => value.is_object(),
+	"array" => value.is_array(),
+	"string" => value.is_string(),
+	"number" => value.is_number(),
+	"integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+	_ => false,
+    }
+}
+
+/// Names the place in the instance being validated
+fn at(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
+impl<'a> Validator<'a> {
+    /// Resolves a `$ref` such as `#/$defs/item` within the root schema
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+	let pointer = reference.strip_prefix('#')?;
+	self.root.pointer(pointer)
+    }
+
+    fn check(&self, schema: &'a Value, instance: &Value, path: &str, errors: &mut Vec<String>, depth: usize) {
+	let schema = match schema {
+	    Value::Bool(true) => return,
+	    Value::Bool(false) => return errors.push(format!("{}: no value is allowed", at(path))),
+	    Value::Object(schema) => schema,
+	    _ => return,
+	};
+	if depth > 64 {
+	    return errors.push(format!("{}: schema references nest too deeply", at(path)))
+	}
+
+	if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+	    match self.resolve(reference) {
+		Some(target) => self.check(target, instance, path, errors, depth + 1),
+		None => errors.push(format!("{}: unresolvable $ref `{}`", at(path), reference)),
+	    }
+	}
+
+	if let Some(types) = schema.get("type") {
+	    let names: Vec<&str> = match types {
+		Value::String(name) => vec![name.as_str()],
+		Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+		_ => Vec::new(),
+	    };
+	    if !names.is_empty() && !names.iter().any(|name| is_type(instance, name)) {
+		errors.push(format!("{}: expected {}, found {}", at(path), names.join(" or "), kind(instance)));
+		return
+	    }
+	}
+	if let Some(Value::Array(allowed)) = schema.get("enum") {
+	    if !allowed.contains(instance) {
+		errors.push(format!("{}: {} is not one of {}", at(path), instance, Value::Array(allowed.clone())));
+	    }
+	}
+	if let Some(expected) = schema.get("const") {
+	    if expected != instance {
+		errors.push(format!("{}: expected {}, found {}", at(path), expected, instance));
+	    }
+	}
+
+	match instance {
+	    Value::Number(n) => self.check_number(schema, n.as_f64().unwrap_or_default(), path, errors),
+	    Value::String(s) => self.check_string(schema, s, path, errors),
+	    Value::Array(items) => self.check_array(schema, items, path, errors, depth),
+	    Value::Object(object) => self.check_object(schema, object, path, errors, depth),
+	    _ => (),
+	}
+
+	if let Some(Value::Array(all)) = schema.get("allOf") {
+	    for sub in all {
+		self.check(sub, instance, path, errors, depth + 1);
+	    }
+	}
+	if let Some(Value::Array(any)) = schema.get("anyOf") {
+	    if !any.iter().any(|sub| self.passes(sub, instance, path, depth)) {
+		errors.push(format!("{}: matches none of anyOf", at(path)));
+	    }
+	}
+	if let Some(Value::Array(one)) = schema.get("oneOf") {
+	    let matched = one.iter().filter(|sub| self.passes(sub, instance, path, depth)).count();
+	    if matched != 1 {
+		errors.push(format!("{}: matches {} of oneOf instead of exactly one", at(path), matched));
+	    }
+	}
+	if let Some(not) = schema.get("not") {
+	    if self.passes(not, instance, path, depth) {
+		errors.push(format!("{}: matches a schema it must not", at(path)));
+	    }
+	}
+    }
+
+    fn passes(&self, schema: &'a Value, instance: &Value, path: &str, depth: usize) -> bool {
+	let mut errors = Vec::new();
+	self.check(schema, instance, path, &mut errors, depth + 1);
+	errors.is_empty()
+    }
+
+    fn check_number(&self, schema: &Map<String, Value>, n: f64, path: &str, errors: &mut Vec<String>) {
+	let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+	if let Some(min) = bound("minimum").filter(|&min| n < min) {
+	    errors.push(format!("{}: {} is less than {}", at(path), n, min));
+	}
+	if let Some(max) = bound("maximum").filter(|&max| n > max) {
+	    errors.push(format!("{}: {} is greater than {}", at(path), n, max));
+	}
+	if let Some(min) = bound("exclusiveMinimum").filter(|&min| n <= min) {
+	    errors.push(format!("{}: {} is not greater than {}", at(path), n, min));
+	}
+	if let Some(max) = bound("exclusiveMaximum").filter(|&max| n >= max) {
+	    errors.push(format!("{}: {} is not less than {}", at(path), n, max));
+	}
+	if let Some(step) = bound("multipleOf").filter(|&step| step > 0.0 && (n / step).fract() != 0.0) {
+	    errors.push(format!("{}: {} is not a multiple of {}", at(path), n, step));
+	}
+    }
+
+    fn check_string(&self, schema: &Map<String, Value>, s: &str, path: &str, errors: &mut Vec<String>) {
+	let len = s.chars().count() as u64;
+	if let Some(min) = schema.get("minLength").and_then(Value::as_u64).filter(|&min| len < min) {
+	    errors.push(format!("{}: shorter than {} characters", at(path), min));
+	}
+	if let Some(max) = schema.get("maxLength").and_then(Value::as_u64).filter(|&max| len > max) {
+	    errors.push(format!("{}: longer than {} characters", at(path), max));
+	}
+	if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+	    match Regex::new(pattern) {
+		Ok(compiled) if !compiled.is_match(s) => {
+		    errors.push(format!("{}: \"{}\" does not match `{}`", at(path), s, pattern));
+		},
+		Ok(_) => (),
+		Err(e) => errors.push(format!("{}: invalid pattern `{}`: {}", at(path), pattern, e)),
+	    }
+	}
+    }
+
+    fn check_array(&self, schema: &'a Map<String, Value>, items: &[Value], path: &str, errors: &mut Vec<String>, depth: usize) {
+	let len = items.len() as u64;
+	if let Some(min) = schema.get("minItems").and_then(Value::as_u64).filter(|&min| len < min) {
+	    errors.push(format!("{}: fewer than {} items", at(path), min));
+	}
+	if let Some(max) = schema.get("maxItems").and_then(Value::as_u64).filter(|&max| len > max) {
+	    errors.push(format!("{}: more than {} items", at(path), max));
+	}
+	if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+	    let duplicated = items.iter().enumerate().any(|(i, item)| items[..i].contains(item));
+	    if duplicated {
+		errors.push(format!("{}: items are not unique", at(path)));
+	    }
+	}
+	let prefix = match schema.get("prefixItems") {
+	    Some(Value::Array(prefix)) => prefix.as_slice(),
+	    _ => &[],
+	};
+	for (i, item) in items.iter().enumerate() {
+	    let item_path = format!("{}/{}", path, i);
+	    match prefix.get(i) {
+		Some(sub) => self.check(sub, item, &item_path, errors, depth + 1),
+		None => if let Some(sub) = schema.get("items") {
+		    self.check(sub, item, &item_path, errors, depth + 1);
+		},
+	    }
+	}
+	if let Some(contains) = schema.get("contains") {
+	    if !items.iter().any(|item| self.passes(contains, item, path, depth)) {
+		errors.push(format!("{}: no item matches contains", at(path)));
+	    }
+	}
+    }
+
+    fn check_object(&self, schema: &'a Map<String, Value>, object: &Map<String, Value>, path: &str,
+		    errors: &mut Vec<String>, depth: usize) {
+	for name in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+	    if !object.contains_key(name) {
+		errors.push(format!("{}: missing required property `{}`", at(path), name));
+	    }
+	}
+	let len = object.len() as u64;
+	if let Some(min) = schema.get("minProperties").and_then(Value::as_u64).filter(|&min| len < min) {
+	    errors.push(format!("{}: fewer than {} properties", at(path), min));
+	}
+	if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64).filter(|&max| len > max) {
+	    errors.push(format!("{}: more than {} properties", at(path), max));
+	}
+
+	let properties = schema.get("properties").and_then(Value::as_object);
+	let mut patterns: Vec<(Regex, &Value)> = Vec::new();
+	for (pattern, sub) in schema.get("patternProperties").and_then(Value::as_object).into_iter().flatten() {
+	    match Regex::new(pattern) {
+		Ok(compiled) => patterns.push((compiled, sub)),
+		Err(e) => errors.push(format!("{}: invalid pattern `{}`: {}", at(path), pattern, e)),
+	    }
+	}
+	for (name, value) in object {
+	    let value_path = format!("{}/{}", path, name.replace('~', "~0").replace('/', "~1"));
+	    let mut known = false;
+	    if let Some(sub) = properties.and_then(|p| p.get(name)) {
+		known = true;
+		self.check(sub, value, &value_path, errors, depth + 1);
+	    }
+	    for (_, sub) in patterns.iter().filter(|(pattern, _)| pattern.is_match(name)) {
+		known = true;
+		self.check(sub, value, &value_path, errors, depth + 1);
+	    }
+	    match schema.get("additionalProperties") {
+		Some(Value::Bool(false)) if !known => {
+		    errors.push(format!("{}: unexpected property `{}`", at(path), name));
+		},
+		Some(sub @ Value::Object(_)) if !known => self.check(sub, value, &value_path, errors, depth + 1),
+		_ => (),
+	    }
+	}
+    }
+}
+
+/// Names the JSON type of `value` for messages
+fn kind(value: &Value) -> &'static str {
+    match value {
+	Value::Null => "null",
+	Value::Bool(_) => "boolean",
+	Value::Number(_) => "number",
+	Value::String(_) => "string",
+	Value::Array(_) => "array",
+	Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn errors(schema: Value, instance: Value) -> Vec<String> {
+	validate(&schema, &instance)
+    }
+
+    #[test]
+    fn types_are_checked() {
+	assert!(errors(json!({"type": "integer"}), json!(3.0)).is_empty());
+	assert_eq!(errors(json!({"type": "integer"}), json!(3.5)), ["/: expected integer, found number"]);
+	assert!(errors(json!({"type": ["string", "null"]}), json!(null)).is_empty());
+	assert_eq!(errors(json!({"type": ["string", "null"]}), json!([])), ["/: expected string or null, found array"]);
+	assert!(errors(json!(true), json!(1)).is_empty());
+	assert_eq!(errors(json!(false), json!(1)), ["/: no value is allowed"]);
+    }
+
+    #[test]
+    fn enum_and_const_are_checked() {
+	assert!(errors(json!({"enum": ["up", "down"]}), json!("up")).is_empty());
+	assert_eq!(errors(json!({"enum": ["up", "down"]}), json!("gone")), [r#"/: "gone" is not one of ["up","down"]"#]);
+	assert_eq!(errors(json!({"const": 2}), json!(3)), ["/: expected 2, found 3"]);
+    }
+
+    #[test]
+    fn numbers_are_bounded() {
+	let schema = json!({"minimum": 1, "maximum": 10, "exclusiveMinimum": 1, "exclusiveMaximum": 10, "multipleOf": 2});
+	assert!(errors(schema.clone(), json!(4)).is_empty());
+	assert_eq!(errors(schema.clone(), json!(1)), ["/: 1 is not greater than 1", "/: 1 is not a multiple of 2"]);
+	assert_eq!(errors(schema.clone(), json!(12)), ["/: 12 is greater than 10", "/: 12 is not less than 10"]);
+	assert_eq!(errors(schema, json!(0)), ["/: 0 is less than 1", "/: 0 is not greater than 1"]);
+    }
+
+    #[test]
+    fn strings_are_bounded() {
+	let schema = json!({"minLength": 2, "maxLength": 3, "pattern": "^[a-zé]+$"});
+	assert!(errors(schema.clone(), json!("éé")).is_empty());
+	assert_eq!(errors(schema.clone(), json!("a")), ["/: shorter than 2 characters"]);
+	assert_eq!(errors(schema, json!("ab1c")), ["/: longer than 3 characters", r#"/: "ab1c" does not match `^[a-zé]+$`"#]);
+    }
+
+    #[test]
+    fn arrays_are_checked() {
+	let schema = json!({
+	    "minItems": 2, "maxItems": 3, "uniqueItems": true,
+	    "prefixItems": [{"type": "string"}], "items": {"type": "integer"},
+	    "contains": {"const": 7},
+	});
+	assert!(errors(schema.clone(), json!(["a", 7])).is_empty());
+	assert_eq!(errors(schema.clone(), json!([1])), ["/: fewer than 2 items", "/0: expected string, found number", "/: no item matches contains"]);
+	assert_eq!(errors(schema, json!(["a", 7, 7, "b"])), ["/: more than 3 items", "/: items are not unique", "/3: expected integer, found string"]);
+    }
+
+    #[test]
+    fn objects_are_checked() {
+	let schema = json!({
+	    "required": ["id"], "minProperties": 1, "maxProperties": 3,
+	    "properties": {"id": {"type": "integer"}},
+	    "patternProperties": {"^x-": {"type": "string"}},
+	    "additionalProperties": false,
+	});
+	assert!(errors(schema.clone(), json!({"id": 1, "x-trace": "abc"})).is_empty());
+	assert_eq!(errors(schema.clone(), json!({"x-trace": 1, "a/b": 0})), [
+	    "/: missing required property `id`",
+	    "/: unexpected property `a/b`",
+	    "/x-trace: expected string, found number",
+	]);
+	assert_eq!(errors(schema, json!({"id": 1, "x-a": "", "x-b": "", "x-c": ""})), ["/: more than 3 properties"]);
+	let nested = json!({"additionalProperties": {"type": "boolean"}});
+	assert_eq!(errors(nested, json!({"a~b": 1})), ["/a~0b: expected boolean, found number"]);
+    }
+
+    #[test]
+    fn combinators_are_checked() {
+	let schema = json!({
+	    "allOf": [{"type": "number"}, {"minimum": 0}],
+	    "anyOf": [{"maximum": 10}, {"multipleOf": 100}],
+	    "oneOf": [{"multipleOf": 2}, {"multipleOf": 3}],
+	    "not": {"const": 4},
+	});
+	assert!(errors(schema.clone(), json!(200)).is_empty());
+	assert_eq!(errors(schema.clone(), json!(-3)), ["/: -3 is less than 0"]);
+	assert_eq!(errors(schema.clone(), json!(6)), ["/: matches 2 of oneOf instead of exactly one"]);
+	assert_eq!(errors(schema.clone(), json!(4)), ["/: matches a schema it must not"]);
+	assert_eq!(errors(schema, json!(53)), ["/: matches none of anyOf", "/: matches 0 of oneOf instead of exactly one"]);
+    }
+
+    #[test]
+    fn references_are_resolved() {
+	let schema = json!({
+	    "$defs": {"item": {"type": "object", "required": ["id"], "properties": {"children": {"items": {"$ref": "#/$defs/item"}}}}},
+	    "$ref": "#/$defs/item",
+	});
+	assert!(errors(schema.clone(), json!({"id": 1, "children": [{"id": 2, "children": []}]})).is_empty());
+	assert_eq!(errors(schema, json!({"id": 1, "children": [{}]})), ["/children/0: missing required property `id`"]);
+    }
+
+    #[test]
+    fn malformed_schemas_are_reported() {
+	assert_eq!(errors(json!({"$ref": "#/missing"}), json!(1)), ["/: unresolvable $ref `#/missing`"]);
+	assert_eq!(errors(json!({"$ref": "other.json"}), json!(1)), ["/: unresolvable $ref `other.json`"]);
+	let looping = errors(json!({"$ref": "#"}), json!(1));
+	assert!(looping.iter().all(|e| e == "/: schema references nest too deeply"));
+	assert!(errors(json!({"pattern": "("}), json!("a"))[0].starts_with("/: invalid pattern `(`"));
+	assert!(errors(json!({"patternProperties": {"(": {}}}), json!({"a": 1}))[0].starts_with("/: invalid pattern `(`"));
+	// Unknown keywords, and known ones of the wrong kind, are ignored
+	assert!(errors(json!({"format": "email", "minimum": "1"}), json!(0)).is_empty());
+    }
+
+    #[test]
+    fn summary_is_limited() {
+	let errors: Vec<String> = (1..=7).map(|i| i.to_string()).collect();
+	assert_eq!(summarize(&errors), "1; 2; 3; 4; 5; and 2 more");
+	assert_eq!(summarize(&errors[..2]), "1; 2");
+    }
+}
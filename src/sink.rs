@@ -0,0 +1,724 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Destinations for the results of a run. Every sink implements
+//! [`OutputSink`], and any number of them may be configured with `[[sink]]`
+//! tables, each receiving every run.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use ring::digest;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{NetworkResources, Resource};
+use crate::notify::{Notifier, Severity};
+use crate::outcome::{CheckResult, Outcome, Verbosity};
+use crate::report;
+use crate::suite::Counts;
+
+/// Receives the results of each run of a configuration
+pub trait OutputSink: Send {
+    /// Handles a completed run of the configuration called `name`
+    fn emit(&mut self, name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Completes a round once every configuration the sink receives has
+    /// been emitted, for sinks writing one report of them all
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+	Ok(())
+    }
+}
+
+/// Provides a deserialize target for the `[[sink]]` tables of a
+/// configuration, selected by their `type`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// Prints a row per target, each prefixed with the time of the run
+    /// when `timestamps` is set. Rows are colored when `color` is set, or
+    /// by default when standard output is a terminal and `NO_COLOR` is
+    /// unset. `verbosity` leaves out passing rows when `quiet`, and adds
+    /// the diagnostics of each check when `verbose` or `very_verbose`.
+    Console { timestamps: Option<bool>, color: Option<bool>, verbosity: Option<Verbosity> },
+    /// Writes the JSON summary of each run as a line to `path`, or to
+    /// standard output when no path is given
+    Json { path: Option<PathBuf> },
+    /// Replaces `path` with the Prometheus text format metrics of each run,
+    /// e.g. for the node_exporter textfile collector
+    Prometheus { path: PathBuf },
+    /// POSTs the JSON summary of each run, signed and retried like a
+    /// notifier
+    Webhook(Notifier),
+    /// Sends a message per target to syslog, through `server` as
+    /// `host:port` over UDP, or the local `/dev/log` otherwise
+    Syslog { server: Option<String>, tag: Option<String> },
+    /// Prints a GitHub Actions workflow command per failed or skipped
+    /// target, annotating the configuration line defining it
+    Github,
+    /// Replaces `path`, `gl-code-quality-report.json` if not given, with a
+    /// GitLab Code Quality report of the failed targets
+    Gitlab { path: Option<PathBuf> },
+    /// Replaces `path`, `connchk-junit.xml` if not given, with a JUnit XML
+    /// report holding a test case per target
+    Junit { path: Option<PathBuf> },
+    /// Writes a TAP stream with a test point per target to standard output,
+    /// or replaces `path` with it
+    Tap { path: Option<PathBuf> },
+}
+
+impl FromStr for SinkConfig {
+    type Err = String;
+
+    /// Parses the `--annotate` formats, `github` and `gitlab`
+    fn from_str(s: &str) -> Result<SinkConfig, String> {
+	match s.to_ascii_lowercase().as_str() {
+	    "github" => Ok(SinkConfig::Github),
+	    "gitlab" => Ok(SinkConfig::Gitlab { path: None }),
+	    _ => Err(format!("`{}` is not an annotation format, expected github or gitlab", s)),
+	}
+    }
+}
+
+impl SinkConfig {
+    /// Returns true when the sink writes to standard output
+    pub fn prints(&self) -> bool {
+	matches!(self, SinkConfig::Console { .. } | SinkConfig::Github
+		 | SinkConfig::Json { path: None } | SinkConfig::Tap { path: None })
+    }
+
+    /// Returns the report a sink gathers the runs of several configurations
+    /// into, for sinks that must be shared by every configuration selecting
+    /// the same report and finished once they have all been emitted
+    pub fn shared_report(&self) -> Option<String> {
+	match self {
+	    SinkConfig::Gitlab { path } => Some(format!("gitlab {}", path.as_deref().unwrap_or(GITLAB_REPORT.as_ref()).display())),
+	    SinkConfig::Junit { path } => Some(format!("junit {}", path.as_deref().unwrap_or(JUNIT_REPORT.as_ref()).display())),
+	    SinkConfig::Tap { path: Some(path) } => Some(format!("tap {}", path.display())),
+	    SinkConfig::Tap { path: None } => Some("tap -".to_string()),
+	    _ => None,
+	}
+    }
+
+    /// Returns the sink described by this configuration
+    pub fn build(&self) -> Box<dyn OutputSink> {
+	match self {
+	    SinkConfig::Console { timestamps, color, verbosity } => Box::new(Console {
+		timestamps: timestamps.unwrap_or(false),
+		color: color.unwrap_or_else(|| io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()),
+		verbosity: verbosity.unwrap_or_default(),
+	    }),
+	    SinkConfig::Json { path } => Box::new(Json { path: path.clone() }),
+	    SinkConfig::Prometheus { path } => Box::new(Prometheus { path: path.clone() }),
+	    SinkConfig::Webhook(notifier) => Box::new(Webhook { notifier: notifier.clone() }),
+	    SinkConfig::Syslog { server, tag } => Box::new(Syslog {
+		server: server.clone(),
+		tag: tag.clone().unwrap_or_else(|| "connchk".to_string()),
+	    }),
+	    SinkConfig::Github => Box::new(Github),
+	    SinkConfig::Gitlab { path } => Box::new(Gitlab {
+		path: path.clone().unwrap_or_else(|| PathBuf::from(GITLAB_REPORT)),
+		issues: BTreeMap::new(),
+	    }),
+	    SinkConfig::Junit { path } => Box::new(Junit {
+		path: path.clone().unwrap_or_else(|| PathBuf::from(JUNIT_REPORT)),
+		suites: BTreeMap::new(),
+	    }),
+	    SinkConfig::Tap { path } => Box::new(Tap { path: path.clone(), points: BTreeMap::new() }),
+	}
+    }
+}
+
+/// Prints a table of each target's result, with a badge for its outcome,
+/// its description, latency, and any error or details, followed by a
+/// summary. Passing rows are left out at [`Verbosity::Quiet`], and trace
+/// lines up to the verbosity are added under each note. Warns about checks
+/// queued for lack of local resources.
+#[derive(Debug, Default)]
+pub struct Console {
+    pub timestamps: bool,
+    pub color: bool,
+    pub verbosity: Verbosity,
+}
+
+impl Console {
+    /// Returns the badge of `outcome`, colored when enabled
+    fn badge(&self, outcome: Outcome) -> String {
+	let (text, color) = match outcome {
+	    Outcome::Passed => ("PASS", "32"),
+	    Outcome::Failed => ("FAIL", "1;31"),
+	    Outcome::Skipped => ("SKIP", "33"),
+	    Outcome::Unknown => ("UNKN", "35"),
+	};
+	match self.color {
+	    true => format!("\x1b[{}m{}\x1b[0m", color, text),
+	    false => text.to_string(),
+	}
+    }
+
+    /// Returns the last column of a checked target's row: its custom success
+    /// message or details when it passed, and its error otherwise, after any
+    /// alias or retries and followed by any retries, hint, or runbook lines
+    /// of its message
+    fn note(target: &Resource, result: &CheckResult) -> String {
+	let res = target.res.as_deref().unwrap_or_default();
+	let first = match result.outcome {
+	    Outcome::Passed if target.success_message.is_some() => res.lines().next().unwrap_or_default(),
+	    Outcome::Passed => result.details.as_deref().unwrap_or_default(),
+	    _ => result.error.as_deref().unwrap_or_default(),
+	};
+	let first = first.split_whitespace().collect::<Vec<_>>().join(" ");
+	let context = format!("{}{}", target.alias_note(), target.attempts_note());
+	let mut note = match (context.trim_start(), first.as_str()) {
+	    ("", first) => first.to_string(),
+	    (context, "") => context.to_string(),
+	    (context, first) => format!("{}: {}", context, first),
+	};
+	for line in res.lines().skip(1) {
+	    note.push_str(&format!("\n{}", line.trim()));
+	}
+	note
+    }
+
+    /// Returns the note of a row followed by the trace lines shown at the
+    /// sink's verbosity
+    fn traced_note(&self, target: &Resource, result: &CheckResult) -> String {
+	let mut note = Console::note(target, result);
+	for (_, line) in result.trace.iter().filter(|(level, _)| *level <= self.verbosity) {
+	    note.push_str(&format!("\n{}", line));
+	}
+	note.trim_start_matches('\n').to_string()
+    }
+}
+
+impl OutputSink for Console {
+    fn emit(&mut self, _name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	let prefix = match self.timestamps {
+	    true => format!("[{}] ", report::timestamp(SystemTime::now())),
+	    false => String::new(),
+	};
+	let checked: Vec<(&Resource, &CheckResult)> = resources.target.iter()
+	    .filter_map(|t| Some((t, t.result.as_ref()?)))
+	    .filter(|(_, r)| self.verbosity > Verbosity::Quiet || r.outcome != Outcome::Passed)
+	    .collect();
+	let smoothing = resources.smoothing.unwrap_or_default().label();
+	let latency = |result: &CheckResult| match (result.latency, smoothing, result.smoothed_ms) {
+	    (Some(latency), Some(label), Some(smoothed)) => format!("{}ms ({} {:.0}ms)", latency.as_millis(), label, smoothed),
+	    (Some(latency), _, _) => format!("{}ms", latency.as_millis()),
+	    (None, _, _) => "-".to_string(),
+	};
+	let desc_width = checked.iter().map(|(t, _)| t.desc.chars().count()).max().unwrap_or(0);
+	let latency_width = checked.iter().map(|(_, r)| latency(r).len()).max().unwrap_or(0);
+	// Continuation lines of a note start under its first line
+	let indent = " ".repeat(prefix.chars().count() + 4 + 2 + desc_width + 2 + latency_width + 2);
+	for (target, result) in checked.iter() {
+	    let note = self.traced_note(target, result).replace('\n', &format!("\n{}", indent));
+	    let row = format!("{}{}  {:<desc_width$}  {:>latency_width$}  {}", prefix, self.badge(result.outcome),
+			      target.desc, latency(result), note);
+	    println!("{}", row.trim_end());
+	}
+	let counts = Counts::of(resources);
+	if counts.checked > 0 {
+	    println!("{}{}", prefix, counts);
+	}
+	let queued = resources.target.iter().filter(|t| t.requeued > 0).count();
+	if queued > 0 {
+	    eprintln!("Warning: {} checks were queued because this host ran out of file descriptors or \
+		       ephemeral ports. Lower the concurrency or raise `ulimit -n`.", queued);
+	}
+	Ok(())
+    }
+}
+
+/// Writes JSON summaries as lines of a file or standard output
+pub struct Json {
+    pub path: Option<PathBuf>,
+}
+
+impl OutputSink for Json {
+    fn emit(&mut self, name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	let line = report::summary(name, resources).to_string();
+	match &self.path {
+	    Some(path) => {
+		let mut file = OpenOptions::new().create(true).append(true).open(path)
+		    .map_err(|e| format!("{}: {}", path.display(), e))?;
+		writeln!(file, "{}", line)?;
+	    },
+	    None => println!("{}", line),
+	}
+	Ok(())
+    }
+}
+
+/// Keeps a file of Prometheus metrics up to date
+pub struct Prometheus {
+    pub path: PathBuf,
+}
+
+impl OutputSink for Prometheus {
+    fn emit(&mut self, name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	// Written aside and renamed so collectors never read a partial file
+	let partial = self.path.with_extension("prom.partial");
+	fs::write(&partial, report::prometheus(&[(name.to_string(), resources)]))
+	    .and_then(|_| fs::rename(&partial, &self.path))
+	    .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+	Ok(())
+    }
+}
+
+/// POSTs JSON summaries to a URL
+pub struct Webhook {
+    pub notifier: Notifier,
+}
+
+impl OutputSink for Webhook {
+    fn emit(&mut self, name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	self.notifier.send(&report::summary(name, resources))
+    }
+}
+
+/// Sends RFC 3164 messages to syslog
+pub struct Syslog {
+    pub server: Option<String>,
+    pub tag: String,
+}
+
+/// Priority of messages for passed, failed, and unknown checks: the user
+/// facility with the informational, error, and warning severities
+const SYSLOG_INFO: u8 = 14;
+const SYSLOG_ERR: u8 = 11;
+const SYSLOG_WARNING: u8 = 12;
+
+impl Syslog {
+    /// Delivers a single formatted message
+    fn send(&self, message: &str) -> std::io::Result<()> {
+	match &self.server {
+	    Some(server) => {
+		let socket = UdpSocket::bind("0.0.0.0:0")?;
+		socket.send_to(message.as_bytes(), server.as_str())?;
+	    },
+	    #[cfg(unix)]
+	    None => {
+		let socket = std::os::unix::net::UnixDatagram::unbound()?;
+		socket.send_to(message.as_bytes(), "/dev/log")?;
+	    },
+	    #[cfg(not(unix))]
+	    None => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+						   "a syslog server is required on this platform")),
+	}
+	Ok(())
+    }
+}
+
+impl OutputSink for Syslog {
+    fn emit(&mut self, _name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	for target in resources.target.iter() {
+	    if let Some(result) = &target.res {
+		let priority = match target.outcome() {
+		    Outcome::Failed => SYSLOG_ERR,
+		    Outcome::Unknown => SYSLOG_WARNING,
+		    Outcome::Passed | Outcome::Skipped => SYSLOG_INFO,
+		};
+		let text = result.split_whitespace().collect::<Vec<_>>().join(" ");
+		let message = format!("<{}>{}[{}]: {}", priority, self.tag, std::process::id(), text);
+		self.send(&message).map_err(|e| format!("syslog: {}", e))?;
+	    }
+	}
+	Ok(())
+    }
+}
+
+/// Prints GitHub Actions `::error` and `::warning` workflow commands
+pub struct Github;
+
+/// Escapes the message of a workflow command
+fn escape_data(text: &str) -> String {
+    text.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Escapes a property of a workflow command, such as its `title`
+fn escape_property(text: &str) -> String {
+    escape_data(text).replace(':', "%3A").replace(',', "%2C")
+}
+
+impl OutputSink for Github {
+    fn emit(&mut self, _name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	for target in resources.target.iter() {
+	    let command = match target.outcome() {
+		Outcome::Failed => "error",
+		Outcome::Skipped | Outcome::Unknown => "warning",
+		Outcome::Passed => continue,
+	    };
+	    let mut properties = Vec::new();
+	    if let Some(origin) = &target.origin {
+		properties.push(format!("file={}", escape_property(&origin.path.to_string_lossy())));
+		if let Some(line) = origin.line {
+		    properties.push(format!("line={}", line));
+		}
+	    }
+	    properties.push(format!("title={}", escape_property(&format!("connchk: {}", target.desc))));
+	    let message = target.res.as_deref().unwrap_or_default();
+	    println!("::{} {}::{}", command, properties.join(","), escape_data(message.trim()));
+	}
+	Ok(())
+    }
+}
+
+/// Default path of the GitLab Code Quality report
+pub const GITLAB_REPORT: &str = "gl-code-quality-report.json";
+
+/// Writes a GitLab Code Quality report of every configuration emitted in a
+/// round
+pub struct Gitlab {
+    pub path: PathBuf,
+    /// Issues of each configuration emitted in the current round
+    issues: BTreeMap<String, Vec<Value>>,
+}
+
+impl Gitlab {
+    /// Describes a failed target as a Code Quality issue. The fingerprint
+    /// identifies the target across runs so merge requests show only new
+    /// failures.
+    fn issue(name: &str, target: &Resource) -> Value {
+	let key = format!("{}\0{}\0{}", name, target.desc, target.addr);
+	let fingerprint: String = digest::digest(&digest::SHA256, key.as_bytes()).as_ref().iter()
+	    .map(|b| format!("{:02x}", b))
+	    .collect();
+	let severity = match target.severity {
+	    Some(Severity::Info) => "info",
+	    Some(Severity::Warning) => "minor",
+	    Some(Severity::Critical) => "critical",
+	    None => "major",
+	};
+	let (path, line) = match &target.origin {
+	    Some(origin) => (origin.path.to_string_lossy().into_owned(), origin.line.unwrap_or(1)),
+	    None => (name.to_string(), 1),
+	};
+	let result = target.res.as_deref().unwrap_or_default();
+	json!({
+	    "description": result.split_whitespace().collect::<Vec<_>>().join(" "),
+	    "check_name": "connchk",
+	    "fingerprint": fingerprint,
+	    "severity": severity,
+	    "location": { "path": path, "lines": { "begin": line } },
+	})
+    }
+}
+
+impl OutputSink for Gitlab {
+    fn emit(&mut self, name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	let issues = resources.target.iter()
+	    .filter(|target| target.outcome() == Outcome::Failed)
+	    .map(|target| Gitlab::issue(name, target))
+	    .collect();
+	self.issues.insert(name.to_string(), issues);
+	Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+	let issues = std::mem::take(&mut self.issues);
+	let report: Vec<&Value> = issues.values().flatten().collect();
+	fs::write(&self.path, serde_json::to_string_pretty(&report)?)
+	    .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+	Ok(())
+    }
+}
+
+/// Default path of the JUnit XML report
+pub const JUNIT_REPORT: &str = "connchk-junit.xml";
+
+/// A rendered `testsuite` element and its counts
+struct Suite {
+    tests: usize,
+    failures: usize,
+    errors: usize,
+    skipped: usize,
+    time: f64,
+    xml: String,
+}
+
+/// Writes a JUnit XML report, which CI systems such as Jenkins and GitLab
+/// render as test results. Failed checks are failures, checks whose state
+/// is unknown are errors, and skipped checks are skipped. Each configuration
+/// emitted in a round is a test suite of the report.
+pub struct Junit {
+    pub path: PathBuf,
+    /// Test suite of each configuration emitted in the current round
+    suites: BTreeMap<String, Suite>,
+}
+
+/// Escapes text for an XML attribute or element, dropping control
+/// characters XML cannot hold
+fn escape_xml(text: &str) -> String {
+    text.chars()
+	.filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+	.map(|c| match c {
+	    '&' => "&amp;".to_string(),
+	    '<' => "&lt;".to_string(),
+	    '>' => "&gt;".to_string(),
+	    '"' => "&quot;".to_string(),
+	    '\'' => "&apos;".to_string(),
+	    c => c.to_string(),
+	})
+	.collect()
+}
+
+impl Junit {
+    /// Renders the `testcase` element of a checked target
+    fn testcase(target: &Resource) -> String {
+	let result = target.result.as_ref();
+	let time = result.and_then(|r| r.latency).map_or(0.0, |l| l.as_secs_f64());
+	let mut attrs = format!("name=\"{}\" classname=\"connchk.{:?}\" time=\"{:.3}\"",
+				escape_xml(&target.desc), target.kind, time);
+	if let Some(origin) = &target.origin {
+	    attrs.push_str(&format!(" file=\"{}\"", escape_xml(&origin.path.to_string_lossy())));
+	    if let Some(line) = origin.line {
+		attrs.push_str(&format!(" line=\"{}\"", line));
+	    }
+	}
+	let message = result.and_then(|r| r.error.as_deref()).unwrap_or_default();
+	let message = escape_xml(&message.split_whitespace().collect::<Vec<_>>().join(" "));
+	let code = escape_xml(target.error_code.as_deref().unwrap_or("error"));
+	let text = escape_xml(target.res.as_deref().unwrap_or_default().trim());
+	let body = match target.outcome() {
+	    Outcome::Passed => return format!("    <testcase {}>\n      <system-out>{}</system-out>\n    </testcase>\n", attrs, text),
+	    Outcome::Failed => format!("<failure message=\"{}\" type=\"{}\">{}</failure>", message, code, text),
+	    Outcome::Unknown => format!("<error message=\"{}\" type=\"{}\">{}</error>", message, code, text),
+	    Outcome::Skipped => format!("<skipped message=\"{}\"/>", message),
+	};
+	format!("    <testcase {}>\n      {}\n    </testcase>\n", attrs, body)
+    }
+}
+
+impl OutputSink for Junit {
+    fn emit(&mut self, name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	let checked: Vec<&Resource> = resources.target.iter().filter(|t| t.result.is_some()).collect();
+	let count = |outcome: Outcome| checked.iter().filter(|t| t.outcome() == outcome).count();
+	let time = resources.stats.last_run.as_secs_f64();
+	let mut xml = format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" \
+			       time=\"{:.3}\" timestamp=\"{}\">\n",
+			      escape_xml(name), checked.len(), count(Outcome::Failed), count(Outcome::Unknown),
+			      count(Outcome::Skipped), time, report::timestamp(SystemTime::now()).trim_end_matches('Z'));
+	for target in checked.iter() {
+	    xml.push_str(&Junit::testcase(target));
+	}
+	xml.push_str("  </testsuite>\n");
+	let suite = Suite {
+	    tests: checked.len(),
+	    failures: count(Outcome::Failed),
+	    errors: count(Outcome::Unknown),
+	    skipped: count(Outcome::Skipped),
+	    time,
+	    xml,
+	};
+
+	self.suites.insert(name.to_string(), suite);
+	Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+	let all = std::mem::take(&mut self.suites);
+	let mut report = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+				  <testsuites name=\"connchk\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+				 all.values().map(|s| s.tests).sum::<usize>(), all.values().map(|s| s.failures).sum::<usize>(),
+				 all.values().map(|s| s.errors).sum::<usize>(), all.values().map(|s| s.skipped).sum::<usize>(),
+				 all.values().map(|s| s.time).sum::<f64>());
+	for suite in all.values() {
+	    report.push_str(&suite.xml);
+	}
+	report.push_str("</testsuites>\n");
+	fs::write(&self.path, report).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+	Ok(())
+    }
+}
+
+/// Writes TAP (Test Anything Protocol) version 13, consumed by harnesses
+/// such as `prove`. Failed checks and those whose state is unknown are
+/// `not ok`, and skipped checks are `ok` with a SKIP directive. The
+/// configurations emitted in a round are numbered as one stream, with a
+/// single header and plan.
+pub struct Tap {
+    pub path: Option<PathBuf>,
+    /// Test points of each configuration emitted in the current round
+    points: BTreeMap<String, Vec<(bool, String)>>,
+}
+
+impl Tap {
+    /// Returns whether a checked target is `ok`, and the rest of its test
+    /// point after the number: the description, any directive, and the
+    /// diagnostics
+    fn point(target: &Resource) -> (bool, String) {
+	// `#` would start a directive, so descriptions escape it
+	let desc = target.desc.split_whitespace().collect::<Vec<_>>().join(" ").replace('#', "\\#");
+	let error = target.result.as_ref().and_then(|r| r.error.as_deref()).unwrap_or_default();
+	let mut diagnostics = String::new();
+	for line in error.lines().map(str::trim).filter(|line| !line.is_empty()) {
+	    diagnostics.push_str(&format!("# {}\n", line));
+	}
+	match target.outcome() {
+	    Outcome::Passed => (true, format!(" - {}\n", desc)),
+	    Outcome::Skipped => (true, format!(" - {} # SKIP {}\n", desc, error.replace('\n', " "))),
+	    Outcome::Failed | Outcome::Unknown => {
+		let code = target.error_code.as_deref().unwrap_or("error");
+		let state = if target.outcome() == Outcome::Unknown { "unknown" } else { "failed" };
+		(false, format!(" - {}\n# {}: {} ({})\n{}", desc, state, target.addr, code, diagnostics))
+	    },
+	}
+    }
+
+    /// Renders the test points of each named configuration as one stream
+    fn render(runs: &BTreeMap<String, Vec<(bool, String)>>) -> String {
+	let total: usize = runs.values().map(Vec::len).sum();
+	let mut out = format!("TAP version 13\n1..{}\n", total);
+	let mut number = 0;
+	for (name, points) in runs.iter() {
+	    out.push_str(&format!("# {}\n", name));
+	    for (ok, rest) in points.iter() {
+		number += 1;
+		out.push_str(&format!("{} {}{}", if *ok { "ok" } else { "not ok" }, number, rest));
+	    }
+	}
+	out
+    }
+}
+
+impl OutputSink for Tap {
+    fn emit(&mut self, name: &str, resources: &NetworkResources) -> Result<(), Box<dyn std::error::Error>> {
+	let points: Vec<(bool, String)> = resources.target.iter()
+	    .filter(|t| t.result.is_some())
+	    .map(Tap::point)
+	    .collect();
+	self.points.insert(name.to_string(), points);
+	Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+	let stream = Tap::render(&std::mem::take(&mut self.points));
+	match &self.path {
+	    Some(path) => fs::write(path, stream).map_err(|e| format!("{}: {}", path.display(), e))?,
+	    None => print!("{}", stream),
+	}
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    /// Returns a checked configuration with a passing and a failing target
+    fn checked() -> NetworkResources {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+	let (mut resources, _) = config::parse(&format!(
+	    "[[target]]\ndesc = \"up\"\naddr = \"{}\"\nkind = \"Tcp\"\n\
+	     [[target]]\ndesc = \"down\"\naddr = \"{}\"\nkind = \"Tcp\"\n",
+	    listener.local_addr().unwrap(), closed)).unwrap();
+	resources.run_checks();
+	resources
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+	env::temp_dir().join(format!("connchk-sink-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn gitlab_reports_keep_to_their_own_sink() {
+	let resources = checked();
+	let (first, second) = (temp_path("first.json"), temp_path("second.json"));
+	let mut a = SinkConfig::Gitlab { path: Some(first.clone()) }.build();
+	let mut b = SinkConfig::Gitlab { path: Some(second.clone()) }.build();
+	a.emit("prod", &resources).unwrap();
+	a.emit("lab", &resources).unwrap();
+	b.emit("edge", &resources).unwrap();
+	a.finish().unwrap();
+	b.finish().unwrap();
+	let read = |path: &PathBuf| -> Vec<Value> { serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap() };
+	assert_eq!(read(&first).len(), 2);
+	assert_eq!(read(&second).len(), 1);
+	assert_eq!(read(&second)[0]["severity"], "major");
+
+	// Each round starts afresh
+	a.emit("prod", &resources).unwrap();
+	a.finish().unwrap();
+	assert_eq!(read(&first).len(), 1);
+	fs::remove_file(first).unwrap();
+	fs::remove_file(second).unwrap();
+    }
+
+    #[test]
+    fn junit_reports_keep_to_their_own_sink() {
+	let resources = checked();
+	let (first, second) = (temp_path("first.xml"), temp_path("second.xml"));
+	let mut a = SinkConfig::Junit { path: Some(first.clone()) }.build();
+	let mut b = SinkConfig::Junit { path: Some(second.clone()) }.build();
+	a.emit("prod", &resources).unwrap();
+	a.emit("lab", &resources).unwrap();
+	b.emit("edge", &resources).unwrap();
+	a.finish().unwrap();
+	b.finish().unwrap();
+	let first_xml = fs::read_to_string(&first).unwrap();
+	assert!(first_xml.contains("<testsuites name=\"connchk\" tests=\"4\" failures=\"2\" errors=\"0\" skipped=\"0\""),
+		"{}", first_xml);
+	assert!(first_xml.contains("<testsuite name=\"lab\"") && first_xml.contains("<testsuite name=\"prod\""));
+	assert!(!first_xml.contains("\"edge\""), "{}", first_xml);
+	let second_xml = fs::read_to_string(&second).unwrap();
+	assert!(second_xml.contains("tests=\"2\" failures=\"1\""), "{}", second_xml);
+	assert_eq!(second_xml.matches("<testsuite ").count(), 1);
+
+	// Each round starts afresh
+	a.emit("lab", &resources).unwrap();
+	a.finish().unwrap();
+	let first_xml = fs::read_to_string(&first).unwrap();
+	assert!(!first_xml.contains("\"prod\""), "{}", first_xml);
+	fs::remove_file(first).unwrap();
+	fs::remove_file(second).unwrap();
+    }
+
+    #[test]
+    fn tap_numbers_every_configuration_in_one_stream() {
+	let resources = checked();
+	let path = temp_path("run.tap");
+	let mut tap = SinkConfig::Tap { path: Some(path.clone()) }.build();
+	tap.emit("prod", &resources).unwrap();
+	tap.emit("lab", &resources).unwrap();
+	tap.finish().unwrap();
+	let stream = fs::read_to_string(&path).unwrap();
+	assert!(stream.starts_with("TAP version 13\n1..4\n# lab\nok 1 - up\nnot ok 2 - down\n"), "{}", stream);
+	assert!(stream.contains("# prod\nok 3 - up\nnot ok 4 - down\n"), "{}", stream);
+	assert_eq!(stream.matches("TAP version 13").count(), 1);
+
+	// Each round starts afresh
+	tap.emit("lab", &resources).unwrap();
+	tap.finish().unwrap();
+	let stream = fs::read_to_string(&path).unwrap();
+	assert!(stream.starts_with("TAP version 13\n1..2\n# lab\n"), "{}", stream);
+	assert!(!stream.contains("# prod"), "{}", stream);
+	fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_report_sinks_are_shared() {
+	assert_eq!(SinkConfig::Gitlab { path: None }.shared_report().as_deref(),
+		   Some("gitlab gl-code-quality-report.json"));
+	assert_eq!(SinkConfig::Junit { path: Some(PathBuf::from("out.xml")) }.shared_report().as_deref(),
+		   Some("junit out.xml"));
+	assert_eq!(SinkConfig::Tap { path: None }.shared_report().as_deref(), Some("tap -"));
+	assert_eq!(SinkConfig::Json { path: None }.shared_report(), None);
+	assert_eq!(SinkConfig::Github.shared_report(), None);
+    }
+}
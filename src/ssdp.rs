@@ -0,0 +1,156 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! UPnP discovery checks. An SSDP M-SEARCH is multicast on the local segment
+//! and the devices answering it are matched against those expected, which
+//! catches the multicast breakage that usually takes AV and IoT VLANs down.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use socket2::SockRef;
+
+use crate::addr;
+use crate::error::{self, Stage};
+
+/// Seconds devices may wait before answering when no `mx` is given
+const DEFAULT_MX: u8 = 2;
+
+/// Copies of the search sent, as multicast datagrams are easily lost
+const SEARCHES: usize = 2;
+
+/// Provides a deserialize target for SSDP checks. All fields are optional.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SsdpOptions {
+    /// Search target, e.g. `upnp:rootdevice` or
+    /// `urn:schemas-upnp-org:device:MediaRenderer:1`. Defaults to `ssdp:all`.
+    pub st: Option<String>,
+    /// Seconds devices may wait before answering, 2 if not given
+    pub mx: Option<u8>,
+    /// Devices that must answer, each matched against the address of a
+    /// response or its `USN`, `SERVER`, `LOCATION`, and `ST` headers
+    pub expect: Option<Vec<String>>,
+    /// Responses needed to pass when nothing is expected, 1 if not given
+    pub min_responses: Option<usize>,
+    /// Local address of the interface to search from
+    pub interface: Option<IpAddr>,
+}
+
+/// A response to a search
+struct Response {
+    from: SocketAddr,
+    text: String,
+}
+
+impl Response {
+    /// Returns the value of `header`, ignoring case
+    fn header(&self, header: &str) -> Option<&str> {
+	self.text.lines()
+	    .filter_map(|line| line.split_once(':'))
+	    .find(|(name, _)| name.trim().eq_ignore_ascii_case(header))
+	    .map(|(_, value)| value.trim())
+    }
+
+    /// Returns true when `device` names this response's sender
+    fn matches(&self, device: &str) -> bool {
+	let device = device.to_ascii_lowercase();
+	self.from.ip().to_string() == device
+	    || ["USN", "SERVER", "LOCATION", "ST"].iter()
+	    .filter_map(|header| self.header(header))
+	    .any(|value| value.to_ascii_lowercase().contains(&device))
+    }
+
+    /// Identifies the device for reports: its USN up to any `::` suffix,
+    /// or its address
+    fn device(&self) -> String {
+	match self.header("USN") {
+	    Some(usn) => usn.split("::").next().unwrap_or(usn).to_string(),
+	    None => self.from.ip().to_string(),
+	}
+    }
+}
+
+/// Builds an M-SEARCH request for `st` sent to `host`
+fn search(host: &SocketAddr, st: &str, mx: u8) -> String {
+    format!("M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n", host, mx, st)
+}
+
+/// Searches for devices through the group at `addr`, usually
+/// `239.255.255.250:1900`, and checks the expected devices answered.
+/// Responses are collected for `mx` seconds and a half, or until `timeout`.
+pub fn check(addr: &str, options: &SsdpOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let group = addr::resolve_or_port(addr, 1900)
+	.map_err(|e| error::from_io(e, Stage::Resolve, timeout))?
+	.into_iter()
+	.next()
+	.ok_or_else(|| format!("{} did not resolve to any address", addr))?;
+    let local = match (options.interface, group) {
+	(Some(interface), _) => SocketAddr::new(interface, 0),
+	(None, SocketAddr::V4(_)) => "0.0.0.0:0".parse()?,
+	(None, SocketAddr::V6(_)) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(local)?;
+    if let (Some(IpAddr::V4(interface)), SocketAddr::V4(_)) = (options.interface, group) {
+	SockRef::from(&socket).set_multicast_if_v4(&interface)?;
+    }
+
+    let mx = options.mx.unwrap_or(DEFAULT_MX);
+    let wait = timeout.unwrap_or(Duration::from_millis(u64::from(mx) * 1000 + 500));
+    let request = search(&group, options.st.as_deref().unwrap_or("ssdp:all"), mx);
+    for _ in 0..SEARCHES {
+	socket.send_to(request.as_bytes(), group).map_err(|e| error::from_io(e, Stage::Connect, timeout))?;
+    }
+
+    let start = Instant::now();
+    let mut responses = Vec::new();
+    let mut buf = [0u8; 65536];
+    while let Some(left) = wait.checked_sub(start.elapsed()).filter(|left| !left.is_zero()) {
+	socket.set_read_timeout(Some(left))?;
+	match socket.recv_from(&mut buf) {
+	    Ok((len, from)) => {
+		let text = String::from_utf8_lossy(&buf[..len]).into_owned();
+		if text.starts_with("HTTP/1.1 200") {
+		    responses.push(Response { from, text });
+		}
+	    },
+	    Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => break,
+	    Err(e) => return Err(error::from_io(e, Stage::Response, Some(wait))),
+	}
+    }
+
+    let devices: BTreeSet<String> = responses.iter().map(Response::device).collect();
+    let expected = options.expect.as_deref().unwrap_or_default();
+    let missing: Vec<&str> = expected.iter()
+	.filter(|device| !responses.iter().any(|r| r.matches(device)))
+	.map(String::as_str)
+	.collect();
+    if !missing.is_empty() {
+	return Err(From::from(format!("\n\tMissing: {}\n\tAnswered: {} devices in {:.1}s{}",
+				      missing.join(", "), devices.len(), wait.as_secs_f64(), listed(&devices))))
+    }
+    let wanted = if expected.is_empty() { options.min_responses.unwrap_or(1) } else { 0 };
+    if devices.len() < wanted {
+	return Err(From::from(format!("{} devices answered in {:.1}s, {} needed{}",
+				      devices.len(), wait.as_secs_f64(), wanted, listed(&devices))))
+    }
+    Ok(format!("{} devices answered{}", devices.len(), listed(&devices)))
+}
+
+/// Lists the devices that answered, for details
+fn listed(devices: &BTreeSet<String>) -> String {
+    if devices.is_empty() {
+	String::new()
+    } else {
+	format!(": {}", devices.iter().cloned().collect::<Vec<_>>().join(", "))
+    }
+}
@@ -0,0 +1,183 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Acceptable HTTP status codes. A check's `ok` is a single code as it
+//! always was, or a list mixing codes, classes such as `"2xx"`, and ranges
+//! such as `"200-299"`. Checks without an `http` table accept only 200.
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+
+/// One acceptable code, class of codes, or range of codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusMatch {
+    Code(u16),
+    /// Every code starting with this digit, e.g. 2 for `2xx`
+    Class(u16),
+    /// Codes between the bounds, inclusive
+    Range(u16, u16),
+}
+
+impl StatusMatch {
+    fn contains(&self, code: u16) -> bool {
+	match *self {
+	    StatusMatch::Code(c) => code == c,
+	    StatusMatch::Class(class) => code / 100 == class,
+	    StatusMatch::Range(lo, hi) => lo <= code && code <= hi,
+	}
+    }
+
+    fn parse(text: &str) -> Result<StatusMatch, String> {
+	let text = text.trim();
+	let code = |s: &str| match s.trim().parse::<u16>() {
+	    Ok(code) if (100..=599).contains(&code) => Ok(code),
+	    _ => Err(format!("`{}` is not an HTTP status code", s.trim())),
+	};
+	let lower = text.to_ascii_lowercase();
+	if let Some(class) = lower.strip_suffix("xx") {
+	    return match class.parse::<u16>() {
+		Ok(class) if (1..=5).contains(&class) => Ok(StatusMatch::Class(class)),
+		_ => Err(format!("`{}` is not a status class like `2xx`", text)),
+	    }
+	}
+	match text.split_once('-') {
+	    Some((lo, hi)) => {
+		let (lo, hi) = (code(lo)?, code(hi)?);
+		if lo > hi {
+		    return Err(format!("`{}` is an empty range", text))
+		}
+		Ok(StatusMatch::Range(lo, hi))
+	    },
+	    None => code(text).map(StatusMatch::Code),
+	}
+    }
+}
+
+impl fmt::Display for StatusMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    StatusMatch::Code(code) => write!(f, "{}", code),
+	    StatusMatch::Class(class) => write!(f, "{}xx", class),
+	    StatusMatch::Range(lo, hi) => write!(f, "{}-{}", lo, hi),
+	}
+    }
+}
+
+/// The status codes a check accepts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Accepted(pub Vec<StatusMatch>);
+
+impl Accepted {
+    /// Returns true when `code` is acceptable
+    pub fn contains(&self, code: u16) -> bool {
+	self.0.iter().any(|m| m.contains(code))
+    }
+}
+
+impl Default for Accepted {
+    /// Only `200 OK`, as accepted by checks without an `http` table
+    fn default() -> Accepted {
+	Accepted(vec![StatusMatch::Code(200)])
+    }
+}
+
+impl fmt::Display for Accepted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	let all: Vec<String> = self.0.iter().map(StatusMatch::to_string).collect();
+	write!(f, "{}", all.join(", "))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMatch {
+    Code(u16),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAccepted {
+    One(RawMatch),
+    Many(Vec<RawMatch>),
+}
+
+impl<'de> Deserialize<'de> for Accepted {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Accepted, D::Error> {
+	let raw = match RawAccepted::deserialize(deserializer)? {
+	    RawAccepted::One(one) => vec![one],
+	    RawAccepted::Many(many) => many,
+	};
+	if raw.is_empty() {
+	    return Err(D::Error::custom("`ok` must accept at least one status code"))
+	}
+	raw.into_iter()
+	    .map(|m| match m {
+		RawMatch::Code(code) => StatusMatch::parse(&code.to_string()),
+		RawMatch::Text(text) => StatusMatch::parse(&text),
+	    })
+	    .collect::<Result<Vec<_>, _>>()
+	    .map(Accepted)
+	    .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Http {
+	ok: Accepted,
+    }
+
+    fn accepted(toml: &str) -> Result<Accepted, String> {
+	toml::from_str::<Http>(toml).map(|http| http.ok).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn codes_classes_and_ranges_are_parsed() {
+	assert_eq!(accepted("ok = 204").unwrap(), Accepted(vec![StatusMatch::Code(204)]));
+	assert_eq!(accepted("ok = \"3XX\"").unwrap(), Accepted(vec![StatusMatch::Class(3)]));
+	let list = accepted("ok = [\"2xx\", 302, \" 401 - 403 \", \"418\"]").unwrap();
+	assert_eq!(list, Accepted(vec![StatusMatch::Class(2), StatusMatch::Code(302),
+				       StatusMatch::Range(401, 403), StatusMatch::Code(418)]));
+	assert_eq!(list.to_string(), "2xx, 302, 401-403, 418");
+    }
+
+    #[test]
+    fn codes_are_matched() {
+	let list = accepted("ok = [\"2xx\", 302, \"401-403\"]").unwrap();
+	for code in [200, 204, 299, 302, 401, 402, 403] {
+	    assert!(list.contains(code), "{}", code);
+	}
+	for code in [199, 300, 301, 400, 404, 500] {
+	    assert!(!list.contains(code), "{}", code);
+	}
+	assert!(Accepted::default().contains(200));
+	assert!(!Accepted::default().contains(204));
+    }
+
+    #[test]
+    fn invalid_codes_are_rejected() {
+	let err = |toml: &str| accepted(toml).unwrap_err();
+	assert!(err("ok = []").contains("at least one status code"));
+	assert!(err("ok = 99").contains("`99` is not an HTTP status code"));
+	assert!(err("ok = 600").contains("`600` is not an HTTP status code"));
+	assert!(err("ok = \"abc\"").contains("`abc` is not an HTTP status code"));
+	assert!(err("ok = \"6xx\"").contains("`6xx` is not a status class"));
+	assert!(err("ok = \"2x\"").contains("`2x` is not an HTTP status code"));
+	assert!(err("ok = \"299-200\"").contains("`299-200` is an empty range"));
+	assert!(err("ok = \"200-\"").contains("`` is not an HTTP status code"));
+	assert!(err("ok = [200, \"teapot\"]").contains("`teapot` is not an HTTP status code"));
+    }
+}
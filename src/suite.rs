@@ -0,0 +1,180 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Suites. Each configuration checked by one invocation is a suite, named
+//! by its top-level `name` or else its path, whose `exit_policy` sets how
+//! far its results decide the exit status. A strict production suite and a
+//! best-effort lab suite may then be checked together.
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::NetworkResources;
+use crate::outcome::Outcome;
+
+/// Which results of a suite decide the exit status
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitPolicy {
+    /// Failed, unknown, and regressed checks all count
+    #[default]
+    Strict,
+    /// Failed and regressed checks count, unknown ones do not
+    FailuresOnly,
+    /// Results are reported without affecting the exit status
+    ReportOnly,
+}
+
+impl ExitPolicy {
+    /// Returns true when failed checks decide the exit status
+    pub fn counts_failures(&self) -> bool {
+	*self != ExitPolicy::ReportOnly
+    }
+
+    /// Returns true when unknown checks decide the exit status
+    pub fn counts_unknown(&self) -> bool {
+	*self == ExitPolicy::Strict
+    }
+
+    /// Returns true when latency regressions since a baseline decide the
+    /// exit status
+    pub fn counts_regressions(&self) -> bool {
+	*self != ExitPolicy::ReportOnly
+    }
+}
+
+/// Outcomes of the latest run of one or more suites
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub checked: usize,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub unknown: usize,
+}
+
+impl Counts {
+    /// Counts the outcomes of the checked targets of `resources`
+    pub fn of(resources: &NetworkResources) -> Counts {
+	let mut counts = Counts::default();
+	for target in resources.target.iter().filter(|t| t.result.is_some()) {
+	    counts.checked += 1;
+	    match target.outcome() {
+		Outcome::Passed => counts.passed += 1,
+		Outcome::Failed => counts.failed += 1,
+		Outcome::Skipped => counts.skipped += 1,
+		Outcome::Unknown => counts.unknown += 1,
+	    }
+	}
+	counts
+    }
+
+    /// Adds the counts of another suite
+    pub fn add(&mut self, other: Counts) {
+	self.checked += other.checked;
+	self.passed += other.passed;
+	self.failed += other.failed;
+	self.skipped += other.skipped;
+	self.unknown += other.unknown;
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{} checks, {} passed, {} failed, {} skipped, {} unknown",
+	       self.checked, self.passed, self.failed, self.skipped, self.unknown)
+    }
+}
+
+/// Returns the name of the suite loaded from `path`
+pub fn name(path: &Path, resources: &NetworkResources) -> String {
+    resources.name.clone().unwrap_or_else(|| path.display().to_string())
+}
+
+/// Returns the summary line of a suite, noting a policy other than strict
+pub fn summary(name: &str, resources: &NetworkResources) -> String {
+    let counts = Counts::of(resources);
+    match resources.exit_policy.unwrap_or_default() {
+	ExitPolicy::Strict => format!("{}: {}", name, counts),
+	ExitPolicy::FailuresOnly => format!("{}: {} (failures only)", name, counts),
+	ExitPolicy::ReportOnly => format!("{}: {} (report only)", name, counts),
+    }
+}
+
+/// Returns the line closing every run, whatever the output format, for
+/// scripts and CI log parsers to find with grep, e.g. `CONNCHK_RESULT
+/// total=42 ok=40 failed=2 skipped=0 unknown=0 duration_ms=1234`
+pub fn result_line(counts: Counts, elapsed: Duration) -> String {
+    format!("CONNCHK_RESULT total={} ok={} failed={} skipped={} unknown={} duration_ms={}",
+	    counts.checked, counts.passed, counts.failed, counts.skipped, counts.unknown, elapsed.as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn policies_are_parsed() {
+	#[derive(Deserialize)]
+	struct Suite {
+	    exit_policy: ExitPolicy,
+	}
+	let policy = |text: &str| toml::from_str::<Suite>(&format!("exit_policy = \"{}\"", text)).map(|s| s.exit_policy);
+	assert_eq!(policy("strict").unwrap(), ExitPolicy::Strict);
+	assert_eq!(policy("failures_only").unwrap(), ExitPolicy::FailuresOnly);
+	assert_eq!(policy("report_only").unwrap(), ExitPolicy::ReportOnly);
+	assert!(policy("lenient").is_err());
+	assert_eq!(ExitPolicy::default(), ExitPolicy::Strict);
+    }
+
+    #[test]
+    fn policies_decide_what_counts() {
+	assert!(ExitPolicy::Strict.counts_failures() && ExitPolicy::Strict.counts_unknown());
+	assert!(ExitPolicy::FailuresOnly.counts_failures() && !ExitPolicy::FailuresOnly.counts_unknown());
+	assert!(!ExitPolicy::ReportOnly.counts_failures() && !ExitPolicy::ReportOnly.counts_unknown());
+	assert!(ExitPolicy::Strict.counts_regressions() && ExitPolicy::FailuresOnly.counts_regressions());
+	assert!(!ExitPolicy::ReportOnly.counts_regressions());
+    }
+
+    #[test]
+    fn counts_cover_checked_targets() {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+	let (mut resources, _) = config::parse(&format!(
+	    "name = \"lab\"\nexit_policy = \"report_only\"\n\
+	     [[target]]\ndesc = \"up\"\naddr = \"{}\"\nkind = \"Tcp\"\n\
+	     [[target]]\ndesc = \"down\"\naddr = \"{}\"\nkind = \"Tcp\"\n",
+	    listener.local_addr().unwrap(), closed)).unwrap();
+	assert_eq!(Counts::of(&resources), Counts::default());
+	resources.run_checks();
+	let counts = Counts::of(&resources);
+	assert_eq!(counts, Counts { checked: 2, passed: 1, failed: 1, skipped: 0, unknown: 0 });
+	assert_eq!(name(Path::new("lab.toml"), &resources), "lab");
+	assert_eq!(summary("lab", &resources),
+		   "lab: 2 checks, 1 passed, 1 failed, 0 skipped, 0 unknown (report only)");
+
+	let mut total = counts;
+	total.add(Counts { checked: 3, passed: 1, failed: 0, skipped: 1, unknown: 1 });
+	assert_eq!(result_line(total, Duration::from_millis(1234)),
+		   "CONNCHK_RESULT total=5 ok=2 failed=1 skipped=1 unknown=1 duration_ms=1234");
+    }
+
+    #[test]
+    fn suites_without_a_name_use_their_path() {
+	let (resources, _) = config::parse("target = []").unwrap();
+	assert_eq!(name(Path::new("conf/prod.toml"), &resources), "conf/prod.toml");
+	assert_eq!(summary("conf/prod.toml", &resources),
+		   "conf/prod.toml: 0 checks, 0 passed, 0 failed, 0 skipped, 0 unknown");
+    }
+}
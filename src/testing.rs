@@ -0,0 +1,111 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Connectivity preconditions for test suites. Integration tests that need
+//! a database, a message broker, or an upstream API can assert it is
+//! reachable first with [`assert_reachable!`](crate::assert_reachable) or
+//! [`assert_checks!`](crate::assert_checks), and fail with a message naming
+//! the unreachable targets instead of a timeout deep inside the test. Any
+//! check kind may be used through a configuration, given inline or as a
+//! file shared with the command-line tool.
+
+use std::path::Path;
+
+use crate::config;
+use crate::outcome::{CheckResult, Outcome};
+
+/// Checks a single `addr`: an `Http` check for `http` and `https` URLs and
+/// a `Tcp` check otherwise
+pub fn check_addr(addr: &str) -> Result<Vec<CheckResult>, Box<dyn std::error::Error>> {
+    let result = match addr.starts_with("http://") || addr.starts_with("https://") {
+	true => crate::check_url(addr)?,
+	false => crate::check_tcp(addr)?,
+    };
+    Ok(vec![result])
+}
+
+/// Checks every target of `config`, a TOML configuration
+pub fn check_config(config: &str) -> Result<Vec<CheckResult>, Box<dyn std::error::Error>> {
+    let (mut resources, _) = config::parse(config)?;
+    Ok(resources.run_checks())
+}
+
+/// Checks every target of the configuration file or directory at `path`,
+/// following its includes
+pub fn check_file(path: &Path) -> Result<Vec<CheckResult>, Box<dyn std::error::Error>> {
+    Ok(crate::check_file(path)?.results)
+}
+
+/// Panics with a description of every failed check in `results`, prefixed
+/// with `message` when given. Checks whose state is unknown fail as well.
+/// Skipped checks, such as ICMP checks without the needed privileges, are
+/// noted on stderr and do not fail.
+#[track_caller]
+pub fn assert_results(results: Result<Vec<CheckResult>, Box<dyn std::error::Error>>, message: Option<String>) {
+    let prefix = message.map(|message| format!("{}: ", message)).unwrap_or_default();
+    let results = match results {
+	Ok(results) => results,
+	Err(e) => panic!("{}connectivity checks could not run: {}", prefix, e),
+    };
+    for result in results.iter().filter(|r| r.outcome == Outcome::Skipped) {
+	eprintln!("connchk: skipped {}: {}", result.desc, result.error.as_deref().unwrap_or_default());
+    }
+    let failed: Vec<&CheckResult> = results.iter()
+	.filter(|r| matches!(r.outcome, Outcome::Failed | Outcome::Unknown))
+	.collect();
+    if failed.is_empty() {
+	return
+    }
+    let mut report = format!("{}{} of {} connectivity checks failed", prefix, failed.len(), results.len());
+    for result in failed {
+	let error = result.error.as_deref().unwrap_or_default().split_whitespace().collect::<Vec<_>>().join(" ");
+	match result.desc == result.addr {
+	    true => report.push_str(&format!("\n    {}: {}", result.desc, error)),
+	    false => report.push_str(&format!("\n    {} ({}): {}", result.desc, result.addr, error)),
+	}
+	if let Some(code) = &result.error_code {
+	    report.push_str(&format!(" [{}]", code));
+	}
+    }
+    panic!("{}", report)
+}
+
+/// Asserts that `addr`, a `host:port` address or an HTTP(s) URL, is
+/// reachable, panicking otherwise. An optional message in the style of
+/// `assert!` prefixes the failure.
+#[macro_export]
+macro_rules! assert_reachable {
+    ($addr:expr $(,)?) => {
+	$crate::testing::assert_results($crate::testing::check_addr($addr), None)
+    };
+    ($addr:expr, $($arg:tt)+) => {
+	$crate::testing::assert_results($crate::testing::check_addr($addr), Some(::std::format!($($arg)+)))
+    };
+}
+
+/// Asserts that every target of a TOML configuration passes its checks,
+/// panicking otherwise. `file = path` checks a configuration file instead.
+/// An optional message in the style of `assert!` prefixes the failure.
+#[macro_export]
+macro_rules! assert_checks {
+    (file = $path:expr $(,)?) => {
+	$crate::testing::assert_results($crate::testing::check_file(::std::path::Path::new($path)), None)
+    };
+    (file = $path:expr, $($arg:tt)+) => {
+	$crate::testing::assert_results($crate::testing::check_file(::std::path::Path::new($path)),
+					Some(::std::format!($($arg)+)))
+    };
+    ($config:expr $(,)?) => {
+	$crate::testing::assert_results($crate::testing::check_config($config), None)
+    };
+    ($config:expr, $($arg:tt)+) => {
+	$crate::testing::assert_results($crate::testing::check_config($config), Some(::std::format!($($arg)+)))
+    };
+}
@@ -0,0 +1,126 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Topology view of a run. Failed targets are clustered by the address or
+//! subnet they resolved to, so that many failures behind one network read
+//! as a single unreachable subnet rather than a list of targets.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+
+use crate::{addr, NetworkResources, ResType, Resource};
+use crate::outcome::Outcome;
+
+/// Prefix lengths tried for IPv4 addresses, widest first
+const V4_PREFIXES: [u32; 3] = [16, 24, 32];
+
+/// Prefix lengths tried for IPv6 addresses, widest first
+const V6_PREFIXES: [u32; 4] = [32, 48, 64, 128];
+
+/// Failed targets sharing a network in which no target passed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cluster {
+    pub network: IpAddr,
+    pub prefix: u32,
+    /// Descriptions of the failed targets within the network
+    pub targets: Vec<String>,
+}
+
+impl Cluster {
+    /// Returns the network as an address, or in CIDR notation when it is
+    /// wider than one
+    pub fn label(&self) -> String {
+	match (self.network, self.prefix) {
+	    (IpAddr::V4(_), 32) | (IpAddr::V6(_), 128) => self.network.to_string(),
+	    (network, prefix) => format!("{}/{}", network, prefix),
+	}
+    }
+}
+
+/// Returns the first address of the network of `ip` with `prefix` bits
+fn network(ip: IpAddr, prefix: u32) -> IpAddr {
+    match ip {
+	IpAddr::V4(ip) => {
+	    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+	    IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+	},
+	IpAddr::V6(ip) => {
+	    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+	    IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+	},
+    }
+}
+
+/// Returns the addresses a checked target resolved to, resolving the hosts
+/// of Tcp and Http targets whose checks failed before noting any
+fn addresses(target: &Resource) -> Vec<IpAddr> {
+    let noted = target.result.as_ref().map(|r| r.addresses.clone()).unwrap_or_default();
+    if !noted.is_empty() || !matches!(target.kind, ResType::Tcp | ResType::Http) {
+	return noted
+    }
+    let host = target.addr[addr::host_span(&target.addr)].trim_start_matches('[').trim_end_matches(']');
+    match host.parse() {
+	Ok(ip) => vec![ip],
+	Err(_) => (host, 0).to_socket_addrs()
+	    .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+	    .unwrap_or_default(),
+    }
+}
+
+/// Clusters the failed targets of `suites` by the widest network holding at
+/// least two of them and no passing target. Returns the clusters, largest
+/// first, and the failed targets left out of any.
+pub fn clusters(suites: &[&NetworkResources]) -> (Vec<Cluster>, Vec<String>) {
+    let checked: Vec<(&Resource, Outcome, Vec<IpAddr>)> = suites.iter()
+	.flat_map(|resources| resources.target.iter())
+	.filter(|t| t.result.is_some())
+	.map(|t| (t, t.outcome(), addresses(t)))
+	.collect();
+    let count = |outcome: Outcome, net: IpAddr, prefix: u32| checked.iter()
+	.filter(|(_, o, ips)| *o == outcome && ips.iter().any(|ip| ip.is_ipv4() == net.is_ipv4() && network(*ip, prefix) == net))
+	.count();
+    let mut clusters: Vec<Cluster> = Vec::new();
+    let mut others = Vec::new();
+    for (target, _, ips) in checked.iter().filter(|(_, o, _)| *o == Outcome::Failed) {
+	let found = ips.iter().find_map(|ip| {
+	    let prefixes: &[u32] = if ip.is_ipv4() { &V4_PREFIXES } else { &V6_PREFIXES };
+	    prefixes.iter().map(|prefix| (network(*ip, *prefix), *prefix))
+		.find(|(net, prefix)| count(Outcome::Failed, *net, *prefix) >= 2 && count(Outcome::Passed, *net, *prefix) == 0)
+	});
+	match found {
+	    Some((network, prefix)) => match clusters.iter_mut().find(|c| c.network == network && c.prefix == prefix) {
+		Some(cluster) => cluster.targets.push(target.desc.clone()),
+		None => clusters.push(Cluster { network, prefix, targets: vec![target.desc.clone()] }),
+	    },
+	    None => others.push(target.desc.clone()),
+	}
+    }
+    clusters.sort_by(|a, b| b.targets.len().cmp(&a.targets.len()).then(a.prefix.cmp(&b.prefix)));
+    (clusters, others)
+}
+
+/// Returns the topology report of `suites`, or None when nothing failed
+pub fn report(suites: &[&NetworkResources]) -> Option<String> {
+    let (clusters, others) = clusters(suites);
+    if clusters.is_empty() && others.is_empty() {
+	return None
+    }
+    let mut out = String::from("Failures by topology:");
+    for cluster in clusters.iter() {
+	let scope = match cluster.label().contains('/') {
+	    true => format!("everything checked behind {} is unreachable", cluster.label()),
+	    false => format!("everything checked at {} is unreachable", cluster.label()),
+	};
+	out.push_str(&format!("\n\t{} ({} targets: {})", scope, cluster.targets.len(), cluster.targets.join(", ")));
+    }
+    if !others.is_empty() {
+	out.push_str(&format!("\n\t{} other failures: {}", others.len(), others.join(", ")));
+    }
+    Some(out)
+}
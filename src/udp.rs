@@ -0,0 +1,111 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! UDP reachability checks. UDP has no handshake, so a datagram is sent and
+//! the target counts as reachable unless the host reports the port closed,
+//! or, when a response is expected, once one arrives.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::addr;
+use crate::error::{self, Stage};
+
+/// Time to wait for a response, when one is expected and no timeout is
+/// configured
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time to wait for a port unreachable report when no response is expected
+const UNREACHABLE_WAIT: Duration = Duration::from_millis(250);
+
+/// Provides a deserialize target for UDP checks. All fields are optional.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct UdpOptions {
+    /// Text to send. Defaults to an empty datagram.
+    pub payload: Option<String>,
+    /// Bytes to send, as hexadecimal, in place of `payload`
+    pub payload_hex: Option<String>,
+    /// Fail unless a response arrives before the timeout
+    pub expect_response: Option<bool>,
+    /// Fail unless the response contains this text. Implies
+    /// `expect_response`.
+    pub expect: Option<String>,
+}
+
+impl UdpOptions {
+    /// Returns the bytes to send
+    fn payload(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+	match (&self.payload_hex, &self.payload) {
+	    (Some(hex), _) => decode_hex(hex),
+	    (None, Some(text)) => Ok(text.as_bytes().to_vec()),
+	    (None, None) => Ok(Vec::new()),
+	}
+    }
+}
+
+/// Decodes a hexadecimal string, ignoring whitespace
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+	return Err(From::from(format!("odd number of digits in payload_hex `{}`", hex)))
+    }
+    digits.chunks(2)
+	.map(|pair| {
+	    let pair: String = pair.iter().collect();
+	    u8::from_str_radix(&pair, 16)
+		.map_err(|_| From::from(format!("invalid byte `{}` in payload_hex", pair)))
+	})
+	.collect()
+}
+
+/// Sends a datagram to `addr` and reports what came back
+pub fn check(addr: &str, options: &UdpOptions, timeout: Option<Duration>) -> Result<String, Box<dyn std::error::Error>> {
+    let target = addr::resolve(addr)
+	.map_err(|e| error::from_io(e, Stage::Resolve, timeout))?
+	.into_iter()
+	.next()
+	.ok_or_else(|| format!("{} did not resolve to any address", addr))?;
+    let local: SocketAddr = if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse()?;
+    let socket = UdpSocket::bind(local)?;
+    // Connecting lets the host report an ICMP port unreachable as an error
+    socket.connect(target).map_err(|e| error::from_io(e, Stage::Connect, timeout))?;
+
+    let payload = options.payload()?;
+    let expect = options.expect.is_some() || options.expect_response.unwrap_or(false);
+    let wait = match (expect, timeout) {
+	(true, timeout) => timeout.unwrap_or(RESPONSE_TIMEOUT),
+	(false, Some(timeout)) => timeout.min(UNREACHABLE_WAIT),
+	(false, None) => UNREACHABLE_WAIT,
+    };
+    socket.set_read_timeout(Some(wait))?;
+
+    let start = Instant::now();
+    socket.send(&payload).map_err(|e| error::from_io(e, Stage::Connect, timeout))?;
+    let mut buf = [0u8; 65536];
+    match socket.recv(&mut buf) {
+	Ok(len) => {
+	    let elapsed = start.elapsed();
+	    if let Some(text) = &options.expect {
+		let response = String::from_utf8_lossy(&buf[..len]);
+		if !response.contains(text.as_str()) {
+		    return Err(From::from(format!("response of {} bytes did not contain `{}`", len, text)))
+		}
+	    }
+	    Ok(format!("response of {} bytes in {}ms", len, elapsed.as_millis()))
+	},
+	Err(e) if !expect && matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => {
+	    Ok(format!("sent {} bytes, no port unreachable reported", payload.len()))
+	},
+	Err(e) => Err(error::from_io(e, Stage::Response, Some(wait))),
+    }
+}
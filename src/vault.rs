@@ -0,0 +1,210 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Secrets read from HashiCorp Vault's KV version 2 engine. References take
+//! the form `mount/path#field`, e.g. `secret/connchk/reports#token` for the
+//! `token` field of `connchk/reports` in the engine mounted at `secret`.
+//! A [`Session`] logs in when first needed and reads each secret once, so a
+//! run with many checks sharing a secret makes few requests to Vault.
+
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use reqwest::Certificate;
+use reqwest::blocking::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::auth;
+
+/// Limit for each request to Vault
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The data of a secret, or the reason it could not be read
+type SecretData = Result<Map<String, Value>, String>;
+
+/// Provides a deserialize target for the top-level `vault` table
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct VaultConfig {
+    /// Address of the Vault server, defaulting to `VAULT_ADDR`
+    pub address: Option<String>,
+    /// Enterprise namespace, defaulting to `VAULT_NAMESPACE`
+    pub namespace: Option<String>,
+    /// PEM certificates trusted in addition to the bundled roots
+    pub ca_bundle: Option<PathBuf>,
+    /// How to log in. Defaults to a token from `VAULT_TOKEN` or
+    /// `~/.vault-token`.
+    pub auth: Option<VaultAuth>,
+}
+
+/// Ways of logging in to Vault
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum VaultAuth {
+    /// An existing token, given directly or through an environment variable
+    Token {
+	token: Option<String>,
+	token_env: Option<String>,
+    },
+    /// The AppRole method, for machines
+    AppRole {
+	role_id: Option<String>,
+	role_id_env: Option<String>,
+	secret_id: Option<String>,
+	secret_id_env: Option<String>,
+	/// Path the method is mounted at, `approle` if not given
+	mount: Option<String>,
+    },
+}
+
+/// A connection to Vault lasting one run
+#[derive(Debug)]
+pub struct Session {
+    config: VaultConfig,
+    token: OnceLock<Result<String, String>>,
+    /// Secret data by `mount/path`
+    secrets: Mutex<HashMap<String, SecretData>>,
+}
+
+impl Session {
+    pub fn new(config: VaultConfig) -> Session {
+	Session { config, token: OnceLock::new(), secrets: Mutex::new(HashMap::new()) }
+    }
+
+    fn address(&self) -> Result<String, String> {
+	self.config.address.clone()
+	    .or_else(|| env::var("VAULT_ADDR").ok())
+	    .map(|addr| addr.trim_end_matches('/').to_string())
+	    .ok_or_else(|| "the vault table has no `address` and VAULT_ADDR is not set".to_string())
+    }
+
+    fn client(&self) -> Result<Client, Box<dyn std::error::Error>> {
+	let mut builder = Client::builder().timeout(TIMEOUT);
+	if let Some(path) = &self.config.ca_bundle {
+	    let pem = std::fs::read(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+	    for cert in Certificate::from_pem_bundle(&pem)? {
+		builder = builder.add_root_certificate(cert);
+	    }
+	}
+	Ok(builder.build()?)
+    }
+
+    /// Adds the namespace header, when there is one
+    fn namespaced(&self, request: RequestBuilder) -> RequestBuilder {
+	match self.config.namespace.clone().or_else(|| env::var("VAULT_NAMESPACE").ok()) {
+	    Some(namespace) => request.header("X-Vault-Namespace", namespace),
+	    None => request,
+	}
+    }
+
+    /// Returns the token to use, logging in on first use
+    fn token(&self) -> Result<String, String> {
+	self.token.get_or_init(|| self.login().map_err(|e| format!("Vault login failed: {}", e))).clone()
+    }
+
+    fn login(&self) -> Result<String, Box<dyn std::error::Error>> {
+	match &self.config.auth {
+	    None => default_token(),
+	    Some(VaultAuth::Token { token, token_env }) => auth::credential("token", token, token_env)?
+		.ok_or_else(|| From::from("token login requires `token` or `token_env`")),
+	    Some(VaultAuth::AppRole { role_id, role_id_env, secret_id, secret_id_env, mount }) => {
+		let role_id = auth::credential("role_id", role_id, role_id_env)?
+		    .ok_or("approle login requires `role_id` or `role_id_env`")?;
+		let secret_id = auth::credential("secret_id", secret_id, secret_id_env)?;
+		let url = format!("{}/v1/auth/{}/login", self.address()?, mount.as_deref().unwrap_or("approle"));
+		let body = match secret_id {
+		    Some(secret_id) => json!({ "role_id": role_id, "secret_id": secret_id }),
+		    None => json!({ "role_id": role_id }),
+		};
+		let resp = self.namespaced(self.client()?.post(&url).json(&body)).send()?;
+		let status = resp.status();
+		let reply: Value = resp.json().unwrap_or_default();
+		if !status.is_success() {
+		    return Err(From::from(format!("{} returned {}{}", url, status, vault_errors(&reply))))
+		}
+		reply.pointer("/auth/client_token").and_then(Value::as_str).map(str::to_string)
+		    .ok_or_else(|| From::from(format!("{} returned no client token", url)))
+	    },
+	}
+    }
+
+    /// Reads the data of the secret at `path` within the KV engine mounted
+    /// at `mount`
+    fn read(&self, mount: &str, path: &str) -> SecretData {
+	let key = format!("{}/{}", mount, path);
+	let mut secrets = self.secrets.lock().unwrap_or_else(|e| e.into_inner());
+	if let Some(data) = secrets.get(&key) {
+	    return data.clone()
+	}
+	let data = self.fetch(mount, path).map_err(|e| e.to_string());
+	secrets.insert(key, data.clone());
+	data
+    }
+
+    fn fetch(&self, mount: &str, path: &str) -> Result<Map<String, Value>, Box<dyn std::error::Error>> {
+	let url = format!("{}/v1/{}/data/{}", self.address()?, mount, path);
+	let request = self.client()?.get(&url).header("X-Vault-Token", self.token()?);
+	let resp = self.namespaced(request).send()?;
+	let status = resp.status();
+	let reply: Value = resp.json().unwrap_or_default();
+	if !status.is_success() {
+	    return Err(From::from(format!("{} returned {}{}", url, status, vault_errors(&reply))))
+	}
+	match reply.pointer("/data/data") {
+	    Some(Value::Object(data)) => Ok(data.clone()),
+	    _ => Err(From::from(format!("{} is not a KV version 2 secret", url))),
+	}
+    }
+
+    /// Returns the secret named by `reference`, `mount/path#field`
+    pub fn resolve(&self, reference: &str) -> Result<String, Box<dyn std::error::Error>> {
+	let invalid = || format!("invalid vault reference `{}`, expected `mount/path#field`", reference);
+	let (location, field) = reference.split_once('#').ok_or_else(invalid)?;
+	let (mount, path) = location.trim_matches('/').split_once('/').ok_or_else(invalid)?;
+	if field.is_empty() || path.is_empty() {
+	    return Err(From::from(invalid()))
+	}
+	let data = self.read(mount, path)?;
+	match data.get(field) {
+	    Some(Value::String(secret)) => Ok(secret.clone()),
+	    Some(other) => Ok(other.to_string()),
+	    None => Err(From::from(format!("{}/{} has no field `{}`", mount, path, field))),
+	}
+    }
+}
+
+/// Returns the token Vault's own CLI would use
+fn default_token() -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(token) = env::var("VAULT_TOKEN") {
+	return Ok(token)
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))
+	.ok_or("VAULT_TOKEN is not set")?;
+    let path = PathBuf::from(home).join(".vault-token");
+    let token = std::fs::read_to_string(&path)
+	.map_err(|_| format!("VAULT_TOKEN is not set and {} is missing", path.display()))?;
+    Ok(token.trim().to_string())
+}
+
+/// Formats the `errors` Vault returns with a failed request
+fn vault_errors(reply: &Value) -> String {
+    match reply.get("errors").and_then(Value::as_array) {
+	Some(errors) if !errors.is_empty() => {
+	    let errors: Vec<String> = errors.iter()
+		.map(|e| e.as_str().map(str::to_string).unwrap_or_else(|| e.to_string()))
+		.collect();
+	    format!(": {}", errors.join("; "))
+	},
+	_ => String::new(),
+    }
+}
@@ -0,0 +1,85 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+//! Wake-on-LAN for targets that are allowed to sleep. When such a target
+//! fails its check, a magic packet is broadcast to its MAC address and the
+//! target is checked again once it has had time to wake.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::addr;
+
+/// Where magic packets are sent when no `broadcast` is given
+const DEFAULT_BROADCAST: &str = "255.255.255.255:9";
+
+/// Time allowed for a host to wake when no `delay_ms` is given
+const DEFAULT_DELAY: Duration = Duration::from_secs(30);
+
+/// Provides a deserialize target for a target's `wake` table
+#[derive(Deserialize, Debug, Clone)]
+pub struct Wake {
+    /// MAC address of the host, e.g. `00:11:22:33:44:55`
+    pub mac: String,
+    /// Broadcast address and port to send the packet to, e.g.
+    /// `192.168.10.255:9` for a directed broadcast. Defaults to
+    /// `255.255.255.255:9`.
+    pub broadcast: Option<String>,
+    /// Milliseconds to wait after waking before checking again, 30000 if
+    /// not given
+    pub delay_ms: Option<u64>,
+}
+
+impl Wake {
+    /// Returns the time to wait before checking again
+    pub fn delay(&self) -> Duration {
+	self.delay_ms.map(Duration::from_millis).unwrap_or(DEFAULT_DELAY)
+    }
+
+    /// Broadcasts the magic packet
+    pub fn send(&self) -> Result<(), Box<dyn std::error::Error>> {
+	let packet = magic_packet(&self.mac)?;
+	let broadcast = self.broadcast.as_deref().unwrap_or(DEFAULT_BROADCAST);
+	let target: SocketAddr = addr::resolve_or_port(broadcast, 9)?.into_iter().next()
+	    .ok_or_else(|| format!("unable to resolve {}", broadcast))?;
+	let bind: SocketAddr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+	let socket = UdpSocket::bind(bind)?;
+	socket.set_broadcast(true)?;
+	socket.send_to(&packet, target)?;
+	Ok(())
+    }
+}
+
+/// Parses a MAC address written with `:` or `-` separators, or none
+fn parse_mac(mac: &str) -> Result<[u8; 6], Box<dyn std::error::Error>> {
+    let hex: String = mac.chars().filter(|c| !matches!(c, ':' | '-' | '.')).collect();
+    let invalid = || format!("invalid MAC address `{}`", mac);
+    if hex.len() != 12 || !hex.is_ascii() {
+	return Err(From::from(invalid()))
+    }
+    let mut bytes = [0u8; 6];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+	*byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// Builds a magic packet: six bytes of `0xff` followed by the MAC address
+/// repeated sixteen times
+fn magic_packet(mac: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mac = parse_mac(mac)?;
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+	packet.extend_from_slice(&mac);
+    }
+    Ok(packet)
+}
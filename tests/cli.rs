@@ -0,0 +1,144 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// Returns a loopback address nothing listens on
+fn closed_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Writes a configuration checking each of `addrs`, with a top-level
+/// `header` such as an `exit_policy`, returning its path
+fn suite(file: &str, header: &str, addrs: &[SocketAddr]) -> PathBuf {
+    let mut config = format!("{}\n", header);
+    for (i, addr) in addrs.iter().enumerate() {
+	config.push_str(&format!("[[target]]\ndesc = \"target {}\"\naddr = \"{}\"\nkind = \"Tcp\"\n", i, addr));
+    }
+    let path = std::env::temp_dir().join(format!("connchk-cli-{}-{}.toml", std::process::id(), file));
+    std::fs::write(&path, config).unwrap();
+    path
+}
+
+/// Runs connchk on the configurations at `paths`, through the `check`
+/// subcommand when `check` is set, returning its exit code and stderr
+fn connchk(paths: &[&PathBuf], check: bool) -> (i32, String) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_connchk"));
+    match check {
+	true => {
+	    command.arg("check");
+	    for path in paths {
+		command.arg("-c").arg(path);
+	    }
+	},
+	false => {
+	    command.args(paths);
+	},
+    }
+    let output = command.output().unwrap();
+    for path in paths {
+	let _ = std::fs::remove_file(path);
+    }
+    (output.status.code().unwrap(), String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+#[test]
+fn reachable_targets_exit_zero() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let path = suite("up", "", &[listener.local_addr().unwrap()]);
+    let (code, stderr) = connchk(&[&path], false);
+    assert_eq!(code, 0, "{}", stderr);
+    let last = stderr.lines().last().unwrap_or_default();
+    assert!(last.starts_with("CONNCHK_RESULT total=1 ok=1 failed=0 skipped=0 unknown=0 duration_ms="), "{}", stderr);
+}
+
+#[test]
+fn failed_targets_exit_one() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let path = suite("down", "", &[listener.local_addr().unwrap(), closed_addr()]);
+    let (code, stderr) = connchk(&[&path], false);
+    assert_eq!(code, 1, "{}", stderr);
+    assert!(stderr.contains("1 of 2 checks failed\n"), "{}", stderr);
+    let last = stderr.lines().last().unwrap_or_default();
+    assert!(last.starts_with("CONNCHK_RESULT total=2 ok=1 failed=1 "), "{}", stderr);
+}
+
+#[test]
+fn errors_and_doctor_close_with_the_result_line() {
+    let missing = std::env::temp_dir().join(format!("connchk-cli-{}-missing.toml", std::process::id()));
+    let (code, stderr) = connchk(&[&missing], false);
+    assert_eq!(code, 1, "{}", stderr);
+    assert!(stderr.starts_with("Error: "), "{}", stderr);
+    let last = stderr.lines().last().unwrap_or_default();
+    assert!(last.starts_with("CONNCHK_RESULT total=0 ok=0 failed=0 skipped=0 unknown=0 duration_ms="), "{}", stderr);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_connchk")).arg("doctor").output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.lines().last().unwrap_or_default().starts_with("CONNCHK_RESULT total=0 "), "{}", stderr);
+}
+
+#[test]
+fn report_only_suites_do_not_fail_the_run() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let prod = suite("prod", "name = \"prod\"", &[listener.local_addr().unwrap()]);
+    let lab = suite("lab", "name = \"lab\"\nexit_policy = \"report_only\"", &[closed_addr()]);
+    let (code, stderr) = connchk(&[&prod, &lab], true);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stderr.contains("prod: 1 checks, 1 passed, 0 failed, 0 skipped, 0 unknown\n"), "{}", stderr);
+    assert!(stderr.contains("lab: 1 checks, 0 passed, 1 failed, 0 skipped, 0 unknown (report only)\n"), "{}", stderr);
+    assert!(stderr.contains("Overall: 2 checks, 1 passed, 1 failed, 0 skipped, 0 unknown\n"), "{}", stderr);
+    assert!(stderr.lines().last().unwrap_or_default().starts_with("CONNCHK_RESULT total=2 ok=1 failed=1 "), "{}", stderr);
+}
+
+#[test]
+fn failures_only_suites_fail_the_run() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let prod = suite("strict", "name = \"prod\"", &[listener.local_addr().unwrap()]);
+    let edge = suite("edge", "name = \"edge\"\nexit_policy = \"failures_only\"", &[closed_addr()]);
+    let (code, stderr) = connchk(&[&prod, &edge], true);
+    assert_eq!(code, 1, "{}", stderr);
+    assert!(stderr.contains("edge: 1 checks, 0 passed, 1 failed, 0 skipped, 0 unknown (failures only)\n"), "{}", stderr);
+    assert!(stderr.contains("1 of 2 checks failed\n"), "{}", stderr);
+}
+
+#[test]
+fn failures_only_suites_fail_on_regressions() {
+    // An HTTP server answering after 50ms, well beyond the baseline's 1ms
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+	for mut stream in listener.incoming().flatten() {
+	    let _ = stream.read(&mut [0; 1024]);
+	    thread::sleep(Duration::from_millis(50));
+	    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+	}
+    });
+    let config = std::env::temp_dir().join(format!("connchk-cli-{}-slow.toml", std::process::id()));
+    std::fs::write(&config, format!("name = \"edge\"\nexit_policy = \"failures_only\"\n\
+				     [[target]]\ndesc = \"slow api\"\naddr = \"http://{}/\"\nkind = \"Http\"\n", addr)).unwrap();
+    let baseline = std::env::temp_dir().join(format!("connchk-cli-{}-baseline.json", std::process::id()));
+    std::fs::write(&baseline, "{\"config\": \"edge\", \"targets\": \
+			       [{\"desc\": \"slow api\", \"outcome\": \"passed\", \"latency_ms\": 1.0}]}\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_connchk"))
+	.arg("check").arg("-c").arg(&config).arg("--baseline").arg(&baseline)
+	.output().unwrap();
+    let _ = std::fs::remove_file(&config);
+    let _ = std::fs::remove_file(&baseline);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(stderr.contains("1 of 1 checks regressed since the baseline\n"), "{}", stderr);
+}
@@ -0,0 +1,85 @@
+/*
+    connchk gives a status of reachability of plain tcp or http(s) endpoints from your machine
+    Copyright (C) 2020-2024 Anthony Martinez
+
+    Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+    http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+    http://opensource.org/licenses/MIT>, at your option. This file may not be
+    copied, modified, or distributed except according to those terms.
+*/
+
+use std::net::TcpListener;
+use std::panic;
+
+use connchk::{assert_checks, assert_reachable};
+
+/// Returns a loopback address nothing listens on
+fn closed_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Runs `f`, returning the message it panicked with
+fn panic_message(f: impl FnOnce() + panic::UnwindSafe) -> String {
+    let payload = panic::catch_unwind(f).expect_err("the assertion should fail");
+    match payload.downcast::<String>() {
+	Ok(message) => *message,
+	Err(payload) => payload.downcast_ref::<&str>().unwrap().to_string(),
+    }
+}
+
+#[test]
+fn reachable_listener_passes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    assert_reachable!(&addr);
+    assert_reachable!(&addr, "listener on {}", addr);
+}
+
+#[test]
+fn unreachable_addr_names_the_target() {
+    let addr = closed_addr();
+    let message = panic_message(|| assert_reachable!(&addr));
+    assert!(message.starts_with("1 of 1 connectivity checks failed\n    "), "{}", message);
+    assert!(message.contains(&addr), "{}", message);
+
+    let addr = closed_addr();
+    let message = panic_message(|| assert_reachable!(&addr, "database {}", "primary"));
+    assert!(message.starts_with("database primary: 1 of 1 connectivity checks failed"), "{}", message);
+}
+
+#[test]
+fn config_checks_report_only_failures() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap();
+    let config = |closed: &str| format!("[[target]]\ndesc = \"broker\"\naddr = \"{}\"\nkind = \"Tcp\"\n\n\
+					 [[target]]\ndesc = \"cache\"\naddr = \"{}\"\nkind = \"Tcp\"\n", open, closed);
+    assert_checks!(&config(&open.to_string()));
+
+    let closed = closed_addr();
+    let config = config(&closed);
+    let message = panic_message(|| assert_checks!(&config, "suite preconditions"));
+    assert!(message.starts_with("suite preconditions: 1 of 2 connectivity checks failed\n    cache ("),
+	    "{}", message);
+    assert!(message.contains(&closed), "{}", message);
+    assert!(!message.contains("broker"), "{}", message);
+}
+
+#[test]
+fn config_file_is_checked() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let path = std::env::temp_dir().join(format!("connchk-testing-{}.toml", std::process::id()));
+    std::fs::write(&path, format!("[[target]]\ndesc = \"api\"\naddr = \"{}\"\nkind = \"Tcp\"\n",
+				  listener.local_addr().unwrap())).unwrap();
+    assert_checks!(file = path.to_str().unwrap());
+    drop(listener);
+    let message = panic_message(|| assert_checks!(file = path.to_str().unwrap()));
+    std::fs::remove_file(&path).unwrap();
+    assert!(message.starts_with("1 of 1 connectivity checks failed\n    api ("), "{}", message);
+}
+
+#[test]
+fn invalid_config_is_reported() {
+    let message = panic_message(|| assert_checks!("[[target]]\ndesc = 1"));
+    assert!(message.starts_with("connectivity checks could not run: "), "{}", message);
+}